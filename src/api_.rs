@@ -1,14 +1,25 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_multipart::Multipart;
-use futures::{StreamExt, TryStreamExt};
+use futures::TryStreamExt;
 use uuid::Uuid;
-use std::{error::Error, io::Write, sync::PoisonError};
+use std::{io::Write, sync::PoisonError};
 use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
-use anyhow::Result;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 
+use crate::auth::AuthSystem;
+use crate::decommission::{run_drain, Drain, DrainState};
+use crate::ipfs::{Cid, CidLookup};
+use crate::namespace::{Namespace, NamespaceError, ObjectLocation, DEFAULT_MAX_KEYS};
 use crate::node::StorageNode;
+use crate::pbe_::{AccessType, Permission, ProgrammableBusinessEngine, QuotaError};
+use crate::placement::{select_storage_node, strategy_from_env, FirstFit, PlacementStrategy};
+use crate::proof_of_spacetime::{answer, mode_from_env, periodic_check, PostMode, Prover, ProverFuture, ProverRequest, Verifier};
+use crate::rebalance::{file_stem, rebalance_nodes, ChunkMove, Rebalancer, DEFAULT_BUDGET};
+use crate::reputation::Reputation;
+use crate::storage_api_p2p::{cid_lookup_from_env, storage_api_from_env, wait_for_peers, StorageAPI};
 
 // Request/Response structs
 #[derive(Deserialize)]
@@ -25,16 +36,210 @@ struct NodeResponse {
     health_status: bool,
 }
 
+// Optional byte range of a chunk read from a peer
+#[derive(Deserialize)]
+struct ChunkRangeQuery {
+    offset: Option<u64>,
+    length: Option<u64>,
+}
+
+// Chunks `start..end` of a file, as indexes in file order
+#[derive(Deserialize)]
+struct ChunkIndexRange {
+    start: usize,
+    end: usize,
+}
+
+// How long the p2p node is watched for peers after startup
+const PEER_WAIT_SECONDS: u64 = 20;
+// Buckets and object keys, kept across restarts
+const NAMESPACE_PATH: &str = "storage/api/namespace.json";
+// Where the server keeps the identity it reads the cluster's metadata with
+// when it runs no p2p node of its own
+const API_CLIENT_DIR: &str = "storage/api";
+
+#[derive(Deserialize)]
+struct CreateBucketRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ListObjectsQuery {
+    prefix: Option<String>,
+    delimiter: Option<String>,
+    marker: Option<String>,
+    max_keys: Option<usize>,
+}
+
+// `from` and `to` ending in '/' move everything under that prefix
+#[derive(Deserialize)]
+struct RenameRequest {
+    from: String,
+    to: String,
+    // Moves to another bucket of the same user
+    to_bucket: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StakeRequest {
+    amount: u64,
+}
+
+#[derive(Deserialize)]
+struct CredentialsRequest {
+    user_id: String,
+    password: String,
+}
+
+// Storage bytes each staked token buys
+const TOKEN_RATE: u64 = 1_000_000;
+
 // State management for storage nodes
 pub struct AppState {
     nodes: Mutex<HashMap<String, StorageNode>>,
+    // Scores of the nodes above, from the transfers served here
+    reputation: Arc<Reputation>,
+    // Rebalancer control; run_server rebalances this server's nodes with it
+    rebalancer: Arc<Rebalancer>,
+    // Files the rebalancer moved, by file id, to the node holding them now
+    relocated: Mutex<HashMap<String, String>>,
+    // Placement strategy of the nodes above, followed by the rebalancer and
+    // when the files of a drained node are taken over
+    placement: Arc<dyn PlacementStrategy>,
+    // Decommissions by node id, kept after they end
+    drains: Mutex<HashMap<String, Arc<Drain>>>,
+    // The p2p node run beside the nodes above; the /network routes use it
+    p2p: Option<Arc<StorageAPI>>,
+    // Buckets and keys of the objects stored on the nodes
+    namespace: Namespace,
+    // Resolves CIDs to stored files, from the StorageAPI metadata
+    cids: Option<Arc<dyn CidLookup>>,
+    // Users and their session tokens; uploads and deletes must present one
+    auth: Arc<Mutex<AuthSystem>>,
+    // Stakes and what they are used for; uploads reserve space here first
+    stakes: Arc<Mutex<ProgrammableBusinessEngine>>,
+    // Merkle commitments to the stored files, for proof-of-spacetime challenges
+    post: Arc<Verifier>,
 }
 
 impl AppState {
     pub fn new() -> Self {
+        Self::with_reputation(Arc::new(Reputation::new()))
+    }
+
+    pub fn with_reputation(reputation: Arc<Reputation>) -> Self {
         Self {
             nodes: Mutex::new(HashMap::new()),
+            reputation,
+            rebalancer: Arc::new(Rebalancer::new(DEFAULT_BUDGET)),
+            relocated: Mutex::new(HashMap::new()),
+            placement: Arc::new(FirstFit),
+            drains: Mutex::new(HashMap::new()),
+            p2p: None,
+            namespace: Namespace::new(),
+            cids: None,
+            auth: Arc::new(Mutex::new(AuthSystem::new())),
+            stakes: Arc::new(Mutex::new(ProgrammableBusinessEngine::new(TOKEN_RATE))),
+            post: Arc::new(Verifier::new()),
+        }
+    }
+
+    pub fn with_placement(mut self, placement: Arc<dyn PlacementStrategy>) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: Namespace) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn with_rebalancer(mut self, rebalancer: Arc<Rebalancer>) -> Self {
+        self.rebalancer = rebalancer;
+        self
+    }
+
+    pub fn with_storage_api(mut self, storage_api: Arc<StorageAPI>) -> Self {
+        self.p2p = Some(storage_api);
+        self
+    }
+
+    // Scores the node on a transfer to or from it, as the p2p pipeline does
+    fn record_transfer<T, E>(&self, node_id: &str, started: Instant, result: &Result<T, E>) {
+        self.reputation
+            .record_transfer(node_id, result.is_ok(), result.is_ok().then(|| started.elapsed()));
+    }
+
+    // Node holding the file: the given node, or the node the rebalancer or a
+    // decommission moved it to. A file can move again after a drain, so the
+    // drains are followed until the file is on a node that was not removed,
+    // each at most once in case moves ever point back
+    pub fn with_cid_lookup(mut self, cids: Arc<dyn CidLookup>) -> Self {
+        self.cids = Some(cids);
+        self
+    }
+
+    pub fn with_stakes(mut self, stakes: Arc<Mutex<ProgrammableBusinessEngine>>) -> Self {
+        self.stakes = stakes;
+        self
+    }
+
+    pub fn with_verifier(mut self, verifier: Arc<Verifier>) -> Self {
+        self.post = verifier;
+        self
+    }
+
+    // Records a file as the node stored it, so the node can be challenged on it
+    fn commit_stored(&self, node: &StorageNode, stored_name: &str) {
+        let result = node.read_stored_file(stored_name).map_err(|e| e.to_string()).and_then(|bytes| {
+            self.post
+                .track(&node.node_id, stored_name, &bytes, || node.tag_key(stored_name).map_err(|e| e.to_string()))
+        });
+        if let Err(e) = result {
+            println!("Warning: Failed to record {} for proof of spacetime: {}", stored_name, e);
+        }
+    }
+
+    // Stored files carry proof-of-retrievability tags when they are audited with them
+    fn tags_files(&self) -> bool {
+        self.post.mode() == PostMode::Tags
+    }
+
+    // Node holding the file: the given node, or the node a decommission moved it to
+    fn resolve_node(&self, node_id: &str, file_id: &str) -> String {
+        let mut node_id = self.relocated.lock().unwrap().get(file_id).cloned().unwrap_or_else(|| node_id.to_string());
+        let drains = self.drains.lock().unwrap();
+        for _ in 0..drains.len() {
+            match drains.get(&node_id).and_then(|drain| drain.location_of(file_id)) {
+                Some(next) => node_id = next,
+                None => break,
+            }
         }
+        node_id
+    }
+
+    // Points the file at the node the rebalancer copied it to; called with
+    // the node lock held, so deletes find the file where it is
+    fn relocate(&self, chunk_move: &ChunkMove) {
+        self.relocated.lock().unwrap().insert(chunk_move.file_id.clone(), chunk_move.to.clone());
+        self.namespace.relocate(&chunk_move.file_id, &chunk_move.to);
+        self.post.reassign(&chunk_move.from, &chunk_move.to, &chunk_move.chunk_id);
+    }
+}
+
+// The nodes of this server are directories it manages, so they answer
+// challenges here, from the node's own directory and off the async runtime.
+// Nodes of the p2p network answer over their chunk service instead.
+impl Prover for AppState {
+    fn prove(&self, node_id: &str, request: ProverRequest) -> ProverFuture<'_> {
+        let storage_path = self.nodes.lock().unwrap().get(node_id).map(|node| node.storage_path.clone());
+        let node_id = node_id.to_string();
+        Box::pin(async move {
+            let storage_path = storage_path.ok_or_else(|| format!("Node {} not found", node_id))?;
+            tokio::task::spawn_blocking(move || answer(std::path::Path::new(&storage_path), &request))
+                .await
+                .map_err(|e| e.to_string())
+        })
     }
 }
 
@@ -42,12 +247,28 @@ impl AppState {
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api/v1")
+            .service(
+                web::scope("/auth")
+                    .route("/register", web::post().to(register))
+                    .route("/login", web::post().to(login))
+                    .route("/logout", web::post().to(logout))
+            )
+            .service(
+                web::scope("/usage")
+                    .route("", web::get().to(storage_usage))
+            )
+            .service(
+                web::scope("/stakes")
+                    .route("", web::post().to(stake_tokens))
+            )
             .service(
                 web::scope("/nodes")
                     .route("", web::post().to(create_node))
                     .route("/{node_id}", web::get().to(get_node))
                     .route("/{node_id}", web::delete().to(delete_node))
                     .route("/{node_id}/health", web::get().to(check_node_health))
+                    .route("/{node_id}/drain", web::get().to(get_drain))
+                    .route("/{node_id}/drain/cancel", web::post().to(cancel_drain))
             )
             .service(
                 web::scope("/files")
@@ -55,6 +276,62 @@ pub fn config(cfg: &mut web::ServiceConfig) {
                     .route("/download/{node_id}/{file_id}", web::get().to(download_file))
                     .route("/{node_id}/{file_id}", web::delete().to(delete_file))
             )
+            .service(
+                web::scope("/buckets")
+                    .route("", web::post().to(create_bucket))
+                    .route("", web::get().to(list_buckets))
+                    .route("/{bucket}", web::get().to(list_objects))
+                    .route("/{bucket}", web::delete().to(delete_bucket))
+                    .route("/{bucket}/rename", web::post().to(rename_objects))
+                    .route("/{bucket}/objects/{key:.*}", web::put().to(put_object))
+                    .route("/{bucket}/objects/{key:.*}", web::get().to(get_object))
+                    .route("/{bucket}/objects/{key:.*}", web::delete().to(delete_object))
+            )
+            .service(
+                web::scope("/cids")
+                    .route("/{cid}", web::get().to(lookup_cid))
+            )
+            .service(
+                web::scope("/reputation")
+                    .route("", web::get().to(list_reputation))
+                    .route("/{node_id}", web::get().to(get_reputation))
+            )
+            .service(
+                web::scope("/rebalance")
+                    .route("", web::get().to(rebalance_status))
+                    .route("/pause", web::post().to(pause_rebalance))
+                    .route("/resume", web::post().to(resume_rebalance))
+            )
+            .service(
+                web::scope("/network")
+                    .route("/nodes", web::get().to(list_network_nodes))
+                    .route("/nodes/{node_id}/chunks", web::get().to(list_node_chunks))
+                    .route("/peers", web::get().to(list_network_peers))
+                    .route("/reputation", web::get().to(list_peer_scores))
+                    .route("/placement/violations", web::get().to(list_placement_violations))
+                    .route("/integrity", web::get().to(list_node_integrity))
+                    .route("/integrity/repairs", web::get().to(list_pending_repairs))
+                    .route("/rebalance", web::get().to(network_rebalance_status))
+                    .route("/rebalance/pause", web::post().to(pause_network_rebalance))
+                    .route("/rebalance/resume", web::post().to(resume_network_rebalance))
+                    .route("/nodes/{node_id}/chunks/{chunk_id}", web::get().to(read_node_chunk))
+                    .route("/nodes/{node_id}/chunks/{chunk_id}", web::head().to(check_node_chunk))
+                    .route("/chunks/{hash}", web::get().to(locate_network_chunk))
+                    .route("/files", web::get().to(list_network_files))
+                    .route("/files", web::post().to(upload_network_file))
+                    .route("/files/{file_id}", web::get().to(download_network_file))
+                    .route("/files/{file_id}", web::delete().to(delete_network_file))
+                    .route("/files/{file_id}/take", web::post().to(take_network_file))
+                    .route("/files/{file_id}/car", web::get().to(export_network_car))
+                    .route("/car", web::post().to(import_network_car))
+                    .route("/files/{file_id}/permissions", web::get().to(list_network_permissions))
+                    .route("/files/{file_id}/permissions", web::post().to(grant_network_permission))
+                    .route("/files/{file_id}/permissions/{user_id}", web::delete().to(revoke_network_permission))
+                    .route("/quotas/{owner}", web::get().to(get_network_quota))
+                    .route("/metadata", web::get().to(network_metadata_status))
+                    .route("/manifests/{content_id}", web::get().to(resolve_network_manifest))
+                    .route("/manifests/{content_id}/chunks", web::get().to(download_network_range))
+            )
             .service(
                 web::scope("/test")
                     .route("", web::get().to(test_endpoint))
@@ -68,11 +345,11 @@ async fn create_node(
     data: web::Data<AppState>,
     req: web::Json<CreateNodeRequest>,
 ) -> impl Responder {
-    let mut nodes = data.nodes.lock().unwrap();
-    
     match StorageNode::new(req.node_id.clone(), req.total_space).await {
         Ok(node) => {
-            nodes.insert(req.node_id.clone(), node);
+            data.nodes.lock().unwrap().insert(req.node_id.clone(), node);
+            // A new node starts empty; move files to it now
+            data.rebalancer.trigger();
             HttpResponse::Created().json(NodeResponse {
                 node_id: req.node_id.clone(),
                 total_space: req.total_space,
@@ -101,16 +378,61 @@ async fn get_node(
     }
 }
 
+// Decommissions the node: it is drained first and only removed once its
+// files are copied to other nodes and verified
 async fn delete_node(
     data: web::Data<AppState>,
     node_id: web::Path<String>,
 ) -> impl Responder {
-    let mut nodes = data.nodes.lock().unwrap();
-    
-    if nodes.remove(&node_id.to_string()).is_some() {
-        HttpResponse::Ok().body("Node deleted successfully")
-    } else {
-        HttpResponse::NotFound().body("Node not found")
+    let node_id = node_id.into_inner();
+    match data.nodes.lock().unwrap().get_mut(&node_id) {
+        // Marked here already so uploads are refused from this response on
+        Some(node) if !node.draining => node.draining = true,
+        Some(_) => return HttpResponse::Conflict().body("Node is already being drained"),
+        None => return HttpResponse::NotFound().body("Node not found"),
+    }
+
+    let drain = Arc::new(Drain::new(&node_id));
+    data.drains.lock().unwrap().insert(node_id, drain.clone());
+    let state = data.clone();
+    let task_drain = drain.clone();
+    actix_web::rt::spawn(async move {
+        run_drain(&state.nodes, &task_drain, &*state.placement).await;
+        let progress = task_drain.progress();
+        if progress.state == DrainState::Decommissioned {
+            for moved in &progress.moves {
+                // Recorded like the rebalancer's moves, so a later drain of
+                // the new node is followed from here
+                state.relocated.lock().unwrap().insert(file_stem(&moved.file), moved.to.clone());
+                state.post.reassign(&progress.node_id, &moved.to, &moved.file);
+                state.namespace.relocate(&file_stem(&moved.file), &moved.to);
+            }
+        }
+    });
+    HttpResponse::Accepted().json(drain.progress())
+}
+
+async fn get_drain(
+    data: web::Data<AppState>,
+    node_id: web::Path<String>,
+) -> impl Responder {
+    match data.drains.lock().unwrap().get(node_id.as_str()) {
+        Some(drain) => HttpResponse::Ok().json(drain.progress()),
+        None => HttpResponse::NotFound().body("No drain for node"),
+    }
+}
+
+async fn cancel_drain(
+    data: web::Data<AppState>,
+    node_id: web::Path<String>,
+) -> impl Responder {
+    let drain = match data.drains.lock().unwrap().get(node_id.as_str()) {
+        Some(drain) => drain.clone(),
+        None => return HttpResponse::NotFound().body("No drain for node"),
+    };
+    match drain.cancel() {
+        Ok(()) => HttpResponse::Accepted().json(drain.progress()),
+        Err(e) => HttpResponse::Conflict().body(e),
     }
 }
 
@@ -118,202 +440,1355 @@ async fn check_node_health(
     data: web::Data<AppState>,
     node_id: web::Path<String>,
 ) -> impl Responder {
-    let mut nodes = data.nodes.lock().unwrap();
-    
-    if let Some(node) = nodes.get_mut(&node_id.to_string()) {
-        match node.update_health_status().await {
-            Ok(_) => HttpResponse::Ok().json(node.health_status),
-            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    // The check runs on a copy so the node map is not locked meanwhile
+    let node = data.nodes.lock().unwrap().get(node_id.as_str()).cloned();
+    let Some(mut node) = node else {
+        return HttpResponse::NotFound().body("Node not found");
+    };
+    match node.update_health_status().await {
+        Ok(_) => {
+            if let Some(stored) = data.nodes.lock().unwrap().get_mut(node_id.as_str()) {
+                stored.health_status = node.health_status;
+                stored.last_checked = node.last_checked;
+            }
+            HttpResponse::Ok().json(node.health_status)
         }
-    } else {
-        HttpResponse::NotFound().body("Node not found")
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
+// Reputation Handlers
+async fn list_reputation(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.reputation.scores())
+}
 
-use std::fs::File;
+async fn get_reputation(
+    data: web::Data<AppState>,
+    node_id: web::Path<String>,
+) -> impl Responder {
+    match data.reputation.peer_score(node_id.as_str()) {
+        Some(score) => HttpResponse::Ok().json(score),
+        None => HttpResponse::NotFound().body("No reputation recorded for node"),
+    }
+}
 
-fn handle_poison_error<T>(_: PoisonError<T>) -> HttpResponse {
-    HttpResponse::InternalServerError().body("Internal server error")
+// CID Handlers
+async fn lookup_cid(
+    data: web::Data<AppState>,
+    cid: web::Path<String>,
+) -> impl Responder {
+    let cid: Cid = match cid.parse() {
+        Ok(cid) => cid,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid CID: {}", e)),
+    };
+    let Some(cids) = &data.cids else {
+        return HttpResponse::ServiceUnavailable().body("CID lookups are not available");
+    };
+    match cids.lookup(&cid).await {
+        Ok(records) if records.is_empty() => HttpResponse::NotFound().body("No file holds this CID"),
+        Ok(records) => HttpResponse::Ok().json(records),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
 }
 
-async fn upload_file(
+// Rebalance Handlers
+async fn rebalance_status(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.rebalancer.status())
+}
+
+async fn pause_rebalance(data: web::Data<AppState>) -> impl Responder {
+    data.rebalancer.pause();
+    HttpResponse::Ok().json(data.rebalancer.status())
+}
+
+async fn resume_rebalance(data: web::Data<AppState>) -> impl Responder {
+    data.rebalancer.resume();
+    HttpResponse::Ok().json(data.rebalancer.status())
+}
+
+// Auth Handlers
+// Uploads, deletes and buckets belong to the user in the X-User-Id header,
+// who proves it with the token from login as `Authorization: Bearer <token>`
+fn authenticated_user(data: &AppState, req: &HttpRequest) -> Option<String> {
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let user = header("X-User-Id").filter(|user| !user.is_empty())?;
+    let token = header("Authorization").and_then(|value| value.strip_prefix("Bearer "))?;
+    data.auth.lock().unwrap().validate_token(user, token).then(|| user.to_string())
+}
+
+fn unauthenticated() -> HttpResponse {
+    HttpResponse::Unauthorized().body("Missing or invalid X-User-Id and Authorization headers")
+}
+
+async fn register(
     data: web::Data<AppState>,
-    node_id: web::Path<String>,
-    mut payload: Multipart,
-) -> HttpResponse {
-    println!("Starting file upload for node: {}", node_id);
-    
-    let mut nodes = match data.nodes.lock() {
-        Ok(guard) => guard,
-        Err(poison_err) => return handle_poison_error(poison_err),
+    body: web::Json<CredentialsRequest>,
+) -> impl Responder {
+    if data.auth.lock().unwrap().register_user(&body.user_id, &body.password) {
+        HttpResponse::Created().finish()
+    } else {
+        HttpResponse::Conflict().body("User already exists")
+    }
+}
+
+async fn login(
+    data: web::Data<AppState>,
+    body: web::Json<CredentialsRequest>,
+) -> impl Responder {
+    match data.auth.lock().unwrap().login(&body.user_id, &body.password) {
+        Some(token) => HttpResponse::Ok().json(serde_json::json!({ "user_id": body.user_id, "token": token })),
+        None => HttpResponse::Unauthorized().body("Invalid user id or password"),
+    }
+}
+
+// Ends the session of the authenticated user; their token stops working
+async fn logout(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
     };
+    data.auth.lock().unwrap().logout(&user);
+    HttpResponse::NoContent().finish()
+}
 
-    let node = match nodes.get_mut(node_id.as_str()) {
-        Some(n) => n,
-        None => {
-            println!("Node not found: {}", node_id);
-            return HttpResponse::NotFound().body("Node not found");
-        }
+// Stake Handlers
+// Stakes come from STAKES at startup, from this endpoint, or from the staking
+// contract with `update_user_storage_limit` on the engine shared through
+// `with_stakes`. A new stake replaces the user's previous one.
+async fn stake_tokens(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: web::Json<StakeRequest>,
+) -> impl Responder {
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
     };
+    if body.amount == 0 {
+        return HttpResponse::BadRequest().body("A stake must be at least one token");
+    }
+    match data.stakes.lock().unwrap().stake_tokens(&user, body.amount) {
+        Ok(token) => HttpResponse::Created().json(token),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
 
-    let temp_dir = "temp_uploads";
-    if let Err(e) = std::fs::create_dir_all(temp_dir) {
-        println!("Error creating temp directory: {}", e);
-        return HttpResponse::InternalServerError().body(format!("Failed to create temp directory: {}", e));
+async fn storage_usage(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    match authenticated_user(&data, &req) {
+        Some(user) => HttpResponse::Ok().json(data.stakes.lock().unwrap().storage_usage(&user)),
+        None => unauthenticated(),
     }
+}
 
-    let file_future = async {
-        // Dosyanın var olup olmadığını kontrol et
-        if let Some(mut field) = payload.try_next().await.unwrap_or(None) {
-            let content_disposition = field.content_disposition();
-            let filename = match content_disposition.as_ref().and_then(|cd| cd.get_filename()) {
-                Some(name) => name.to_string(),
-                None => {
-                    println!("No filename in field");
-                    return Err("No filename in field".to_string());
-                }
-            };
-            println!("Processing file: {}", filename);
-
-            let temp_filepath = format!("{}/{}_{}", temp_dir, Uuid::new_v4(), filename);
-            println!("Temp file path: {}", temp_filepath);
-
-            let temp_filepath_clone = temp_filepath.clone();
-            let file_result = web::block(move || std::fs::File::create(temp_filepath_clone)).await;
-            let mut temp_file = match file_result {
-                Ok(Ok(file)) => file,
-                Ok(Err(e)) => {
-                    println!("Failed to create temp file: {}", e);
-                    return Err(format!("Failed to create temp file: {}", e));
-                }
-                Err(e) => {
-                    println!("Block error: {}", e);
-                    return Err(format!("Block error: {}", e));
-                }
-            };
+// The size an upload declares, reserved against the stake before its body is read
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(actix_web::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
 
-            while let Ok(Some(chunk)) = field.try_next().await {
-                let mut temp_file_clone = temp_file.try_clone().map_err(|e| format!("Failed to clone temp file: {}", e))?;
-                if let Err(e) = web::block(move || {
-                    temp_file_clone.write_all(&chunk)
-                })
-                .await
-                {
-                    println!("Error writing chunk: {}", e);
-                    return Err(format!("Failed to write file chunk: {}", e));
-                }
-            }
+fn length_required() -> HttpResponse {
+    HttpResponse::LengthRequired().body("Uploads must declare their Content-Length")
+}
 
+// No active stake is 402 Payment Required, too little room left 413 Payload Too Large
+fn quota_error(e: QuotaError) -> HttpResponse {
+    println!("Upload rejected: {}", e);
+    match e {
+        QuotaError::NoStake { .. } | QuotaError::StakeExpired { .. } => HttpResponse::PaymentRequired().json(e),
+        QuotaError::QuotaExceeded { .. } => HttpResponse::PayloadTooLarge().json(e),
+    }
+}
 
-            let unique_filename: String = format!("{}_{}", Uuid::new_v4(), filename.split('.').next().unwrap_or("").replace(|c: char| !c.is_alphanumeric(), "_"));
-            match node.store_file(&unique_filename, &temp_filepath).await {
-                Ok(_) => {
-                    println!("File stored successfully");
-                    if let Err(e) = std::fs::remove_file(&temp_filepath) {
-                        println!("Warning: Failed to remove temp file: {}", e);
-                    }
-                    return Ok(filename);
-                }
-                Err(e) => {
-                    println!("Error storing file: {}", e);
-                    if let Err(cleanup_err) = std::fs::remove_file(&temp_filepath) {
-                        println!("Warning: Failed to remove temp file: {}", cleanup_err);
-                    }
-                    return Err(format!("Failed to store file: {}", e));
-                }
-            }
-        } else {
-            println!("No file found in request");
-            return Err("No file found in request".to_string());
-        }
-    };
 
+// Network Handlers
+// The p2p network seen from the node this server runs; without one every
+// route answers 503
+fn no_p2p_node() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body("This server runs no p2p node; set P2P_ADDR to start one")
+}
 
-    match file_future.await {
-        Ok(unique_filename) => HttpResponse::Ok().body(format!("File '{}' uploaded successfully", unique_filename)),
-        Err(e) => HttpResponse::BadRequest().body(e),
+async fn list_network_nodes(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.list_nodes().await {
+        Ok(nodes) => HttpResponse::Ok().json(nodes),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
+// Alive, suspect or dead, as the failure detector sees each peer
+async fn list_network_peers(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    HttpResponse::Ok().json(storage_api.list_peer_states().await)
+}
 
+// Reputation of the peers, from the transfers and heartbeats of the p2p node
+async fn list_peer_scores(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    HttpResponse::Ok().json(storage_api.list_peer_scores())
+}
 
+// Files that break the anti-affinity policy; empty when no policy is set
+async fn list_placement_violations(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    HttpResponse::Ok().json(storage_api.placement_violations().await)
+}
 
+// Verified and corrupt chunk reads per peer, most corrupt first
+async fn list_node_integrity(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    HttpResponse::Ok().json(storage_api.list_node_integrity())
+}
 
-async fn download_file(
-    data: web::Data<AppState>,
-    path: web::Path<(String, String)>,
-) -> impl Responder {
-    let (node_id, file_id) = path.into_inner();
-    let mut nodes = data.nodes.lock().unwrap();
-    
-    let node = match nodes.get_mut(&node_id) {
-        Some(n) => n,
-        None => return HttpResponse::NotFound().body("Node not found"),
+// Corrupt copies waiting to be overwritten from an intact holder
+async fn list_pending_repairs(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
     };
+    HttpResponse::Ok().json(storage_api.pending_repairs())
+}
 
-    // Create temporary directory for download
-    let temp_dir = "temp_downloads";
-    std::fs::create_dir_all(temp_dir).unwrap();
-    let temp_path = format!("{}/{}", temp_dir, file_id);
+// The p2p node's rebalancer, which moves chunks between the peers
+async fn network_rebalance_status(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    HttpResponse::Ok().json(storage_api.rebalancer().status())
+}
 
-    match node.retrieve_file(&file_id, &temp_path).await {
-        Ok(_) => {
-            // Read the file contents
-            println!("Dosya başarıyla alındı: {}", file_id);
-            match std::fs::read(&temp_path) {
-                Ok(contents) => {
-                    std::fs::remove_file(&temp_path).ok(); // Clean up
-                    HttpResponse::Ok()
-                        .content_type("application/octet-stream")
-                        .body(contents)
-                }
-                Err(e) => {
-                    std::fs::remove_file(&temp_path).ok(); // Clean up
-                    HttpResponse::InternalServerError().body(e.to_string())
-                }
-            }
-        }
-        Err(e) =>{        println!("Dosya alma işlemi sırasında hata oluştu: {}", e);
-            HttpResponse::InternalServerError().body(e.to_string())} 
+async fn pause_network_rebalance(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let rebalancer = storage_api.rebalancer();
+    rebalancer.pause();
+    HttpResponse::Ok().json(rebalancer.status())
+}
+
+async fn resume_network_rebalance(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let rebalancer = storage_api.rebalancer();
+    rebalancer.resume();
+    HttpResponse::Ok().json(rebalancer.status())
+}
+
+// Chunks held by a peer, read from its chunk service
+async fn list_node_chunks(
+    data: web::Data<AppState>,
+    node_id: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.list_node_chunks(&node_id).await {
+        Ok(chunks) => HttpResponse::Ok().json(chunks),
+        Err(e) => HttpResponse::BadGateway().body(e.to_string()),
     }
 }
 
-async fn delete_file(
+// `offset` alone reads to the end of the chunk
+async fn read_node_chunk(
     data: web::Data<AppState>,
     path: web::Path<(String, String)>,
+    query: web::Query<ChunkRangeQuery>,
 ) -> impl Responder {
-    let (node_id, file_id) = path.into_inner();
-    let mut nodes = data.nodes.lock().unwrap();
-    
-    let node = match nodes.get_mut(&node_id) {
-        Some(n) => n,
-        None => return HttpResponse::NotFound().body("Node not found"),
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let (node_id, chunk_id) = path.into_inner();
+    let range = match (query.offset, query.length) {
+        (None, None) => None,
+        (offset, length) => Some((offset.unwrap_or(0), length.unwrap_or(u64::MAX))),
     };
+    match storage_api.read_node_chunk(&node_id, &chunk_id, range).await {
+        Ok(contents) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(contents),
+        Err(e) => HttpResponse::BadGateway().body(e.to_string()),
+    }
+}
 
-    match node.delete_file(&file_id) {
-        Ok(_) => HttpResponse::Ok().body("File deleted successfully"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+async fn check_node_chunk(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let (node_id, chunk_id) = path.into_inner();
+    match storage_api.node_chunk_size(&node_id, &chunk_id).await {
+        Ok(Some(size)) => HttpResponse::Ok()
+            .insert_header(("X-Chunk-Size", size.to_string()))
+            .finish(),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::BadGateway().body(e.to_string()),
     }
 }
 
-async fn test_endpoint() -> HttpResponse {
-    HttpResponse::Ok().body("Sunucu çalışıyor!")
+// Peers that announced the chunk hash to the DHT
+async fn locate_network_chunk(
+    data: web::Data<AppState>,
+    hash: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.locate_chunk(&hash).await {
+        Ok(providers) => HttpResponse::Ok().json(providers),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
 }
 
+async fn list_network_files(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.list_files().await {
+        Ok(files) => HttpResponse::Ok().json(files),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
 
-// Server Configuration
-pub async fn run_server() -> std::io::Result<()> {
-    let app_state = web::Data::new(AppState {
-        nodes: Mutex::new(HashMap::new()),
-    });
+// Uploaded for the authenticated user, within their stake
+async fn upload_network_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+) -> HttpResponse {
+    let Some(storage_api) = data.p2p.clone() else {
+        return no_p2p_node();
+    };
+    let owner = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(app_state.clone())
-            .configure(config)
+    // upload_file names the file after its path, so it is saved under its
+    // own name in a directory of its own
+    let upload_dir = Uuid::new_v4().to_string();
+    let upload_path = std::path::Path::new("temp_uploads").join(&upload_dir);
+    if let Err(e) = std::fs::create_dir_all(&upload_path) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    let saved = save_upload(&mut payload, |filename| {
+        let name = std::path::Path::new(filename)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "upload".to_string());
+        format!("{}/{}", upload_dir, name)
+    })
+    .await;
+    let result = match saved {
+        Ok((_, temp_filepath)) => storage_api.upload_file(&temp_filepath, &owner, "").await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = std::fs::remove_dir_all(&upload_path) {
+        println!("Warning: Failed to remove temp upload: {}", e);
+    }
+    match result {
+        Ok(file_id) => HttpResponse::Created().json(serde_json::json!({ "file_id": file_id })),
+        Err(e) => network_upload_error(e),
+    }
+}
+
+// A file that does not fit its owner's stake is refused like a local upload
+fn network_upload_error(e: Box<dyn std::error::Error>) -> HttpResponse {
+    match e.downcast::<QuotaError>() {
+        Ok(e) => quota_error(*e),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// The decrypted file, streamed as its chunks are restored
+async fn download_network_file(
+    data: web::Data<AppState>,
+    file_id: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.download_stream(&file_id).await {
+        Ok(stream) => HttpResponse::Ok().content_type("application/octet-stream").streaming(stream),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// The file as a CARv1 archive, for IPFS
+async fn export_network_car(
+    data: web::Data<AppState>,
+    file_id: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let temp_dir = format!("temp_downloads/{}", Uuid::new_v4());
+    if let Err(e) = std::fs::create_dir_all(&temp_dir) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    let destination = format!("{}/{}.car", temp_dir, file_id);
+    let result = match storage_api.export_car(&file_id, &destination).await {
+        Ok(_) => std::fs::read(&destination).map_err(|e| e.into()),
+        Err(e) => Err(e),
+    };
+    std::fs::remove_dir_all(&temp_dir).ok(); // Clean up
+    match result {
+        Ok(contents) => HttpResponse::Ok().content_type("application/vnd.ipld.car").body(contents),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// Stores the blocks of a multipart CARv1 archive as a new file of the
+// authenticated user
+async fn import_network_car(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+) -> HttpResponse {
+    let Some(storage_api) = data.p2p.clone() else {
+        return no_p2p_node();
+    };
+    let owner = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+
+    // import_car names the file after the archive, saved like an upload
+    let upload_dir = Uuid::new_v4().to_string();
+    let upload_path = std::path::Path::new("temp_uploads").join(&upload_dir);
+    if let Err(e) = std::fs::create_dir_all(&upload_path) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    let saved = save_upload(&mut payload, |filename| {
+        let name = std::path::Path::new(filename)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "import.car".to_string());
+        format!("{}/{}", upload_dir, name)
+    })
+    .await;
+    let result = match saved {
+        Ok((_, temp_filepath)) => storage_api.import_car(&temp_filepath, &owner).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = std::fs::remove_dir_all(&upload_path) {
+        println!("Warning: Failed to remove temp upload: {}", e);
+    }
+    match result {
+        Ok(file_id) => HttpResponse::Created().json(serde_json::json!({ "file_id": file_id })),
+        Err(e) => network_upload_error(e),
+    }
+}
+
+// Deleted for the owner or a user granted delete access
+async fn delete_network_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    file_id: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    match storage_api.delete_file(&file_id, &user).await {
+        Ok(message) => HttpResponse::Ok().body(message),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// Downloads the file and removes it from the network once it was restored
+async fn take_network_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    file_id: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    let temp_dir = format!("temp_downloads/{}", Uuid::new_v4());
+    if let Err(e) = std::fs::create_dir_all(&temp_dir) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    let destination = format!("{}/{}", temp_dir, file_id);
+    let result = match storage_api.download_file_and_remove(&file_id, &user, &destination, "").await {
+        Ok(_) => std::fs::read(&destination).map_err(|e| e.into()),
+        Err(e) => Err(e),
+    };
+    std::fs::remove_dir_all(&temp_dir).ok(); // Clean up
+    match result {
+        Ok(contents) => HttpResponse::Ok().content_type("application/octet-stream").body(contents),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+async fn list_network_permissions(
+    data: web::Data<AppState>,
+    file_id: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.metadata().permissions(&file_id).await {
+        Ok(permissions) => HttpResponse::Ok().json(permissions),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// Granted by the owner or a user with admin access
+async fn grant_network_permission(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    file_id: web::Path<String>,
+    permission: web::Json<Permission>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    match storage_api.metadata().grant(&file_id, permission.into_inner(), &user).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+async fn revoke_network_permission(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    let (file_id, user_id) = path.into_inner();
+    match storage_api.metadata().revoke(&file_id, &user_id, &user).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("User {} holds no permission on file {}", user_id, file_id)),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+async fn get_network_quota(
+    data: web::Data<AppState>,
+    owner: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.metadata().quota(&owner).await {
+        Ok(quota) => HttpResponse::Ok().json(quota),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// The data chunks of a file, resolved from its content id alone
+async fn resolve_network_manifest(
+    data: web::Data<AppState>,
+    content_id: web::Path<String>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.resolve_manifest(&content_id).await {
+        Ok(chunks) => HttpResponse::Ok().json(chunks),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+// Part of a file, decrypted, with every chunk verified against the content id
+async fn download_network_range(
+    data: web::Data<AppState>,
+    content_id: web::Path<String>,
+    range: web::Query<ChunkIndexRange>,
+) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    let temp_dir = format!("temp_downloads/{}", Uuid::new_v4());
+    if let Err(e) = std::fs::create_dir_all(&temp_dir) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    let destination = format!("{}/range", temp_dir);
+    let result = match storage_api.download_range(&content_id, range.start..range.end, &destination).await {
+        Ok(_) => std::fs::read(&destination).map_err(|e| e.into()),
+        Err(e) => Err(e),
+    };
+    std::fs::remove_dir_all(&temp_dir).ok(); // Clean up
+    match result {
+        Ok(contents) => HttpResponse::Ok().content_type("application/octet-stream").body(contents),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// Raft role, term and leader of this node's metadata replica; 404 when the
+// node only talks to the metadata nodes
+async fn network_metadata_status(data: web::Data<AppState>) -> impl Responder {
+    let Some(storage_api) = &data.p2p else {
+        return no_p2p_node();
+    };
+    match storage_api.metadata().status().await {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => HttpResponse::NotFound().body("This node is not a metadata node"),
+    }
+}
+
+fn handle_poison_error<T>(_: PoisonError<T>) -> HttpResponse {
+    HttpResponse::InternalServerError().body("Internal server error")
+}
+
+async fn upload_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    node_id: web::Path<String>,
+    mut payload: Multipart,
+) -> HttpResponse {
+    println!("Starting file upload for node: {}", node_id);
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    let declared_size = match content_length(&req) {
+        Some(size) => size,
+        None => return length_required(),
+    };
+
+    // The node is cloned out of the lock for the upload and written back
+    // once the file is stored
+    let mut node = match data.nodes.lock() {
+        Ok(nodes) => match nodes.get(node_id.as_str()) {
+            Some(n) => n.clone(),
+            None => {
+                println!("Node not found: {}", node_id);
+                return HttpResponse::NotFound().body("Node not found");
+            }
+        },
+        Err(poison_err) => return handle_poison_error(poison_err),
+    };
+    if node.draining {
+        return HttpResponse::Conflict().body("Node is being drained and takes no new files");
+    }
+
+    // The whole request is reserved; the file entry gets the stored size
+    let upload_id = Uuid::new_v4().to_string();
+    if let Err(e) = data.stakes.lock().unwrap().reserve_storage(&user, &upload_id, declared_size) {
+        return quota_error(e);
+    }
+
+    let file_future = async {
+        let (filename, temp_filepath) =
+            save_upload(&mut payload, |filename| format!("{}_{}", Uuid::new_v4(), filename)).await?;
+
+        let unique_filename: String = format!("{}_{}", Uuid::new_v4(), filename.split('.').next().unwrap_or("").replace(|c: char| !c.is_alphanumeric(), "_"));
+        let size = std::fs::metadata(&temp_filepath).map(|metadata| metadata.len()).unwrap_or(declared_size);
+        let started = Instant::now();
+        let result = node.store_file(&unique_filename, &temp_filepath, data.tags_files()).await;
+        data.record_transfer(&node.node_id, started, &result);
+        match result {
+            Ok(stored_name) => {
+                println!("File stored successfully");
+                if let Ok(mut nodes) = data.nodes.lock() {
+                    nodes.insert(node.node_id.clone(), node.clone());
+                }
+                data.commit_stored(&node, &stored_name);
+                if let Err(e) = std::fs::remove_file(&temp_filepath) {
+                    println!("Warning: Failed to remove temp file: {}", e);
+                }
+                Ok((filename, unique_filename, size))
+            }
+            Err(e) => {
+                println!("Error storing file: {}", e);
+                if let Err(cleanup_err) = std::fs::remove_file(&temp_filepath) {
+                    println!("Warning: Failed to remove temp file: {}", cleanup_err);
+                }
+                Err(format!("Failed to store file: {}", e))
+            }
+        }
+    };
+
+
+    match file_future.await {
+        Ok((filename, file_id, size)) => {
+            if let Err(e) = data.stakes.lock().unwrap().commit_storage(&upload_id, &file_id, node_id.as_str(), size) {
+                println!("Warning: {}", e);
+            }
+            HttpResponse::Ok().body(format!("File '{}' uploaded successfully as {}", filename, file_id))
+        }
+        Err(e) => {
+            data.stakes.lock().unwrap().release_storage(&upload_id);
+            HttpResponse::BadRequest().body(e)
+        }
+    }
+}
+
+// Saves the first multipart field under temp_uploads, named by `temp_name`
+// from the uploaded file name; returns the file name and the saved path
+async fn save_upload<F: FnOnce(&str) -> String>(
+    payload: &mut Multipart,
+    temp_name: F,
+) -> Result<(String, String), String> {
+    let temp_dir = "temp_uploads";
+    if let Err(e) = std::fs::create_dir_all(temp_dir) {
+        println!("Error creating temp directory: {}", e);
+        return Err(format!("Failed to create temp directory: {}", e));
+    }
+
+    // Dosyanın var olup olmadığını kontrol et
+    let mut field = match payload.try_next().await.unwrap_or(None) {
+        Some(field) => field,
+        None => {
+            println!("No file found in request");
+            return Err("No file found in request".to_string());
+        }
+    };
+    let content_disposition = field.content_disposition();
+    let filename = match content_disposition.as_ref().and_then(|cd| cd.get_filename()) {
+        Some(name) => name.to_string(),
+        None => {
+            println!("No filename in field");
+            return Err("No filename in field".to_string());
+        }
+    };
+    println!("Processing file: {}", filename);
+
+    let temp_filepath = format!("{}/{}", temp_dir, temp_name(&filename));
+    println!("Temp file path: {}", temp_filepath);
+
+    let temp_filepath_clone = temp_filepath.clone();
+    let file_result = web::block(move || std::fs::File::create(temp_filepath_clone)).await;
+    let temp_file = match file_result {
+        Ok(Ok(file)) => file,
+        Ok(Err(e)) => {
+            println!("Failed to create temp file: {}", e);
+            return Err(format!("Failed to create temp file: {}", e));
+        }
+        Err(e) => {
+            println!("Block error: {}", e);
+            return Err(format!("Block error: {}", e));
+        }
+    };
+
+    while let Ok(Some(chunk)) = field.try_next().await {
+        let mut temp_file_clone = temp_file.try_clone().map_err(|e| format!("Failed to clone temp file: {}", e))?;
+        if let Err(e) = web::block(move || {
+            temp_file_clone.write_all(&chunk)
+        })
+        .await
+        {
+            println!("Error writing chunk: {}", e);
+            return Err(format!("Failed to write file chunk: {}", e));
+        }
+    }
+    Ok((filename, temp_filepath))
+}
+
+
+
+
+
+async fn download_file(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (node_id, file_id) = path.into_inner();
+    let node_id = data.resolve_node(&node_id, &file_id);
+    let node = data.nodes.lock().unwrap().get(&node_id).cloned();
+    let mut node = match node {
+        Some(n) => n,
+        None => return HttpResponse::NotFound().body("Node not found"),
+    };
+
+    // retrieve_file writes the file into a directory, one per download
+    let temp_dir = format!("temp_downloads/{}", Uuid::new_v4());
+    if let Err(e) = std::fs::create_dir_all(&temp_dir) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+
+    // Only files the node holds count, so unknown ids do not hurt it
+    let held = matches!(node.find_file(&file_id), Ok(Some(_)));
+    let started = Instant::now();
+    let result = node.retrieve_file(&file_id, &temp_dir).await;
+    if held {
+        data.record_transfer(&node_id, started, &result);
+    }
+    let result = result.and_then(|_| Ok(read_retrieved(&temp_dir)?));
+    std::fs::remove_dir_all(&temp_dir).ok(); // Clean up
+    match result {
+        Ok(contents) => {
+            println!("Dosya başarıyla alındı: {}", file_id);
+            HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(contents)
+        }
+        Err(e) =>{        println!("Dosya alma işlemi sırasında hata oluştu: {}", e);
+            HttpResponse::InternalServerError().body(e.to_string())} 
+    }
+}
+
+// The file retrieve_file wrote into `dir`, saved under its stored name
+fn read_retrieved(dir: &str) -> std::io::Result<Vec<u8>> {
+    let entry = std::fs::read_dir(dir)?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Nothing was retrieved"))??;
+    std::fs::read(entry.path())
+}
+
+// Only a user with access to the file may delete it; its bytes go back to
+// the owner's stake
+async fn delete_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (node_id, file_id) = path.into_inner();
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    {
+        let stakes = data.stakes.lock().unwrap();
+        if !stakes.has_file(&file_id) {
+            return HttpResponse::NotFound().body("File not found");
+        }
+        if !stakes.check_access(&user, &file_id, AccessType::Delete) {
+            return HttpResponse::Forbidden().body("Not allowed to delete this file");
+        }
+    }
+    let mut nodes = data.nodes.lock().unwrap();
+    let node_id = data.resolve_node(&node_id, &file_id);
+    let node = match nodes.get_mut(&node_id) {
+        Some(n) => n,
+        None => return HttpResponse::NotFound().body("Node not found"),
+    };
+
+    match node.delete_file(&file_id) {
+        Ok(_) => {
+            data.stakes.lock().unwrap().remove_file_entry(&file_id);
+            data.post.forget(&node_id, &file_id);
+            HttpResponse::Ok().body("File deleted successfully")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// Bucket Handlers
+// Buckets belong to the authenticated user. Objects are addressed by bucket
+// and key; the node holding one is chosen at upload and looked up on every
+// access, so clients never name nodes.
+fn namespace_error(e: NamespaceError) -> HttpResponse {
+    match e {
+        NamespaceError::InvalidBucketName(_) | NamespaceError::InvalidKey(_) => HttpResponse::BadRequest().body(e.to_string()),
+        NamespaceError::NoSuchBucket(_) | NamespaceError::NoSuchKey(_) => HttpResponse::NotFound().body(e.to_string()),
+        NamespaceError::BucketExists(_) | NamespaceError::KeyExists(_) | NamespaceError::BucketNotEmpty(_) => {
+            HttpResponse::Conflict().body(e.to_string())
+        }
+        NamespaceError::Forbidden(_) => HttpResponse::Forbidden().body(e.to_string()),
+    }
+}
+
+// Deletes an object's bytes from the node holding them now and gives them
+// back to the owner's stake
+fn delete_object_data(data: &AppState, location: &ObjectLocation) {
+    data.stakes.lock().unwrap().remove_file_entry(&location.file_id);
+    let node_id = data.resolve_node(&location.node_id, &location.file_id);
+    let result = match data.nodes.lock().unwrap().get_mut(&node_id) {
+        Some(node) => node.delete_file(&location.file_id).map_err(|e| e.to_string()),
+        None => Err("Node not found".to_string()),
+    };
+    data.post.forget(&node_id, &location.file_id);
+    if let Err(e) = result {
+        println!("Warning: Failed to delete file {} from node {}: {}", location.file_id, node_id, e);
+    }
+}
+
+async fn create_bucket(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: web::Json<CreateBucketRequest>,
+) -> impl Responder {
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    match data.namespace.create_bucket(&body.name, &user) {
+        Ok(bucket) => HttpResponse::Created().json(bucket),
+        Err(e) => namespace_error(e),
+    }
+}
+
+async fn list_buckets(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    match authenticated_user(&data, &req) {
+        Some(user) => HttpResponse::Ok().json(data.namespace.buckets(&user)),
+        None => unauthenticated(),
+    }
+}
+
+async fn delete_bucket(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    bucket: web::Path<String>,
+) -> impl Responder {
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    match data.namespace.delete_bucket(&bucket, &user) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => namespace_error(e),
+    }
+}
+
+async fn list_objects(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    bucket: web::Path<String>,
+    query: web::Query<ListObjectsQuery>,
+) -> impl Responder {
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    let listing = data.namespace.list(
+        &bucket,
+        &user,
+        query.prefix.as_deref().unwrap_or(""),
+        query.delimiter.as_deref(),
+        query.marker.as_deref(),
+        query.max_keys.unwrap_or(DEFAULT_MAX_KEYS).clamp(1, DEFAULT_MAX_KEYS),
+    );
+    match listing {
+        Ok(listing) => HttpResponse::Ok().json(listing),
+        Err(e) => namespace_error(e),
+    }
+}
+
+// Renames and moves are metadata only, the bytes stay where they are
+async fn rename_objects(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    bucket: web::Path<String>,
+    body: web::Json<RenameRequest>,
+) -> impl Responder {
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    let to_bucket = body.to_bucket.as_deref().unwrap_or(bucket.as_str());
+    match data.namespace.rename(&user, &bucket, &body.from, to_bucket, &body.to) {
+        Ok(moved) => HttpResponse::Ok().json(serde_json::json!({ "moved": moved })),
+        Err(e) => namespace_error(e),
+    }
+}
+
+// Stores the object on the node the placement strategy picks among the
+// healthy ones, replacing any object under the same key
+async fn put_object(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    mut payload: Multipart,
+) -> impl Responder {
+    let (bucket, key) = path.into_inner();
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    if let Err(e) = data.namespace.bucket(&bucket, &user) {
+        return namespace_error(e);
+    }
+    let declared_size = match content_length(&req) {
+        Some(size) => size,
+        None => return length_required(),
+    };
+
+    // Stored without an extension, the file id alone names the file on the node.
+    // The file id also names the reservation, released on every failure below.
+    let file_id = Uuid::new_v4().to_string();
+    if let Err(e) = data.stakes.lock().unwrap().reserve_storage(&user, &file_id, declared_size) {
+        return quota_error(e);
+    }
+    let release = || {
+        data.stakes.lock().unwrap().release_storage(&file_id);
+    };
+    let temp_filepath = match save_upload(&mut payload, |_| file_id.clone()).await {
+        Ok((_, temp_filepath)) => temp_filepath,
+        Err(e) => {
+            release();
+            return HttpResponse::BadRequest().body(e);
+        }
+    };
+    let size = match std::fs::metadata(&temp_filepath) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            release();
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    };
+
+    let stored = async {
+        // Chosen and cloned under the lock, stored outside it
+        let mut node = {
+            let nodes = data.nodes.lock().unwrap();
+            select_storage_node(&file_id, size, nodes.values(), Some(&data.reputation), data.placement.as_ref())
+                .cloned()
+                .ok_or_else(|| HttpResponse::InsufficientStorage().body("No node has room for the object"))?
+        };
+        let started = Instant::now();
+        let result = node.store_file(&file_id, &temp_filepath, data.tags_files()).await;
+        data.record_transfer(&node.node_id, started, &result);
+        let stored_name =
+            result.map_err(|e| HttpResponse::InternalServerError().body(format!("Failed to store file: {}", e)))?;
+        if let Ok(mut nodes) = data.nodes.lock() {
+            nodes.insert(node.node_id.clone(), node.clone());
+        }
+        data.commit_stored(&node, &stored_name);
+        Ok::<String, HttpResponse>(node.node_id)
+    }
+    .await;
+    if let Err(e) = std::fs::remove_file(&temp_filepath) {
+        println!("Warning: Failed to remove temp file: {}", e);
+    }
+    let node_id = match stored {
+        Ok(node_id) => node_id,
+        Err(response) => {
+            release();
+            return response;
+        }
+    };
+
+    let location = ObjectLocation { node_id, file_id: file_id.clone(), size };
+    match data.namespace.put(&bucket, &key, &user, location.clone()) {
+        Ok(replaced) => {
+            if let Err(e) = data.stakes.lock().unwrap().commit_storage(&file_id, &file_id, &location.node_id, size) {
+                println!("Warning: {}", e);
+            }
+            if let Some(old) = replaced {
+                delete_object_data(&data, &old.location);
+            }
+            println!("Object {}/{} stored on node {}", bucket, key, location.node_id);
+            HttpResponse::Ok().json(data.namespace.get(&bucket, &key, &user).ok())
+        }
+        Err(e) => {
+            // The bucket went away while the bytes were being stored
+            delete_object_data(&data, &location);
+            release();
+            namespace_error(e)
+        }
+    }
+}
+
+async fn get_object(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (bucket, key) = path.into_inner();
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    let entry = match data.namespace.get(&bucket, &key, &user) {
+        Ok(entry) => entry,
+        Err(e) => return namespace_error(e),
+    };
+    let location = entry.location;
+    let node_id = data.resolve_node(&location.node_id, &location.file_id);
+
+    // retrieve_file writes the file into a directory, one per download
+    let temp_dir = format!("temp_downloads/{}", Uuid::new_v4());
+    if let Err(e) = std::fs::create_dir_all(&temp_dir) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    let result = async {
+        let node = data.nodes.lock().unwrap().get(&node_id).cloned();
+        let mut node = node.ok_or_else(|| "Node not found".to_string())?;
+        let started = Instant::now();
+        let retrieved = node.retrieve_file(&location.file_id, &temp_dir).await;
+        data.record_transfer(&node_id, started, &retrieved);
+        retrieved.map_err(|e| e.to_string())?;
+        std::fs::read(format!("{}/{}", temp_dir, location.file_id)).map_err(|e| e.to_string())
+    }
+    .await;
+    std::fs::remove_dir_all(&temp_dir).ok(); // Clean up
+
+    match result {
+        Ok(contents) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(contents),
+        Err(e) => {
+            println!("Failed to read object {}/{} from node {}: {}", bucket, key, node_id, e);
+            HttpResponse::InternalServerError().body(e)
+        }
+    }
+}
+
+async fn delete_object(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (bucket, key) = path.into_inner();
+    let user = match authenticated_user(&data, &req) {
+        Some(user) => user,
+        None => return unauthenticated(),
+    };
+    match data.namespace.remove(&bucket, &key, &user) {
+        Ok(entry) => {
+            delete_object_data(&data, &entry.location);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => namespace_error(e),
+    }
+}
+
+async fn test_endpoint() -> HttpResponse {
+    HttpResponse::Ok().body("Sunucu çalışıyor!")
+}
+
+
+// Server Configuration
+pub async fn run_server() -> std::io::Result<()> {
+    let placement = strategy_from_env().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let post_mode = mode_from_env().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let rebalancer = Rebalancer::from_env().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let namespace = Namespace::open(std::path::Path::new(NAMESPACE_PATH))?;
+    let stakes = ProgrammableBusinessEngine::from_env(TOKEN_RATE).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let stakes = Arc::new(Mutex::new(stakes));
+    let mut app_state = AppState::new()
+        .with_stakes(stakes.clone())
+        .with_namespace(namespace)
+        .with_rebalancer(Arc::new(rebalancer))
+        .with_placement(placement)
+        .with_verifier(Arc::new(Verifier::with_mode(post_mode)));
+    let storage_api = storage_api_from_env()
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    match storage_api {
+        Some(storage_api) => {
+            // CID lookups read the file metadata of the node's cluster, and
+            // uploads to it are limited by the same stakes as local ones
+            let cids = storage_api.cid_lookup();
+            app_state = app_state.with_storage_api(Arc::new(storage_api.with_stakes(stakes))).with_cid_lookup(cids);
+        }
+        None => {
+            println!("P2P_ADDR is unset; the /network routes are disabled");
+            // Without a node, CID lookups read the cluster's metadata as a client
+            match cid_lookup_from_env(std::path::Path::new(API_CLIENT_DIR)) {
+                Ok(Some(cids)) => app_state = app_state.with_cid_lookup(cids),
+                Ok(None) => println!("METADATA_NODES is unset; CID lookups are disabled"),
+                Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())),
+            }
+        }
+    }
+    let app_state = web::Data::new(app_state);
+
+    // Report the peers the p2p node finds while the server starts up
+    if let Some(storage_api) = app_state.p2p.clone() {
+        actix_web::rt::spawn(async move {
+            if let Err(e) = wait_for_peers(&storage_api, PEER_WAIT_SECONDS).await {
+                eprintln!("Failed to list the p2p peers: {:?}", e);
+            }
+        });
+    }
+
+    // Challenge the nodes on the files they stored; outcomes go to /reputation
+    actix_web::rt::spawn(periodic_check(
+        app_state.post.clone(),
+        app_state.reputation.clone(),
+        app_state.clone().into_inner(),
+    ));
+
+    // Move files onto new nodes and off full ones; paused, resumed and shown
+    // under /rebalance
+    let state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        loop {
+            state.rebalancer.wait_for_round().await;
+            state.rebalancer.wait_while_paused().await;
+            rebalance_nodes(&state.nodes, &state.rebalancer, &*state.placement, |chunk_move| state.relocate(chunk_move)).await;
+        }
+    });
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .configure(config)
     })
     .bind("127.0.0.1:8080")?
     .run()
     .await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+    use std::path::PathBuf;
+
+    const BOUNDARY: &str = "upload-boundary";
+
+    // Knows one CID, held as the root of one file
+    struct OneCid(Cid);
+
+    impl CidLookup for OneCid {
+        fn lookup(&self, cid: &Cid) -> crate::ipfs::LookupFuture<'_> {
+            let records = if *cid == self.0 {
+                vec![crate::ipfs::CidRecord {
+                    cid: cid.to_string(),
+                    file_id: "file1".to_string(),
+                    file_name: "hello.txt".to_string(),
+                    owner: "alice".to_string(),
+                    root: true,
+                    size: 5,
+                }]
+            } else {
+                Vec::new()
+            };
+            Box::pin(async move { Ok(records) })
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_lookup_cid() {
+        let known = Cid::new_v1(crate::ipfs::RAW, b"hello");
+        let unknown = Cid::new_v1(crate::ipfs::RAW, b"other");
+        let get = |cid: &str| test::TestRequest::get().uri(&format!("/api/v1/cids/{}", cid)).to_request();
+
+        let app = test::init_service(App::new().app_data(web::Data::new(AppState::new())).configure(config)).await;
+        assert_eq!(test::call_service(&app, get(&known.to_string())).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let state = web::Data::new(AppState::new().with_cid_lookup(Arc::new(OneCid(known))));
+        let app = test::init_service(App::new().app_data(state).configure(config)).await;
+        let records: serde_json::Value = test::call_and_read_body_json(&app, get(&known.to_string())).await;
+        assert_eq!(records[0]["file_id"], "file1");
+        assert_eq!(records[0]["root"], true);
+        assert_eq!(test::call_service(&app, get(&unknown.to_string())).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::call_service(&app, get("not-a-cid")).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // A node under a temp dir, with its own key store
+    fn state_with_node(node_id: &str) -> (PathBuf, web::Data<AppState>) {
+        let root = std::env::temp_dir().join(format!("api-{}", Uuid::new_v4()));
+        let storage_path = root.join(node_id);
+        std::fs::create_dir_all(&storage_path).unwrap();
+        std::fs::File::create(storage_path.join("storage_file.dat")).unwrap().set_len(1024 * 1024).unwrap();
+        let node = StorageNode {
+            node_id: node_id.to_string(),
+            storage_path: storage_path.to_string_lossy().to_string(),
+            total_space: 1024 * 1024,
+            available_space: 1024 * 1024,
+            health_status: true,
+            last_checked: 0,
+            labels: Default::default(),
+            draining: false,
+            key_store: Some(root.join("key_data.json")),
+        };
+        let state = AppState::new();
+        state.nodes.lock().unwrap().insert(node_id.to_string(), node);
+        (root, web::Data::new(state))
+    }
+
+    fn multipart(filename: &str, content: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            BOUNDARY, filename
+        )
+        .into_bytes();
+        body.extend_from_slice(content);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    fn upload(user: &str, token: &str, body: Vec<u8>) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/v1/files/upload/node1")
+            .insert_header(("X-User-Id", user))
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .insert_header(("Content-Type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .insert_header(("Content-Length", body.len().to_string()))
+            .set_payload(body)
+    }
+
+    #[actix_web::test]
+    async fn test_upload_after_staking() {
+        let (root, state) = state_with_node("node1");
+        let app = test::init_service(App::new().app_data(state.clone()).configure(config)).await;
+
+        let mut tokens = HashMap::new();
+        for user in ["alice", "bob"] {
+            let credentials = serde_json::json!({ "user_id": user, "password": "secret" });
+            let req = test::TestRequest::post().uri("/api/v1/auth/register").set_json(&credentials).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+            let req = test::TestRequest::post().uri("/api/v1/auth/login").set_json(&credentials).to_request();
+            let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            tokens.insert(user, login["token"].as_str().unwrap().to_string());
+        }
+
+        // No stake yet
+        let req = upload("alice", &tokens["alice"], multipart("notes.txt", b"staked upload")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::PAYMENT_REQUIRED);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/stakes")
+            .insert_header(("X-User-Id", "alice"))
+            .insert_header(("Authorization", format!("Bearer {}", tokens["alice"])))
+            .set_json(serde_json::json!({ "amount": 1 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        let req = upload("alice", &tokens["alice"], multipart("notes.txt", b"staked upload")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let file_id = body.rsplit(' ').next().unwrap().to_string();
+        assert_eq!(state.stakes.lock().unwrap().storage_usage("alice").used, b"staked upload".len() as u64);
+
+        // Only a user with delete access may delete, and unknown files are not found
+        let delete = |user: &str, file_id: &str| {
+            test::TestRequest::delete()
+                .uri(&format!("/api/v1/files/node1/{}", file_id))
+                .insert_header(("X-User-Id", user.to_string()))
+                .insert_header(("Authorization", format!("Bearer {}", tokens[user])))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, delete("bob", &file_id)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, delete("alice", "no-such-file")).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::call_service(&app, delete("alice", &file_id)).await.status(), StatusCode::OK);
+        assert_eq!(state.stakes.lock().unwrap().storage_usage("alice").used, 0);
+
+        // A token stops working once its user logs out
+        let req = test::TestRequest::post()
+            .uri("/api/v1/auth/logout")
+            .insert_header(("X-User-Id", "alice"))
+            .insert_header(("Authorization", format!("Bearer {}", tokens["alice"])))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        let req = upload("alice", &tokens["alice"], multipart("notes.txt", b"staked upload")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        std::fs::remove_dir_all(root).ok();
+    }
+}
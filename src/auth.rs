@@ -74,6 +74,7 @@ impl AuthSystem {
     pub fn logout(&mut self, user_id: &str) -> bool {
         if let Some(user) = self.users.get_mut(user_id) {
             user.token = None;
+            println!("User {} logged out", user.user_id);
             return true;
         }
         false
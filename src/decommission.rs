@@ -0,0 +1,461 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::node::StorageNode;
+use crate::placement::{select_storage_node, PlacementStrategy};
+use crate::rebalance::file_stem;
+
+// draining -> verifying -> decommissioned. Until the node is removed its own
+// copies stay the ones in use, so a cancelled or failed drain only removes
+// the copies made so far and the node goes back to normal.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DrainState {
+    Draining,
+    Verifying,
+    Decommissioned,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FileMove {
+    pub file: String,
+    pub to: String,
+    pub size: u64,
+    pub verified: bool,
+    #[serde(skip)]
+    hash: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DrainProgress {
+    pub node_id: String,
+    pub state: DrainState,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub total_files: usize,
+    pub total_bytes: u64,
+    pub copied_files: usize,
+    pub copied_bytes: u64,
+    pub verified_files: usize,
+    pub moves: Vec<FileMove>,
+    pub error: Option<String>,
+}
+
+// One node's decommission, kept after it ends so clients can still find
+// where the files went
+pub struct Drain {
+    cancelled: AtomicBool,
+    progress: Mutex<DrainProgress>,
+}
+
+impl Drain {
+    pub fn new(node_id: &str) -> Self {
+        Drain {
+            cancelled: AtomicBool::new(false),
+            progress: Mutex::new(DrainProgress {
+                node_id: node_id.to_string(),
+                state: DrainState::Draining,
+                started_at: unix_now(),
+                finished_at: None,
+                total_files: 0,
+                total_bytes: 0,
+                copied_files: 0,
+                copied_bytes: 0,
+                verified_files: 0,
+                moves: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+
+    pub fn progress(&self) -> DrainProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    // Asks the drain to stop; it is too late once the copies are verified
+    pub fn cancel(&self) -> Result<(), String> {
+        match self.progress().state {
+            DrainState::Draining | DrainState::Verifying => {
+                self.cancelled.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            state => Err(format!("The drain has already ended ({:?})", state)),
+        }
+    }
+
+    // Node a file of the decommissioned node was moved to, by file id
+    pub fn location_of(&self, file_id: &str) -> Option<String> {
+        let progress = self.progress.lock().unwrap();
+        if progress.state != DrainState::Decommissioned {
+            return None;
+        }
+        progress
+            .moves
+            .iter()
+            .find(|moved| file_stem(&moved.file) == file_id)
+            .map(|moved| moved.to.clone())
+    }
+
+    fn update<F: FnOnce(&mut DrainProgress)>(&self, f: F) {
+        f(&mut self.progress.lock().unwrap());
+    }
+
+    fn finish(&self, state: DrainState, error: Option<String>) {
+        self.update(|progress| {
+            progress.state = state;
+            progress.error = error;
+            progress.finished_at = Some(unix_now());
+        });
+    }
+}
+
+// Copies every file of the node to the other nodes, verifies the copies and
+// removes the node. The node is marked draining first so it takes no new files.
+// Files are read and written on copies of the nodes off the async runtime; the
+// node lock is only held to pick targets and update the shared nodes.
+pub async fn run_drain(
+    nodes: &Mutex<HashMap<String, StorageNode>>,
+    drain: &Drain,
+    strategy: &dyn PlacementStrategy,
+) {
+    let node_id = drain.progress().node_id;
+    let node = nodes.lock().unwrap().get_mut(&node_id).map(|node| {
+        node.draining = true;
+        node.clone()
+    });
+    let Some(node) = node else {
+        return abort(nodes, drain, DrainState::Failed, Some("Node not found".to_string())).await;
+    };
+    let files = match list_files(node).await {
+        Ok(files) => files,
+        Err(e) => return abort(nodes, drain, DrainState::Failed, Some(format!("Failed to list files: {}", e))).await,
+    };
+    drain.update(|progress| {
+        progress.total_files = files.len();
+        progress.total_bytes = files.iter().map(|(_, size)| size).sum();
+    });
+    println!("Draining node {}: {} file(s) to move", node_id, files.len());
+
+    // Copy
+    for (file, size) in files {
+        if drain.cancelled.load(Ordering::SeqCst) {
+            return abort(nodes, drain, DrainState::Cancelled, None).await;
+        }
+        if let Err(e) = copy_file(nodes, drain, &node_id, &file, size, strategy).await {
+            return abort(nodes, drain, DrainState::Failed, Some(e)).await;
+        }
+    }
+
+    // Verify
+    drain.update(|progress| progress.state = DrainState::Verifying);
+    for moved in drain.progress().moves {
+        if drain.cancelled.load(Ordering::SeqCst) {
+            return abort(nodes, drain, DrainState::Cancelled, None).await;
+        }
+        if let Err(e) = verify_copy(nodes, drain, &node_id, &moved).await {
+            return abort(nodes, drain, DrainState::Failed, Some(e)).await;
+        }
+    }
+
+    // Remove the node together with its data, but only if every file it
+    // still holds has a verified copy
+    if drain.cancelled.load(Ordering::SeqCst) {
+        return abort(nodes, drain, DrainState::Cancelled, None).await;
+    }
+    if let Err(e) = check_all_moved(nodes, drain, &node_id).await {
+        return abort(nodes, drain, DrainState::Failed, Some(e)).await;
+    }
+    let removed = nodes.lock().unwrap().remove(&node_id);
+    if let Some(node) = removed {
+        let removal = tokio::task::spawn_blocking(move || std::fs::remove_dir_all(&node.storage_path))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result.map_err(|e| e.to_string()));
+        if let Err(e) = removal {
+            eprintln!("Failed to remove the storage of node {}: {}", node_id, e);
+        }
+    }
+    drain.finish(DrainState::Decommissioned, None);
+    println!("Node {} decommissioned", node_id);
+}
+
+async fn list_files(node: StorageNode) -> Result<Vec<(String, u64)>, String> {
+    tokio::task::spawn_blocking(move || node.stored_files())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+async fn copy_file(
+    nodes: &Mutex<HashMap<String, StorageNode>>,
+    drain: &Drain,
+    node_id: &str,
+    file: &str,
+    size: u64,
+    strategy: &dyn PlacementStrategy,
+) -> Result<(), String> {
+    let (source, mut target) = {
+        let nodes = nodes.lock().unwrap();
+        let source = nodes.get(node_id).cloned().ok_or("Node was removed during the drain")?;
+        let others = nodes.values().filter(|node| node.node_id != node_id);
+        let target = select_storage_node(file, size, others, None, strategy)
+            .cloned()
+            .ok_or_else(|| format!("No node has room for file {} ({} bytes)", file, size))?;
+        (source, target)
+    };
+
+    let target_id = target.node_id.clone();
+    let name = file.to_string();
+    let data = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
+        let data = source
+            .read_stored_file(&name)
+            .map_err(|e| format!("Failed to read file {}: {}", name, e))?;
+        target
+            .receive_file(&name, &data)
+            .map_err(|e| format!("Failed to copy file {} to node {}: {}", name, target.node_id, e))?;
+        Ok(data)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    // The space is taken on the shared node so the next file sees it
+    if let Some(target) = nodes.lock().unwrap().get_mut(&target_id) {
+        target.available_space = target.available_space.saturating_sub(size);
+    }
+    drain.update(|progress| {
+        progress.copied_files += 1;
+        progress.copied_bytes += size;
+        progress.moves.push(FileMove {
+            file: file.to_string(),
+            to: target_id,
+            size,
+            verified: false,
+            hash: hash(&data),
+        });
+    });
+    Ok(())
+}
+
+async fn verify_copy(
+    nodes: &Mutex<HashMap<String, StorageNode>>,
+    drain: &Drain,
+    node_id: &str,
+    moved: &FileMove,
+) -> Result<(), String> {
+    let (original, target) = {
+        let nodes = nodes.lock().unwrap();
+        let original = nodes.get(node_id).map(|node| Path::new(&node.storage_path).join(&moved.file));
+        let target = nodes
+            .get(&moved.to)
+            .cloned()
+            .ok_or_else(|| format!("Node {} holding file {} is gone", moved.to, moved.file))?;
+        (original, target)
+    };
+
+    // None when the file was deleted from the node during the drain
+    let file = moved.file.clone();
+    let copy_hash = tokio::task::spawn_blocking(move || -> Result<Option<String>, String> {
+        if !original.is_some_and(|path| path.exists()) {
+            return Ok(None);
+        }
+        let copy = target
+            .read_stored_file(&file)
+            .map_err(|e| format!("Failed to read the copy of {} on node {}: {}", file, target.node_id, e))?;
+        Ok(Some(hash(&copy)))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    match copy_hash {
+        // A file deleted from the node during the drain does not need to move
+        None => {
+            if let Err(e) = discard_copy(nodes, moved).await {
+                eprintln!("Failed to remove the copy of {} from node {}: {}", moved.file, moved.to, e);
+            }
+            drain.update(|progress| progress.moves.retain(|other| other.file != moved.file));
+            Ok(())
+        }
+        Some(copy_hash) if copy_hash != moved.hash => {
+            Err(format!("The copy of {} on node {} does not match the original", moved.file, moved.to))
+        }
+        Some(_) => {
+            drain.update(|progress| {
+                progress.verified_files += 1;
+                if let Some(entry) = progress.moves.iter_mut().find(|other| other.file == moved.file) {
+                    entry.verified = true;
+                }
+            });
+            Ok(())
+        }
+    }
+}
+
+// Lists the node again and fails unless each of its files was copied and verified
+async fn check_all_moved(
+    nodes: &Mutex<HashMap<String, StorageNode>>,
+    drain: &Drain,
+    node_id: &str,
+) -> Result<(), String> {
+    let node = nodes.lock().unwrap().get(node_id).cloned().ok_or("Node was removed during the drain")?;
+    let files = list_files(node).await.map_err(|e| format!("Failed to list files: {}", e))?;
+    let moves = drain.progress().moves;
+    match files
+        .iter()
+        .find(|(file, _)| !moves.iter().any(|moved| &moved.file == file && moved.verified))
+    {
+        Some((file, _)) => Err(format!("File {} has no verified copy; the node is kept", file)),
+        None => Ok(()),
+    }
+}
+
+// Deletes the copy of a moved file from the node it went to and gives the
+// space back
+async fn discard_copy(nodes: &Mutex<HashMap<String, StorageNode>>, moved: &FileMove) -> Result<(), String> {
+    let target = nodes.lock().unwrap().get(&moved.to).cloned();
+    let Some(mut target) = target else {
+        return Ok(());
+    };
+    let file_id = file_stem(&moved.file);
+    tokio::task::spawn_blocking(move || target.delete_file(&file_id))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    if let Some(target) = nodes.lock().unwrap().get_mut(&moved.to) {
+        target.available_space = target.available_space.saturating_add(moved.size).min(target.total_space);
+    }
+    Ok(())
+}
+
+// Removes the copies made so far and puts the node back in service
+async fn abort(nodes: &Mutex<HashMap<String, StorageNode>>, drain: &Drain, state: DrainState, error: Option<String>) {
+    let progress = drain.progress();
+    for moved in &progress.moves {
+        if let Err(e) = discard_copy(nodes, moved).await {
+            eprintln!("Failed to remove the copy of {} from node {}: {}", moved.file, moved.to, e);
+        }
+    }
+    if let Some(node) = nodes.lock().unwrap().get_mut(&progress.node_id) {
+        node.draining = false;
+    }
+    if let Some(e) = &error {
+        eprintln!("Drain of node {} failed: {}", progress.node_id, e);
+    }
+    drain.update(|progress| progress.moves.clear());
+    drain.finish(state, error);
+}
+
+fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::placement::FirstFit;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn cluster(ids: &[&str]) -> (std::path::PathBuf, Mutex<HashMap<String, StorageNode>>) {
+        let root = std::env::temp_dir().join(format!("drain-{}", Uuid::new_v4()));
+        let nodes = ids
+            .iter()
+            .map(|id| {
+                let storage_path = root.join(id);
+                fs::create_dir_all(&storage_path).unwrap();
+                let node = StorageNode {
+                    node_id: id.to_string(),
+                    storage_path: storage_path.to_string_lossy().to_string(),
+                    total_space: 1024 * 1024,
+                    available_space: 1024 * 1024,
+                    health_status: true,
+                    last_checked: 0,
+                    labels: Default::default(),
+                    draining: false,
+                    key_store: None,
+                };
+                (id.to_string(), node)
+            })
+            .collect();
+        (root, Mutex::new(nodes))
+    }
+
+    fn write(nodes: &Mutex<HashMap<String, StorageNode>>, node_id: &str, file: &str, data: &[u8]) {
+        let nodes = nodes.lock().unwrap();
+        fs::write(Path::new(&nodes[node_id].storage_path).join(file), data).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_moves_every_file_and_removes_the_node() {
+        let (root, nodes) = cluster(&["a", "b", "c"]);
+        write(&nodes, "a", "f1.txt", b"first file");
+        write(&nodes, "a", "f10", b"second file");
+
+        let drain = Drain::new("a");
+        run_drain(&nodes, &drain, &FirstFit).await;
+
+        let progress = drain.progress();
+        assert_eq!(progress.state, DrainState::Decommissioned);
+        assert_eq!((progress.total_files, progress.verified_files), (2, 2));
+        assert!(!nodes.lock().unwrap().contains_key("a"));
+        assert!(!root.join("a").exists());
+        let to = drain.location_of("f1").unwrap();
+        assert_eq!(nodes.lock().unwrap()[&to].read_stored_file("f1.txt").unwrap(), b"first file");
+        assert!(drain.location_of("f").is_none());
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_drain_keeps_the_node_and_removes_the_copies() {
+        let (root, nodes) = cluster(&["a", "b"]);
+        write(&nodes, "a", "f1", b"first file");
+
+        let drain = Drain::new("a");
+        drain.cancel().unwrap();
+        run_drain(&nodes, &drain, &FirstFit).await;
+
+        assert_eq!(drain.progress().state, DrainState::Cancelled);
+        assert!(drain.cancel().is_err());
+        let nodes = nodes.lock().unwrap();
+        assert!(!nodes["a"].draining);
+        assert_eq!(nodes["a"].stored_files().unwrap().len(), 1);
+        assert!(nodes["b"].stored_files().unwrap().is_empty());
+        drop(nodes);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_node_is_kept_while_a_file_has_no_verified_copy() {
+        let (root, nodes) = cluster(&["a", "b"]);
+        write(&nodes, "a", "f1", b"first file");
+
+        let drain = Drain::new("a");
+        assert!(check_all_moved(&nodes, &drain, "a").await.is_err());
+        drain.update(|progress| {
+            progress.moves.push(FileMove {
+                file: "f1".to_string(),
+                to: "b".to_string(),
+                size: 10,
+                verified: false,
+                hash: hash(b"first file"),
+            })
+        });
+        assert!(check_all_moved(&nodes, &drain, "a").await.is_err());
+        drain.update(|progress| progress.moves[0].verified = true);
+        assert!(check_all_moved(&nodes, &drain, "a").await.is_ok());
+        fs::remove_dir_all(root).ok();
+    }
+}
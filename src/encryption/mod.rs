@@ -1,13 +1,13 @@
-use aes::{Aes128, Aes256};
+use aes::Aes128;
 use block_modes::{Cbc, BlockMode};
 use block_modes::block_padding::Pkcs7;
-use std::error::Error;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};use hmac::{Hmac, Mac, NewMac};
+use std::io;use hmac::{Hmac, Mac, NewMac};
 use sha2::Sha256;
-use hex::{encode};
 
-use crate::key_management::{ load_and_decrypt_key, save_encrypted_key_to_store, generate_key_iv };
+use crate::key_management::generate_key_iv;
+use crate::key_management::{load_and_decrypt_key_at, save_encrypted_key_to_store_at};
+use crate::proof_of_spacetime::por::{self, TagKey};
+use std::path::Path;
 const CHUNK_SIZE: usize = 10 * 1024 * 1024; // 5 MB
 const HMAC_LENGTH: usize = 32;  // HMAC length (in bytes)
 
@@ -20,146 +20,24 @@ fn encrypt_chunk(chunk: &[u8], cipher: &Aes128Cbc) -> Vec<u8> {
     cipher.clone().encrypt_vec(chunk)
 }
 
-// Function to encrypt a file in chunks
-pub fn encrypt_file_chunked(
-    file_id: &str,
-    file_path: &str,
-    output_path: &str
-) -> io::Result<()> {
-
-     // Anahtarları yükle veya oluştur
-     let key_data = match load_and_decrypt_key(file_id) {
-        Ok(key_data) => key_data,
-        Err(_) => {
-            // Anahtar bulunamazsa yeni bir anahtar oluştur ve kaydet
-            let new_key_data = generate_key_iv();
-            save_encrypted_key_to_store(&new_key_data,file_id)?;
-            new_key_data
-        }
-    };
-
-    //AES-128 in Cipher Block Chaining mode
-    let mut input_file = File::open(file_path)?;
-    let mut output_file = File::create(output_path)?;
-    let cipher = Aes128Cbc::new_from_slices(&key_data.key, &key_data.iv).expect("Cipher creation failed");
-    
-    // Create a temporary buffer to store all encrypted data before writing
-    let mut encrypted_buffer = Vec::new();
-    let mut buffer = vec![0; CHUNK_SIZE];
-
-    loop {
-        // Read a chunk of data from the input file
-        let bytes_read = input_file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        
-        let chunk = &buffer[..bytes_read];
-        let encrypted_chunk = encrypt_chunk(chunk, &cipher);
-        
-        // Write chunk length
-        // Chunk length is stored as a 4-byte little-endian integer
-        let chunk_len = (encrypted_chunk.len() as u32).to_le_bytes();
-        encrypted_buffer.extend_from_slice(&chunk_len);
-        
-        // Write encrypted chunk
-        encrypted_buffer.extend_from_slice(&encrypted_chunk);
-    }
-
-    // Calculate HMAC for all encrypted data
-    //Hmac : Hash-based Message Authentication Code
-    //Hmac's purpose is to verify the integrity and authenticity of a message
-    let mut hmac = Hmac::<Sha256>::new_from_slice(&key_data.key).expect("HMAC creation failed");
-    hmac.update(&encrypted_buffer);
-    let hmac_result = hmac.finalize().into_bytes();
-
-    // Write everything to file
-    output_file.write_all(&encrypted_buffer)?;
-    output_file.write_all(&hmac_result)?;
-    
-    Ok(())
-}
-
-
-// Function to decrypt a file in chunks
-pub fn decrypt_file_chunked(
-    file_id: &str,
-    file_path: &str,
-    output_path: &str,
-) -> Result<(), Box<dyn Error>> {
-
-    // Load and decrypt the key 
-    let key_data = load_and_decrypt_key(file_id)?;
-
-    let mut input_file = File::open(file_path)?;
-    let mut encrypted_data = Vec::new();
-    input_file.read_to_end(&mut encrypted_data)?;
-
-    // Verify file length
-    if encrypted_data.len() < HMAC_LENGTH {
-        return Err("File too short".into());
-    }
-
-    // Split HMAC and encrypted data
-    let hmac_offset = encrypted_data.len() - HMAC_LENGTH;
-    let hmac_received = &encrypted_data[hmac_offset..];
-    let encrypted_data = &encrypted_data[..hmac_offset];
-
-    // Verify HMAC
-    let mut hmac = Hmac::<Sha256>::new_from_slice(&key_data.key).expect("HMAC creation failed");
-    hmac.update(encrypted_data);
-    let hmac_calculated = hmac.finalize().into_bytes();
-
-    if hmac_received != hmac_calculated.as_slice() {
-        println!("Expected HMAC: {:?}", hmac_calculated.as_slice());
-        println!("Received HMAC: {:?}", hmac_received);
-        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "HMAC verification failed")));
-    }
-
-    // Prepare for decryption
-    let cipher = Aes128Cbc::new_from_slices(&key_data.key, &key_data.iv)?;
-    let mut writer = BufWriter::new(File::create(output_path)?);
-    let mut offset = 0;
-
-    // Process chunks
-    while offset + 4 <= encrypted_data.len() {
-        // Read chunk length
-        let chunk_len = u32::from_le_bytes(
-            encrypted_data[offset..offset + 4].try_into().unwrap()
-        ) as usize;
-        offset += 4;
-
-        // Verify chunk boundaries
-        if offset + chunk_len > encrypted_data.len() {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk length")));
-        }
-
-        // Decrypt chunk
-        let encrypted_chunk = &encrypted_data[offset..offset + chunk_len];
-        let decrypted_chunk = cipher.clone().decrypt_vec(encrypted_chunk)?;
-        writer.write_all(&decrypted_chunk)?;
-
-        offset += chunk_len;
-    }
-
-    // Flush the writer to ensure all data is written
-    writer.flush()?;
-    Ok(())
-}
-
-// Function to encrypt data in chunks
+// Function to encrypt data in chunks, with the file key kept in the key
+// store at `key_store`. With `tagged`, the blocks are tagged for
+// proof-of-retrievability audits; the header in front records which it is
 pub fn encrypt_data_chunked(
+    key_store: &Path,
+    master_key: &[u8; 32],
     file_data_id: &str,
     file_data: &[u8],
+    tagged: bool,
 ) -> std::io::Result<Vec<u8>> {
 
     // Load or generate the key and IV
-    let key_data = match load_and_decrypt_key(file_data_id) {
+    let key_data = match load_and_decrypt_key_at(key_store, master_key, file_data_id) {
         Ok(key_data) => key_data,
         Err(_) => {
             // If the key is not found, generate a new key and save it
             let new_key_data = generate_key_iv();
-            save_encrypted_key_to_store( &new_key_data, file_data_id)?;
+            save_encrypted_key_to_store_at(key_store, master_key, &new_key_data, file_data_id)?;
             new_key_data
         }
     };
@@ -168,6 +46,7 @@ pub fn encrypt_data_chunked(
     
     let mut encrypted_buffer = Vec::new();
     let mut offset = 0;
+    encrypted_buffer.extend_from_slice(&por::header(tagged));
 
     // Process chunks of file data
     while offset < file_data.len() {
@@ -187,23 +66,39 @@ pub fn encrypt_data_chunked(
 
     // Calculate HMAC for the encrypted data
     let mut hmac = Hmac::<Sha256>::new_from_slice(&key_data.key).expect("HMAC creation failed");
-    hmac.update(&encrypted_buffer);
+    hmac.update(&encrypted_buffer[por::HEADER_SIZE..]);
     let hmac_result = hmac.finalize().into_bytes();
 
     // Append HMAC to the encrypted buffer
     encrypted_buffer.extend_from_slice(&hmac_result);
 
+    // Tag the blocks as stored, for proof-of-retrievability audits
+    if tagged {
+        por::append_tags(&por::tag_key(&key_data.key), file_data_id, &mut encrypted_buffer);
+    }
+
     Ok(encrypted_buffer)
 }
 
+// Key of the proof-of-retrievability tags encrypt_data_chunked stored with the file
+pub fn tag_key(key_store: &Path, master_key: &[u8; 32], file_data_id: &str) -> std::io::Result<TagKey> {
+    Ok(por::tag_key(&load_and_decrypt_key_at(key_store, master_key, file_data_id)?.key))
+}
+
 // Function to decrypt data in chunks
 pub fn decrypt_data_chunked(
+    key_store: &Path,
+    master_key: &[u8; 32],
     file_data_id: &str,
     encrypted_data: &[u8],
 ) -> std::io::Result<Vec<u8>> {
 
     // Anahtarları yükle
-    let key_data = load_and_decrypt_key( file_data_id)?;
+    let key_data = load_and_decrypt_key_at(key_store, master_key, file_data_id)?;
+
+    // The header and the tags are only for audits
+    let encrypted_data = por::encrypted_part(encrypted_data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
     // Verify the HMAC
     if encrypted_data.len() < HMAC_LENGTH {
@@ -253,167 +148,68 @@ pub fn decrypt_data_chunked(
 }
 
 
-pub fn encrypt_file(file_id: &str,file_path: &str, output_path: &str) -> std::io::Result<()> {
-     // Anahtarları yükle veya oluştur
-    let key_data = match load_and_decrypt_key( file_id) {
-        Ok(key_data) => key_data,
-        Err(_) => {
-            // Anahtar bulunamazsa yeni bir anahtar oluştur ve kaydet
-            let new_key_data = generate_key_iv();
-            save_encrypted_key_to_store(&new_key_data, file_id)?;
-            new_key_data
-        }
-    };
-
-    println!("Key data: {:?}", key_data);
-    println!("keyiv lennnnnn: {:?}", key_data.iv.len());
-    // Dosyayı şifrele
-    // AES-256 için anahtar 32 byte ve IV 16 byte
-    let cipher = Aes128Cbc::new_from_slices(&key_data.key, &key_data.iv).unwrap();  // Bu doğru şekilde çalışmalıdır
-    let mut file = File::open(file_path)?;
-    let mut data = Vec::new();
-    file.read_to_end(&mut data)?;
-
-    let encrypted_data = cipher.encrypt_vec(&data);
-
-    // HMAC hesaplama
-    let mut hmac = Hmac::<Sha256>::new_from_slice(&key_data.key).expect("HMAC can take key of any size");
-    hmac.update(&encrypted_data);
-    let hmac_result = hmac.finalize().into_bytes();
-
-    // Şifreli veriyi ve HMAC'ı dosyaya yazma
-    let mut output_file = File::create(output_path)?;
-    output_file.write_all(&encrypted_data)?;
-    output_file.write_all(&hmac_result)?;
-
-    Ok(())
+// Encrypts and decrypts one segment at a time, for pipelines that cannot hold
+// the whole file. A segment is `length || ciphertext` exactly as written by
+// encrypt_data_chunked, so the concatenated segments followed by the HMAC of
+// `hmac()` form the same layout decrypt_data_chunked reads after the header.
+#[derive(Clone)]
+pub struct SegmentCipher {
+    cipher: Aes128Cbc,
+    key: [u8; 16],
 }
 
-
-
-// Decrypt the file with key from the key manager
-pub fn decrypt_file(file_id: &str,file_path: &str, output_path: &str) -> std::io::Result<()> {
-    // Load and decrypt the key 
-    let key_data = load_and_decrypt_key(file_id)?;
-
-    let mut file = File::open(file_path)?;
-    let mut encrypted_data = Vec::new();
-    file.read_to_end(&mut encrypted_data)?;
-
-    // HMAC check (we assume the HMAC is stored at the end of the file)
-    if encrypted_data.len() < 32 {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Data too short to contain HMAC"));
+impl SegmentCipher {
+    // Loads the file's key from `key_store`, or creates and stores one (for
+    // uploads); the keys there are encrypted with `master_key`
+    pub fn for_file(key_store: &Path, master_key: &[u8; 32], file_id: &str) -> io::Result<Self> {
+        let key_data = match load_and_decrypt_key_at(key_store, master_key, file_id) {
+            Ok(key_data) => key_data,
+            Err(_) => {
+                let new_key_data = generate_key_iv();
+                save_encrypted_key_to_store_at(key_store, master_key, &new_key_data, file_id)?;
+                new_key_data
+            }
+        };
+        Self::from_key_data(&key_data)
     }
 
-    let hmac_offset = encrypted_data.len() - 32;
-    let hmac_received = &encrypted_data[hmac_offset..];
-    let encrypted_data = &encrypted_data[..hmac_offset];
-
-    // Compute HMAC
-    let mut hmac = Hmac::<Sha256>::new_from_slice(&key_data.key).expect("HMAC can take key of any size");
-    hmac.update(encrypted_data);
-    let hmac_calculated = hmac.finalize().into_bytes();
-
-    // HMAC verification
-    if hmac_received != hmac_calculated.as_slice() {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "HMAC mismatch: Data is corrupted"));
+    // Loads an existing key only (for downloads)
+    pub fn load(key_store: &Path, master_key: &[u8; 32], file_id: &str) -> io::Result<Self> {
+        Self::from_key_data(&load_and_decrypt_key_at(key_store, master_key, file_id)?)
     }
 
-    // Decrypt the file data
-    let cipher = Aes128Cbc::new_from_slices(&key_data.key, &key_data.iv).expect("Error creating cipher");
-    let decrypted_data = cipher.decrypt_vec(encrypted_data).map_err(|_| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, "Decryption failed")
-    })?;
-
-    // Write the decrypted data to the output file
-    let mut output_file = File::create(output_path)?;
-    output_file.write_all(&decrypted_data)?;
-
-    Ok(())
-}
-
-
-
-
-
-pub fn encrypt_data(file_data_id: &str,file_data: &[u8]) -> std::io::Result<Vec<u8>> {
-  
-     // Anahtarları yükle veya oluştur
-     let key_data = match load_and_decrypt_key(file_data_id) {
-        Ok(key_data) => key_data,
-        Err(_) => {
-            // Anahtar bulunamazsa yeni bir anahtar oluştur ve kaydet
-            let new_key_data = generate_key_iv();
-            save_encrypted_key_to_store(&new_key_data, file_data_id)?;
-            new_key_data
-        }
-    };
-    // AES CBC ile şifreleme
-    let cipher = Aes128Cbc::new_from_slices(&key_data.key, &key_data.iv).expect("Error creating cipher");
-    let encrypted_data = cipher.encrypt_vec(&file_data);
-     // HMAC hesaplama
-     let mut hmac = Hmac::<Sha256>::new_from_slice(&key_data.key).expect("HMAC can take key of any size");
-     hmac.update(&encrypted_data);
-     let hmac_result = hmac.finalize().into_bytes();
-
-     Ok(encrypted_data)
-}
-
-
-
-
-pub fn decrypt_data(file_data_id: &str,encrypted_data: &[u8]) -> std::io::Result<Vec<u8>> {
-
-    // Anahtarları yükle
-    let key_data = load_and_decrypt_key(file_data_id)?;
-    
-    // HMAC'ı kontrol etme
-    if encrypted_data.len() < 32 {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Data too short to contain HMAC"));
+    fn from_key_data(key_data: &crate::key_management::KeyData) -> io::Result<Self> {
+        let cipher = Aes128Cbc::new_from_slices(&key_data.key, &key_data.iv)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(SegmentCipher { cipher, key: key_data.key })
     }
 
-    let hmac_offset = encrypted_data.len() - 32;
-    let hmac_received = &encrypted_data[hmac_offset..];
-    let encrypted_data = &encrypted_data[..hmac_offset];
-
-    println!("Received HMAC: {:?}", hmac_received);
-
-    // HMAC'ı hesaplama
-    let mut hmac = Hmac::<Sha256>::new_from_slice(&key_data.key).expect("HMAC can take key of any size");
-    hmac.update(encrypted_data);
-    let hmac_calculated = hmac.finalize().into_bytes();
-
-    println!("Calculated HMAC: {:?}", hmac_calculated);
-
-    // HMAC doğrulama
-    if hmac_received != hmac_calculated.as_slice() {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "HMAC mismatch: Data is corrupted"));
+    pub fn encrypt_segment(&self, plaintext: &[u8]) -> Vec<u8> {
+        let encrypted_chunk = encrypt_chunk(plaintext, &self.cipher);
+        let mut segment = Vec::with_capacity(4 + encrypted_chunk.len());
+        segment.extend_from_slice(&(encrypted_chunk.len() as u32).to_le_bytes());
+        segment.extend_from_slice(&encrypted_chunk);
+        segment
     }
 
-    // Şifreyi çözme
-    // AES CBC ile şifre çözme
-    let cipher = Aes128Cbc::new_from_slices(&key_data.key, &key_data.iv).expect("Error creating cipher");
-    let decrypted_data = cipher.decrypt_vec(encrypted_data).map_err(|_| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, "Decryption failed")
-    })?;
-
-    Ok(decrypted_data)
-}
-
-
-
-
-
-pub fn split_file(file_path: &str, chunk_size: usize) -> Vec<Vec<u8>> {
-    let mut file = File::open(file_path).expect("Dosya açılamadı");
-    let mut file_contents = Vec::new();
-    file.read_to_end(&mut file_contents).expect("Dosya okunamadı");
+    pub fn decrypt_segment(&self, segment: &[u8]) -> io::Result<Vec<u8>> {
+        if segment.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment too short"));
+        }
+        let chunk_len = u32::from_le_bytes(segment[..4].try_into().unwrap()) as usize;
+        if chunk_len != segment.len() - 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk length"));
+        }
+        self.cipher
+            .clone()
+            .decrypt_vec(&segment[4..])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Decryption failed"))
+    }
 
-    let mut chunks = Vec::new();
-    for chunk in file_contents.chunks(chunk_size) {
-        chunks.push(chunk.to_vec());
+    // HMAC over the segments in file order
+    pub fn hmac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC creation failed")
     }
-    chunks
 }
 
 
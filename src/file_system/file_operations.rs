@@ -1,6 +1,5 @@
-use std::fs::{self, File};
-use std::io::{self, Write};
-use std::path::Path;
+use std::fs;
+use std::io;
 use std::process::Command;
 
 pub fn change_file_permission(path: &str) -> io::Result<()> {
@@ -13,11 +12,11 @@ pub fn change_file_permission(path: &str) -> io::Result<()> {
             .output()?;
 
         if !output.status.success() {
-            return Err(io::Error::new(io::ErrorKind::Other, "Permission change failed"));
+            return Err(io::Error::other("Permission change failed"));
         }
     }
 
-    #[cfg(target_os = "unix")]
+    #[cfg(target_family = "unix")]
     {
         let output = Command::new("chmod")
             .arg("+w")
@@ -25,7 +24,7 @@ pub fn change_file_permission(path: &str) -> io::Result<()> {
             .output()?;
 
         if !output.status.success() {
-            return Err(io::Error::new(io::ErrorKind::Other, "Permission change failed"));
+            return Err(io::Error::other("Permission change failed"));
         }
     }
 
@@ -49,25 +48,3 @@ pub fn control_permission(path: &str) -> bool {
         }
     }
 }
-
-pub fn can_write_to_path(path: &str) -> bool {
-    let path = Path::new(path);
-
-    if path.is_dir() {
-        let test_file = format!("{}/.test_write_permission", path.display());
-        match fs::File::create(&test_file) {
-            Ok(_) => {
-                // Test file created, remove it immediately
-                let _ = fs::remove_file(test_file);
-                true
-            }
-            Err(_) => false,
-        }
-    } else {
-        match fs::OpenOptions::new().write(true).open(path) {
-            Ok(_) => true,
-            Err(_) => false,
-        }
-    }
-}
-
@@ -1,6 +1,7 @@
-use sys_info;
 
 pub mod file_operations; 
+// FAT32 and exFAT are never detected, but their limits are kept with the rest
+#[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum FileSystem {
     FAT32,
     ExFat,
@@ -15,14 +16,14 @@ impl FileSystem {
             FileSystem::FAT32 => 4 * 1024 * 1024 * 1024, // 4 GB
             FileSystem::ExFat => {
                 // 16 EB (using u128 to avoid overflow)
-                16 * 1024 * 1024 * 1024 * 1024 * 1024 * 1024 as u128
+                16 * 1024 * 1024 * 1024 * 1024 * 1024 * 1024_u128
             }
             FileSystem::NTFS => {
                 // 16 EB (using u128 to avoid overflow)
-                16 * 1024 * 1024 * 1024 * 1024 * 1024 * 1024 as u128
+                16 * 1024 * 1024 * 1024 * 1024 * 1024 * 1024_u128
             }
             FileSystem::Ext4 => 16 * 1024 * 1024 * 1024 * 1024, // 16 TB
-            FileSystem::APFS => 8 * 1024 * 1024 * 1024 * 1024 * 1024 * 1024 as u128, // 8 EB
+            FileSystem::APFS => 8 * 1024 * 1024 * 1024 * 1024 * 1024 * 1024_u128, // 8 EB
         }
     }
 
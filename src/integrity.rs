@@ -0,0 +1,154 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+// Corrupt copies waiting for repair; reports beyond this are counted but
+// dropped, the copy is reported again by the next read that hits it
+pub const MAX_QUEUED_REPAIRS: usize = 1024;
+
+// A chunk copy whose bytes did not match the hash recorded at upload
+#[derive(Clone, Debug, Serialize)]
+pub struct CorruptCopy {
+    pub file_id: String,
+    pub chunk_id: String,
+    pub node_id: String,
+    pub expected_hash: String,
+    pub actual_hash: String,
+    pub detected_at: u64,
+}
+
+// Read verification results for one node, as reported by the API
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NodeIntegrity {
+    pub node_id: String,
+    pub verified_reads: u64,
+    pub corrupt_reads: u64,
+    pub repaired: u64,
+    pub repair_failures: u64,
+    pub last_corruption: Option<u64>,
+}
+
+// Counts verified and corrupt chunk reads per node and queues the corrupt
+// copies for the repair task
+#[derive(Default)]
+pub struct Integrity {
+    nodes: Mutex<HashMap<String, NodeIntegrity>>,
+    queue: Mutex<VecDeque<CorruptCopy>>,
+    reported: Notify,
+}
+
+impl Integrity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update<F: FnOnce(&mut NodeIntegrity)>(&self, node_id: &str, apply: F) {
+        let mut nodes = self.nodes.lock().unwrap();
+        let counters = nodes.entry(node_id.to_string()).or_insert_with(|| NodeIntegrity {
+            node_id: node_id.to_string(),
+            ..Default::default()
+        });
+        apply(counters);
+    }
+
+    // A chunk read from the node matched its recorded hash
+    pub fn record_verified(&self, node_id: &str) {
+        self.update(node_id, |counters| counters.verified_reads += 1);
+    }
+
+    // A chunk read from the node did not match; queues the copy for repair
+    // unless it is queued already
+    pub fn report_corrupt(&self, copy: CorruptCopy) {
+        eprintln!(
+            "Chunk {} on node {} is corrupt: expected hash {}, got {}",
+            copy.chunk_id, copy.node_id, copy.expected_hash, copy.actual_hash
+        );
+        self.update(&copy.node_id, |counters| {
+            counters.corrupt_reads += 1;
+            counters.last_corruption = Some(copy.detected_at);
+        });
+        let mut queue = self.queue.lock().unwrap();
+        let queued = queue
+            .iter()
+            .any(|queued| queued.chunk_id == copy.chunk_id && queued.node_id == copy.node_id);
+        if !queued && queue.len() < MAX_QUEUED_REPAIRS {
+            queue.push_back(copy);
+            self.reported.notify_one();
+        }
+    }
+
+    pub fn record_repair(&self, node_id: &str, repaired: bool) {
+        self.update(node_id, |counters| {
+            if repaired {
+                counters.repaired += 1;
+            } else {
+                counters.repair_failures += 1;
+            }
+        });
+    }
+
+    // Waits for the next corrupt copy to repair
+    pub async fn next_corrupt(&self) -> CorruptCopy {
+        loop {
+            let notified = self.reported.notified();
+            if let Some(copy) = self.queue.lock().unwrap().pop_front() {
+                return copy;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn pending_repairs(&self) -> Vec<CorruptCopy> {
+        self.queue.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Counters of every node that served a read, most corrupt reads first
+    pub fn counters(&self) -> Vec<NodeIntegrity> {
+        let mut counters: Vec<NodeIntegrity> = self.nodes.lock().unwrap().values().cloned().collect();
+        counters.sort_by(|a, b| b.corrupt_reads.cmp(&a.corrupt_reads).then_with(|| a.node_id.cmp(&b.node_id)));
+        counters
+    }
+}
+
+pub fn corrupt_copy(file_id: &str, chunk_id: &str, node_id: &str, expected_hash: &str, actual_hash: &str) -> CorruptCopy {
+    CorruptCopy {
+        file_id: file_id.to_string(),
+        chunk_id: chunk_id.to_string(),
+        node_id: node_id.to_string(),
+        expected_hash: expected_hash.to_string(),
+        actual_hash: actual_hash.to_string(),
+        detected_at: unix_now(),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_corrupt_copies_are_counted_and_queued_once() {
+        let integrity = Integrity::new();
+        integrity.record_verified("a");
+        integrity.report_corrupt(corrupt_copy("f", "c1", "b", "h1", "x"));
+        integrity.report_corrupt(corrupt_copy("f", "c1", "b", "h1", "x"));
+
+        let counters = integrity.counters();
+        assert_eq!(counters[0].node_id, "b");
+        assert_eq!(counters[0].corrupt_reads, 2);
+        assert_eq!(counters[1].verified_reads, 1);
+        assert_eq!(integrity.pending_repairs().len(), 1);
+
+        let copy = integrity.next_corrupt().await;
+        assert_eq!((copy.chunk_id.as_str(), copy.node_id.as_str()), ("c1", "b"));
+        assert!(integrity.pending_repairs().is_empty());
+    }
+}
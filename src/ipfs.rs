@@ -0,0 +1,593 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::str::FromStr;
+
+// Content identifiers (CIDs) and CARv1 files as used by IPFS, for exchanging
+// datasets with IPFS nodes. Only sha2-256 multihashes are supported.
+//
+// Exported files use the UnixFS layout `ipfs add --cid-version 1
+// --raw-leaves --chunker size-1048576` produces: raw leaves of 1 MiB under a
+// balanced tree of dag-pb nodes, so both sides compute the same root CID.
+
+pub const RAW: u64 = 0x55;
+pub const DAG_PB: u64 = 0x70;
+const SHA2_256: u64 = 0x12;
+const DIGEST_LENGTH: usize = 32;
+// Links per dag-pb node, as in the IPFS balanced layout
+pub const UNIXFS_FANOUT: usize = 174;
+// Largest block accepted from a CAR; IPFS nodes do not exchange larger ones
+pub const MAX_BLOCK_SIZE: usize = 2 * 1024 * 1024;
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+const UNIXFS_FILE: u64 = 2;
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cid {
+    version: u8,
+    codec: u64,
+    digest: [u8; DIGEST_LENGTH],
+}
+
+impl Cid {
+    pub fn new_v1(codec: u64, data: &[u8]) -> Self {
+        Cid {
+            version: 1,
+            codec,
+            digest: Sha256::digest(data).into(),
+        }
+    }
+
+    // The same content as CIDv1; CIDv0 is always dag-pb
+    pub fn to_v1(self) -> Self {
+        Cid { version: 1, ..self }
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        <[u8; DIGEST_LENGTH]>::from(Sha256::digest(data)) == self.digest
+    }
+
+    fn multihash(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + DIGEST_LENGTH);
+        write_varint(&mut bytes, SHA2_256);
+        write_varint(&mut bytes, DIGEST_LENGTH as u64);
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    // Binary form: the bare multihash for CIDv0,
+    // version || codec || multihash for CIDv1
+    pub fn to_bytes(self) -> Vec<u8> {
+        if self.version == 0 {
+            return self.multihash();
+        }
+        let mut bytes = Vec::with_capacity(4 + DIGEST_LENGTH);
+        write_varint(&mut bytes, 1);
+        write_varint(&mut bytes, self.codec);
+        bytes.extend_from_slice(&self.multihash());
+        bytes
+    }
+
+    // Reads a binary CID from the start of `bytes`; returns it with its length
+    pub fn read(bytes: &[u8]) -> io::Result<(Self, usize)> {
+        let mut input = bytes;
+        // A CIDv0 is a multihash, which starts with 0x12 0x20
+        let (version, codec) = if bytes.starts_with(&[SHA2_256 as u8, DIGEST_LENGTH as u8]) {
+            (0, DAG_PB)
+        } else {
+            let version = read_varint(&mut input)?;
+            if version != 1 {
+                return Err(invalid(format!("Unsupported CID version {}", version)));
+            }
+            (1, read_varint(&mut input)?)
+        };
+        let hash = read_varint(&mut input)?;
+        let length = read_varint(&mut input)?;
+        if hash != SHA2_256 || length != DIGEST_LENGTH as u64 {
+            return Err(invalid("Only sha2-256 CIDs are supported"));
+        }
+        if input.len() < DIGEST_LENGTH {
+            return Err(invalid("Truncated CID"));
+        }
+        let digest = input[..DIGEST_LENGTH].try_into().unwrap();
+        let read = bytes.len() - input.len() + DIGEST_LENGTH;
+        Ok((Cid { version, codec, digest }, read))
+    }
+}
+
+// CIDv0 in base58btc (`Qm...`), CIDv1 in base32 (`b...`) like IPFS prints them
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version == 0 {
+            write!(f, "{}", bs58::encode(self.to_bytes()).into_string())
+        } else {
+            let encoded = base32::encode(base32::Alphabet::Rfc4648Lower { padding: false }, &self.to_bytes());
+            write!(f, "b{}", encoded)
+        }
+    }
+}
+
+impl FromStr for Cid {
+    type Err = io::Error;
+
+    fn from_str(cid: &str) -> io::Result<Self> {
+        let bytes = if cid.len() == 46 && cid.starts_with("Qm") {
+            bs58::decode(cid).into_vec().map_err(invalid)?
+        } else if let Some(encoded) = cid.strip_prefix('b') {
+            base32::decode(base32::Alphabet::Rfc4648Lower { padding: false }, encoded)
+                .ok_or_else(|| invalid("Invalid base32 CID"))?
+        } else {
+            return Err(invalid("Expected a base58 CIDv0 or a base32 CIDv1"));
+        };
+        match Cid::read(&bytes)? {
+            (cid, read) if read == bytes.len() => Ok(cid),
+            _ => Err(invalid("Trailing bytes after the CID")),
+        }
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first().ok_or_else(|| invalid("Truncated varint"))?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("Varint is too long"))
+}
+
+// Varint read from a stream; None at a clean end of the stream
+fn read_varint_from<R: Read>(reader: &mut R) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+    for (index, shift) in (0..64).step_by(7).enumerate() {
+        let mut byte = [0u8];
+        if reader.read(&mut byte)? == 0 {
+            return match index {
+                0 => Ok(None),
+                _ => Err(invalid("Truncated varint")),
+            };
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(invalid("Varint is too long"))
+}
+
+// The subset of CBOR a CAR header uses
+#[derive(Debug, PartialEq)]
+enum Cbor {
+    Uint(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Tag(u64, Box<Cbor>),
+}
+
+const CID_TAG: u64 = 42;
+
+impl Cbor {
+    fn write_head(bytes: &mut Vec<u8>, major: u8, value: u64) {
+        let major = major << 5;
+        match value {
+            0..=23 => bytes.push(major | value as u8),
+            24..=0xff => bytes.extend_from_slice(&[major | 24, value as u8]),
+            0x100..=0xffff => {
+                bytes.push(major | 25);
+                bytes.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+            0x10000..=0xffff_ffff => {
+                bytes.push(major | 26);
+                bytes.extend_from_slice(&(value as u32).to_be_bytes());
+            }
+            _ => {
+                bytes.push(major | 27);
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Cbor::Uint(value) => Self::write_head(bytes, 0, *value),
+            Cbor::Bytes(data) => {
+                Self::write_head(bytes, 2, data.len() as u64);
+                bytes.extend_from_slice(data);
+            }
+            Cbor::Text(text) => {
+                Self::write_head(bytes, 3, text.len() as u64);
+                bytes.extend_from_slice(text.as_bytes());
+            }
+            Cbor::Array(items) => {
+                Self::write_head(bytes, 4, items.len() as u64);
+                items.iter().for_each(|item| item.encode(bytes));
+            }
+            Cbor::Map(entries) => {
+                Self::write_head(bytes, 5, entries.len() as u64);
+                for (key, value) in entries {
+                    key.encode(bytes);
+                    value.encode(bytes);
+                }
+            }
+            Cbor::Tag(tag, item) => {
+                Self::write_head(bytes, 6, *tag);
+                item.encode(bytes);
+            }
+        }
+    }
+
+    fn decode(input: &mut &[u8], depth: usize) -> io::Result<Cbor> {
+        if depth > 16 {
+            return Err(invalid("CBOR is nested too deeply"));
+        }
+        let (&first, rest) = input.split_first().ok_or_else(|| invalid("Truncated CBOR"))?;
+        *input = rest;
+        let mut take = |count: usize| -> io::Result<&[u8]> {
+            if input.len() < count {
+                return Err(invalid("Truncated CBOR"));
+            }
+            let (taken, rest) = input.split_at(count);
+            *input = rest;
+            Ok(taken)
+        };
+        let value = match first & 0x1f {
+            value @ 0..=23 => u64::from(value),
+            24 => u64::from(take(1)?[0]),
+            25 => u64::from(u16::from_be_bytes(take(2)?.try_into().unwrap())),
+            26 => u64::from(u32::from_be_bytes(take(4)?.try_into().unwrap())),
+            27 => u64::from_be_bytes(take(8)?.try_into().unwrap()),
+            _ => return Err(invalid("Unsupported CBOR length")),
+        };
+        let length = |value: u64| usize::try_from(value).ok().filter(|&length| length <= MAX_HEADER_SIZE);
+        match first >> 5 {
+            0 => Ok(Cbor::Uint(value)),
+            2 => {
+                let length = length(value).ok_or_else(|| invalid("CBOR item is too long"))?;
+                Ok(Cbor::Bytes(take(length)?.to_vec()))
+            }
+            3 => {
+                let length = length(value).ok_or_else(|| invalid("CBOR item is too long"))?;
+                String::from_utf8(take(length)?.to_vec()).map(Cbor::Text).map_err(invalid)
+            }
+            4 => {
+                let count = length(value).ok_or_else(|| invalid("CBOR item is too long"))?;
+                (0..count).map(|_| Cbor::decode(input, depth + 1)).collect::<io::Result<_>>().map(Cbor::Array)
+            }
+            5 => {
+                let count = length(value).ok_or_else(|| invalid("CBOR item is too long"))?;
+                (0..count)
+                    .map(|_| Ok((Cbor::decode(input, depth + 1)?, Cbor::decode(input, depth + 1)?)))
+                    .collect::<io::Result<_>>()
+                    .map(Cbor::Map)
+            }
+            6 => Ok(Cbor::Tag(value, Box::new(Cbor::decode(input, depth + 1)?))),
+            _ => Err(invalid("Unsupported CBOR type")),
+        }
+    }
+}
+
+// dag-cbor header: {"roots": [CID...], "version": 1}, keys in canonical order
+fn encode_header(roots: &[Cid]) -> Vec<u8> {
+    let roots = roots
+        .iter()
+        .map(|root| {
+            // CIDs in dag-cbor are prefixed with the identity multibase 0x00
+            let mut bytes = vec![0];
+            bytes.extend_from_slice(&root.to_bytes());
+            Cbor::Tag(CID_TAG, Box::new(Cbor::Bytes(bytes)))
+        })
+        .collect();
+    let header = Cbor::Map(vec![
+        (Cbor::Text("roots".to_string()), Cbor::Array(roots)),
+        (Cbor::Text("version".to_string()), Cbor::Uint(1)),
+    ]);
+    let mut bytes = Vec::new();
+    header.encode(&mut bytes);
+    bytes
+}
+
+fn decode_header(mut bytes: &[u8]) -> io::Result<Vec<Cid>> {
+    let entries = match Cbor::decode(&mut bytes, 0)? {
+        Cbor::Map(entries) if bytes.is_empty() => entries,
+        _ => return Err(invalid("CAR header is not a map")),
+    };
+    let field = |name: &str| {
+        entries
+            .iter()
+            .find(|(key, _)| *key == Cbor::Text(name.to_string()))
+            .map(|(_, value)| value)
+    };
+    if field("version") != Some(&Cbor::Uint(1)) {
+        return Err(invalid("Only CARv1 files are supported"));
+    }
+    let roots = match field("roots") {
+        Some(Cbor::Array(roots)) => roots,
+        _ => return Err(invalid("CAR header has no roots")),
+    };
+    roots
+        .iter()
+        .map(|root| match root {
+            Cbor::Tag(CID_TAG, bytes) => match bytes.as_ref() {
+                Cbor::Bytes(bytes) if bytes.first() == Some(&0) => match Cid::read(&bytes[1..])? {
+                    (cid, read) if read == bytes.len() - 1 => Ok(cid),
+                    _ => Err(invalid("Trailing bytes after a root CID")),
+                },
+                _ => Err(invalid("Malformed root CID")),
+            },
+            _ => Err(invalid("Malformed root CID")),
+        })
+        .collect()
+}
+
+pub struct CarBlock {
+    pub cid: Cid,
+    pub data: Vec<u8>,
+}
+
+// Reads a CARv1 stream: varint-prefixed header, then varint-prefixed
+// (CID || block) sections. Every block is checked against its CID.
+pub struct CarReader<R: Read> {
+    reader: R,
+    roots: Vec<Cid>,
+}
+
+impl<R: Read> CarReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let length = read_varint_from(&mut reader)?.ok_or_else(|| invalid("Empty CAR file"))?;
+        if length as usize > MAX_HEADER_SIZE {
+            return Err(invalid("CAR header is too large"));
+        }
+        let mut header = vec![0u8; length as usize];
+        reader.read_exact(&mut header)?;
+        let roots = decode_header(&header)?;
+        Ok(CarReader { reader, roots })
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    pub fn next_block(&mut self) -> io::Result<Option<CarBlock>> {
+        let length = match read_varint_from(&mut self.reader)? {
+            Some(length) => length as usize,
+            None => return Ok(None),
+        };
+        if length > MAX_BLOCK_SIZE + 64 {
+            return Err(invalid(format!("CAR section of {} bytes is too large", length)));
+        }
+        let mut section = vec![0u8; length];
+        self.reader.read_exact(&mut section)?;
+        let (cid, read) = Cid::read(&section)?;
+        let data = section.split_off(read);
+        if !cid.matches(&data) {
+            return Err(invalid(format!("Block {} does not match its CID", cid)));
+        }
+        Ok(Some(CarBlock { cid, data }))
+    }
+}
+
+pub struct CarWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CarWriter<W> {
+    pub fn new(mut writer: W, roots: &[Cid]) -> io::Result<Self> {
+        let header = encode_header(roots);
+        let mut prefix = Vec::new();
+        write_varint(&mut prefix, header.len() as u64);
+        writer.write_all(&prefix)?;
+        writer.write_all(&header)?;
+        Ok(CarWriter { writer })
+    }
+
+    pub fn write_block(&mut self, cid: &Cid, data: &[u8]) -> io::Result<()> {
+        let cid = cid.to_bytes();
+        let mut prefix = Vec::new();
+        write_varint(&mut prefix, (cid.len() + data.len()) as u64);
+        self.writer.write_all(&prefix)?;
+        self.writer.write_all(&cid)?;
+        self.writer.write_all(data)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// A protobuf field key
+fn write_key(bytes: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(bytes, field << 3 | wire_type);
+}
+
+fn write_bytes_field(bytes: &mut Vec<u8>, field: u64, data: &[u8]) {
+    write_key(bytes, field, 2);
+    write_varint(bytes, data.len() as u64);
+    bytes.extend_from_slice(data);
+}
+
+// A dag-pb link in an exported file
+struct PbLink {
+    cid: Cid,
+    // Bytes of the linked block and everything below it
+    tsize: u64,
+    // File bytes below the link
+    filesize: u64,
+}
+
+// dag-pb node holding a UnixFS file node; links come before the data as
+// canonical dag-pb requires
+fn encode_file_node(links: &[PbLink]) -> Vec<u8> {
+    let mut unixfs = Vec::new();
+    write_key(&mut unixfs, 1, 0);
+    write_varint(&mut unixfs, UNIXFS_FILE);
+    write_key(&mut unixfs, 3, 0);
+    write_varint(&mut unixfs, links.iter().map(|link| link.filesize).sum());
+    for link in links {
+        write_key(&mut unixfs, 4, 0);
+        write_varint(&mut unixfs, link.filesize);
+    }
+
+    let mut node = Vec::new();
+    for link in links {
+        let mut encoded = Vec::new();
+        write_bytes_field(&mut encoded, 1, &link.cid.to_bytes());
+        write_bytes_field(&mut encoded, 2, b"");
+        write_key(&mut encoded, 3, 0);
+        write_varint(&mut encoded, link.tsize);
+        write_bytes_field(&mut node, 2, &encoded);
+    }
+    write_bytes_field(&mut node, 1, &unixfs);
+    node
+}
+
+// The dag-pb nodes of an exported file, root first, and its root CID
+pub struct UnixfsFile {
+    pub root: Cid,
+    pub nodes: Vec<(Cid, Vec<u8>)>,
+}
+
+// Builds the balanced UnixFS tree over the raw leaves of a file, given as
+// (CID, length) in file order. A single leaf is the file itself.
+pub fn unixfs_file(leaves: &[(Cid, u64)]) -> UnixfsFile {
+    match leaves {
+        [] => {
+            return UnixfsFile {
+                root: Cid::new_v1(RAW, b""),
+                nodes: Vec::new(),
+            }
+        }
+        [(cid, _)] => return UnixfsFile { root: *cid, nodes: Vec::new() },
+        _ => {}
+    }
+    let mut level: Vec<PbLink> = leaves
+        .iter()
+        .map(|&(cid, length)| PbLink { cid, tsize: length, filesize: length })
+        .collect();
+    let mut levels = Vec::new();
+    while level.len() > 1 || levels.is_empty() {
+        let mut parents = Vec::new();
+        let mut nodes = Vec::new();
+        for links in level.chunks(UNIXFS_FANOUT) {
+            let node = encode_file_node(links);
+            let cid = Cid::new_v1(DAG_PB, &node);
+            parents.push(PbLink {
+                cid,
+                tsize: node.len() as u64 + links.iter().map(|link| link.tsize).sum::<u64>(),
+                filesize: links.iter().map(|link| link.filesize).sum(),
+            });
+            nodes.push((cid, node));
+        }
+        levels.push(nodes);
+        level = parents;
+    }
+    UnixfsFile {
+        root: level[0].cid,
+        nodes: levels.into_iter().rev().flatten().collect(),
+    }
+}
+
+// A stored file holding the content with a CID, as returned by the lookup endpoint
+#[derive(Clone, Debug, Serialize)]
+pub struct CidRecord {
+    pub cid: String,
+    pub file_id: String,
+    pub file_name: String,
+    pub owner: String,
+    // The CID is a root of the file rather than one of its blocks
+    pub root: bool,
+    pub size: u64,
+}
+
+pub type LookupFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<CidRecord>, String>> + Send + 'a>>;
+
+// Resolves CIDs to the stored files holding them; the StorageAPI provides it
+// over the replicated file metadata
+pub trait CidLookup: Send + Sync {
+    fn lookup(&self, cid: &Cid) -> LookupFuture<'_>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cids_match_the_ones_ipfs_prints() {
+        let empty = Cid::new_v1(RAW, b"");
+        assert_eq!(empty.to_string(), "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku");
+
+        // The empty UnixFS directory
+        let directory: Cid = "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn".parse().unwrap();
+        assert!(directory.matches(&[0x0a, 0x02, 0x08, 0x01]));
+        assert_eq!(directory.to_string(), "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn");
+        assert_eq!(
+            directory.to_v1().to_string(),
+            "bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354"
+        );
+        assert_eq!(directory.to_v1().to_string().parse::<Cid>().unwrap(), directory.to_v1());
+        assert!("bafy".parse::<Cid>().is_err());
+    }
+
+    #[test]
+    fn test_car_round_trip() {
+        let blocks: Vec<(Cid, Vec<u8>)> = (0..3u8)
+            .map(|i| vec![i; 1000 * i as usize])
+            .map(|data| (Cid::new_v1(RAW, &data), data))
+            .collect();
+        let file = unixfs_file(&blocks.iter().map(|(cid, data)| (*cid, data.len() as u64)).collect::<Vec<_>>());
+        assert_eq!(file.nodes.len(), 1);
+        assert_eq!(file.nodes[0].0, file.root);
+
+        let mut writer = CarWriter::new(Vec::new(), &[file.root]).unwrap();
+        for (cid, data) in file.nodes.iter().chain(&blocks) {
+            writer.write_block(cid, data).unwrap();
+        }
+        let car = writer.finish().unwrap();
+
+        let mut reader = CarReader::new(&car[..]).unwrap();
+        assert_eq!(reader.roots(), &[file.root]);
+        let mut read = Vec::new();
+        while let Some(block) = reader.next_block().unwrap() {
+            read.push((block.cid, block.data));
+        }
+        assert_eq!(read.len(), 4);
+        assert_eq!(&read[1..], &blocks[..]);
+
+        // A block that does not match its CID is rejected
+        let mut corrupt = car.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut reader = CarReader::new(&corrupt[..]).unwrap();
+        let result = (0..4).try_for_each(|_| reader.next_block().map(|_| ()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_large_files_get_a_balanced_tree() {
+        let leaves: Vec<(Cid, u64)> = (0..UNIXFS_FANOUT as u32 + 1)
+            .map(|i| (Cid::new_v1(RAW, &i.to_be_bytes()), 4))
+            .collect();
+        let file = unixfs_file(&leaves);
+        // Two full-depth children under the root
+        assert_eq!(file.nodes.len(), 3);
+        assert_eq!(file.nodes[0].0, file.root);
+    }
+}
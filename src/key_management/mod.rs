@@ -1,7 +1,7 @@
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
-use ethers::core::k256::elliptic_curve::rand_core::le;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
 use rand::Rng;
 use serde::{Serialize, Deserialize};
 use aes::{Aes256};
@@ -22,7 +22,7 @@ pub struct KeyData {
 }
 
 // Define the file path as a constant
-const KEY_FILE_PATH: &str = "keys/key_data.json";
+pub const KEY_FILE_PATH: &str = "keys/key_data.json";
 /*WINDOWS
 $FilePath = "keys\key_data.json"
 $Acl = Get-Acl $FilePath
@@ -35,7 +35,7 @@ Set-Acl $FilePath $Acl
 chmod 600 keys/key_data.json */
 use std::env;
 
-fn get_master_key() -> [u8; 32] {
+pub fn get_master_key() -> [u8; 32] {
     dotenv().ok(); // This will load the .env file into the environment variables
     let key_str = env::var("MASTER_KEY").expect("MASTER_KEY is not set!");
     let key_bytes = key_str.as_bytes();
@@ -47,9 +47,9 @@ fn get_master_key() -> [u8; 32] {
     master_key
 }
 
-// Load the key store from the JSON file
-pub fn load_key_store() -> io::Result<KeyStore> {
-    match File::open(KEY_FILE_PATH) {
+// Load the key store from the JSON file at `path`; nodes may keep their own
+pub fn load_key_store_at(path: &Path) -> io::Result<KeyStore> {
+    match File::open(path) {
         Ok(mut file) => {
             let mut content = String::new();
             file.read_to_string(&mut content)?;
@@ -61,8 +61,11 @@ pub fn load_key_store() -> io::Result<KeyStore> {
 }
 
 // Save the key store to the JSON file
-pub fn save_key_store(key_store: &KeyStore) -> io::Result<()> {
-    let file = File::create(KEY_FILE_PATH)?;
+pub fn save_key_store_at(path: &Path, key_store: &KeyStore) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = File::create(path)?;
     serde_json::to_writer(file, key_store).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
@@ -74,10 +77,9 @@ pub fn generate_key_iv() -> KeyData {
     KeyData { key, iv }
 }
 
-// Encrypt the key data
-pub fn encrypt_key_data(key_data: &KeyData) -> Vec<u8> {
-    let  master_key = get_master_key();
-    let cipher = Aes256Cbc::new_from_slices(& master_key, &key_data.iv).unwrap();
+// Encrypt the key data with the master key
+pub fn encrypt_key_data(master_key: &[u8; 32], key_data: &KeyData) -> Vec<u8> {
+    let cipher = Aes256Cbc::new_from_slices(master_key, &key_data.iv).unwrap();
     let encrypted_key = cipher.encrypt_vec(&key_data.key);
 
     let mut result = Vec::new();
@@ -86,13 +88,12 @@ pub fn encrypt_key_data(key_data: &KeyData) -> Vec<u8> {
     result
 }
 
-// Decrypt the key data
-pub fn decrypt_key_data(encrypted_key: &[u8]) -> KeyData {
+// Decrypt the key data with the master key
+pub fn decrypt_key_data(master_key: &[u8; 32], encrypted_key: &[u8]) -> KeyData {
     let iv: &[u8; 16] = encrypted_key[0..16].try_into().expect("Invalid IV length");
     let encrypted_key_data = &encrypted_key[16..];
 
-    let  master_key = get_master_key();
-    let cipher = Aes256Cbc::new_from_slices(& master_key, iv).expect("Cipher initialization failed");
+    let cipher = Aes256Cbc::new_from_slices(master_key, iv).expect("Cipher initialization failed");
     let decrypted_key = cipher.decrypt_vec(encrypted_key_data).expect("Decryption failed");
 
     if decrypted_key.len() != 16 {
@@ -105,11 +106,11 @@ pub fn decrypt_key_data(encrypted_key: &[u8]) -> KeyData {
     }
 }
 
-// Save the encrypted key to the key store
-pub fn save_encrypted_key_to_store(key_data: &KeyData, file_id: &str) -> io::Result<()> {
-    let encrypted_key = encrypt_key_data(key_data);
+// Save the encrypted key to the key store at `path`
+pub fn save_encrypted_key_to_store_at(path: &Path, master_key: &[u8; 32], key_data: &KeyData, file_id: &str) -> io::Result<()> {
+    let encrypted_key = encrypt_key_data(master_key, key_data);
     println!("Key encrypted. Saving to key store...");
-    let mut key_store = load_key_store()?;
+    let mut key_store = load_key_store_at(path)?;
 
     if key_store.contains_key(file_id) {
         println!("Key already exists for file ID: '{}'. Skipping save.", file_id);
@@ -119,16 +120,16 @@ pub fn save_encrypted_key_to_store(key_data: &KeyData, file_id: &str) -> io::Res
     key_store.insert(file_id.to_string(), encrypted_key);
     println!("Key saved for file ID: '{}'.", file_id);
 
-    save_key_store(&key_store)
+    save_key_store_at(path, &key_store)
 }
 
-// Load and decrypt the key from the key store
-pub fn load_and_decrypt_key(file_id: &str) -> io::Result<KeyData> {
-    let key_store = load_key_store()?;
+// Load and decrypt the key from the key store at `path`
+pub fn load_and_decrypt_key_at(path: &Path, master_key: &[u8; 32], file_id: &str) -> io::Result<KeyData> {
+    let key_store = load_key_store_at(path)?;
     
     if let Some(encrypted_key) = key_store.get(file_id) {
         println!("Key found for file ID: '{}'. Decrypting...", file_id);
-        let key_data = decrypt_key_data(encrypted_key);
+        let key_data = decrypt_key_data(master_key, encrypted_key);
         Ok(key_data)
     } else {
         Err(io::Error::new(io::ErrorKind::NotFound, format!("File ID '{}' not found", file_id)))
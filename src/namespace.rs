@@ -0,0 +1,536 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Buckets owned by users, holding objects under path-like keys
+// (`photos/2026/x.jpg`). Keys only name objects; where an object's bytes live
+// is recorded with it, so renames and moves never touch the data. A namespace
+// opened on a file is written back to it after every change.
+
+pub const MAX_KEY_LENGTH: usize = 1024;
+pub const DEFAULT_MAX_KEYS: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum NamespaceError {
+    InvalidBucketName(String),
+    InvalidKey(String),
+    BucketExists(String),
+    NoSuchBucket(String),
+    NoSuchKey(String),
+    KeyExists(String),
+    BucketNotEmpty(String),
+    Forbidden(String),
+}
+
+impl fmt::Display for NamespaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NamespaceError::InvalidBucketName(name) => write!(
+                f,
+                "Invalid bucket name '{}': use 3 to 63 lowercase letters, digits, '-' or '.'",
+                name
+            ),
+            NamespaceError::InvalidKey(key) => write!(
+                f,
+                "Invalid key '{}': keys are 1 to {} bytes and do not start with '/'",
+                key, MAX_KEY_LENGTH
+            ),
+            NamespaceError::BucketExists(name) => write!(f, "Bucket '{}' already exists", name),
+            NamespaceError::NoSuchBucket(name) => write!(f, "Bucket '{}' not found", name),
+            NamespaceError::NoSuchKey(key) => write!(f, "Key '{}' not found", key),
+            NamespaceError::KeyExists(key) => write!(f, "Key '{}' already exists", key),
+            NamespaceError::BucketNotEmpty(name) => write!(f, "Bucket '{}' is not empty", name),
+            NamespaceError::Forbidden(name) => write!(f, "Bucket '{}' belongs to another user", name),
+        }
+    }
+}
+
+impl std::error::Error for NamespaceError {}
+
+pub type Result<T> = std::result::Result<T, NamespaceError>;
+
+// Where an object's bytes are stored
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ObjectLocation {
+    pub node_id: String,
+    pub file_id: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ObjectEntry {
+    pub key: String,
+    #[serde(flatten)]
+    pub location: ObjectLocation,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BucketInfo {
+    pub name: String,
+    pub owner: String,
+    pub created_at: u64,
+    pub objects: usize,
+    pub bytes: u64,
+}
+
+// One page of a bucket listing. With a delimiter, keys that continue past
+// the prefix with the delimiter are rolled up into `common_prefixes`, like
+// directories.
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    pub bucket: String,
+    pub prefix: String,
+    pub delimiter: Option<String>,
+    pub objects: Vec<ObjectEntry>,
+    pub common_prefixes: Vec<String>,
+    pub truncated: bool,
+    // Pass as `marker` to get the next page
+    pub next_marker: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    owner: String,
+    created_at: u64,
+    objects: BTreeMap<String, ObjectEntry>,
+}
+
+impl Bucket {
+    fn info(&self, name: &str) -> BucketInfo {
+        BucketInfo {
+            name: name.to_string(),
+            owner: self.owner.clone(),
+            created_at: self.created_at,
+            objects: self.objects.len(),
+            bytes: self.objects.values().map(|entry| entry.location.size).sum(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Namespace {
+    buckets: Mutex<BTreeMap<String, Bucket>>,
+    // File the buckets are saved to; None keeps them in memory only
+    path: Option<PathBuf>,
+}
+
+impl Namespace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Loads the buckets saved at `path`, or starts empty if there are none
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let buckets = if path.exists() {
+            serde_json::from_slice(&fs::read(path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Namespace {
+            buckets: Mutex::new(buckets),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    // Writes the buckets through a temporary file so a crash never leaves
+    // them half written; called with the lock held, so saves keep their order.
+    // A failed save keeps the change in memory and the next save writes it.
+    fn save(&self, buckets: &BTreeMap<String, Bucket>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let write = || -> std::io::Result<()> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let tmp_path = path.with_extension("json.tmp");
+            fs::write(&tmp_path, serde_json::to_vec(buckets)?)?;
+            fs::rename(&tmp_path, path)
+        };
+        if let Err(e) = write() {
+            eprintln!("Failed to save the namespace to {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn create_bucket(&self, name: &str, owner: &str) -> Result<BucketInfo> {
+        if !valid_bucket_name(name) {
+            return Err(NamespaceError::InvalidBucketName(name.to_string()));
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.contains_key(name) {
+            return Err(NamespaceError::BucketExists(name.to_string()));
+        }
+        let bucket = Bucket {
+            owner: owner.to_string(),
+            created_at: unix_now(),
+            objects: BTreeMap::new(),
+        };
+        let info = bucket.info(name);
+        buckets.insert(name.to_string(), bucket);
+        self.save(&buckets);
+        Ok(info)
+    }
+
+    // Only empty buckets can be deleted
+    pub fn delete_bucket(&self, name: &str, user: &str) -> Result<()> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = owned(&buckets, name, user)?;
+        if !bucket.objects.is_empty() {
+            return Err(NamespaceError::BucketNotEmpty(name.to_string()));
+        }
+        buckets.remove(name);
+        self.save(&buckets);
+        Ok(())
+    }
+
+    pub fn bucket(&self, name: &str, user: &str) -> Result<BucketInfo> {
+        let buckets = self.buckets.lock().unwrap();
+        Ok(owned(&buckets, name, user)?.info(name))
+    }
+
+    pub fn buckets(&self, owner: &str) -> Vec<BucketInfo> {
+        self.buckets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, bucket)| bucket.owner == owner)
+            .map(|(name, bucket)| bucket.info(name))
+            .collect()
+    }
+
+    // Stores or replaces the object at `key`; returns the entry it replaced,
+    // whose bytes the caller deletes
+    pub fn put(&self, bucket: &str, key: &str, user: &str, location: ObjectLocation) -> Result<Option<ObjectEntry>> {
+        if !valid_key(key) {
+            return Err(NamespaceError::InvalidKey(key.to_string()));
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = owned_mut(&mut buckets, bucket, user)?;
+        let now = unix_now();
+        let created_at = bucket.objects.get(key).map(|old| old.created_at).unwrap_or(now);
+        let entry = ObjectEntry {
+            key: key.to_string(),
+            location,
+            created_at,
+            updated_at: now,
+        };
+        let replaced = bucket.objects.insert(key.to_string(), entry);
+        self.save(&buckets);
+        Ok(replaced)
+    }
+
+    pub fn get(&self, bucket: &str, key: &str, user: &str) -> Result<ObjectEntry> {
+        let buckets = self.buckets.lock().unwrap();
+        owned(&buckets, bucket, user)?
+            .objects
+            .get(key)
+            .cloned()
+            .ok_or_else(|| NamespaceError::NoSuchKey(key.to_string()))
+    }
+
+    pub fn remove(&self, bucket: &str, key: &str, user: &str) -> Result<ObjectEntry> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let removed = owned_mut(&mut buckets, bucket, user)?
+            .objects
+            .remove(key)
+            .ok_or_else(|| NamespaceError::NoSuchKey(key.to_string()))?;
+        self.save(&buckets);
+        Ok(removed)
+    }
+
+    // Points the objects stored as `file_id` at the node the file was moved to
+    pub fn relocate(&self, file_id: &str, node_id: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut moved = false;
+        for entry in buckets.values_mut().flat_map(|bucket| bucket.objects.values_mut()) {
+            if entry.location.file_id == file_id {
+                entry.location.node_id = node_id.to_string();
+                moved = true;
+            }
+        }
+        if moved {
+            self.save(&buckets);
+        }
+    }
+
+    // Lists the keys starting with `prefix` after `marker`, at most `max_keys`
+    // objects and common prefixes together
+    pub fn list(
+        &self,
+        bucket: &str,
+        user: &str,
+        prefix: &str,
+        delimiter: Option<&str>,
+        marker: Option<&str>,
+        max_keys: usize,
+    ) -> Result<Listing> {
+        let buckets = self.buckets.lock().unwrap();
+        let objects = &owned(&buckets, bucket, user)?.objects;
+        let delimiter = delimiter.filter(|delimiter| !delimiter.is_empty());
+        let mut listing = Listing {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            delimiter: delimiter.map(str::to_string),
+            objects: Vec::new(),
+            common_prefixes: Vec::new(),
+            truncated: false,
+            next_marker: None,
+        };
+
+        let start = match marker {
+            Some(marker) if marker >= prefix => Bound::Excluded(marker.to_string()),
+            _ => Bound::Included(prefix.to_string()),
+        };
+        for (key, entry) in objects.range((start, Bound::Unbounded)) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            // A marker that is a common prefix skips everything rolled up into it
+            if let (Some(marker), Some(delimiter)) = (marker, delimiter) {
+                if marker.ends_with(delimiter) && key.starts_with(marker) {
+                    continue;
+                }
+            }
+            let rolled_up = delimiter.and_then(|delimiter| {
+                key[prefix.len()..]
+                    .find(delimiter)
+                    .map(|at| key[..prefix.len() + at + delimiter.len()].to_string())
+            });
+            if let Some(common_prefix) = &rolled_up {
+                if listing.common_prefixes.last() == Some(common_prefix) {
+                    continue;
+                }
+            }
+            if listing.objects.len() + listing.common_prefixes.len() == max_keys {
+                listing.truncated = true;
+                break;
+            }
+            match rolled_up {
+                Some(common_prefix) => {
+                    listing.next_marker = Some(common_prefix.clone());
+                    listing.common_prefixes.push(common_prefix);
+                }
+                None => {
+                    listing.next_marker = Some(key.clone());
+                    listing.objects.push(entry.clone());
+                }
+            }
+        }
+        if !listing.truncated {
+            listing.next_marker = None;
+        }
+        Ok(listing)
+    }
+
+    // Renames or moves objects without touching their bytes. A `from` ending
+    // in '/' moves every key under that prefix and then `to` must end in '/'
+    // too. Fails without changing anything if a destination key is taken.
+    // Returns the number of objects moved.
+    pub fn rename(&self, user: &str, from_bucket: &str, from: &str, to_bucket: &str, to: &str) -> Result<usize> {
+        let directory = from.ends_with('/');
+        if !valid_key(from) {
+            return Err(NamespaceError::InvalidKey(from.to_string()));
+        }
+        if !valid_key(to) || directory != to.ends_with('/') {
+            return Err(NamespaceError::InvalidKey(to.to_string()));
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        owned(&buckets, to_bucket, user)?;
+        let source = &owned(&buckets, from_bucket, user)?.objects;
+
+        let moves: Vec<(String, String)> = if directory {
+            source
+                .range::<str, _>((Bound::Included(from), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(from))
+                .map(|(key, _)| (key.clone(), format!("{}{}", to, &key[from.len()..])))
+                .collect()
+        } else if source.contains_key(from) {
+            vec![(from.to_string(), to.to_string())]
+        } else {
+            Vec::new()
+        };
+        if moves.is_empty() {
+            return Err(NamespaceError::NoSuchKey(from.to_string()));
+        }
+        if from_bucket == to_bucket && from == to {
+            return Ok(moves.len());
+        }
+
+        let destination = &buckets[to_bucket].objects;
+        let moved: std::collections::HashSet<&str> = if from_bucket == to_bucket {
+            moves.iter().map(|(key, _)| key.as_str()).collect()
+        } else {
+            Default::default()
+        };
+        if let Some((_, taken)) = moves
+            .iter()
+            .find(|(_, new_key)| !valid_key(new_key) || (destination.contains_key(new_key) && !moved.contains(new_key.as_str())))
+        {
+            return Err(NamespaceError::KeyExists(taken.clone()));
+        }
+
+        let now = unix_now();
+        let entries: Vec<(String, ObjectEntry)> = {
+            let source = &mut buckets.get_mut(from_bucket).unwrap().objects;
+            moves
+                .iter()
+                .map(|(key, new_key)| (new_key.clone(), source.remove(key).unwrap()))
+                .collect()
+        };
+        let destination = &mut buckets.get_mut(to_bucket).unwrap().objects;
+        for (new_key, mut entry) in entries {
+            entry.key = new_key.clone();
+            entry.updated_at = now;
+            destination.insert(new_key, entry);
+        }
+        self.save(&buckets);
+        Ok(moves.len())
+    }
+}
+
+fn owned<'a>(buckets: &'a BTreeMap<String, Bucket>, name: &str, user: &str) -> Result<&'a Bucket> {
+    match buckets.get(name) {
+        Some(bucket) if bucket.owner == user => Ok(bucket),
+        Some(_) => Err(NamespaceError::Forbidden(name.to_string())),
+        None => Err(NamespaceError::NoSuchBucket(name.to_string())),
+    }
+}
+
+fn owned_mut<'a>(buckets: &'a mut BTreeMap<String, Bucket>, name: &str, user: &str) -> Result<&'a mut Bucket> {
+    match buckets.get_mut(name) {
+        Some(bucket) if bucket.owner == user => Ok(bucket),
+        Some(_) => Err(NamespaceError::Forbidden(name.to_string())),
+        None => Err(NamespaceError::NoSuchBucket(name.to_string())),
+    }
+}
+
+fn valid_bucket_name(name: &str) -> bool {
+    (3..=63).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && !key.starts_with('/') && !key.contains('\0')
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(file_id: &str) -> ObjectLocation {
+        ObjectLocation {
+            node_id: "node-1".to_string(),
+            file_id: file_id.to_string(),
+            size: 10,
+        }
+    }
+
+    fn namespace(keys: &[&str]) -> Namespace {
+        let namespace = Namespace::new();
+        namespace.create_bucket("photos", "alice").unwrap();
+        for key in keys {
+            namespace.put("photos", key, "alice", location(key)).unwrap();
+        }
+        namespace
+    }
+
+    fn keys(listing: &Listing) -> Vec<&str> {
+        listing.objects.iter().map(|entry| entry.key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_delimiter_rolls_up_directories() {
+        let namespace = namespace(&["2025/a.jpg", "2026/x.jpg", "2026/y/z.jpg", "cover.jpg"]);
+
+        let root = namespace.list("photos", "alice", "", Some("/"), None, DEFAULT_MAX_KEYS).unwrap();
+        assert_eq!(keys(&root), vec!["cover.jpg"]);
+        assert_eq!(root.common_prefixes, vec!["2025/", "2026/"]);
+
+        let year = namespace.list("photos", "alice", "2026/", Some("/"), None, DEFAULT_MAX_KEYS).unwrap();
+        assert_eq!(keys(&year), vec!["2026/x.jpg"]);
+        assert_eq!(year.common_prefixes, vec!["2026/y/"]);
+
+        let flat = namespace.list("photos", "alice", "2026/", None, None, DEFAULT_MAX_KEYS).unwrap();
+        assert_eq!(keys(&flat), vec!["2026/x.jpg", "2026/y/z.jpg"]);
+    }
+
+    #[test]
+    fn test_listing_pages_with_markers() {
+        let namespace = namespace(&["a/1", "a/2", "b", "c/1", "d"]);
+        let mut seen = Vec::new();
+        let mut marker: Option<String> = None;
+        loop {
+            let page = namespace
+                .list("photos", "alice", "", Some("/"), marker.as_deref(), 2)
+                .unwrap();
+            seen.extend(page.common_prefixes.iter().cloned());
+            seen.extend(keys(&page).into_iter().map(str::to_string));
+            if !page.truncated {
+                break;
+            }
+            marker = page.next_marker;
+        }
+        seen.sort();
+        assert_eq!(seen, vec!["a/", "b", "c/", "d"]);
+    }
+
+    #[test]
+    fn test_rename_moves_directories_without_clobbering() {
+        let namespace = namespace(&["2026/x.jpg", "2026/y.jpg", "archive/x.jpg"]);
+
+        assert_eq!(
+            namespace.rename("alice", "photos", "2026/", "photos", "archive/"),
+            Err(NamespaceError::KeyExists("archive/x.jpg".to_string()))
+        );
+        assert_eq!(namespace.rename("alice", "photos", "2026/", "photos", "2027/").unwrap(), 2);
+        let entry = namespace.get("photos", "2027/y.jpg", "alice").unwrap();
+        assert_eq!(entry.location, location("2026/y.jpg"));
+        assert!(namespace.get("photos", "2026/y.jpg", "alice").is_err());
+
+        namespace.create_bucket("backup", "alice").unwrap();
+        namespace.create_bucket("other", "bob").unwrap();
+        assert_eq!(namespace.rename("alice", "photos", "2027/x.jpg", "backup", "x.jpg").unwrap(), 1);
+        assert_eq!(
+            namespace.rename("alice", "photos", "2027/y.jpg", "other", "y.jpg"),
+            Err(NamespaceError::Forbidden("other".to_string()))
+        );
+        assert_eq!(namespace.buckets("alice").iter().map(|bucket| bucket.objects).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn test_namespace_survives_restart() {
+        let path = std::env::temp_dir().join(format!("namespace-{}.json", uuid::Uuid::new_v4()));
+        let namespace = Namespace::open(&path).unwrap();
+        namespace.create_bucket("photos", "alice").unwrap();
+        namespace.put("photos", "2026/x.jpg", "alice", location("x")).unwrap();
+        namespace.put("photos", "cover.jpg", "alice", location("cover")).unwrap();
+        namespace.rename("alice", "photos", "2026/", "photos", "2027/").unwrap();
+        namespace.remove("photos", "cover.jpg", "alice").unwrap();
+        namespace.relocate("x", "node-2");
+
+        let reloaded = Namespace::open(&path).unwrap();
+        let entry = reloaded.get("photos", "2027/x.jpg", "alice").unwrap();
+        assert_eq!(entry.location.node_id, "node-2");
+        assert!(reloaded.get("photos", "cover.jpg", "alice").is_err());
+        assert_eq!(reloaded.create_bucket("photos", "bob").unwrap_err(), NamespaceError::BucketExists("photos".to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,17 +3,13 @@ use std::path::{Path, PathBuf};
 use std::io::{self, Read, Write};
 use std::fs::DirBuilder;
 use std::time::{SystemTime, UNIX_EPOCH};
-use actix_rt::System;
 use anyhow::{anyhow, Result};
-use futures::future::ok;
 use serde::{Deserialize, Serialize};
-use winapi::shared::ntdef::PULARGE_INTEGER;
-use crate::encryption::{decrypt_data_chunked, encrypt_data_chunked};
+use crate::encryption::{decrypt_data_chunked, encrypt_data_chunked, tag_key};
+use crate::key_management::{get_master_key, KEY_FILE_PATH};
 use crate::file_system::{file_operations, FileSystem};
-use std::fs::metadata;
+use crate::proof_of_spacetime::por::TagKey;
 
-#[cfg(target_family = "unix")]
-use std::os::unix::fs::MetadataExt; // Unix için ekstra bilgi
 
 #[cfg(target_family = "windows")]
 use std::os::windows::fs::MetadataExt; // Windows için ekstra bilgi
@@ -26,6 +22,14 @@ pub struct StorageNode {
     pub available_space: u64,
     pub health_status: bool,
     pub last_checked: u64,
+    #[serde(default)]
+    pub labels: FailureDomain,
+    // Set while the node is decommissioned; it takes no new files
+    #[serde(default)]
+    pub draining: bool,
+    // Key store of the files this node encrypts; the shared one when unset
+    #[serde(skip)]
+    pub key_store: Option<PathBuf>,
 }
 
 impl StorageNode {
@@ -59,6 +63,7 @@ impl StorageNode {
         let mut storage_file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&storage_file_path)
             .map_err(|e| anyhow!("Failed to open storage file {}: {}", storage_file_path, e))?;
         storage_file.set_len(self.total_space)
@@ -97,6 +102,9 @@ impl StorageNode {
             available_space: total_space,
             health_status: true,
             last_checked: 0,
+            labels: FailureDomain::from_env(),
+            draining: false,
+            key_store: None,
         };
 
         node.initialize_storage_file().await?;
@@ -104,6 +112,15 @@ impl StorageNode {
         Ok(node)
     }
 
+    fn key_store_path(&self) -> PathBuf {
+        self.key_store.clone().unwrap_or_else(|| PathBuf::from(KEY_FILE_PATH))
+    }
+
+    // Key of the proof-of-retrievability tags stored with a file of this node
+    pub fn tag_key(&self, file_name: &str) -> io::Result<TagKey> {
+        tag_key(&self.key_store_path(), &get_master_key(), file_name)
+    }
+
     pub fn validate_capacity(file_system: FileSystem, capacity: u64) -> Result<()> {
         let max_size = file_system.max_file_size();
         if capacity as u128 > max_size {
@@ -179,16 +196,18 @@ impl StorageNode {
 
 
  
-    pub async fn store_file(&mut self, file_id: &str, source_file_path: &str) -> Result<()> {
+    // Encrypts and stores the file; returns the name it is stored under, the
+    // file id with the extension of the source file
+    pub async fn store_file(&mut self, file_id: &str, source_file_path: &str, tagged: bool) -> Result<String> {
         let source_path = Path::new(source_file_path);
     
         if !source_path.exists() {
-            return Err(anyhow!("Source file '{}' does not exist", source_file_path).into());
+            return Err(anyhow!("Source file '{}' does not exist", source_file_path));
         }
     
         let file_size = fs::metadata(source_path)?.len();
         if file_size > self.available_space {
-            return Err(anyhow!("Insufficient storage space").into());
+            return Err(anyhow!("Insufficient storage space"));
         }
     // Dosyanın orijinal uzantısını al
     let extension = source_path
@@ -203,11 +222,11 @@ impl StorageNode {
         format!("{}.{}", file_id, extension) // Örnek: "12345.mp4"
     };
     
-    let destination_path = PathBuf::from(self.get_file_path(&destination_filename));
+    let destination_path = self.get_file_path(&destination_filename);
     
     // Dosya içeriğini oku ve şifrele
     let file_data = fs::read(source_path)?;
-    let encrypted_data = encrypt_data_chunked(&destination_filename, &file_data)?;
+    let encrypted_data = encrypt_data_chunked(&self.key_store_path(), &get_master_key(), &destination_filename, &file_data, tagged)?;
     println!("encrypt file id {}", destination_filename);
 
         //Şifrelenmiş veriyi hedef dosyaya yaz
@@ -233,29 +252,25 @@ impl StorageNode {
         self.update_health_status().await?;
     
         println!("Encrypted file stored successfully as: {:?}", destination_path);
-        Ok(())
+        Ok(destination_filename)
     }
     
-    pub async fn retrieve_file(&mut self, file_id: &str, download_path: &str) -> Result<()> {
-        // Dosyanın bulunduğu dizini al
-        let storage_dir = Path::new(&self.storage_path);
-        
-        // Verilen file_id'ye sahip dosyayı, uzantısı fark etmeksizin bul
-        let mut matched_file: Option<PathBuf> = None;
-        
-        for entry in read_dir(storage_dir)? {
+    // Verilen file_id'ye sahip dosyayı, uzantısı fark etmeksizin bul
+    pub fn find_file(&self, file_id: &str) -> Result<Option<PathBuf>> {
+        for entry in read_dir(Path::new(&self.storage_path))? {
             let entry = entry?;
-            let file_name = entry.file_name();
-            
-            // Dosyanın ismi file_id ile eşleşiyorsa, matched_file'ı ayarla
-            if file_name.to_str().unwrap_or_default().starts_with(file_id) {
-                matched_file = Some(entry.path());
-                break;
+            if entry.file_name().to_str().unwrap_or_default().starts_with(file_id) {
+                return Ok(Some(entry.path()));
             }
         }
-    
+        Ok(None)
+    }
+
+    pub async fn retrieve_file(&mut self, file_id: &str, download_path: &str) -> Result<()> {
         // Eğer dosya bulunamazsa hata döndür
-        let file_path = matched_file.ok_or_else(|| anyhow!("File with ID '{}' not found", file_id))?;
+        let file_path = self
+            .find_file(file_id)?
+            .ok_or_else(|| anyhow!("File with ID '{}' not found", file_id))?;
     
         // Orijinal dosya adı ve uzantısını al
         let file_name = file_path
@@ -269,8 +284,8 @@ impl StorageNode {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
     
-        // Şifreli veriyi çöz
-        let decrypted_data = decrypt_data_chunked(file_id, &buffer)
+        // Şifreli veriyi çöz; the key is kept under the stored file name
+        let decrypted_data = decrypt_data_chunked(&self.key_store_path(), &get_master_key(), &file_name, &buffer)
             .map_err(|e| anyhow!("Decryption failed: {}", e))?;
         println!("decrypt file id {}",file_id);
 
@@ -288,6 +303,51 @@ impl StorageNode {
         Ok(())
     }
     
+    // Stored (encrypted) files with their sizes, without the reserved space file
+    pub fn stored_files(&self) -> io::Result<Vec<(String, u64)>> {
+        let mut files = Vec::new();
+        for entry in read_dir(&self.storage_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !entry.path().is_file() || name == "storage_file.dat" || name == "health_check.tmp" {
+                continue;
+            }
+            files.push((name, entry.metadata()?.len()));
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    // Raw bytes of a stored file, still encrypted
+    pub fn read_stored_file(&self, file_name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.get_file_path(file_name))
+    }
+
+    // Stores a file moved from another node as it is. The key is looked up by
+    // file name, so the file decrypts the same way here.
+    pub fn receive_file(&mut self, file_name: &str, data: &[u8]) -> Result<()> {
+        if self.draining {
+            return Err(anyhow!("Node {} is being drained", self.node_id));
+        }
+        let file_size = data.len() as u64;
+        if file_size > self.available_space {
+            return Err(anyhow!("Insufficient storage space"));
+        }
+        fs::write(self.get_file_path(file_name), data)
+            .map_err(|e| anyhow!("Failed to write moved file: {}", e))?;
+
+        // storage_file.dat boyutunu güncelle
+        let storage_file_path = Path::new(&self.storage_path).join("storage_file.dat");
+        if let Ok(metadata) = fs::metadata(&storage_file_path) {
+            OpenOptions::new()
+                .write(true)
+                .open(&storage_file_path)?
+                .set_len(metadata.len().saturating_sub(file_size))?;
+        }
+        self.update_available_space()?;
+        Ok(())
+    }
+
     // Helper to construct file path
     fn get_file_path(&self, file_id: &str) -> PathBuf {
         Path::new(&self.storage_path).join(file_id)
@@ -311,7 +371,7 @@ impl StorageNode {
 
         // Check write capability
         let test_file = self.get_file_path("health_check.tmp");
-        if let Err(_) = fs::write(&test_file, b"health check") {
+        if fs::write(&test_file, b"health check").is_err() {
             return Ok(false);
         }
         fs::remove_file(test_file).map_err(|e| anyhow!("Error cleaning up health check file: {}", e))?;
@@ -371,6 +431,104 @@ impl StorageNode {
 }
 
 
+// Levels of the failure domain hierarchy, smallest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DomainLevel {
+    Host,
+    Rack,
+    Zone,
+    Region,
+}
+
+impl std::str::FromStr for DomainLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> std::result::Result<Self, Self::Err> {
+        match level.trim().to_ascii_lowercase().as_str() {
+            "host" => Ok(DomainLevel::Host),
+            "rack" => Ok(DomainLevel::Rack),
+            "zone" => Ok(DomainLevel::Zone),
+            "region" => Ok(DomainLevel::Region),
+            other => Err(format!("Unknown failure domain level `{}` (expected host, rack, zone or region)", other)),
+        }
+    }
+}
+
+// Where a node runs, used to keep the chunks of a file apart. Unset labels
+// are left out of the serialized node, so unlabelled nodes announce exactly
+// the same record as before labels existed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct FailureDomain {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rack: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+impl FailureDomain {
+    // Labels of this node from NODE_HOST, NODE_RACK, NODE_ZONE and NODE_REGION (.env is loaded too)
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let label = |name: &str| std::env::var(name).ok().filter(|value| !value.trim().is_empty());
+        FailureDomain {
+            host: label("NODE_HOST"),
+            rack: label("NODE_RACK"),
+            zone: label("NODE_ZONE"),
+            region: label("NODE_REGION"),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == FailureDomain::default()
+    }
+
+    pub fn get(&self, level: DomainLevel) -> Option<&str> {
+        match level {
+            DomainLevel::Host => self.host.as_deref(),
+            DomainLevel::Rack => self.rack.as_deref(),
+            DomainLevel::Zone => self.zone.as_deref(),
+            DomainLevel::Region => self.region.as_deref(),
+        }
+    }
+}
+
+// A peer of the p2p network as seen by other nodes.
+// The id is derived from the node's Ed25519 public key (see p2p::identity).
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Node {
+    pub id: String,
+    pub storage_path: String,
+    pub total_space: u64,
+    pub available_space: u64,
+    pub address: String,
+    #[serde(default, skip_serializing_if = "FailureDomain::is_empty")]
+    pub labels: FailureDomain,
+}
+
+impl Node {
+    pub fn reduce_available_space(&mut self, size: u64) {
+        self.available_space = self.available_space.saturating_sub(size);
+        println!(
+            "Node {}: Space reduced. Remaining available space: {}",
+            self.id, self.available_space
+        );
+    }
+
+    pub async fn free_up_space(&mut self, size: u64) {
+        self.available_space = self.available_space.saturating_add(size).min(self.total_space);
+        println!(
+            "Node {}: Space freed. Available space: {}",
+            self.id, self.available_space
+        );
+    }
+}
+
+
 
 /*use std::time::{SystemTime, UNIX_EPOCH};
 use tokio; // Tokio async runtime
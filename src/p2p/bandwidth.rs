@@ -0,0 +1,391 @@
+use dotenv::dotenv;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+// Longest a waiter sleeps before re-checking, so new tokens are noticed even without a wakeup
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+// Traffic class of a transfer. Under contention each class gets bandwidth in
+// proportion to its weight, so repair cannot starve user requests and vice versa.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Priority {
+    #[default]
+    User,
+    Repair,
+    Rebalance,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+// Rates are in bytes per second; None means unlimited
+#[derive(Clone, Debug)]
+pub struct BandwidthConfig {
+    pub global_inbound: Option<u64>,
+    pub global_outbound: Option<u64>,
+    pub peer_inbound: Option<u64>,
+    pub peer_outbound: Option<u64>,
+    pub user_weight: u32,
+    pub repair_weight: u32,
+    pub rebalance_weight: u32,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        BandwidthConfig {
+            global_inbound: None,
+            global_outbound: None,
+            peer_inbound: None,
+            peer_outbound: None,
+            user_weight: 6,
+            repair_weight: 3,
+            rebalance_weight: 1,
+        }
+    }
+}
+
+impl BandwidthConfig {
+    // Reads MAX_DOWNLOAD_RATE, MAX_UPLOAD_RATE, PEER_DOWNLOAD_RATE, PEER_UPLOAD_RATE
+    // (bytes per second) and USER_WEIGHT, REPAIR_WEIGHT, REBALANCE_WEIGHT from the
+    // environment or .env; unset values keep their defaults
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let rate = |name: &str| env::var(name).ok().and_then(|value| value.parse().ok());
+        let defaults = Self::default();
+        let weight = |name: &str, default: u32| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|weight| *weight > 0)
+                .unwrap_or(default)
+        };
+        BandwidthConfig {
+            global_inbound: rate("MAX_DOWNLOAD_RATE"),
+            global_outbound: rate("MAX_UPLOAD_RATE"),
+            peer_inbound: rate("PEER_DOWNLOAD_RATE"),
+            peer_outbound: rate("PEER_UPLOAD_RATE"),
+            user_weight: weight("USER_WEIGHT", defaults.user_weight),
+            repair_weight: weight("REPAIR_WEIGHT", defaults.repair_weight),
+            rebalance_weight: weight("REBALANCE_WEIGHT", defaults.rebalance_weight),
+        }
+    }
+
+    fn weight(&self, priority: Priority) -> f64 {
+        match priority {
+            Priority::User => self.user_weight,
+            Priority::Repair => self.repair_weight,
+            Priority::Rebalance => self.rebalance_weight,
+        }
+        .max(1) as f64
+    }
+}
+
+// Classic token bucket holding up to one second of traffic. A request larger
+// than the bucket is let through once the bucket is full and leaves it in
+// debt, so chunks of any size make progress at the configured rate.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            capacity: rate as f64,
+            tokens: rate as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    fn needed(&self, bytes: u64) -> f64 {
+        (bytes as f64).min(self.capacity)
+    }
+
+    // Time until `bytes` can be taken, zero if they can be taken now
+    fn wait_time(&self, bytes: u64) -> Duration {
+        let missing = self.needed(bytes) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    fn take(&mut self, bytes: u64) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+// A transfer waiting for bandwidth, ordered by its virtual finish time
+struct Ticket {
+    id: u64,
+    peer_id: String,
+    bytes: u64,
+    finish: f64,
+}
+
+#[derive(Default)]
+struct DirectionState {
+    global: Option<TokenBucket>,
+    peers: HashMap<String, TokenBucket>,
+    queue: Vec<Ticket>,
+    // Weighted fair queueing: every class advances its own virtual clock by
+    // bytes / weight, and tickets are granted in order of their finish tag
+    virtual_time: f64,
+    class_finish: HashMap<Priority, f64>,
+}
+
+struct LimiterState {
+    inbound: DirectionState,
+    outbound: DirectionState,
+    next_ticket: u64,
+}
+
+impl LimiterState {
+    fn direction(&mut self, direction: Direction) -> &mut DirectionState {
+        match direction {
+            Direction::Inbound => &mut self.inbound,
+            Direction::Outbound => &mut self.outbound,
+        }
+    }
+}
+
+// Global and per-peer token buckets for both directions, shared by every
+// transfer of this node
+pub struct BandwidthLimiter {
+    config: BandwidthConfig,
+    state: Mutex<LimiterState>,
+    released: Notify,
+}
+
+impl BandwidthLimiter {
+    pub fn new(config: BandwidthConfig) -> Self {
+        let now = Instant::now();
+        let inbound = DirectionState {
+            global: config.global_inbound.map(|rate| TokenBucket::new(rate, now)),
+            ..Default::default()
+        };
+        let outbound = DirectionState {
+            global: config.global_outbound.map(|rate| TokenBucket::new(rate, now)),
+            ..Default::default()
+        };
+        BandwidthLimiter {
+            config,
+            state: Mutex::new(LimiterState {
+                inbound,
+                outbound,
+                next_ticket: 0,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(BandwidthConfig::default())
+    }
+
+    pub fn throttle(self: &Arc<Self>, priority: Priority) -> Throttle {
+        Throttle {
+            limiter: self.clone(),
+            priority,
+        }
+    }
+
+    fn peer_rate(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Inbound => self.config.peer_inbound,
+            Direction::Outbound => self.config.peer_outbound,
+        }
+    }
+
+    // Waits until `bytes` may be transferred with `peer_id`
+    pub async fn acquire(&self, peer_id: &str, direction: Direction, priority: Priority, bytes: u64) {
+        let peer_rate = self.peer_rate(direction);
+        let weight = self.config.weight(priority);
+
+        let ticket_id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_ticket;
+            state.next_ticket += 1;
+            let dir = state.direction(direction);
+            if dir.global.is_none() && peer_rate.is_none() {
+                return;
+            }
+            let start = dir
+                .virtual_time
+                .max(dir.class_finish.get(&priority).copied().unwrap_or(0.0));
+            let finish = start + bytes as f64 / weight;
+            dir.class_finish.insert(priority, finish);
+            dir.queue.push(Ticket {
+                id,
+                peer_id: peer_id.to_string(),
+                bytes,
+                finish,
+            });
+            id
+        };
+
+        // Withdraws the ticket if the transfer is cancelled while waiting, so it
+        // does not hold back the tickets queued behind it
+        let _guard = scopeguard::guard((), |_| {
+            let mut state = self.state.lock().unwrap();
+            state.direction(direction).queue.retain(|ticket| ticket.id != ticket_id);
+        });
+
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                match Self::try_grant(state.direction(direction), peer_rate, ticket_id) {
+                    None => {
+                        drop(state);
+                        // Let the next ticket in line re-check
+                        self.released.notify_waiters();
+                        return;
+                    }
+                    Some(wait) => wait,
+                }
+            };
+            tokio::select! {
+                _ = self.released.notified() => {}
+                _ = tokio::time::sleep(wait.clamp(Duration::from_millis(1), MAX_WAIT)) => {}
+            }
+        }
+    }
+
+    // Grants the ticket if no ticket with an earlier finish tag could be served
+    // right now; returns how long to wait otherwise. A ticket blocked only by
+    // its own peer's bucket does not hold back tickets for other peers.
+    fn try_grant(dir: &mut DirectionState, peer_rate: Option<u64>, ticket_id: u64) -> Option<Duration> {
+        let now = Instant::now();
+        if let Some(global) = dir.global.as_mut() {
+            global.refill(now);
+        }
+        for bucket in dir.peers.values_mut() {
+            bucket.refill(now);
+        }
+
+        let position = dir.queue.iter().position(|ticket| ticket.id == ticket_id)?;
+        let ticket = &dir.queue[position];
+        let peer_wait = |dir: &DirectionState, ticket: &Ticket| match (peer_rate, dir.peers.get(&ticket.peer_id)) {
+            (Some(_), Some(bucket)) => bucket.wait_time(ticket.bytes),
+            _ => Duration::ZERO,
+        };
+
+        let own_peer_wait = peer_wait(dir, ticket);
+        let global_wait = dir
+            .global
+            .as_ref()
+            .map_or(Duration::ZERO, |global| global.wait_time(ticket.bytes));
+        let wait = own_peer_wait.max(global_wait);
+        if wait > Duration::ZERO {
+            return Some(wait);
+        }
+        let ahead = dir
+            .queue
+            .iter()
+            .any(|other| other.finish < ticket.finish && peer_wait(dir, other) == Duration::ZERO);
+        if ahead {
+            return Some(MAX_WAIT);
+        }
+
+        let ticket = dir.queue.remove(position);
+        if let Some(global) = dir.global.as_mut() {
+            global.take(ticket.bytes);
+        }
+        if let Some(rate) = peer_rate {
+            dir.peers
+                .entry(ticket.peer_id.clone())
+                .or_insert_with(|| TokenBucket::new(rate, now))
+                .take(ticket.bytes);
+        }
+        dir.virtual_time = dir.virtual_time.max(ticket.finish);
+        // Buckets of idle peers are full again and can be dropped
+        dir.peers.retain(|_, bucket| bucket.tokens < bucket.capacity);
+        None
+    }
+}
+
+// A limiter together with the traffic class of the transfers using it
+#[derive(Clone)]
+pub struct Throttle {
+    limiter: Arc<BandwidthLimiter>,
+    priority: Priority,
+}
+
+impl Throttle {
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub async fn acquire(&self, peer_id: &str, direction: Direction, bytes: u64) {
+        self.limiter.acquire(peer_id, direction, self.priority, bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_global_rate_is_enforced() {
+        let limiter = BandwidthLimiter::new(BandwidthConfig {
+            global_outbound: Some(10_000),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        // The first second worth of tokens is available immediately
+        for _ in 0..3 {
+            limiter.acquire("peer", Direction::Outbound, Priority::User, 5_000).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+
+        // Inbound traffic has its own, unlimited, budget
+        let start = Instant::now();
+        limiter.acquire("peer", Direction::Inbound, Priority::User, 1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_user_traffic_gets_larger_share_than_rebalance() {
+        let limiter = Arc::new(BandwidthLimiter::new(BandwidthConfig {
+            global_outbound: Some(10_000),
+            ..Default::default()
+        }));
+        // Drain the initial burst so both classes compete from the start
+        limiter.acquire("peer", Direction::Outbound, Priority::User, 10_000).await;
+
+        let run = |priority: Priority| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                for _ in 0..6 {
+                    limiter.acquire("peer", Direction::Outbound, priority, 1_000).await;
+                }
+                start.elapsed()
+            })
+        };
+        let rebalance = run(Priority::Rebalance);
+        let user = run(Priority::User);
+        let user_elapsed = user.await.unwrap();
+        let rebalance_elapsed = rebalance.await.unwrap();
+        assert!(user_elapsed < rebalance_elapsed, "user {:?}, rebalance {:?}", user_elapsed, rebalance_elapsed);
+    }
+}
@@ -0,0 +1,551 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use super::bandwidth::{BandwidthLimiter, Direction, Priority, Throttle};
+use super::dht::{Dht, DhtRequest, DhtResponse, Key};
+use super::faults;
+use super::identity::NodeIdentity;
+use super::raft::{RaftNode, RaftReply, RaftRequest};
+use super::transport::{SecureChannel, SECURE_PREAMBLE};
+use crate::node::Node;
+use crate::proof_of_spacetime::{self, ProverRequest, ProverResponse};
+
+// Requests sent over a SecureChannel. A Store request is followed by one
+// message carrying the chunk bytes. Transfers carry their traffic class so the
+// serving node schedules them like the requester does.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChunkRequest {
+    Store {
+        chunk_id: String,
+        hash: String,
+        size: u64,
+        #[serde(default)]
+        priority: Priority,
+    },
+    Get {
+        chunk_id: String,
+        #[serde(default)]
+        priority: Priority,
+    },
+    GetRange {
+        chunk_id: String,
+        offset: u64,
+        length: u64,
+        #[serde(default)]
+        priority: Priority,
+    },
+    Delete { chunk_id: String },
+    Has { chunk_id: String },
+    List,
+    // A proof-of-spacetime challenge or audit on a stored chunk
+    Prove(ProverRequest),
+}
+
+// Reply to every request except Store. Data is followed by one message
+// carrying the requested bytes.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChunkResponse {
+    Data { chunk_id: String, size: u64 },
+    Deleted { chunk_id: String },
+    Has { chunk_id: String, size: Option<u64> },
+    List(Vec<StoredChunk>),
+    Proved(ProverResponse),
+    Error(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredChunk {
+    pub chunk_id: String,
+    pub size: u64,
+}
+
+// Sent by the receiver once the chunk is on disk; `hash` is what the receiver
+// computed over the bytes it persisted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkAck {
+    pub chunk_id: String,
+    pub hash: String,
+    pub stored: bool,
+    pub error: Option<String>,
+}
+
+pub fn chunk_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+// Chunk ids become file names, so only uuid-like ids are accepted from peers
+fn is_valid_chunk_id(chunk_id: &str) -> bool {
+    !chunk_id.is_empty()
+        && chunk_id.len() <= 128
+        && chunk_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Stores and deletes change what this node holds, so they are only taken
+// from the cluster: this node itself or a peer whose signed announcement the
+// network accepted. Uploads, repairs and rebalancing all run on such nodes.
+async fn may_modify(nodes: &Mutex<HashMap<String, Node>>, local_id: &str, remote_id: &str) -> bool {
+    remote_id == local_id || nodes.lock().await.contains_key(remote_id)
+}
+
+// Serves one secure connection accepted by Network::serve. Stored chunks
+// are announced in the DHT with this node as provider, deleted ones withdrawn.
+// Chunk bytes in both directions go through the node's bandwidth limiter.
+pub async fn serve_connection(
+    mut stream: TcpStream,
+    identity: Arc<NodeIdentity>,
+    nodes: Arc<Mutex<HashMap<String, Node>>>,
+    chunk_dir: PathBuf,
+    dht: Option<Arc<Dht>>,
+    bandwidth: Arc<BandwidthLimiter>,
+    raft: Option<Arc<RaftNode>>,
+) -> Result<()> {
+    let mut preamble = [0u8; SECURE_PREAMBLE.len()];
+    stream.read_exact(&mut preamble).await?;
+
+    let mut channel = SecureChannel::accept(stream, &identity).await?;
+    println!(
+        "Secure session established with node {} (protocol v{}, software {})",
+        channel.remote_node_id(),
+        channel.capabilities().protocol_version,
+        channel.capabilities().peer_software_version
+    );
+
+    loop {
+        // The client closes the connection when it has nothing more to send
+        let request = match channel.recv().await {
+            Ok(request) => request,
+            Err(_) => break,
+        };
+
+        // DHT and metadata requests share the session with chunk requests
+        let request = match serde_json::from_slice::<ChunkRequest>(&request) {
+            Ok(request) => request,
+            Err(e) => {
+                if let Ok(dht_request) = serde_json::from_slice::<DhtRequest>(&request) {
+                    let response = match &dht {
+                        Some(dht) => dht.handle_request(channel.remote_node_id(), dht_request).await,
+                        None => DhtResponse::Rejected("Not a DHT node".to_string()),
+                    };
+                    channel.send(&serde_json::to_vec(&response)?).await?;
+                    continue;
+                }
+                let raft_request = serde_json::from_slice::<RaftRequest>(&request).map_err(|_| e)?;
+                let reply = match &raft {
+                    Some(raft) => raft.handle(channel.remote_node_id(), raft_request).await,
+                    None => RaftReply::Error("Not a metadata node".to_string()),
+                };
+                channel.send(&serde_json::to_vec(&reply)?).await?;
+                continue;
+            }
+        };
+
+        match request {
+            ChunkRequest::Store { chunk_id, hash, size, priority } => {
+                if !may_modify(&nodes, &identity.node_id(), channel.remote_node_id()).await {
+                    // The chunk bytes follow the request unread, so the session ends here
+                    let ack = ChunkAck {
+                        chunk_id,
+                        hash: String::new(),
+                        stored: false,
+                        error: Some(format!("Node {} is not a member of the cluster", channel.remote_node_id())),
+                    };
+                    channel.send(&serde_json::to_vec(&ack)?).await?;
+                    break;
+                }
+                // The bandwidth is charged for the declared size, so a chunk
+                // longer than declared is refused before it is read
+                bandwidth
+                    .acquire(channel.remote_node_id(), Direction::Inbound, priority, size)
+                    .await;
+                let data = channel.recv_at_most(usize::try_from(size).unwrap_or(usize::MAX)).await?;
+                let ack = store_chunk(&identity.node_id(), &chunk_dir, &chunk_id, &hash, size, &data).await;
+                if let Some(error) = &ack.error {
+                    eprintln!("Rejected chunk {} from {}: {}", chunk_id, channel.remote_node_id(), error);
+                } else if let Some(dht) = &dht {
+                    let dht = dht.clone();
+                    tokio::spawn(async move {
+                        if let Ok(key) = Key::from_chunk_hash(&hash) {
+                            let provider = dht.local_contact().clone();
+                            if let Err(e) = dht.provide(key, provider).await {
+                                eprintln!("Failed to publish provider record for chunk {}: {:?}", chunk_id, e);
+                            }
+                        }
+                    });
+                }
+                channel.send(&serde_json::to_vec(&ack)?).await?;
+            }
+            ChunkRequest::Get { chunk_id, priority } => {
+                let result = read_chunk(&chunk_dir, &chunk_id, None).await;
+                send_data(&mut channel, chunk_id, result, &bandwidth.throttle(priority)).await?;
+            }
+            ChunkRequest::GetRange { chunk_id, offset, length, priority } => {
+                let result = read_chunk(&chunk_dir, &chunk_id, Some((offset, length))).await;
+                send_data(&mut channel, chunk_id, result, &bandwidth.throttle(priority)).await?;
+            }
+            ChunkRequest::Delete { chunk_id } => {
+                let response = if !may_modify(&nodes, &identity.node_id(), channel.remote_node_id()).await {
+                    ChunkResponse::Error(format!("Node {} is not a member of the cluster", channel.remote_node_id()))
+                } else {
+                    match delete_chunk(&chunk_dir, &chunk_id).await {
+                        Ok(data) => {
+                            if let Some(dht) = &dht {
+                                if let Ok(key) = Key::from_chunk_hash(&chunk_hash(&data)) {
+                                    dht.withdraw(&key, &dht.local_contact().node_id).await;
+                                }
+                            }
+                            ChunkResponse::Deleted { chunk_id }
+                        }
+                        Err(e) => ChunkResponse::Error(e.to_string()),
+                    }
+                };
+                channel.send(&serde_json::to_vec(&response)?).await?;
+            }
+            ChunkRequest::Has { chunk_id } => {
+                let size = match chunk_path(&chunk_dir, &chunk_id) {
+                    Ok(path) => tokio::fs::metadata(path).await.ok().map(|m| m.len()),
+                    Err(_) => None,
+                };
+                channel.send(&serde_json::to_vec(&ChunkResponse::Has { chunk_id, size })?).await?;
+            }
+            ChunkRequest::List => {
+                let response = match list_chunks(&chunk_dir).await {
+                    Ok(chunks) => ChunkResponse::List(chunks),
+                    Err(e) => ChunkResponse::Error(e.to_string()),
+                };
+                channel.send(&serde_json::to_vec(&response)?).await?;
+            }
+            ChunkRequest::Prove(request) => {
+                let dir = chunk_dir.clone();
+                let response = tokio::task::spawn_blocking(move || proof_of_spacetime::answer(&dir, &request))
+                    .await
+                    .unwrap_or_else(|e| ProverResponse::Error(e.to_string()));
+                channel.send(&serde_json::to_vec(&ChunkResponse::Proved(response))?).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send_data(
+    channel: &mut SecureChannel,
+    chunk_id: String,
+    result: Result<Vec<u8>>,
+    throttle: &Throttle,
+) -> Result<()> {
+    match result {
+        Ok(data) => {
+            throttle
+                .acquire(channel.remote_node_id(), Direction::Outbound, data.len() as u64)
+                .await;
+            let response = ChunkResponse::Data { chunk_id, size: data.len() as u64 };
+            channel.send(&serde_json::to_vec(&response)?).await?;
+            channel.send(&data).await
+        }
+        Err(e) => channel.send(&serde_json::to_vec(&ChunkResponse::Error(e.to_string()))?).await,
+    }
+}
+
+fn chunk_path(chunk_dir: &Path, chunk_id: &str) -> Result<PathBuf> {
+    if !is_valid_chunk_id(chunk_id) {
+        return Err(anyhow!("Invalid chunk id"));
+    }
+    Ok(chunk_dir.join(chunk_id))
+}
+
+async fn read_chunk(chunk_dir: &Path, chunk_id: &str, range: Option<(u64, u64)>) -> Result<Vec<u8>> {
+    let path = chunk_path(chunk_dir, chunk_id)?;
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| anyhow!("Chunk {} not found: {}", chunk_id, e))?;
+
+    match range {
+        None => {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).await?;
+            Ok(data)
+        }
+        Some((offset, length)) => {
+            let size = file.metadata().await?.len();
+            if offset > size {
+                return Err(anyhow!("Offset {} is past the end of chunk {} ({} bytes)", offset, chunk_id, size));
+            }
+            let length = length.min(size - offset);
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            let mut data = vec![0u8; length as usize];
+            file.read_exact(&mut data).await?;
+            Ok(data)
+        }
+    }
+}
+
+// Removes the chunk and returns its former content (used to withdraw its DHT record)
+async fn delete_chunk(chunk_dir: &Path, chunk_id: &str) -> Result<Vec<u8>> {
+    let path = chunk_path(chunk_dir, chunk_id)?;
+    let data = tokio::fs::read(&path)
+        .await
+        .map_err(|e| anyhow!("Chunk {} not found: {}", chunk_id, e))?;
+    tokio::fs::remove_file(&path).await?;
+    println!("Chunk {} deleted from {}", chunk_id, chunk_dir.display());
+    Ok(data)
+}
+
+async fn list_chunks(chunk_dir: &Path) -> Result<Vec<StoredChunk>> {
+    let mut chunks = Vec::new();
+    let mut entries = match tokio::fs::read_dir(chunk_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(chunks),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let metadata = entry.metadata().await?;
+        // Skip partial writes and the other files living in the storage directory
+        if metadata.is_file() && is_valid_chunk_id(&name) {
+            chunks.push(StoredChunk { chunk_id: name, size: metadata.len() });
+        }
+    }
+    Ok(chunks)
+}
+
+async fn store_chunk(local_id: &str, chunk_dir: &Path, chunk_id: &str, hash: &str, size: u64, data: &[u8]) -> ChunkAck {
+    let computed = chunk_hash(data);
+    let result = if let Err(e) = chunk_path(chunk_dir, chunk_id) {
+        Err(e)
+    } else if data.len() as u64 != size {
+        Err(anyhow!("Expected {} bytes, received {}", size, data.len()))
+    } else if computed != hash {
+        Err(anyhow!("Hash mismatch: expected {}, computed {}", hash, computed))
+    } else if let Err(e) = faults::check_disk(local_id) {
+        Err(e.into())
+    } else {
+        write_chunk_file(chunk_dir, chunk_id, data).await
+    };
+
+    ChunkAck {
+        chunk_id: chunk_id.to_string(),
+        hash: computed,
+        stored: result.is_ok(),
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+// Writes to a temporary file, syncs it and renames it into place so a crash
+// never leaves a partially written chunk under its final name
+async fn write_chunk_file(chunk_dir: &Path, chunk_id: &str, data: &[u8]) -> Result<()> {
+    tokio::fs::create_dir_all(chunk_dir).await?;
+    let final_path = chunk_path(chunk_dir, chunk_id)?;
+    let temp_path = chunk_dir.join(format!("{}.part", chunk_id));
+
+    let mut file = tokio::fs::File::create(&temp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&temp_path, &final_path).await?;
+
+    println!("Chunk {} stored at {}", chunk_id, final_path.display());
+    Ok(())
+}
+
+// Client of the chunk service running on another node
+pub struct ChunkClient {
+    channel: SecureChannel,
+    // Rate limit and traffic class of this client's transfers; None is unlimited user traffic
+    throttle: Option<Throttle>,
+}
+
+impl ChunkClient {
+    // Opens a secure session to the node, authenticated against its id
+    pub async fn connect(node: &Node, identity: &NodeIdentity) -> Result<Self> {
+        Ok(ChunkClient {
+            channel: SecureChannel::connect(&node.address, identity, &node.id).await?,
+            throttle: None,
+        })
+    }
+
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    fn priority(&self) -> Priority {
+        self.throttle.as_ref().map(Throttle::priority).unwrap_or_default()
+    }
+
+    async fn acquire(&self, direction: Direction, bytes: u64) {
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(self.channel.remote_node_id(), direction, bytes).await;
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        self.channel.remote_node_id()
+    }
+
+    // Stores the chunk and waits for the receiver's ack
+    pub async fn put(&mut self, chunk_id: &str, data: &[u8]) -> Result<ChunkAck> {
+        let hash = chunk_hash(data);
+        let request = ChunkRequest::Store {
+            chunk_id: chunk_id.to_string(),
+            hash: hash.clone(),
+            size: data.len() as u64,
+            priority: self.priority(),
+        };
+        self.acquire(Direction::Outbound, data.len() as u64).await;
+        self.channel.send(&serde_json::to_vec(&request)?).await?;
+        self.channel.send(data).await?;
+
+        let ack: ChunkAck = serde_json::from_slice(&self.channel.recv().await?)?;
+        if !ack.stored {
+            return Err(anyhow!(
+                "Node {} did not store chunk {}: {}",
+                self.node_id(),
+                chunk_id,
+                ack.error.unwrap_or_default()
+            ));
+        }
+        if ack.chunk_id != chunk_id || ack.hash != hash {
+            return Err(anyhow!(
+                "Node {} acknowledged chunk {} with hash {}, expected {}",
+                self.node_id(),
+                ack.chunk_id,
+                ack.hash,
+                hash
+            ));
+        }
+        Ok(ack)
+    }
+
+    pub async fn get(&mut self, chunk_id: &str) -> Result<Vec<u8>> {
+        self.request_data(ChunkRequest::Get {
+            chunk_id: chunk_id.to_string(),
+            priority: self.priority(),
+        })
+        .await
+    }
+
+    // Reads `length` bytes starting at `offset`; shorter if the chunk ends first
+    pub async fn get_range(&mut self, chunk_id: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        self.request_data(ChunkRequest::GetRange {
+            chunk_id: chunk_id.to_string(),
+            offset,
+            length,
+            priority: self.priority(),
+        })
+        .await
+    }
+
+    pub async fn delete(&mut self, chunk_id: &str) -> Result<()> {
+        match self.request(ChunkRequest::Delete { chunk_id: chunk_id.to_string() }).await? {
+            ChunkResponse::Deleted { .. } => Ok(()),
+            other => Err(unexpected_response(self.node_id(), other)),
+        }
+    }
+
+    // Size of the chunk if the node holds it
+    pub async fn has(&mut self, chunk_id: &str) -> Result<Option<u64>> {
+        match self.request(ChunkRequest::Has { chunk_id: chunk_id.to_string() }).await? {
+            ChunkResponse::Has { size, .. } => Ok(size),
+            other => Err(unexpected_response(self.node_id(), other)),
+        }
+    }
+
+    pub async fn list(&mut self) -> Result<Vec<StoredChunk>> {
+        match self.request(ChunkRequest::List).await? {
+            ChunkResponse::List(chunks) => Ok(chunks),
+            other => Err(unexpected_response(self.node_id(), other)),
+        }
+    }
+
+    // Sends a challenge or audit; the node answers from the chunk as it holds it
+    pub async fn prove(&mut self, request: ProverRequest) -> Result<ProverResponse> {
+        match self.request(ChunkRequest::Prove(request)).await? {
+            ChunkResponse::Proved(response) => Ok(response),
+            other => Err(unexpected_response(self.node_id(), other)),
+        }
+    }
+
+    async fn request(&mut self, request: ChunkRequest) -> Result<ChunkResponse> {
+        self.channel.send(&serde_json::to_vec(&request)?).await?;
+        Ok(serde_json::from_slice(&self.channel.recv().await?)?)
+    }
+
+    async fn request_data(&mut self, request: ChunkRequest) -> Result<Vec<u8>> {
+        match self.request(request).await? {
+            ChunkResponse::Data { size, .. } => {
+                self.acquire(Direction::Inbound, size).await;
+                let data = self.channel.recv().await?;
+                if data.len() as u64 != size {
+                    return Err(anyhow!("Node {} sent {} bytes, announced {}", self.node_id(), data.len(), size));
+                }
+                Ok(data)
+            }
+            other => Err(unexpected_response(self.node_id(), other)),
+        }
+    }
+}
+
+fn unexpected_response(node_id: &str, response: ChunkResponse) -> anyhow::Error {
+    match response {
+        ChunkResponse::Error(message) => anyhow!("Node {}: {}", node_id, message),
+        other => anyhow!("Unexpected response from node {}: {:?}", node_id, other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_only_cluster_nodes_store_and_delete_chunks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let identity = Arc::new(NodeIdentity::generate());
+        let member = NodeIdentity::generate();
+        let stranger = NodeIdentity::generate();
+        let chunk_dir = std::env::temp_dir().join(format!("chunks-{}", uuid::Uuid::new_v4()));
+        let server_node = Node {
+            id: identity.node_id(),
+            storage_path: chunk_dir.to_string_lossy().to_string(),
+            total_space: 1024,
+            available_space: 1024,
+            address: listener.local_addr().unwrap().to_string(),
+            labels: Default::default(),
+        };
+        let nodes = Arc::new(Mutex::new(HashMap::new()));
+        nodes.lock().await.insert(member.node_id(), Node { id: member.node_id(), ..server_node.clone() });
+
+        let (dir, known) = (chunk_dir.clone(), nodes.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let bandwidth = Arc::new(BandwidthLimiter::unlimited());
+                let (identity, known, dir) = (identity.clone(), known.clone(), dir.clone());
+                tokio::spawn(serve_connection(stream, identity, known, dir, None, bandwidth, None));
+            }
+        });
+
+        let mut client = ChunkClient::connect(&server_node, &stranger).await.unwrap();
+        assert!(client.put("chunk1", b"data").await.is_err());
+        assert!(!chunk_dir.join("chunk1").exists());
+
+        let mut client = ChunkClient::connect(&server_node, &member).await.unwrap();
+        client.put("chunk1", b"data").await.unwrap();
+
+        let mut client = ChunkClient::connect(&server_node, &stranger).await.unwrap();
+        assert!(client.delete("chunk1").await.is_err());
+        assert_eq!(client.get("chunk1").await.unwrap(), b"data");
+
+        let mut client = ChunkClient::connect(&server_node, &member).await.unwrap();
+        client.delete("chunk1").await.unwrap();
+        assert!(!chunk_dir.join("chunk1").exists());
+        std::fs::remove_dir_all(chunk_dir).ok();
+    }
+}
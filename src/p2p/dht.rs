@@ -0,0 +1,601 @@
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration, Instant};
+
+use super::identity::NodeIdentity;
+use super::transport::SecureChannel;
+use crate::node::Node;
+
+// Kademlia parameters
+pub const K: usize = 20; // Bucket size and number of closest nodes returned by a lookup
+const ALPHA: usize = 3; // Number of peers queried in parallel during a lookup
+const KEY_BITS: usize = 256;
+
+// Provider records are dropped if they are not republished within this window
+pub const PROVIDER_RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// Records this node published are sent again at this interval
+pub const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 256-bit identifier shared by node ids and chunk hashes
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    // Node ids are arbitrary strings, so they are hashed into the key space
+    pub fn for_node(node_id: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(node_id.as_bytes());
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&hasher.finalize());
+        Key(key)
+    }
+
+    // Chunk hashes are already sha256 digests (hex encoded in ChunkInfo.hash)
+    pub fn from_chunk_hash(hash: &str) -> Result<Self> {
+        let bytes = hex::decode(hash).map_err(|e| anyhow!("Invalid chunk hash {}: {}", hash, e))?;
+        if bytes.len() != 32 {
+            return Err(anyhow!("Chunk hash {} is not 32 bytes long", hash));
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&bytes);
+        Ok(Key(key))
+    }
+
+    pub fn distance(&self, other: &Key) -> Key {
+        let mut distance = [0u8; 32];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        Key(distance)
+    }
+
+    // Index of the k-bucket `other` falls into, based on the length of the shared prefix
+    fn bucket_index(&self, other: &Key) -> Option<usize> {
+        let distance = self.distance(other);
+        let mut leading_zeros = 0;
+        for byte in distance.0.iter() {
+            if *byte == 0 {
+                leading_zeros += 8;
+            } else {
+                leading_zeros += byte.leading_zeros() as usize;
+                break;
+            }
+        }
+        if leading_zeros == KEY_BITS {
+            None
+        } else {
+            Some(KEY_BITS - 1 - leading_zeros)
+        }
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hash = String::deserialize(deserializer)?;
+        Key::from_chunk_hash(&hash).map_err(serde::de::Error::custom)
+    }
+}
+
+// A peer as seen by the DHT
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Contact {
+    pub node_id: String,
+    pub address: String,
+}
+
+impl Contact {
+    pub fn key(&self) -> Key {
+        Key::for_node(&self.node_id)
+    }
+}
+
+impl From<&Node> for Contact {
+    fn from(node: &Node) -> Self {
+        Contact {
+            node_id: node.id.clone(),
+            address: node.address.clone(),
+        }
+    }
+}
+
+// "provider holds the chunk identified by key"
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderRecord {
+    pub key: Key,
+    pub provider: Contact,
+}
+
+// Sent over a SecureChannel; `sender` must be the node the channel authenticated
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DhtRequest {
+    Ping { sender: Contact },
+    FindNode { sender: Contact, target: Key },
+    AddProvider { sender: Contact, record: ProviderRecord },
+    GetProviders { sender: Contact, key: Key },
+}
+
+impl DhtRequest {
+    pub fn sender(&self) -> &Contact {
+        match self {
+            DhtRequest::Ping { sender }
+            | DhtRequest::FindNode { sender, .. }
+            | DhtRequest::AddProvider { sender, .. }
+            | DhtRequest::GetProviders { sender, .. } => sender,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DhtResponse {
+    Pong,
+    Nodes(Vec<Contact>),
+    Stored,
+    Providers { providers: Vec<Contact>, closer: Vec<Contact> },
+    Rejected(String),
+}
+
+// k-buckets ordered from least to most recently seen contact
+pub struct RoutingTable {
+    local: Key,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(local: Key) -> Self {
+        RoutingTable {
+            local,
+            buckets: vec![Vec::new(); KEY_BITS],
+        }
+    }
+
+    // Inserts or refreshes a contact. If its bucket is full, the least recently
+    // seen contact is returned so the caller can ping it before evicting it.
+    pub fn update(&mut self, contact: Contact) -> Option<Contact> {
+        let index = self.local.bucket_index(&contact.key())?;
+        let bucket = &mut self.buckets[index];
+
+        if let Some(position) = bucket.iter().position(|c| c.node_id == contact.node_id) {
+            bucket.remove(position);
+            bucket.push(contact);
+            None
+        } else if bucket.len() < K {
+            bucket.push(contact);
+            None
+        } else {
+            bucket.first().cloned()
+        }
+    }
+
+    pub fn remove(&mut self, node_id: &str) {
+        if let Some(index) = self.local.bucket_index(&Key::for_node(node_id)) {
+            self.buckets[index].retain(|c| c.node_id != node_id);
+        }
+    }
+
+    pub fn closest(&self, target: &Key, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flatten().cloned().collect();
+        contacts.sort_by_key(|c| c.key().distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+// Provider records received from other peers, expired by local receive time
+#[derive(Default)]
+pub struct ProviderStore {
+    records: HashMap<Key, Vec<(Contact, Instant)>>,
+}
+
+impl ProviderStore {
+    pub fn add(&mut self, record: ProviderRecord) {
+        let providers = self.records.entry(record.key).or_default();
+        providers.retain(|(c, _)| c.node_id != record.provider.node_id);
+        providers.push((record.provider, Instant::now()));
+    }
+
+    pub fn get(&self, key: &Key) -> Vec<Contact> {
+        self.records
+            .get(key)
+            .map(|providers| {
+                providers
+                    .iter()
+                    .filter(|(_, received)| received.elapsed() < PROVIDER_RECORD_TTL)
+                    .map(|(c, _)| c.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn expire(&mut self) {
+        for providers in self.records.values_mut() {
+            providers.retain(|(_, received)| received.elapsed() < PROVIDER_RECORD_TTL);
+        }
+        self.records.retain(|_, providers| !providers.is_empty());
+    }
+}
+
+pub struct Dht {
+    local: Contact,
+    // Authenticates our requests to other nodes
+    identity: Arc<NodeIdentity>,
+    routing_table: Arc<Mutex<RoutingTable>>,
+    providers: Mutex<ProviderStore>,
+    // Records published through this node, kept alive by the republish loop
+    published: Mutex<HashMap<Key, Vec<Contact>>>,
+}
+
+impl Dht {
+    pub fn new(local: Contact, identity: Arc<NodeIdentity>) -> Self {
+        Dht {
+            routing_table: Arc::new(Mutex::new(RoutingTable::new(local.key()))),
+            local,
+            identity,
+            providers: Mutex::new(ProviderStore::default()),
+            published: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn local_contact(&self) -> &Contact {
+        &self.local
+    }
+
+    // Adds a peer to the routing table. When its bucket is full the oldest
+    // contact is pinged in the background and replaced only if it is unreachable.
+    pub async fn observe(&self, contact: Contact) {
+        if contact.node_id == self.local.node_id {
+            return;
+        }
+        let oldest = self.routing_table.lock().await.update(contact.clone());
+        if let Some(oldest) = oldest {
+            let routing_table = self.routing_table.clone();
+            let sender = self.local.clone();
+            let identity = self.identity.clone();
+            tokio::spawn(async move {
+                let ping = DhtRequest::Ping { sender };
+                if send_request(&identity, &oldest, &ping).await.is_ok() {
+                    routing_table.lock().await.update(oldest);
+                } else {
+                    println!("Evicting unresponsive DHT contact: {}", oldest.node_id);
+                    let mut table = routing_table.lock().await;
+                    table.remove(&oldest.node_id);
+                    table.update(contact);
+                }
+            });
+        }
+    }
+
+    pub async fn forget(&self, node_id: &str) {
+        self.routing_table.lock().await.remove(node_id);
+    }
+
+    // Handles a request received over a secure session with `remote_node_id`.
+    // Peers may only speak for themselves: the sender and any provider record
+    // must name the node the session authenticated.
+    pub async fn handle_request(&self, remote_node_id: &str, request: DhtRequest) -> DhtResponse {
+        if request.sender().node_id != remote_node_id {
+            return DhtResponse::Rejected(format!(
+                "Sender {} does not match the authenticated node {}",
+                request.sender().node_id, remote_node_id
+            ));
+        }
+        if let DhtRequest::AddProvider { record, .. } = &request {
+            if record.provider.node_id != remote_node_id {
+                return DhtResponse::Rejected(format!(
+                    "Node {} cannot announce {} as a provider",
+                    remote_node_id, record.provider.node_id
+                ));
+            }
+        }
+        match request {
+            DhtRequest::Ping { sender } => {
+                self.observe(sender).await;
+                DhtResponse::Pong
+            }
+            DhtRequest::FindNode { sender, target } => {
+                let closest = self.closest_except(&target, &sender.node_id).await;
+                self.observe(sender).await;
+                DhtResponse::Nodes(closest)
+            }
+            DhtRequest::AddProvider { sender, record } => {
+                println!("Provider record for {:?} received from {}", record.key, sender.node_id);
+                self.providers.lock().await.add(record);
+                self.observe(sender).await;
+                DhtResponse::Stored
+            }
+            DhtRequest::GetProviders { sender, key } => {
+                let providers = self.providers.lock().await.get(&key);
+                let closer = self.closest_except(&key, &sender.node_id).await;
+                self.observe(sender).await;
+                DhtResponse::Providers { providers, closer }
+            }
+        }
+    }
+
+    async fn closest_except(&self, target: &Key, node_id: &str) -> Vec<Contact> {
+        let mut closest = self.routing_table.lock().await.closest(target, K + 1);
+        closest.retain(|c| c.node_id != node_id);
+        closest.truncate(K);
+        closest
+    }
+
+    // Announces that `provider` holds the chunk identified by `key` to the K
+    // nodes closest to the key. The record is republished until withdrawn.
+    pub async fn provide(&self, key: Key, provider: Contact) -> Result<usize> {
+        {
+            let mut published = self.published.lock().await;
+            let providers = published.entry(key).or_default();
+            if !providers.iter().any(|c| c.node_id == provider.node_id) {
+                providers.push(provider.clone());
+            }
+        }
+        self.send_provider_record(ProviderRecord { key, provider }).await
+    }
+
+    // Stops republishing a record, e.g. after the chunk was deleted. Copies held
+    // by other peers disappear once their TTL runs out.
+    pub async fn withdraw(&self, key: &Key, provider_id: &str) {
+        let mut published = self.published.lock().await;
+        if let Some(providers) = published.get_mut(key) {
+            providers.retain(|c| c.node_id != provider_id);
+            if providers.is_empty() {
+                published.remove(key);
+            }
+        }
+    }
+
+    async fn send_provider_record(&self, record: ProviderRecord) -> Result<usize> {
+        // Keep a local copy so lookups through this node succeed even in small networks
+        self.providers.lock().await.add(record.clone());
+
+        let closest = self.find_closest_nodes(record.key).await;
+        let requests = closest.iter().map(|contact| {
+            let request = DhtRequest::AddProvider {
+                sender: self.local.clone(),
+                record: record.clone(),
+            };
+            async move { send_request(&self.identity, contact, &request).await }
+        });
+        let stored = join_all(requests)
+            .await
+            .into_iter()
+            .filter(|result| matches!(result, Ok(DhtResponse::Stored)))
+            .count();
+
+        println!("Provider record for {:?} stored on {} peers", record.key, stored);
+        Ok(stored)
+    }
+
+    pub async fn find_closest_nodes(&self, target: Key) -> Vec<Contact> {
+        self.iterative_lookup(target, false).await.0
+    }
+
+    // Resolves a chunk key to the peers holding it
+    pub async fn find_providers(&self, key: Key) -> Vec<Contact> {
+        let local = self.providers.lock().await.get(&key);
+        if !local.is_empty() {
+            return local;
+        }
+        self.iterative_lookup(key, true).await.1
+    }
+
+    // Standard Kademlia lookup: repeatedly query the ALPHA closest unqueried
+    // contacts until no closer contacts are learned. Provider lookups stop as
+    // soon as a provider is found.
+    async fn iterative_lookup(&self, target: Key, want_providers: bool) -> (Vec<Contact>, Vec<Contact>) {
+        let mut shortlist = self.routing_table.lock().await.closest(&target, K);
+        let mut queried: HashSet<String> = HashSet::new();
+        let mut providers: Vec<Contact> = Vec::new();
+        queried.insert(self.local.node_id.clone());
+
+        loop {
+            let batch: Vec<Contact> = shortlist
+                .iter()
+                .filter(|c| !queried.contains(&c.node_id))
+                .take(ALPHA)
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let requests = batch.into_iter().map(|contact| {
+                let request = if want_providers {
+                    DhtRequest::GetProviders { sender: self.local.clone(), key: target }
+                } else {
+                    DhtRequest::FindNode { sender: self.local.clone(), target }
+                };
+                async move {
+                    let result = send_request(&self.identity, &contact, &request).await;
+                    (contact, result)
+                }
+            });
+
+            for (contact, result) in join_all(requests).await {
+                queried.insert(contact.node_id.clone());
+                let learned = match result {
+                    Ok(DhtResponse::Nodes(nodes)) => nodes,
+                    Ok(DhtResponse::Providers { providers: found, closer }) => {
+                        for provider in found {
+                            if !providers.iter().any(|p| p.node_id == provider.node_id) {
+                                providers.push(provider);
+                            }
+                        }
+                        closer
+                    }
+                    Ok(_) => Vec::new(),
+                    Err(e) => {
+                        eprintln!("DHT request to {} failed: {:?}", contact.node_id, e);
+                        shortlist.retain(|c| c.node_id != contact.node_id);
+                        self.forget(&contact.node_id).await;
+                        continue;
+                    }
+                };
+
+                self.observe(contact).await;
+                for candidate in learned {
+                    if candidate.node_id != self.local.node_id
+                        && !shortlist.iter().any(|c| c.node_id == candidate.node_id)
+                    {
+                        shortlist.push(candidate);
+                    }
+                }
+            }
+
+            if want_providers && !providers.is_empty() {
+                break;
+            }
+            shortlist.sort_by_key(|c| c.key().distance(&target));
+            shortlist.truncate(K);
+        }
+
+        (shortlist, providers)
+    }
+
+    // Expires stale provider records and republishes our own records
+    pub async fn run_maintenance(&self) {
+        let mut expire_interval = tokio::time::interval(EXPIRE_INTERVAL);
+        let mut republish_interval = tokio::time::interval(REPUBLISH_INTERVAL);
+        // The first tick of an interval fires immediately
+        republish_interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = expire_interval.tick() => {
+                    self.providers.lock().await.expire();
+                }
+                _ = republish_interval.tick() => {
+                    let published: Vec<(Key, Vec<Contact>)> = self
+                        .published
+                        .lock()
+                        .await
+                        .iter()
+                        .map(|(key, providers)| (*key, providers.clone()))
+                        .collect();
+                    println!("Republishing {} provider records", published.len());
+                    for (key, providers) in published {
+                        for provider in providers {
+                            if let Err(e) = self.send_provider_record(ProviderRecord { key, provider }).await {
+                                eprintln!("Failed to republish provider record {:?}: {:?}", key, e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Sends one request over a secure session authenticated against the
+// contact's node id and reads the reply
+pub async fn send_request(identity: &NodeIdentity, contact: &Contact, request: &DhtRequest) -> Result<DhtResponse> {
+    let exchange = async {
+        let mut channel = SecureChannel::connect(&contact.address, identity, &contact.node_id).await?;
+        channel.send(&serde_json::to_vec(request)?).await?;
+        match serde_json::from_slice(&channel.recv().await?)? {
+            DhtResponse::Rejected(reason) => Err(anyhow!("Node {} rejected the request: {}", contact.node_id, reason)),
+            response => Ok(response),
+        }
+    };
+    timeout(RPC_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow!("DHT request to {} timed out", contact.address))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(id: &str) -> Contact {
+        Contact {
+            node_id: id.to_string(),
+            address: format!("{}:9000", id),
+        }
+    }
+
+    #[test]
+    fn test_distance_is_symmetric_xor() {
+        let a = Key::for_node("a");
+        let b = Key::for_node("b");
+        assert_eq!(a.distance(&b), b.distance(&a));
+        assert_eq!(a.distance(&a), Key([0u8; 32]));
+        assert_eq!(a.bucket_index(&a), None);
+    }
+
+    #[test]
+    fn test_chunk_hash_round_trip() {
+        let hash = hex::encode(Sha256::digest(b"chunk"));
+        let key = Key::from_chunk_hash(&hash).unwrap();
+        assert_eq!(format!("{:?}", key), hash);
+        assert!(Key::from_chunk_hash("abcd").is_err());
+    }
+
+    #[test]
+    fn test_closest_orders_by_distance() {
+        let mut table = RoutingTable::new(Key::for_node("local"));
+        for i in 0..50 {
+            table.update(contact(&format!("node{}", i)));
+        }
+        let target = Key::for_node("target");
+        let closest = table.closest(&target, 5);
+        assert_eq!(closest.len(), 5);
+        for pair in closest.windows(2) {
+            assert!(pair[0].key().distance(&target) <= pair[1].key().distance(&target));
+        }
+    }
+
+    #[test]
+    fn test_provider_store_replaces_same_provider() {
+        let mut store = ProviderStore::default();
+        let key = Key::for_node("chunk");
+        store.add(ProviderRecord { key, provider: contact("node1") });
+        store.add(ProviderRecord { key, provider: contact("node1") });
+        store.add(ProviderRecord { key, provider: contact("node2") });
+        assert_eq!(store.get(&key).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_requests_only_speak_for_the_authenticated_node() {
+        let dht = Dht::new(contact("local"), Arc::new(NodeIdentity::generate()));
+        let key = Key::for_node("chunk");
+
+        let spoofed = DhtRequest::Ping { sender: contact("node2") };
+        assert!(matches!(dht.handle_request("node1", spoofed).await, DhtResponse::Rejected(_)));
+        assert_eq!(dht.routing_table.lock().await.len(), 0);
+
+        let record = ProviderRecord { key, provider: contact("node2") };
+        let request = DhtRequest::AddProvider { sender: contact("node1"), record };
+        assert!(matches!(dht.handle_request("node1", request).await, DhtResponse::Rejected(_)));
+        assert!(dht.providers.lock().await.get(&key).is_empty());
+
+        let record = ProviderRecord { key, provider: contact("node1") };
+        let request = DhtRequest::AddProvider { sender: contact("node1"), record };
+        assert!(matches!(dht.handle_request("node1", request).await, DhtResponse::Stored));
+        assert_eq!(dht.providers.lock().await.get(&key), vec![contact("node1")]);
+        assert_eq!(dht.routing_table.lock().await.len(), 1);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,210 @@
+use rand::rngs::StdRng;
+use rand::Rng;
+#[cfg(test)]
+use rand::SeedableRng;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+// Fault injection for in-process clusters. Nodes registered here have their
+// outgoing connections dropped, delayed or partitioned and their chunk writes
+// failed according to a shared FaultPlan. Nothing is registered in a normal
+// node, so every hook is a single lookup in an empty table.
+static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<FaultPlan>>>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<HashMap<String, Arc<FaultPlan>>> {
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+#[cfg(test)]
+pub fn register(node_id: &str, plan: Arc<FaultPlan>) {
+    registry().lock().unwrap().insert(node_id.to_string(), plan);
+}
+
+#[cfg(test)]
+pub fn unregister(node_id: &str) {
+    registry().lock().unwrap().remove(node_id);
+}
+
+fn plan_for(node_id: &str) -> Option<Arc<FaultPlan>> {
+    registry().lock().unwrap().get(node_id).cloned()
+}
+
+// Opens a connection from `local_id` to `address`, applying the faults of its plan
+pub async fn connect(local_id: &str, address: &str) -> io::Result<TcpStream> {
+    if let Some(plan) = plan_for(local_id) {
+        match plan.decide(local_id, address) {
+            Delivery::Deliver => {}
+            Delivery::Delay(delay) => tokio::time::sleep(delay).await,
+            Delivery::Drop(reason) => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason));
+            }
+        }
+    }
+    TcpStream::connect(address).await
+}
+
+// Fails a chunk write on a node whose disk is marked full
+pub fn check_disk(local_id: &str) -> io::Result<()> {
+    match plan_for(local_id) {
+        Some(plan) if plan.state.lock().unwrap().disk_full.contains(local_id) => Err(io::Error::other(format!(
+            "No space left on device (injected on node {})",
+            local_id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Delivery {
+    Deliver,
+    Delay(Duration),
+    Drop(String),
+}
+
+struct FaultState {
+    rng: StdRng,
+    addresses: HashMap<String, String>, // address -> node id
+    drop_rate: f64,
+    delays: HashMap<(String, String), Duration>, // (from, to) -> delay
+    partitions: Vec<HashSet<String>>,
+    disk_full: HashSet<String>,
+}
+
+// Faults shared by all nodes of one simulated cluster. Rules are given by node
+// id; the random drops come from a seeded generator so a run can be repeated.
+pub struct FaultPlan {
+    state: Mutex<FaultState>,
+}
+
+// The rules are only set by the simulation harness
+#[cfg(test)]
+impl FaultPlan {
+    pub fn new(seed: u64) -> Self {
+        FaultPlan {
+            state: Mutex::new(FaultState {
+                rng: StdRng::seed_from_u64(seed),
+                addresses: HashMap::new(),
+                drop_rate: 0.0,
+                delays: HashMap::new(),
+                partitions: Vec::new(),
+                disk_full: HashSet::new(),
+            }),
+        }
+    }
+
+    // Lets rules given by node id apply to connections made by address
+    pub fn set_address(&self, node_id: &str, address: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.addresses.retain(|_, id| id != node_id);
+        state.addresses.insert(address.to_string(), node_id.to_string());
+    }
+
+    // Fraction of connections, between any two nodes, that fail
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.state.lock().unwrap().drop_rate = drop_rate.clamp(0.0, 1.0);
+    }
+
+    // Delays every connection `from` opens to `to`
+    pub fn set_delay(&self, from: &str, to: &str, delay: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .delays
+            .insert((from.to_string(), to.to_string()), delay);
+    }
+
+    // Splits the cluster: nodes in different groups cannot reach each other.
+    // Nodes not listed in any group can still reach everyone.
+    pub fn partition(&self, groups: Vec<Vec<String>>) {
+        self.state.lock().unwrap().partitions =
+            groups.into_iter().map(|group| group.into_iter().collect()).collect();
+    }
+
+    pub fn set_disk_full(&self, node_id: &str, full: bool) {
+        let mut state = self.state.lock().unwrap();
+        if full {
+            state.disk_full.insert(node_id.to_string());
+        } else {
+            state.disk_full.remove(node_id);
+        }
+    }
+
+    // Removes every fault; the random generator keeps its position
+    pub fn heal(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.drop_rate = 0.0;
+        state.delays.clear();
+        state.partitions.clear();
+        state.disk_full.clear();
+    }
+}
+
+impl FaultPlan {
+    fn decide(&self, from: &str, address: &str) -> Delivery {
+        let mut state = self.state.lock().unwrap();
+        // Addresses outside the cluster are not affected
+        let to = match state.addresses.get(address) {
+            Some(to) => to.clone(),
+            None => return Delivery::Deliver,
+        };
+
+        let group_of = |id: &str| state.partitions.iter().position(|group| group.contains(id));
+        if let (Some(a), Some(b)) = (group_of(from), group_of(&to)) {
+            if a != b {
+                return Delivery::Drop(format!("Node {} is partitioned from node {} (injected)", from, to));
+            }
+        }
+        let drop_rate = state.drop_rate;
+        if drop_rate > 0.0 && state.rng.gen_bool(drop_rate) {
+            return Delivery::Drop(format!("Connection from {} to {} dropped (injected)", from, to));
+        }
+        match state.delays.get(&(from.to_string(), to)) {
+            Some(delay) => Delivery::Delay(*delay),
+            None => Delivery::Deliver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(seed: u64) -> FaultPlan {
+        let plan = FaultPlan::new(seed);
+        plan.set_address("a", "127.0.0.1:1001");
+        plan.set_address("b", "127.0.0.1:1002");
+        plan.set_address("c", "127.0.0.1:1003");
+        plan
+    }
+
+    #[test]
+    fn test_partition_and_delay_rules() {
+        let plan = plan(1);
+        plan.partition(vec![vec!["a".to_string()], vec!["b".to_string()]]);
+        plan.set_delay("a", "c", Duration::from_millis(50));
+        assert!(matches!(plan.decide("a", "127.0.0.1:1002"), Delivery::Drop(_)));
+        assert!(matches!(plan.decide("b", "127.0.0.1:1001"), Delivery::Drop(_)));
+        assert_eq!(plan.decide("a", "127.0.0.1:1003"), Delivery::Delay(Duration::from_millis(50)));
+        assert_eq!(plan.decide("c", "127.0.0.1:1002"), Delivery::Deliver);
+        assert_eq!(plan.decide("a", "10.0.0.1:80"), Delivery::Deliver);
+
+        plan.heal();
+        assert_eq!(plan.decide("a", "127.0.0.1:1002"), Delivery::Deliver);
+    }
+
+    #[test]
+    fn test_drops_repeat_for_the_same_seed() {
+        let outcomes = |seed| {
+            let plan = plan(seed);
+            plan.set_drop_rate(0.5);
+            (0..32)
+                .map(|_| plan.decide("a", "127.0.0.1:1002") == Delivery::Deliver)
+                .collect::<Vec<bool>>()
+        };
+        assert_eq!(outcomes(7), outcomes(7));
+        assert!(outcomes(7).contains(&true) && outcomes(7).contains(&false));
+    }
+}
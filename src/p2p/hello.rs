@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+
+use super::transport::MAX_MESSAGE_SIZE;
+
+// Version of the peer protocol (announcement format, chunk requests, DHT
+// messages). Bump it for every incompatible change and raise
+// MIN_PROTOCOL_VERSION once the old format is no longer understood.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+// Chunks are stored without erasure coding for now; peers only use it when both support it
+pub const ERASURE_CODING_SUPPORTED: bool = false;
+// A peer must accept at least one pipeline chunk plus its headers in one message
+pub const MIN_FRAME_SIZE: u64 = 2 * 1024 * 1024;
+
+// First message of every secure session, sent by both sides. The cipher
+// suite is not part of it: the Noise handshake (NOISE_PARAMS) has already run
+// when the Hellos are exchanged.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub software_version: String,
+    pub erasure_coding: bool,
+    pub max_frame_size: u64, // largest application message accepted, in bytes
+}
+
+impl Hello {
+    pub fn local() -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            software_version: SOFTWARE_VERSION.to_string(),
+            erasure_coding: ERASURE_CODING_SUPPORTED,
+            max_frame_size: MAX_MESSAGE_SIZE as u64,
+        }
+    }
+}
+
+// Answer of the responder to the initiator's Hello
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Accept(Hello),
+    Refuse { reason: String },
+}
+
+// What both sides agreed on; used for the rest of the session
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    pub erasure_coding: bool,
+    pub max_frame_size: u64,
+    pub peer_software_version: String,
+}
+
+// Negotiates the session parameters, or returns the reason the peer is refused.
+// Both sides run it on the same pair of Hellos and get the same result.
+pub fn negotiate(local: &Hello, remote: &Hello) -> Result<Capabilities, String> {
+    let protocol_version = local.protocol_version.min(remote.protocol_version);
+    let required = local.min_protocol_version.max(remote.min_protocol_version);
+    if protocol_version < required {
+        return Err(format!(
+            "incompatible protocol versions: we speak {}..={}, peer ({}) speaks {}..={}",
+            local.min_protocol_version,
+            local.protocol_version,
+            remote.software_version,
+            remote.min_protocol_version,
+            remote.protocol_version
+        ));
+    }
+
+    let max_frame_size = local.max_frame_size.min(remote.max_frame_size);
+    if max_frame_size < MIN_FRAME_SIZE {
+        return Err(format!(
+            "max frame size of {} bytes is below the required {} bytes",
+            max_frame_size, MIN_FRAME_SIZE
+        ));
+    }
+
+    Ok(Capabilities {
+        protocol_version,
+        erasure_coding: local.erasure_coding && remote.erasure_coding,
+        max_frame_size,
+        peer_software_version: remote.software_version.clone(),
+    })
+}
+
+// Whether an announcement of the given protocol version can be understood
+pub fn is_supported_version(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatible_peers_agree_on_the_same_capabilities() {
+        let local = Hello::local();
+        let remote = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            software_version: "0.2.0".to_string(),
+            erasure_coding: true,
+            max_frame_size: 8 * 1024 * 1024,
+            ..Hello::local()
+        };
+        let ours = negotiate(&local, &remote).unwrap();
+        let theirs = negotiate(&remote, &local).unwrap();
+        assert_eq!(ours.protocol_version, PROTOCOL_VERSION);
+        assert!(!ours.erasure_coding);
+        assert_eq!(ours.max_frame_size, 8 * 1024 * 1024);
+        assert_eq!(ours.peer_software_version, "0.2.0");
+        assert_eq!(
+            (ours.protocol_version, ours.max_frame_size),
+            (theirs.protocol_version, theirs.max_frame_size)
+        );
+    }
+
+    #[test]
+    fn test_incompatible_peers_are_refused_with_a_reason() {
+        let local = Hello::local();
+        let newer = Hello {
+            protocol_version: PROTOCOL_VERSION + 2,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::local()
+        };
+        assert!(negotiate(&local, &newer).unwrap_err().contains("protocol versions"));
+
+        let tiny_frames = Hello {
+            max_frame_size: 1024,
+            ..Hello::local()
+        };
+        assert!(negotiate(&local, &tiny_frames).unwrap_err().contains("frame size"));
+    }
+}
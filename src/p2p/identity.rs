@@ -0,0 +1,215 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::hello::{is_supported_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::node::Node;
+
+// Announcements older than this are rejected, so nodes re-announce periodically
+pub const ANNOUNCEMENT_MAX_AGE: u64 = 10 * 60;
+// Tolerated clock difference for announcements from the future
+pub const MAX_CLOCK_SKEW: u64 = 60;
+// Prefix of every signed payload so a signature cannot be reused in another context
+const ANNOUNCEMENT_DOMAIN: &str = "decentralized-storage/announcement/v1";
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    secret_key: String,
+}
+
+// Ed25519 key pair of this node. The node id is derived from the public key,
+// so a peer cannot claim an id without holding the matching secret key.
+pub struct NodeIdentity {
+    signing_key: SigningKey,
+}
+
+impl NodeIdentity {
+    pub fn generate() -> Self {
+        NodeIdentity {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    // Loads the identity stored at `path`, or creates and saves a new one.
+    // The file holds the secret key in plain hex: restrict it like keys/key_data.json (chmod 600).
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let content = fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read identity file {}: {}", path.display(), e))?;
+            let stored: StoredIdentity = serde_json::from_str(&content)?;
+            let secret: [u8; 32] = hex::decode(&stored.secret_key)?
+                .try_into()
+                .map_err(|_| anyhow!("Identity file {} has an invalid key length", path.display()))?;
+            return Ok(NodeIdentity {
+                signing_key: SigningKey::from_bytes(&secret),
+            });
+        }
+
+        let identity = Self::generate();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let stored = StoredIdentity {
+            secret_key: hex::encode(identity.signing_key.to_bytes()),
+        };
+        fs::write(path, serde_json::to_vec(&stored)?)
+            .map_err(|e| anyhow!("Failed to write identity file {}: {}", path.display(), e))?;
+        println!("New node identity created: {}", identity.node_id());
+        Ok(identity)
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.verifying_key().to_bytes())
+    }
+
+    pub fn node_id(&self) -> String {
+        node_id_from_public_key(&self.verifying_key())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.signing_key.sign(message)
+    }
+
+    // Signs the current record of this node. `node.id` must be our own id.
+    pub fn announce(&self, node: &Node) -> Result<SignedAnnouncement> {
+        if node.id != self.node_id() {
+            return Err(anyhow!("Node id {} does not match identity {}", node.id, self.node_id()));
+        }
+        let public_key = self.public_key_hex();
+        let timestamp = unix_now();
+        let payload = announcement_payload(node, &public_key, timestamp)?;
+        Ok(SignedAnnouncement {
+            node: node.clone(),
+            public_key,
+            timestamp,
+            signature: hex::encode(self.sign(&payload).to_bytes()),
+            protocol_version: PROTOCOL_VERSION,
+        })
+    }
+}
+
+// Node ids are the hex sha256 of the Ed25519 public key
+pub fn node_id_from_public_key(public_key: &VerifyingKey) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(public_key.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+pub fn parse_public_key(public_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(public_key)?
+        .try_into()
+        .map_err(|_| anyhow!("Public key must be 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| anyhow!("Invalid public key: {}", e))
+}
+
+pub fn verify_signature(public_key: &VerifyingKey, message: &[u8], signature: &str) -> Result<()> {
+    let bytes: [u8; 64] = hex::decode(signature)?
+        .try_into()
+        .map_err(|_| anyhow!("Signature must be 64 bytes"))?;
+    public_key
+        .verify_strict(message, &Signature::from_bytes(&bytes))
+        .map_err(|_| anyhow!("Invalid signature"))
+}
+
+// Node record signed by the node it describes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedAnnouncement {
+    pub node: Node,
+    pub public_key: String, // hex encoded Ed25519 public key
+    pub timestamp: u64,     // unix seconds, must increase with every announcement
+    pub signature: String,  // hex encoded signature over announcement_payload
+    // Protocol the record is encoded with, so a node can tell a record it
+    // cannot read from a malformed one. Records without it are version 1.
+    #[serde(default = "first_protocol_version")]
+    pub protocol_version: u32,
+}
+
+fn first_protocol_version() -> u32 {
+    1
+}
+
+impl SignedAnnouncement {
+    // Checks that the record is signed by the key its id is derived from and
+    // that it is recent. Replay detection against previously seen timestamps
+    // is done by the caller, which knows the last accepted announcement.
+    pub fn verify(&self) -> Result<()> {
+        if !is_supported_version(self.protocol_version) {
+            return Err(anyhow!(
+                "Announcement for {} uses protocol version {}, supported are {}..={}",
+                self.node.id, self.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+        let public_key = parse_public_key(&self.public_key)?;
+        if self.node.id != node_id_from_public_key(&public_key) {
+            return Err(anyhow!("Node id {} is not derived from the announced public key", self.node.id));
+        }
+
+        let now = unix_now();
+        if self.timestamp > now + MAX_CLOCK_SKEW {
+            return Err(anyhow!("Announcement for {} is from the future", self.node.id));
+        }
+        if self.timestamp + ANNOUNCEMENT_MAX_AGE < now {
+            return Err(anyhow!("Announcement for {} has expired", self.node.id));
+        }
+
+        let payload = announcement_payload(&self.node, &self.public_key, self.timestamp)?;
+        verify_signature(&public_key, &payload, &self.signature)
+    }
+}
+
+fn announcement_payload(node: &Node, public_key: &str, timestamp: u64) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&(ANNOUNCEMENT_DOMAIN, node, public_key, timestamp))?)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_node(identity: &NodeIdentity) -> Node {
+        Node {
+            id: identity.node_id(),
+            storage_path: "storage/test".to_string(),
+            total_space: 1000,
+            available_space: 1000,
+            address: "127.0.0.1:8081".to_string(),
+            labels: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_signed_announcement_verifies() {
+        let identity = NodeIdentity::generate();
+        let announcement = identity.announce(&local_node(&identity)).unwrap();
+        assert!(announcement.verify().is_ok());
+    }
+
+    #[test]
+    fn test_tampered_or_spoofed_announcement_is_rejected() {
+        let identity = NodeIdentity::generate();
+        let mut tampered = identity.announce(&local_node(&identity)).unwrap();
+        tampered.node.available_space = 1_000_000;
+        assert!(tampered.verify().is_err());
+
+        // Valid signature, but the claimed id belongs to another key
+        let other = NodeIdentity::generate();
+        let mut spoofed = identity.announce(&local_node(&identity)).unwrap();
+        spoofed.public_key = other.public_key_hex();
+        assert!(spoofed.verify().is_err());
+    }
+}
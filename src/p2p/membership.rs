@@ -0,0 +1,283 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{Duration, Instant};
+
+// Every node pushes its signed announcement to all known peers this often
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+// How often the failure detector re-evaluates the peers
+pub const DETECTOR_INTERVAL: Duration = Duration::from_secs(1);
+// A peer whose phi reaches this value is suspected (phi 8 ~ 1e-8 chance of a false positive)
+pub const SUSPECT_PHI: f64 = 8.0;
+// A suspected peer that stays silent this long is declared dead and evicted
+pub const DEAD_AFTER: Duration = Duration::from_secs(30);
+// Missing heartbeats tolerated on top of the observed interval (e.g. one lost push)
+const ACCEPTABLE_PAUSE: Duration = Duration::from_secs(5);
+// Floor for the interval deviation so a very regular peer is not suspected on the first jitter
+const MIN_STD_DEVIATION: Duration = Duration::from_millis(500);
+// Number of inter-arrival samples kept per peer
+const WINDOW_SIZE: usize = 100;
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum PeerState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+// Membership changes published to placement, repair and anything else that subscribes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum MembershipEvent {
+    Joined { node_id: String },
+    Suspected { node_id: String },
+    Recovered { node_id: String },
+    Died { node_id: String },
+}
+
+// Phi-accrual failure detector for one peer (Hayashibara et al.): instead of a
+// fixed timeout it reports how unlikely the current silence is, given the
+// heartbeat intervals observed so far
+struct HeartbeatHistory {
+    intervals: VecDeque<f64>, // seconds
+    last_heartbeat: Instant,
+}
+
+impl HeartbeatHistory {
+    fn new(now: Instant) -> Self {
+        // Until real samples arrive, assume the peer sends at the nominal rate
+        let mut intervals = VecDeque::with_capacity(WINDOW_SIZE);
+        intervals.push_back(HEARTBEAT_INTERVAL.as_secs_f64());
+        HeartbeatHistory {
+            intervals,
+            last_heartbeat: now,
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        let interval = now.saturating_duration_since(self.last_heartbeat).as_secs_f64();
+        if self.intervals.len() == WINDOW_SIZE {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(interval);
+        self.last_heartbeat = now;
+    }
+
+    fn phi(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_heartbeat).as_secs_f64();
+        let count = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / count;
+        let variance = self.intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / count;
+        let std_deviation = variance.sqrt().max(MIN_STD_DEVIATION.as_secs_f64());
+        phi(elapsed, mean + ACCEPTABLE_PAUSE.as_secs_f64(), std_deviation)
+    }
+}
+
+// -log10 of the probability that a heartbeat arrives later than `elapsed`, using
+// the logistic approximation of the normal CDF
+fn phi(elapsed: f64, mean: f64, std_deviation: f64) -> f64 {
+    let y = (elapsed - mean) / std_deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+struct PeerHealth {
+    state: PeerState,
+    history: HeartbeatHistory,
+    suspected_at: Option<Instant>,
+}
+
+// Snapshot of one peer as seen by the failure detector
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerStatus {
+    pub node_id: String,
+    pub state: PeerState,
+    pub phi: f64,
+    pub seconds_since_heartbeat: u64,
+}
+
+// Tracks the liveness of every known peer
+pub struct Membership {
+    local_id: String,
+    peers: Mutex<HashMap<String, PeerHealth>>,
+    events: broadcast::Sender<MembershipEvent>,
+}
+
+impl Membership {
+    pub fn new(local_id: String) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Membership {
+            local_id,
+            peers: Mutex::new(HashMap::new()),
+            events,
+        }
+    }
+
+    // Receives every state change from now on. Slow receivers miss old events
+    // (RecvError::Lagged) and should resynchronize with states().
+    pub fn subscribe(&self) -> broadcast::Receiver<MembershipEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn heartbeat(&self, node_id: &str) {
+        self.heartbeat_at(node_id, Instant::now()).await;
+    }
+
+    async fn heartbeat_at(&self, node_id: &str, now: Instant) {
+        if node_id == self.local_id {
+            return;
+        }
+        let event = {
+            let mut peers = self.peers.lock().await;
+            match peers.get_mut(node_id) {
+                Some(peer) => {
+                    peer.history.record(now);
+                    peer.suspected_at = None;
+                    match std::mem::replace(&mut peer.state, PeerState::Alive) {
+                        PeerState::Alive => None,
+                        PeerState::Suspect => Some(MembershipEvent::Recovered { node_id: node_id.to_string() }),
+                        PeerState::Dead => Some(MembershipEvent::Joined { node_id: node_id.to_string() }),
+                    }
+                }
+                None => {
+                    peers.insert(
+                        node_id.to_string(),
+                        PeerHealth {
+                            state: PeerState::Alive,
+                            history: HeartbeatHistory::new(now),
+                            suspected_at: None,
+                        },
+                    );
+                    Some(MembershipEvent::Joined { node_id: node_id.to_string() })
+                }
+            }
+        };
+        if let Some(event) = event {
+            self.publish(event);
+        }
+    }
+
+    // Moves silent peers to suspect and long-suspected peers to dead.
+    // Returns the ids of the peers that died in this round; they are
+    // forgotten here and must be evicted from the node table by the caller.
+    pub async fn evaluate(&self) -> Vec<String> {
+        self.evaluate_at(Instant::now()).await
+    }
+
+    async fn evaluate_at(&self, now: Instant) -> Vec<String> {
+        let mut events = Vec::new();
+        let mut dead = Vec::new();
+        {
+            let mut peers = self.peers.lock().await;
+            for (node_id, peer) in peers.iter_mut() {
+                match peer.state {
+                    PeerState::Alive if peer.history.phi(now) >= SUSPECT_PHI => {
+                        peer.state = PeerState::Suspect;
+                        peer.suspected_at = Some(now);
+                        events.push(MembershipEvent::Suspected { node_id: node_id.clone() });
+                    }
+                    PeerState::Suspect
+                        if peer
+                            .suspected_at
+                            .is_some_and(|since| now.saturating_duration_since(since) >= DEAD_AFTER) =>
+                    {
+                        peer.state = PeerState::Dead;
+                        dead.push(node_id.clone());
+                        events.push(MembershipEvent::Died { node_id: node_id.clone() });
+                    }
+                    _ => {}
+                }
+            }
+            for node_id in &dead {
+                peers.remove(node_id);
+            }
+        }
+        for event in events {
+            self.publish(event);
+        }
+        dead
+    }
+
+    pub async fn state(&self, node_id: &str) -> Option<PeerState> {
+        self.peers.lock().await.get(node_id).map(|peer| peer.state)
+    }
+
+    pub async fn states(&self) -> Vec<PeerStatus> {
+        let now = Instant::now();
+        let peers = self.peers.lock().await;
+        peers
+            .iter()
+            .map(|(node_id, peer)| PeerStatus {
+                node_id: node_id.clone(),
+                state: peer.state,
+                phi: peer.history.phi(now),
+                seconds_since_heartbeat: now.saturating_duration_since(peer.history.last_heartbeat).as_secs(),
+            })
+            .collect()
+    }
+
+    // Stops tracking a peer that left the table for another reason
+    pub async fn remove(&self, node_id: &str) {
+        self.peers.lock().await.remove(node_id);
+    }
+
+    fn publish(&self, event: MembershipEvent) {
+        println!("Membership change: {:?}", event);
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let start = Instant::now();
+        let mut history = HeartbeatHistory::new(start);
+        for i in 1..=10 {
+            history.record(start + HEARTBEAT_INTERVAL * i);
+        }
+        let last = start + HEARTBEAT_INTERVAL * 10;
+        let on_time = history.phi(last + HEARTBEAT_INTERVAL);
+        let late = history.phi(last + HEARTBEAT_INTERVAL * 3);
+        assert!(on_time < 1.0);
+        assert!(late > on_time);
+        assert!(late >= SUSPECT_PHI);
+    }
+
+    #[tokio::test]
+    async fn test_peer_goes_suspect_then_dead_and_recovers() {
+        let membership = Membership::new("local".to_string());
+        let mut events = membership.subscribe();
+        let start = Instant::now();
+
+        membership.heartbeat_at("peer", start).await;
+        assert_eq!(events.recv().await.unwrap(), MembershipEvent::Joined { node_id: "peer".to_string() });
+        // Heartbeats from ourselves (relayed announcements) are ignored
+        membership.heartbeat_at("local", start).await;
+        assert_eq!(membership.state("local").await, None);
+
+        let silent = start + HEARTBEAT_INTERVAL * 4;
+        assert!(membership.evaluate_at(silent).await.is_empty());
+        assert_eq!(membership.state("peer").await, Some(PeerState::Suspect));
+        assert_eq!(events.recv().await.unwrap(), MembershipEvent::Suspected { node_id: "peer".to_string() });
+
+        membership.heartbeat_at("peer", silent + Duration::from_secs(1)).await;
+        assert_eq!(membership.state("peer").await, Some(PeerState::Alive));
+        assert_eq!(events.recv().await.unwrap(), MembershipEvent::Recovered { node_id: "peer".to_string() });
+
+        // The long gap widened the observed intervals, so it takes longer to suspect again
+        let suspected = silent + Duration::from_secs(120);
+        membership.evaluate_at(suspected).await;
+        assert_eq!(membership.state("peer").await, Some(PeerState::Suspect));
+        let dead = membership.evaluate_at(suspected + DEAD_AFTER).await;
+        assert_eq!(dead, vec!["peer".to_string()]);
+        assert_eq!(membership.state("peer").await, None);
+    }
+}
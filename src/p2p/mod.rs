@@ -1,12 +1,7 @@
-use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::Write;
 use std::net::SocketAddr;
-use std::path::Path;
-use std::process::Command;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::thread::available_parallelism;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use anyhow::{Result, anyhow};
 use tokio::net::TcpListener;
@@ -15,39 +10,227 @@ use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 
 use crate::node::Node;
+use crate::placement::{AntiAffinity, FirstFit, PlacementStrategy};
+use crate::proof_of_spacetime::{Prover, ProverFuture, ProverRequest};
+use crate::reputation::Reputation;
+
+pub mod bandwidth;
+pub mod chunk_service;
+pub mod dht;
+pub mod faults;
+pub mod hello;
+pub mod identity;
+pub mod membership;
+pub mod peerstore;
+pub mod raft;
+pub mod transport;
+use bandwidth::BandwidthLimiter;
+use chunk_service::ChunkClient;
+use dht::{Contact, Dht};
+use identity::{NodeIdentity, SignedAnnouncement, ANNOUNCEMENT_MAX_AGE};
+use membership::{Membership, PeerState, DETECTOR_INTERVAL, HEARTBEAT_INTERVAL};
+use peerstore::Peerstore;
+use raft::RaftNode;
 
 
 // Network struct that holds the nodes
 pub struct Network {
     nodes: Arc<Mutex<HashMap<String, Node>>>,
+    // Latest accepted announcement of every node, relayed on GET_NODES and
+    // used to reject replayed (older) announcements
+    announcements: Arc<Mutex<HashMap<String, SignedAnnouncement>>>,
+    identity: Arc<NodeIdentity>,
+    // Kademlia DHT used to locate chunk holders, served on the same listener
+    dht: Option<Arc<Dht>>,
+    // Where chunks received over secure sessions are written; None refuses them
+    chunk_dir: Option<PathBuf>,
+    // Failure detector fed by the announcements peers push as heartbeats
+    membership: Arc<Membership>,
+    // Address serve listens on, used to avoid dialing ourselves
+    listen_addr: Option<SocketAddr>,
+    // Known peers persisted across restarts
+    peerstore: Option<Arc<Mutex<Peerstore>>>,
+    // Peer scores used by placement; shared with the API
+    reputation: Arc<Reputation>,
+    // Rate limits for chunk transfers, served and requested
+    bandwidth: Arc<BandwidthLimiter>,
+    // How chunks are spread over the nodes
+    placement: Arc<dyn PlacementStrategy>,
+    // Keeps the chunks of a file out of the same failure domain; None allows anything
+    anti_affinity: Option<AntiAffinity>,
+    // Metadata replica answering raft requests on secure sessions; None when this node is not one
+    raft: Option<Arc<RaftNode>>,
+    // This node's own record; other nodes may place chunks on it, and it
+    // never appears in its own node table
+    local_node: Option<Node>,
 }
 
 impl Network {
-    pub fn new() -> Self {
+    pub fn new(identity: Arc<NodeIdentity>) -> Self {
         Network {
             nodes: Arc::new(Mutex::new(HashMap::new())),
+            announcements: Arc::new(Mutex::new(HashMap::new())),
+            dht: None,
+            chunk_dir: None,
+            membership: Arc::new(Membership::new(identity.node_id())),
+            identity,
+            listen_addr: None,
+            peerstore: None,
+            reputation: Arc::new(Reputation::new()),
+            bandwidth: Arc::new(BandwidthLimiter::unlimited()),
+            placement: Arc::new(FirstFit),
+            anti_affinity: None,
+            raft: None,
+            local_node: None,
         }
     }
 
+    pub fn with_dht(mut self, dht: Arc<Dht>) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+
+    pub fn with_chunk_dir(mut self, chunk_dir: PathBuf) -> Self {
+        self.chunk_dir = Some(chunk_dir);
+        self
+    }
+
+    pub fn with_listen_addr(mut self, listen_addr: SocketAddr) -> Self {
+        self.listen_addr = Some(listen_addr);
+        self
+    }
+
+    pub fn with_local_node(mut self, local_node: Node) -> Self {
+        self.local_node = Some(local_node);
+        self
+    }
+
+    pub fn with_peerstore(mut self, peerstore: Peerstore) -> Self {
+        self.peerstore = Some(Arc::new(Mutex::new(peerstore)));
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: Arc<BandwidthLimiter>) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn with_placement(mut self, placement: Arc<dyn PlacementStrategy>) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    pub fn with_anti_affinity(mut self, anti_affinity: Option<AntiAffinity>) -> Self {
+        self.anti_affinity = anti_affinity;
+        self
+    }
+
+    // The replica takes metadata changes from the nodes this network knows
+    pub fn with_raft(mut self, raft: Arc<RaftNode>) -> Self {
+        raft.admit_storage_nodes(self.nodes.clone());
+        self.raft = Some(raft);
+        self
+    }
+
+    pub fn identity(&self) -> Arc<NodeIdentity> {
+        self.identity.clone()
+    }
+
+    pub fn membership(&self) -> Arc<Membership> {
+        self.membership.clone()
+    }
+
+    pub fn reputation(&self) -> Arc<Reputation> {
+        self.reputation.clone()
+    }
+
+    pub fn bandwidth(&self) -> Arc<BandwidthLimiter> {
+        self.bandwidth.clone()
+    }
+
+    pub fn placement(&self) -> Arc<dyn PlacementStrategy> {
+        self.placement.clone()
+    }
+
+    pub fn anti_affinity(&self) -> Option<&AntiAffinity> {
+        self.anti_affinity.as_ref()
+    }
+
     pub async fn get_nodes(&self) -> Vec<Node> {
         let nodes = self.nodes.lock().await;
         nodes.values().cloned().collect()
     }
 
+    // Nodes the failure detector currently considers alive; placement should
+    // use these so new chunks do not go to suspected peers
+    pub async fn get_live_nodes(&self) -> Vec<Node> {
+        let mut live = Vec::new();
+        for node in self.get_nodes().await {
+            if self.membership.state(&node.id).await == Some(PeerState::Alive) {
+                live.push(node);
+            }
+        }
+        live
+    }
+
     
 
-    pub async fn add_node(&self, node: Node) {
-        let mut nodes = self.nodes.lock().await;
-        if !nodes.contains_key(&node.id) {
-            println!("Node added: {:?}", node.clone());
-            nodes.insert(node.id.clone(), node);
+    // Adds or updates a node from its signed announcement.
+    // Returns Ok(false) if this exact announcement was already accepted.
+    pub async fn add_node(&self, announcement: SignedAnnouncement) -> Result<bool> {
+        Self::accept_announcement(&self.nodes, &self.announcements, &self.dht, &self.membership, announcement).await
+    }
+
+    // Shared by add_node and the server task: rejects unsigned, spoofed, expired
+    // and replayed records before they reach the node table
+    async fn accept_announcement(
+        nodes: &Arc<Mutex<HashMap<String, Node>>>,
+        announcements: &Arc<Mutex<HashMap<String, SignedAnnouncement>>>,
+        dht: &Option<Arc<Dht>>,
+        membership: &Arc<Membership>,
+        announcement: SignedAnnouncement,
+    ) -> Result<bool> {
+        announcement.verify()?;
+
+        let node = announcement.node.clone();
+        {
+            let mut announcements = announcements.lock().await;
+            if let Some(known) = announcements.get(&node.id) {
+                if known.timestamp == announcement.timestamp && known.signature == announcement.signature {
+                    return Ok(false);
+                }
+                if known.timestamp >= announcement.timestamp {
+                    return Err(anyhow!(
+                        "Replayed announcement for node {} (timestamp {} <= {})",
+                        node.id, announcement.timestamp, known.timestamp
+                    ));
+                }
+            }
+            announcements.insert(node.id.clone(), announcement);
+        }
+
+        // A newer signed record proves the node was up when it signed it
+        membership.heartbeat(&node.id).await;
+
+        // Every known peer is also a candidate DHT contact
+        if let Some(dht) = dht {
+            dht.observe(Contact::from(&node)).await;
+        }
+        let mut nodes = nodes.lock().await;
+        if nodes.insert(node.id.clone(), node.clone()).is_none() {
+            println!("Node added: {:?}", node);
             println!("Current network size: {}", nodes.len());
         } else {
-            println!("Node already exists: {:?}", node.id);
+            println!("Node updated: {:?}", node.id);
         }
+        Ok(true)
     }
+
     // Node ID'sine göre node'u döndüren fonksiyon
     pub async fn get_node_by_id(&self, node_id: &str) -> Option<Node> {
+        if let Some(local_node) = self.local_node.as_ref().filter(|node| node.id == node_id) {
+            return Some(local_node.clone());
+        }
         // Node'ları al
         let nodes = self.get_nodes().await;
 
@@ -56,21 +239,41 @@ impl Network {
     }
 
     // This function is used to handle incoming messages from peers
-    async fn handle_peer_message(socket: &mut TcpStream, nodes: Arc<Mutex<HashMap<String, Node>>>) -> io::Result<()> {
-        let mut buffer = [0; 1024];
+    async fn handle_peer_message(
+        socket: &mut TcpStream,
+        nodes: Arc<Mutex<HashMap<String, Node>>>,
+        announcements: Arc<Mutex<HashMap<String, SignedAnnouncement>>>,
+        dht: Option<Arc<Dht>>,
+        membership: Arc<Membership>,
+    ) -> io::Result<()> {
+        let mut buffer = [0; 4096];
         match socket.read(&mut buffer).await {
             Ok(bytes_read) if bytes_read > 0 => {
                 let received_data = &buffer[..bytes_read];
-                if let Ok(message) = serde_json::from_slice::<Node>(received_data) {
-                    println!("Received node data: {:?}", message);
-                    nodes.lock().await.insert(message.id.clone(), message);
-                    println!("Successfully added node to network");
+                if let Ok(announcement) = serde_json::from_slice::<SignedAnnouncement>(received_data) {
+                    let node_id = announcement.node.id.clone();
+                    match Self::accept_announcement(&nodes, &announcements, &dht, &membership, announcement).await {
+                        Ok(_) => println!("Accepted announcement from node {}", node_id),
+                        Err(e) => eprintln!("Rejected announcement for node {}: {:?}", node_id, e),
+                    }
+                // Else if the received data is a command
                 } else if let Ok(command) = String::from_utf8(received_data.to_vec()) {
                     if command == "GET_NODES" {
-                        let node_list: Vec<Node> = nodes.lock().await.values().cloned().collect();
+                        // Relay the signed records so the requester can verify them itself.
+                        // Records of evicted nodes are kept for replay detection only.
+                        let known: Vec<String> = nodes.lock().await.keys().cloned().collect();
+                        let node_list: Vec<SignedAnnouncement> = announcements
+                            .lock()
+                            .await
+                            .values()
+                            .filter(|announcement| known.contains(&announcement.node.id))
+                            .cloned()
+                            .collect();
                         println!("Sending node list of size: {}", node_list.len());
                         let response = serde_json::to_vec(&node_list)?;
                         socket.write_all(&response).await?;
+                    } else {
+                        eprintln!("Ignoring unsigned or unknown message: {}", command);
                     }
                 }
             }
@@ -80,14 +283,85 @@ impl Network {
         Ok(())
     }
 
+    // Sends our own signed record to a peer
+    pub async fn announce_to(&self, local_node: &Node, peer: SocketAddr) -> Result<()> {
+        let announcement = self.identity.announce(local_node)?;
+        send_announcement(&announcement, &peer.to_string()).await
+    }
+
+    // Removes a dead node from the node table and the DHT routing table
+    pub async fn evict_node(&self, node_id: &str) {
+        if self.nodes.lock().await.remove(node_id).is_some() {
+            println!("Node evicted: {}", node_id);
+        }
+        if let Some(dht) = &self.dht {
+            dht.forget(node_id).await;
+        }
+        self.membership.remove(node_id).await;
+
+        // The last announcement is kept so it cannot be replayed to resurrect the
+        // node; once it has expired it would be rejected anyway and can go
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let nodes = self.nodes.lock().await;
+        self.announcements.lock().await.retain(|id, announcement| {
+            nodes.contains_key(id) || announcement.timestamp + ANNOUNCEMENT_MAX_AGE >= now
+        });
+    }
+
+    // Pushes our signed record to every known node as a heartbeat and runs the
+    // failure detector, evicting the nodes it declares dead
+    pub async fn run_heartbeats(&self, local_node: Node) {
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        let mut detector = tokio::time::interval(DETECTOR_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    let announcement = match self.identity.announce(&local_node) {
+                        Ok(announcement) => announcement,
+                        Err(e) => {
+                            eprintln!("Failed to sign heartbeat: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let peers: Vec<Node> = self
+                        .get_nodes()
+                        .await
+                        .into_iter()
+                        .filter(|node| node.id != local_node.id)
+                        .collect();
+                    // Sent concurrently so one unreachable peer does not delay the others
+                    let sends = peers.iter().map(|node| send_announcement(&announcement, &node.address));
+                    for (node, result) in peers.iter().zip(futures::future::join_all(sends).await) {
+                        if let Err(e) = result {
+                            eprintln!("Heartbeat to node {} failed: {:?}", node.id, e);
+                        }
+                    }
+                }
+                _ = detector.tick() => {
+                    for node_id in self.membership.evaluate().await {
+                        self.reputation.record_liveness(&node_id, false);
+                        self.evict_node(&node_id).await;
+                    }
+                    // Uptime is sampled once per detector round
+                    for status in self.membership.states().await {
+                        self.reputation.record_liveness(&status.node_id, status.state == PeerState::Alive);
+                    }
+                }
+            }
+        }
+    }
+
   
 
     // This function is used to discover new peers by connecting to the initial peers
     pub async fn discover_peers(&self, initial_peers: Vec<SocketAddr>) {
         for peer in initial_peers {
             // Purpose is to avoid connecting to self
-            if peer.ip() == std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)
-                && peer.port() == self.local_port()
+            if Some(peer) == self.listen_addr
+                || (peer.ip().is_loopback() && Some(peer.port()) == self.local_port())
             {
                 println!("Skipping self connection to peer: {:?}", peer);
                 continue;
@@ -100,7 +374,8 @@ impl Network {
             while retry_attempts > 0 {
                 // Apply timeout to each connection attempt to avoid hanging indefinitely
                 let connect_result =
-                    tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(peer)).await;
+                    tokio::time::timeout(Duration::from_secs(5), faults::connect(&self.identity.node_id(), &peer.to_string()))
+                        .await;
 
                 match connect_result {
                     Ok(Ok(mut stream)) => {
@@ -113,13 +388,19 @@ impl Network {
                             break;
                         }
 
-                        let mut buffer = vec![0; 1024];
-                        if let Ok(size) = stream.read(&mut buffer).await {
+                        // The peer closes the connection after sending the list
+                        let mut buffer = Vec::new();
+                        if let Ok(Ok(_)) =
+                            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buffer)).await
+                        {
                             if let Ok(peer_nodes) =
-                                serde_json::from_slice::<Vec<Node>>(&buffer[..size])
+                                serde_json::from_slice::<Vec<SignedAnnouncement>>(&buffer)
                             {
-                                for node in peer_nodes {
-                                    self.add_node(node).await;
+                                for announcement in peer_nodes {
+                                    let node_id = announcement.node.id.clone();
+                                    if let Err(e) = self.add_node(announcement).await {
+                                        eprintln!("Rejected announcement for node {} from peer {:?}: {:?}", node_id, peer, e);
+                                    }
                                 }
                                 println!("Discovered and added new peers.");
                             } else {
@@ -154,100 +435,116 @@ impl Network {
         }
     }
 
-    pub async fn start_server(&self, addr: SocketAddr) -> io::Result<()> {
-        match TcpListener::bind(addr).await {
-            Ok(listener) => {
-                println!("Server started on {:?}", addr);
-                loop {
-                    // Accept a new connection
-                    match listener.accept().await {
-                        Ok((mut socket, _)) => {
-                            let nodes = self.nodes.clone();
-                            // tokio spawn is used to run the async block concurrently
-                            tokio::spawn(async move {
-                                let mut buffer = [0; 1024];
-                                match socket.read(&mut buffer).await {
-                                    Ok(bytes_read) if bytes_read > 0 => {
-                                        let received_data = &buffer[..bytes_read];
-                                        if let Ok(message) =
-                                            serde_json::from_slice::<Node>(received_data)
-                                        {
-                                            nodes
-                                                .lock()
-                                                .await
-                                                .insert(message.id.clone(), message.clone());
-                                            println!("Node added: {:?}", message);
-                                        // Else if the received data is a command
-                                        } else if let Ok(command) =
-                                            String::from_utf8(received_data.to_vec())
-                                        {
-                                            if command == "GET_NODES" {
-                                                let node_list = nodes
-                                                    .lock()
-                                                    .await
-                                                    .values()
-                                                    .cloned()
-                                                    .collect::<Vec<_>>();
-                                                let response =
-                                                    serde_json::to_vec(&node_list).unwrap();
-                                                socket.write_all(&response).await.unwrap();
-                                            }
-                                        }
-                                    }
-                                    Ok(_) => {
-                                        println!("Connection closed");
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Failed to read from socket: {:?}", e);
+    // Accepts connections on a listener that is already bound; its address
+    // should match the listen address given to with_listen_addr
+    pub async fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        println!("Server started on {:?}", listener.local_addr()?);
+        loop {
+            // Accept a new connection
+            match listener.accept().await {
+                Ok((mut socket, _)) => {
+                    let nodes = self.nodes.clone();
+                    let announcements = self.announcements.clone();
+                    let dht = self.dht.clone();
+                    let identity = self.identity.clone();
+                    let chunk_dir = self.chunk_dir.clone();
+                    let membership = self.membership.clone();
+                    let bandwidth = self.bandwidth.clone();
+                    let raft = self.raft.clone();
+                    // tokio spawn is used to run the async block concurrently
+                    tokio::spawn(async move {
+                        // Encrypted chunk transfers share the port with the plain JSON messages
+                        if transport::is_secure_connection(&socket).await {
+                            match chunk_dir {
+                                Some(chunk_dir) => {
+                                    if let Err(e) = chunk_service::serve_connection(
+                                        socket, identity, nodes, chunk_dir, dht, bandwidth, raft,
+                                    )
+                                    .await
+                                    {
+                                        eprintln!("Secure session failed: {:?}", e);
                                     }
                                 }
-                            });
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to accept connection: {:?}", e);
+                                None => eprintln!("Refusing secure session: no chunk directory configured"),
+                            }
+                        } else if let Err(e) =
+                            Self::handle_peer_message(&mut socket, nodes, announcements, dht, membership).await
+                        {
+                            eprintln!("Failed to handle peer message: {:?}", e);
                         }
-                    }
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {:?}", e);
                 }
-            }
-            Err(e) => {
-                eprintln!("Failed to bind to port {:?}: {:?}", addr, e);
-                Err(e)
             }
         }
     }
 
-    fn local_port(&self) -> u16 {
-        8080
+    fn local_port(&self) -> Option<u16> {
+        self.listen_addr.map(|addr| addr.port())
     }
 
-    // This function is used to periodically update the peer list
-    pub async fn periodic_peer_update(&self, initial_peers: Vec<SocketAddr>) {
-
-        let initial_peers_clone = initial_peers.clone();
-        for peer in initial_peers_clone {
-            self.add_node(Node {
-                id: peer.to_string(),
-                storage_path: "C:/Users/melisates/development/decentralized-storage-/storage".to_string(),
-                total_space: 1000000000,
-                available_space: 1000000000,
-                address: peer.to_string(),
-            }).await;    
+    // Records every live node in the peerstore and writes it to disk, so a
+    // restarted node can reconnect to the peers it knew
+    pub async fn persist_peers(&self) -> Result<()> {
+        let peerstore = match &self.peerstore {
+            Some(peerstore) => peerstore,
+            None => return Ok(()),
+        };
+        let live = self.get_live_nodes().await;
+        let mut peerstore = peerstore.lock().await;
+        for node in &live {
+            peerstore.record_seen(node);
         }
+        for record in peerstore.records() {
+            peerstore.set_score(&record.node_id, self.reputation.score(&record.node_id));
+        }
+        peerstore.save()
+    }
 
+    // This function is used to periodically update the peer list.
+    // Our own record is re-announced on every round so it never expires on peers.
+    pub async fn periodic_peer_update(&self, initial_peers: Vec<SocketAddr>, local_node: Node) {
         let mut interval = tokio::time::interval(Duration::from_secs(30)); // 30 saniyede bir
         loop {
             interval.tick().await;
             println!("Running periodic peer update...");
+            for peer in initial_peers.iter() {
+                if let Err(e) = self.announce_to(&local_node, *peer).await {
+                    eprintln!("Failed to announce to peer {:?}: {:?}", peer, e);
+                }
+            }
             self.discover_peers(initial_peers.clone()).await;
+            if let Err(e) = self.persist_peers().await {
+                eprintln!("Failed to save peerstore: {:?}", e);
+            }
         }
     }
 }
-// This function is used to find an available node with enough space for the file
-pub fn find_available_node(file_size: u64, nodes: &[Node]) -> Option<Node> {
-    nodes
-        .iter()
-        .find(|&node| node.available_space >= file_size)
-        .cloned()
+// Challenges reach the node over a secure session to its chunk service,
+// authenticated against the node id
+impl Prover for Network {
+    fn prove(&self, node_id: &str, request: ProverRequest) -> ProverFuture<'_> {
+        let node_id = node_id.to_string();
+        Box::pin(async move {
+            let node = self
+                .get_node_by_id(&node_id)
+                .await
+                .ok_or_else(|| format!("Node {} is not known", node_id))?;
+            let mut client = ChunkClient::connect(&node, &self.identity).await.map_err(|e| e.to_string())?;
+            client.prove(request).await.map_err(|e| e.to_string())
+        })
+    }
+}
+
+// Sends one signed announcement over a plain connection
+async fn send_announcement(announcement: &SignedAnnouncement, address: &str) -> Result<()> {
+    let mut stream = tokio::time::timeout(Duration::from_secs(5), faults::connect(&announcement.node.id, address))
+        .await
+        .map_err(|_| anyhow!("Connection to peer {} timed out", address))??;
+    stream.write_all(&serde_json::to_vec(announcement)?).await?;
+    Ok(())
 }
 
 
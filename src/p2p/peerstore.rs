@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use dotenv::dotenv;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::node::Node;
+
+// Peers not seen for this long are dropped from the store
+pub const PEER_RETENTION: u64 = 7 * 24 * 60 * 60;
+// Comma separated `ip:port` list, e.g. BOOTSTRAP_PEERS=10.0.0.2:8080,10.0.0.3:8080
+const BOOTSTRAP_PEERS_VAR: &str = "BOOTSTRAP_PEERS";
+// Path of a seed file with one `ip:port` per line
+const SEED_FILE_VAR: &str = "SEED_FILE";
+
+// What we remember about a peer between restarts
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PeerRecord {
+    pub node_id: String,
+    pub address: String,
+    pub last_seen: u64, // unix seconds of the last accepted announcement
+    pub score: f64,
+}
+
+// Known peers, kept in a JSON file next to the node identity
+pub struct Peerstore {
+    path: PathBuf,
+    peers: HashMap<String, PeerRecord>,
+}
+
+impl Peerstore {
+    // Opens the store at `path`; a missing file is an empty store
+    pub fn load(path: &Path) -> Result<Self> {
+        let peers = if path.exists() {
+            let content = fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read peerstore {}: {}", path.display(), e))?;
+            let records: Vec<PeerRecord> = serde_json::from_str(&content)?;
+            records.into_iter().map(|record| (record.node_id.clone(), record)).collect()
+        } else {
+            HashMap::new()
+        };
+        let mut store = Peerstore {
+            path: path.to_path_buf(),
+            peers,
+        };
+        store.prune(unix_now());
+        Ok(store)
+    }
+
+    // Writes the store through a temporary file so a crash never leaves it half written
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut records: Vec<&PeerRecord> = self.peers.values().collect();
+        records.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&records)?)?;
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| anyhow!("Failed to write peerstore {}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+
+    pub fn record_seen(&mut self, node: &Node) {
+        let now = unix_now();
+        let record = self.peers.entry(node.id.clone()).or_insert_with(|| PeerRecord {
+            node_id: node.id.clone(),
+            address: node.address.clone(),
+            last_seen: now,
+            score: 0.0,
+        });
+        record.address = node.address.clone();
+        record.last_seen = now;
+    }
+
+    pub fn records(&self) -> Vec<PeerRecord> {
+        self.peers.values().cloned().collect()
+    }
+
+    pub fn set_score(&mut self, node_id: &str, score: f64) {
+        if let Some(record) = self.peers.get_mut(node_id) {
+            record.score = score;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, node_id: &str) -> Option<&PeerRecord> {
+        self.peers.get(node_id)
+    }
+
+    // Addresses to reconnect to, most recently seen first
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        let mut records: Vec<&PeerRecord> = self.peers.values().collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.last_seen));
+        records
+            .into_iter()
+            .filter_map(|record| record.address.parse().ok())
+            .collect()
+    }
+
+    fn prune(&mut self, now: u64) {
+        self.peers.retain(|_, record| record.last_seen + PEER_RETENTION >= now);
+    }
+}
+
+// Reads a seed file: one `ip:port` per line, `#` starts a comment. Host names
+// are rejected on purpose, bootstrapping must not depend on DNS.
+pub fn load_seed_file(path: &Path) -> Result<Vec<SocketAddr>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read seed file {}: {}", path.display(), e))?;
+    parse_seeds(&content).map_err(|e| anyhow!("Invalid seed file {}: {}", path.display(), e))
+}
+
+fn parse_seeds(content: &str) -> Result<Vec<SocketAddr>> {
+    let mut seeds = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let addr = line
+            .parse()
+            .map_err(|_| anyhow!("line {}: `{}` is not an ip:port address", number + 1, line))?;
+        seeds.push(addr);
+    }
+    Ok(seeds)
+}
+
+// Bootstrap peers from the environment (.env is loaded too): BOOTSTRAP_PEERS
+// and the file named by SEED_FILE
+pub fn bootstrap_peers_from_env() -> Result<Vec<SocketAddr>> {
+    dotenv().ok();
+    let mut peers = Vec::new();
+    if let Ok(list) = env::var(BOOTSTRAP_PEERS_VAR) {
+        peers.extend(parse_seeds(&list.replace(',', "\n"))?);
+    }
+    if let Ok(seed_file) = env::var(SEED_FILE_VAR) {
+        peers.extend(load_seed_file(Path::new(&seed_file))?);
+    }
+    Ok(peers)
+}
+
+// Merges bootstrap sources in order of preference, dropping duplicates and our own address
+pub fn merge_bootstrap(sources: Vec<Vec<SocketAddr>>, local_addr: SocketAddr) -> Vec<SocketAddr> {
+    let mut peers = Vec::new();
+    for addr in sources.into_iter().flatten() {
+        if addr != local_addr && !peers.contains(&addr) {
+            peers.push(addr);
+        }
+    }
+    peers
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_seeds_skips_comments_and_rejects_host_names() {
+        let seeds = parse_seeds("# seeds\n10.0.0.2:8080\n\n10.0.0.3:8081 # rack b\n").unwrap();
+        assert_eq!(seeds, vec!["10.0.0.2:8080".parse().unwrap(), "10.0.0.3:8081".parse().unwrap()]);
+        assert!(parse_seeds("seed.example.com:8080").is_err());
+    }
+
+    #[test]
+    fn test_peerstore_survives_restart() {
+        let path = env::temp_dir().join(format!("peerstore-{}.json", uuid::Uuid::new_v4()));
+        let node = Node {
+            id: "peer-1".to_string(),
+            storage_path: String::new(),
+            total_space: 0,
+            available_space: 0,
+            address: "127.0.0.1:9000".to_string(),
+            labels: Default::default(),
+        };
+        let mut store = Peerstore::load(&path).unwrap();
+        store.record_seen(&node);
+        store.set_score("peer-1", 0.75);
+        store.save().unwrap();
+
+        let reloaded = Peerstore::load(&path).unwrap();
+        assert_eq!(reloaded.get("peer-1").unwrap().score, 0.75);
+        assert_eq!(reloaded.addresses(), vec!["127.0.0.1:9000".parse().unwrap()]);
+        fs::remove_file(&path).unwrap();
+    }
+}
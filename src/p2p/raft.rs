@@ -0,0 +1,1206 @@
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{sleep, timeout, Duration};
+
+use super::identity::NodeIdentity;
+use super::transport::SecureChannel;
+use crate::node::Node;
+
+// Raft (Ongaro & Ousterhout) replicating an opaque command log to a small set
+// of metadata nodes. RaftCore is the deterministic protocol; RaftNode drives
+// it with a clock, persists it and carries its messages over SecureChannels.
+
+pub const TICK: Duration = Duration::from_millis(50);
+// Election timeout in ticks, randomized between ELECTION_TICKS and twice that
+const ELECTION_TICKS: u64 = 10;
+const HEARTBEAT_TICKS: u64 = 2;
+// Applied entries kept in the log before they are folded into a snapshot
+pub const SNAPSHOT_THRESHOLD: u64 = 1000;
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+// How long a client waits for its command to commit or its read to be confirmed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REDIRECTS: usize = 5;
+// Metadata nodes, e.g. METADATA_NODES=<node id>@10.0.0.1:8084,<node id>@10.0.0.2:8084.
+// When unset this node is the only metadata node.
+const MEMBERS_VAR: &str = "METADATA_NODES";
+
+const STATE_FILE: &str = "raft_state.json";
+const SNAPSHOT_FILE: &str = "raft_snapshot.json";
+
+// What the log is replicated for. Commands are applied in log order on every
+// metadata node, so `apply` must be deterministic. Commands proposed through a
+// RaftNode arrive as a serialized Proposal.
+pub trait StateMachine: Send {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8>;
+    fn query(&self, query: &[u8]) -> Vec<u8>;
+    fn snapshot(&self) -> Vec<u8>;
+    fn restore(&mut self, snapshot: &[u8]);
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Vec<u8>, // empty for the no-op a new leader appends
+}
+
+// A client's command as the node that took it appends it: with the node it
+// came from, as its secure session authenticated it, and that node's clock,
+// so every replica applies it with the same proposer and time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Proposal {
+    pub proposer: String,
+    pub proposed_at: u64,
+    pub command: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: String,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VoteReply {
+    pub term: u64,
+    pub granted: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader: String,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+    pub seq: u64, // heartbeat round, echoed back to confirm leadership for reads
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppendReply {
+    pub term: u64,
+    pub success: bool,
+    // Last index known to match the leader; on failure a hint where to retry from
+    pub match_index: u64,
+    pub seq: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    pub term: u64,
+    pub leader: String,
+    pub last_index: u64,
+    pub last_term: u64,
+    pub data: Vec<u8>,
+    pub seq: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotReply {
+    pub term: u64,
+    pub last_index: u64,
+    pub seq: u64,
+}
+
+// Requests sent over a SecureChannel to a metadata node. Propose and Read come
+// from clients and are answered by the leader only. Proposals are only taken
+// from the metadata nodes and the storage nodes of the cluster; reads are
+// open to any authenticated client, such as the HTTP server's CID lookups.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RaftRequest {
+    Vote(VoteRequest),
+    Append(AppendRequest),
+    Snapshot(SnapshotRequest),
+    Propose { command: Vec<u8> },
+    Read { query: Vec<u8> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RaftReply {
+    Vote(VoteReply),
+    Append(AppendReply),
+    Snapshot(SnapshotReply),
+    Done(Vec<u8>),
+    NotLeader { leader: Option<String> },
+    Error(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// Written before any message that depends on it is sent
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+    pub log: Vec<LogEntry>, // entries after the snapshot
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RaftStatus {
+    pub node_id: String,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub members: usize,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot_index: u64,
+    pub log_entries: usize,
+}
+
+struct PendingRead {
+    id: u64,
+    seq: u64,
+}
+
+pub struct RaftCore {
+    id: String,
+    peers: Vec<String>,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    log: Vec<LogEntry>,
+    snapshot: Snapshot,
+    commit_index: u64,
+    last_applied: u64,
+    machine: Box<dyn StateMachine>,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    acked_seq: HashMap<String, u64>,
+    seq: u64,
+    elapsed: u64,
+    election_timeout: u64,
+    reads: Vec<PendingRead>,
+    // Output collected for the driver
+    outbox: Vec<(String, RaftRequest)>,
+    applied: Vec<(u64, u64, Vec<u8>)>, // (index, term, result)
+    ready_reads: Vec<u64>,
+    failed_reads: Vec<u64>,
+    state_dirty: bool,
+    snapshot_dirty: bool,
+}
+
+impl RaftCore {
+    pub fn new(id: &str, peers: Vec<String>, mut machine: Box<dyn StateMachine>, hard: HardState, snapshot: Snapshot) -> Self {
+        if snapshot.last_index > 0 {
+            machine.restore(&snapshot.data);
+        }
+        let mut core = RaftCore {
+            id: id.to_string(),
+            peers: peers.into_iter().filter(|peer| peer != id).collect(),
+            role: Role::Follower,
+            term: hard.term,
+            voted_for: hard.voted_for,
+            leader: None,
+            log: hard.log,
+            commit_index: snapshot.last_index,
+            last_applied: snapshot.last_index,
+            snapshot,
+            machine,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            acked_seq: HashMap::new(),
+            seq: 0,
+            elapsed: 0,
+            election_timeout: ELECTION_TICKS,
+            reads: Vec::new(),
+            outbox: Vec::new(),
+            applied: Vec::new(),
+            ready_reads: Vec::new(),
+            failed_reads: Vec::new(),
+            state_dirty: false,
+            snapshot_dirty: false,
+        };
+        // Entries older than the snapshot may have survived a crash during compaction
+        let snapshot_index = core.snapshot.last_index;
+        core.log.retain(|entry| entry.index > snapshot_index);
+        core.reset_election_timer();
+        core
+    }
+
+    #[cfg(test)]
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        RaftStatus {
+            node_id: self.id.clone(),
+            role: self.role,
+            term: self.term,
+            leader: self.leader.clone(),
+            members: self.peers.len() + 1,
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            snapshot_index: self.snapshot.last_index,
+            log_entries: self.log.len(),
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.last().map(|entry| entry.index).unwrap_or(self.snapshot.last_index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map(|entry| entry.term).unwrap_or(self.snapshot.last_term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.snapshot.last_index {
+            return None;
+        }
+        self.log.get((index - self.snapshot.last_index - 1) as usize)
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.elapsed = 0;
+        self.election_timeout = rand::thread_rng().gen_range(ELECTION_TICKS..ELECTION_TICKS * 2);
+    }
+
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.role == Role::Leader {
+            if self.elapsed >= HEARTBEAT_TICKS {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+        } else if self.elapsed >= self.election_timeout {
+            self.start_election();
+        }
+    }
+
+    fn start_election(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.votes = HashSet::from([self.id.clone()]);
+        self.state_dirty = true;
+        self.reset_election_timer();
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let request = VoteRequest {
+            term: self.term,
+            candidate: self.id.clone(),
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers.clone() {
+            self.outbox.push((peer, RaftRequest::Vote(request.clone())));
+        }
+    }
+
+    fn become_leader(&mut self) {
+        println!("Node {} is the metadata leader for term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        let next = self.last_index() + 1;
+        for peer in &self.peers {
+            self.next_index.insert(peer.clone(), next);
+            self.match_index.insert(peer.clone(), 0);
+        }
+        self.acked_seq.clear();
+        // Entries of earlier terms only commit together with one of this term
+        self.append_local(Vec::new());
+        self.broadcast_append();
+        self.advance_commit();
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.state_dirty = true;
+        }
+        if self.role == Role::Leader {
+            self.failed_reads.extend(self.reads.drain(..).map(|read| read.id));
+        }
+        self.role = Role::Follower;
+        if leader.is_some() {
+            self.leader = leader;
+        }
+        self.reset_election_timer();
+    }
+
+    fn append_local(&mut self, command: Vec<u8>) -> u64 {
+        let index = self.last_index() + 1;
+        self.log.push(LogEntry {
+            term: self.term,
+            index,
+            command,
+        });
+        self.state_dirty = true;
+        index
+    }
+
+    fn broadcast_append(&mut self) {
+        self.seq += 1;
+        for peer in self.peers.clone() {
+            self.send_append(&peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: &str) {
+        let next = self.next_index.get(peer).copied().unwrap_or(1).max(1);
+        let request = if next <= self.snapshot.last_index {
+            RaftRequest::Snapshot(SnapshotRequest {
+                term: self.term,
+                leader: self.id.clone(),
+                last_index: self.snapshot.last_index,
+                last_term: self.snapshot.last_term,
+                data: self.snapshot.data.clone(),
+                seq: self.seq,
+            })
+        } else {
+            let prev_log_index = next - 1;
+            let entries = self
+                .log
+                .iter()
+                .skip((next - self.snapshot.last_index - 1) as usize)
+                .take(MAX_ENTRIES_PER_APPEND)
+                .cloned()
+                .collect();
+            RaftRequest::Append(AppendRequest {
+                term: self.term,
+                leader: self.id.clone(),
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+                entries,
+                leader_commit: self.commit_index,
+                seq: self.seq,
+            })
+        };
+        self.outbox.push((peer.to_string(), request));
+    }
+
+    // Answers a Vote, Append or Snapshot request from another metadata node
+    pub fn handle_request(&mut self, from: &str, request: RaftRequest) -> RaftReply {
+        match request {
+            RaftRequest::Vote(request) => RaftReply::Vote(self.handle_vote(request)),
+            RaftRequest::Append(request) if request.leader == from => RaftReply::Append(self.handle_append(request)),
+            RaftRequest::Snapshot(request) if request.leader == from => {
+                RaftReply::Snapshot(self.handle_snapshot(request))
+            }
+            RaftRequest::Append(_) | RaftRequest::Snapshot(_) => {
+                RaftReply::Error(format!("Node {} cannot send entries for another leader", from))
+            }
+            RaftRequest::Propose { .. } | RaftRequest::Read { .. } => {
+                RaftReply::Error("Client requests are not handled by the consensus core".to_string())
+            }
+        }
+    }
+
+    fn handle_vote(&mut self, request: VoteRequest) -> VoteReply {
+        if request.term > self.term {
+            self.become_follower(request.term, None);
+        }
+        let up_to_date = request.last_log_term > self.last_term()
+            || (request.last_log_term == self.last_term() && request.last_log_index >= self.last_index());
+        let granted = request.term == self.term
+            && up_to_date
+            && (self.voted_for.is_none() || self.voted_for.as_deref() == Some(request.candidate.as_str()));
+        if granted {
+            self.voted_for = Some(request.candidate);
+            self.state_dirty = true;
+            self.reset_election_timer();
+        }
+        VoteReply {
+            term: self.term,
+            granted,
+        }
+    }
+
+    fn handle_append(&mut self, request: AppendRequest) -> AppendReply {
+        let reject = |core: &Self, match_index| AppendReply {
+            term: core.term,
+            success: false,
+            match_index,
+            seq: request.seq,
+        };
+        if request.term < self.term {
+            return reject(self, 0);
+        }
+        self.become_follower(request.term, Some(request.leader.clone()));
+
+        if request.prev_log_index > self.last_index() {
+            return reject(self, self.last_index());
+        }
+        // Everything up to the snapshot is committed and therefore matches
+        if request.prev_log_index >= self.snapshot.last_index
+            && self.term_at(request.prev_log_index) != Some(request.prev_log_term)
+        {
+            return reject(self, request.prev_log_index.saturating_sub(1).max(self.commit_index));
+        }
+
+        for entry in &request.entries {
+            if entry.index <= self.snapshot.last_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    // Conflicting suffix; committed entries never conflict
+                    self.log.truncate((entry.index - self.snapshot.last_index - 1) as usize);
+                    self.log.push(entry.clone());
+                }
+                None => self.log.push(entry.clone()),
+            }
+            self.state_dirty = true;
+        }
+
+        let match_index = request.prev_log_index + request.entries.len() as u64;
+        let match_index = match_index.max(self.snapshot.last_index);
+        if request.leader_commit > self.commit_index {
+            self.commit_index = request.leader_commit.min(match_index);
+            self.apply_committed();
+        }
+        AppendReply {
+            term: self.term,
+            success: true,
+            match_index,
+            seq: request.seq,
+        }
+    }
+
+    fn handle_snapshot(&mut self, request: SnapshotRequest) -> SnapshotReply {
+        if request.term >= self.term {
+            self.become_follower(request.term, Some(request.leader.clone()));
+        }
+        if request.term == self.term && request.last_index > self.commit_index {
+            let keep = self.term_at(request.last_index) == Some(request.last_term);
+            if keep {
+                self.log.retain(|entry| entry.index > request.last_index);
+            } else {
+                self.log.clear();
+            }
+            self.machine.restore(&request.data);
+            self.snapshot = Snapshot {
+                last_index: request.last_index,
+                last_term: request.last_term,
+                data: request.data,
+            };
+            self.commit_index = request.last_index;
+            self.last_applied = request.last_index;
+            self.state_dirty = true;
+            self.snapshot_dirty = true;
+        }
+        SnapshotReply {
+            term: self.term,
+            last_index: self.snapshot.last_index.max(self.commit_index),
+            seq: request.seq,
+        }
+    }
+
+    // Feeds the reply to a request this node sent
+    pub fn handle_reply(&mut self, from: &str, reply: RaftReply) {
+        let term = match &reply {
+            RaftReply::Vote(reply) => reply.term,
+            RaftReply::Append(reply) => reply.term,
+            RaftReply::Snapshot(reply) => reply.term,
+            _ => return,
+        };
+        if term > self.term {
+            self.become_follower(term, None);
+            return;
+        }
+        if term < self.term {
+            return;
+        }
+
+        match reply {
+            RaftReply::Vote(reply) if self.role == Role::Candidate && reply.granted => {
+                self.votes.insert(from.to_string());
+                if self.votes.len() >= self.quorum() {
+                    self.become_leader();
+                }
+            }
+            RaftReply::Append(reply) if self.role == Role::Leader => {
+                self.ack(from, reply.seq);
+                if reply.success {
+                    self.matched(from, reply.match_index);
+                } else {
+                    let next = self.next_index.get(from).copied().unwrap_or(1);
+                    self.next_index
+                        .insert(from.to_string(), (reply.match_index + 1).min(next.saturating_sub(1)).max(1));
+                    self.send_append(from);
+                }
+            }
+            RaftReply::Snapshot(reply) if self.role == Role::Leader => {
+                self.ack(from, reply.seq);
+                self.matched(from, reply.last_index);
+            }
+            _ => {}
+        }
+        self.check_reads();
+    }
+
+    fn ack(&mut self, from: &str, seq: u64) {
+        let acked = self.acked_seq.entry(from.to_string()).or_insert(0);
+        *acked = (*acked).max(seq);
+    }
+
+    fn matched(&mut self, from: &str, index: u64) {
+        let matched = self.match_index.entry(from.to_string()).or_insert(0);
+        *matched = (*matched).max(index);
+        let next = *matched + 1;
+        self.next_index.insert(from.to_string(), next);
+        self.advance_commit();
+        if next <= self.last_index() {
+            self.send_append(from);
+        }
+    }
+
+    // Commits the highest entry of this term stored on a majority
+    fn advance_commit(&mut self) {
+        let mut index = self.last_index();
+        while index > self.commit_index {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|&&matched| matched >= index).count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                self.apply_committed();
+                break;
+            }
+            index -= 1;
+        }
+        self.check_reads();
+    }
+
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = match self.entry(index) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            let result = if entry.command.is_empty() {
+                Vec::new()
+            } else {
+                self.machine.apply(&entry.command)
+            };
+            self.last_applied = index;
+            self.applied.push((index, entry.term, result));
+        }
+        self.compact();
+    }
+
+    // Folds the applied entries into a snapshot once there are enough of them
+    fn compact(&mut self) {
+        if self.last_applied - self.snapshot.last_index < SNAPSHOT_THRESHOLD {
+            return;
+        }
+        let last_term = self.term_at(self.last_applied).unwrap_or(self.snapshot.last_term);
+        let last_index = self.last_applied;
+        self.snapshot = Snapshot {
+            last_index,
+            last_term,
+            data: self.machine.snapshot(),
+        };
+        self.log.retain(|entry| entry.index > last_index);
+        self.state_dirty = true;
+        self.snapshot_dirty = true;
+    }
+
+    // Appends a command; returns its (index, term) or the known leader
+    pub fn propose(&mut self, command: Vec<u8>) -> std::result::Result<(u64, u64), Option<String>> {
+        if self.role != Role::Leader {
+            return Err(self.leader.clone());
+        }
+        let index = self.append_local(command);
+        self.broadcast_append();
+        self.advance_commit();
+        Ok((index, self.term))
+    }
+
+    // Starts a linearizable read (read index): the read is served once a
+    // majority confirmed this node is still the leader and the state machine
+    // has applied everything committed when it was confirmed
+    pub fn read_index(&mut self, id: u64) -> std::result::Result<(), Option<String>> {
+        if self.role != Role::Leader {
+            return Err(self.leader.clone());
+        }
+        self.reads.push(PendingRead { id, seq: self.seq + 1 });
+        self.broadcast_append();
+        self.check_reads();
+        Ok(())
+    }
+
+    fn check_reads(&mut self) {
+        if self.role != Role::Leader || self.reads.is_empty() {
+            return;
+        }
+        // A leader knows the commit index only after committing an entry of its own term
+        if self.term_at(self.commit_index) != Some(self.term) || self.last_applied < self.commit_index {
+            return;
+        }
+        let quorum = self.quorum();
+        let acked = &self.acked_seq;
+        let (ready, waiting): (Vec<PendingRead>, Vec<PendingRead>) = self
+            .reads
+            .drain(..)
+            .partition(|read| 1 + acked.values().filter(|&&seq| seq >= read.seq).count() >= quorum);
+        self.reads = waiting;
+        self.ready_reads.extend(ready.into_iter().map(|read| read.id));
+    }
+
+    pub fn query(&self, query: &[u8]) -> Vec<u8> {
+        self.machine.query(query)
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<(String, RaftRequest)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub fn take_applied(&mut self) -> Vec<(u64, u64, Vec<u8>)> {
+        std::mem::take(&mut self.applied)
+    }
+
+    pub fn take_reads(&mut self) -> (Vec<u64>, Vec<u64>) {
+        (std::mem::take(&mut self.ready_reads), std::mem::take(&mut self.failed_reads))
+    }
+
+    // Hard state and snapshot to persist, if they changed since the last call
+    pub fn take_dirty(&mut self) -> (Option<HardState>, Option<Snapshot>) {
+        let state = std::mem::take(&mut self.state_dirty).then(|| HardState {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+            log: self.log.clone(),
+        });
+        let snapshot = std::mem::take(&mut self.snapshot_dirty).then(|| self.snapshot.clone());
+        (state, snapshot)
+    }
+}
+
+// Metadata nodes from METADATA_NODES (node id -> address); empty when unset
+pub fn configured_members() -> std::result::Result<HashMap<String, String>, String> {
+    let mut members = HashMap::new();
+    if let Ok(value) = std::env::var(MEMBERS_VAR) {
+        for member in value.split(',').map(str::trim).filter(|member| !member.is_empty()) {
+            let (id, address) = member
+                .split_once('@')
+                .ok_or_else(|| format!("{} entries must be <node id>@<address>, got `{}`", MEMBERS_VAR, member))?;
+            members.insert(id.to_string(), address.to_string());
+        }
+    }
+    Ok(members)
+}
+
+// Metadata nodes from METADATA_NODES; just this node when unset
+pub fn members_from_env(local_id: &str, local_addr: &str) -> std::result::Result<HashMap<String, String>, String> {
+    let mut members = configured_members()?;
+    if members.is_empty() {
+        members.insert(local_id.to_string(), local_addr.to_string());
+    }
+    Ok(members)
+}
+
+// Completes a client's proposal with the command's result
+type ProposalWaiter = oneshot::Sender<std::result::Result<Vec<u8>, String>>;
+
+struct Driver {
+    core: RaftCore,
+    proposals: HashMap<u64, (u64, ProposalWaiter)>, // log index -> (term, waiter)
+    reads: HashMap<u64, oneshot::Sender<bool>>,
+}
+
+// A metadata node: runs RaftCore on a clock, keeps its state in `dir` and
+// talks to the other members over SecureChannels
+pub struct RaftNode {
+    id: String,
+    members: HashMap<String, String>,
+    identity: Arc<NodeIdentity>,
+    dir: PathBuf,
+    driver: Mutex<Driver>,
+    channels: Mutex<HashMap<String, SecureChannel>>,
+    next_read: AtomicU64,
+    // Node table of the network serving this replica, see admit_storage_nodes
+    storage_nodes: OnceLock<Arc<Mutex<HashMap<String, Node>>>>,
+}
+
+impl RaftNode {
+    pub fn open(
+        dir: &Path,
+        identity: Arc<NodeIdentity>,
+        members: HashMap<String, String>,
+        machine: Box<dyn StateMachine>,
+    ) -> Result<Arc<Self>> {
+        fs::create_dir_all(dir)?;
+        let hard: HardState = read_json(&dir.join(STATE_FILE))?.unwrap_or_default();
+        let snapshot: Snapshot = read_json(&dir.join(SNAPSHOT_FILE))?.unwrap_or_default();
+        let id = identity.node_id();
+        let core = RaftCore::new(&id, members.keys().cloned().collect(), machine, hard, snapshot);
+        Ok(Arc::new(RaftNode {
+            id,
+            members,
+            identity,
+            dir: dir.to_path_buf(),
+            driver: Mutex::new(Driver {
+                core,
+                proposals: HashMap::new(),
+                reads: HashMap::new(),
+            }),
+            channels: Mutex::new(HashMap::new()),
+            next_read: AtomicU64::new(1),
+            storage_nodes: OnceLock::new(),
+        }))
+    }
+
+    // Takes proposals from the nodes in `nodes` as well as from the metadata
+    // nodes; set once, by the network this replica is served on
+    pub fn admit_storage_nodes(&self, nodes: Arc<Mutex<HashMap<String, Node>>>) {
+        let _ = self.storage_nodes.set(nodes);
+    }
+
+    async fn may_propose(&self, from: &str) -> bool {
+        if self.members.contains_key(from) {
+            return true;
+        }
+        match self.storage_nodes.get() {
+            Some(nodes) => nodes.lock().await.contains_key(from),
+            None => false,
+        }
+    }
+
+    // Drives elections and heartbeats until the runtime stops
+    pub fn spawn(self: &Arc<Self>) {
+        let node = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TICK);
+            loop {
+                interval.tick().await;
+                let outbox = {
+                    let mut driver = node.driver.lock().await;
+                    driver.core.tick();
+                    node.settle(&mut driver)
+                };
+                node.dispatch(outbox);
+            }
+        });
+    }
+
+    pub async fn status(&self) -> RaftStatus {
+        self.driver.lock().await.core.status()
+    }
+
+    // Persists what changed, wakes the clients whose commands were applied or
+    // whose reads were confirmed and returns the messages to send
+    fn settle(&self, driver: &mut Driver) -> Vec<(String, RaftRequest)> {
+        let (state, snapshot) = driver.core.take_dirty();
+        // The snapshot goes first so the log it replaces is never the only copy
+        if let Some(snapshot) = snapshot {
+            if let Err(e) = write_json(&self.dir.join(SNAPSHOT_FILE), &snapshot) {
+                eprintln!("Failed to persist the metadata snapshot: {:?}", e);
+            }
+        }
+        if let Some(state) = state {
+            if let Err(e) = write_json(&self.dir.join(STATE_FILE), &state) {
+                eprintln!("Failed to persist the raft state: {:?}", e);
+            }
+        }
+
+        for (index, term, result) in driver.core.take_applied() {
+            if let Some((proposed_term, waiter)) = driver.proposals.remove(&index) {
+                let outcome = if proposed_term == term {
+                    Ok(result)
+                } else {
+                    Err("The command was replaced by a new leader's log".to_string())
+                };
+                let _ = waiter.send(outcome);
+            }
+        }
+        let (ready, failed) = driver.core.take_reads();
+        for (id, confirmed) in ready.into_iter().map(|id| (id, true)).chain(failed.into_iter().map(|id| (id, false))) {
+            if let Some(waiter) = driver.reads.remove(&id) {
+                let _ = waiter.send(confirmed);
+            }
+        }
+        driver.core.take_outbox()
+    }
+
+    fn dispatch(self: &Arc<Self>, outbox: Vec<(String, RaftRequest)>) {
+        for (peer, request) in outbox {
+            let node = Arc::clone(self);
+            tokio::spawn(async move {
+                // Unreachable peers are retried with the next heartbeat
+                if let Ok(reply) = node.call(&peer, &request).await {
+                    let outbox = {
+                        let mut driver = node.driver.lock().await;
+                        driver.core.handle_reply(&peer, reply);
+                        node.settle(&mut driver)
+                    };
+                    node.dispatch(outbox);
+                }
+            });
+        }
+    }
+
+    // Sends one request to a metadata node, reusing an open session when there is one
+    async fn call(&self, peer: &str, request: &RaftRequest) -> Result<RaftReply> {
+        let address = self
+            .members
+            .get(peer)
+            .ok_or_else(|| anyhow!("Node {} is not a metadata node", peer))?;
+        let cached = self.channels.lock().await.remove(peer);
+        let mut channel = match cached {
+            Some(channel) => channel,
+            None => SecureChannel::connect(address, &self.identity, peer).await?,
+        };
+        let reply = exchange(&mut channel, request).await?;
+        self.channels.lock().await.insert(peer.to_string(), channel);
+        Ok(reply)
+    }
+
+    // Serves a request received from `from` over a secure session
+    pub async fn handle(self: &Arc<Self>, from: &str, request: RaftRequest) -> RaftReply {
+        match request {
+            RaftRequest::Propose { command } if self.may_propose(from).await => self.propose_local(from, command).await,
+            RaftRequest::Propose { .. } => RaftReply::Error(format!("Node {} may not change the metadata", from)),
+            RaftRequest::Read { query } => self.read_local(query).await,
+            request if self.members.contains_key(from) => {
+                let (reply, outbox) = {
+                    let mut driver = self.driver.lock().await;
+                    let reply = driver.core.handle_request(from, request);
+                    // Persisted by settle before the reply leaves this node
+                    (reply, self.settle(&mut driver))
+                };
+                self.dispatch(outbox);
+                reply
+            }
+            _ => RaftReply::Error(format!("Node {} is not a metadata node", from)),
+        }
+    }
+
+    async fn propose_local(self: &Arc<Self>, from: &str, command: Vec<u8>) -> RaftReply {
+        let proposal = Proposal {
+            proposer: from.to_string(),
+            proposed_at: unix_now(),
+            command,
+        };
+        let command = match serde_json::to_vec(&proposal) {
+            Ok(command) => command,
+            Err(e) => return RaftReply::Error(e.to_string()),
+        };
+        let (receiver, outbox) = {
+            let mut driver = self.driver.lock().await;
+            let (index, term) = match driver.core.propose(command) {
+                Ok(position) => position,
+                Err(leader) => return RaftReply::NotLeader { leader },
+            };
+            let (sender, receiver) = oneshot::channel();
+            driver.proposals.insert(index, (term, sender));
+            (receiver, self.settle(&mut driver))
+        };
+        self.dispatch(outbox);
+        match timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(Ok(Ok(result))) => RaftReply::Done(result),
+            Ok(Ok(Err(e))) => RaftReply::Error(e),
+            _ => RaftReply::Error("Timed out waiting for the command to commit".to_string()),
+        }
+    }
+
+    async fn read_local(self: &Arc<Self>, query: Vec<u8>) -> RaftReply {
+        let id = self.next_read.fetch_add(1, Ordering::SeqCst);
+        let (receiver, outbox) = {
+            let mut driver = self.driver.lock().await;
+            let (sender, receiver) = oneshot::channel();
+            driver.reads.insert(id, sender);
+            if let Err(leader) = driver.core.read_index(id) {
+                driver.reads.remove(&id);
+                return RaftReply::NotLeader { leader };
+            }
+            (receiver, self.settle(&mut driver))
+        };
+        self.dispatch(outbox);
+        match timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(Ok(true)) => RaftReply::Done(self.driver.lock().await.core.query(&query)),
+            Ok(_) => RaftReply::Error("Leadership was lost before the read was confirmed".to_string()),
+            Err(_) => {
+                self.driver.lock().await.reads.remove(&id);
+                RaftReply::Error("Timed out confirming leadership for the read".to_string())
+            }
+        }
+    }
+}
+
+// Client side: sends a Propose or Read to the metadata nodes, starting with
+// `local` when this node is one, and follows the redirects to the leader
+pub async fn request(
+    local: Option<&Arc<RaftNode>>,
+    identity: &NodeIdentity,
+    members: &HashMap<String, String>,
+    request: RaftRequest,
+) -> Result<Vec<u8>> {
+    let mut target: Option<String> = None;
+    let mut last_error = anyhow!("No metadata node is reachable");
+    for attempt in 0..MAX_REDIRECTS * members.len().max(1) {
+        let reply = match (&target, local) {
+            (None, Some(node)) => node.handle(&node.id, request.clone()).await,
+            _ => {
+                let peer = match &target {
+                    Some(peer) => peer.clone(),
+                    None => members.keys().nth(attempt % members.len().max(1)).cloned().unwrap_or_default(),
+                };
+                let address = members.get(&peer).ok_or_else(|| anyhow!("Unknown metadata node {}", peer))?;
+                let reply = async {
+                    let mut channel = SecureChannel::connect(address, identity, &peer).await?;
+                    exchange(&mut channel, &request).await
+                }
+                .await;
+                match reply {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        last_error = e;
+                        target = None;
+                        continue;
+                    }
+                }
+            }
+        };
+        match reply {
+            RaftReply::Done(result) => return Ok(result),
+            RaftReply::NotLeader { leader: Some(leader) } if members.contains_key(&leader) => {
+                target = Some(leader)
+            }
+            RaftReply::NotLeader { .. } => {
+                // An election is running
+                target = None;
+                sleep(TICK * ELECTION_TICKS as u32).await;
+            }
+            RaftReply::Error(e) => last_error = anyhow!(e),
+            other => last_error = anyhow!("Unexpected reply from a metadata node: {:?}", other),
+        }
+    }
+    Err(last_error)
+}
+
+async fn exchange(channel: &mut SecureChannel, request: &RaftRequest) -> Result<RaftReply> {
+    channel.send(&serde_json::to_vec(request)?).await?;
+    Ok(serde_json::from_slice(&channel.recv().await?)?)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replaces the file atomically so a crash leaves the old or the new version
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    let mut file = fs::File::create(&temp)?;
+    std::io::Write::write_all(&mut file, &serde_json::to_vec(value)?)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Appends every command to a list; snapshots are the list itself
+    #[derive(Default)]
+    struct Journal(Vec<u8>);
+
+    impl StateMachine for Journal {
+        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+            self.0.extend_from_slice(command);
+            vec![self.0.len() as u8]
+        }
+        fn query(&self, _query: &[u8]) -> Vec<u8> {
+            self.0.clone()
+        }
+        fn snapshot(&self) -> Vec<u8> {
+            self.0.clone()
+        }
+        fn restore(&mut self, snapshot: &[u8]) {
+            self.0 = snapshot.to_vec();
+        }
+    }
+
+    fn cluster(size: usize) -> HashMap<String, RaftCore> {
+        let ids: Vec<String> = (0..size).map(|i| format!("n{}", i)).collect();
+        ids.iter()
+            .map(|id| {
+                let core = RaftCore::new(id, ids.clone(), Box::<Journal>::default(), HardState::default(), Snapshot::default());
+                (id.clone(), core)
+            })
+            .collect()
+    }
+
+    // Delivers messages until the cluster is quiet; `down` nodes drop everything
+    fn deliver(cores: &mut HashMap<String, RaftCore>, down: &[&str]) {
+        loop {
+            let mut messages = Vec::new();
+            for (id, core) in cores.iter_mut() {
+                for (to, request) in core.take_outbox() {
+                    messages.push((id.clone(), to, request));
+                }
+            }
+            if messages.is_empty() {
+                break;
+            }
+            for (from, to, request) in messages {
+                if down.contains(&from.as_str()) || down.contains(&to.as_str()) {
+                    continue;
+                }
+                let reply = cores.get_mut(&to).unwrap().handle_request(&from, request);
+                cores.get_mut(&from).unwrap().handle_reply(&to, reply);
+            }
+        }
+    }
+
+    // Two ticks make the leader send a heartbeat, which also carries its commit index
+    fn heartbeat(cores: &mut HashMap<String, RaftCore>, leader: &str, down: &[&str]) {
+        cores.get_mut(leader).unwrap().tick();
+        cores.get_mut(leader).unwrap().tick();
+        deliver(cores, down);
+    }
+
+    fn elect(cores: &mut HashMap<String, RaftCore>, id: &str, down: &[&str]) {
+        cores.get_mut(id).unwrap().start_election();
+        deliver(cores, down);
+        assert_eq!(cores[id].role(), Role::Leader);
+    }
+
+    #[test]
+    fn test_committed_entries_survive_a_leader_change() {
+        let mut cores = cluster(3);
+        elect(&mut cores, "n0", &[]);
+        cores.get_mut("n0").unwrap().propose(b"a".to_vec()).unwrap();
+        deliver(&mut cores, &[]);
+        heartbeat(&mut cores, "n0", &[]);
+        assert!(cores.values().all(|core| core.query(&[]) == b"a"));
+
+        // n0 is cut off; its uncommitted entry is replaced once it rejoins
+        cores.get_mut("n0").unwrap().propose(b"x".to_vec()).unwrap();
+        elect(&mut cores, "n1", &["n0"]);
+        cores.get_mut("n1").unwrap().propose(b"b".to_vec()).unwrap();
+        deliver(&mut cores, &["n0"]);
+        heartbeat(&mut cores, "n1", &[]);
+
+        assert_eq!(cores["n0"].role(), Role::Follower);
+        assert!(cores.values().all(|core| core.query(&[]) == b"ab"));
+    }
+
+    #[test]
+    fn test_minority_cannot_elect_or_commit() {
+        let mut cores = cluster(3);
+        elect(&mut cores, "n0", &[]);
+        cores.get_mut("n0").unwrap().propose(b"a".to_vec()).unwrap();
+        deliver(&mut cores, &["n1", "n2"]);
+        assert!(cores["n0"].query(&[]).is_empty());
+
+        // A read is only served once a majority confirms the leader
+        cores.get_mut("n0").unwrap().read_index(7).unwrap();
+        deliver(&mut cores, &["n1", "n2"]);
+        assert_eq!(cores.get_mut("n0").unwrap().take_reads(), (vec![], vec![]));
+        heartbeat(&mut cores, "n0", &[]);
+        assert_eq!(cores.get_mut("n0").unwrap().take_reads().0, vec![7]);
+        assert_eq!(cores["n0"].query(&[]), b"a");
+
+        cores.get_mut("n2").unwrap().start_election();
+        deliver(&mut cores, &["n0", "n1"]);
+        assert_ne!(cores["n2"].role(), Role::Leader);
+    }
+
+    #[test]
+    fn test_lagging_follower_catches_up_from_a_snapshot() {
+        let mut cores = cluster(3);
+        elect(&mut cores, "n0", &[]);
+        for _ in 0..SNAPSHOT_THRESHOLD + 10 {
+            cores.get_mut("n0").unwrap().propose(vec![1]).unwrap();
+            deliver(&mut cores, &["n2"]);
+        }
+        let leader = &cores["n0"];
+        assert!(leader.snapshot.last_index >= SNAPSHOT_THRESHOLD);
+        assert!(leader.log.len() < SNAPSHOT_THRESHOLD as usize);
+
+        heartbeat(&mut cores, "n0", &[]);
+        assert_eq!(cores["n2"].query(&[]), cores["n0"].query(&[]));
+        assert_eq!(cores["n2"].last_applied, cores["n0"].last_applied);
+    }
+
+    #[tokio::test]
+    async fn test_proposals_are_only_taken_from_cluster_nodes() {
+        let dir = std::env::temp_dir().join(format!("raft-{}", uuid::Uuid::new_v4()));
+        let identity = Arc::new(NodeIdentity::generate());
+        let members = HashMap::from([(identity.node_id(), "127.0.0.1:0".to_string())]);
+        let node = RaftNode::open(&dir, identity, members, Box::<Journal>::default()).unwrap();
+        node.spawn();
+        while node.status().await.role != Role::Leader {
+            sleep(TICK).await;
+        }
+
+        let storage_nodes = Arc::new(Mutex::new(HashMap::new()));
+        node.admit_storage_nodes(storage_nodes.clone());
+        let propose = || RaftRequest::Propose { command: b"entry".to_vec() };
+        assert!(matches!(node.handle("stranger", propose()).await, RaftReply::Error(_)));
+
+        let known = Node {
+            id: "storage1".to_string(),
+            storage_path: String::new(),
+            total_space: 0,
+            available_space: 0,
+            address: String::new(),
+            labels: Default::default(),
+        };
+        storage_nodes.lock().await.insert(known.id.clone(), known);
+        assert!(matches!(node.handle("storage1", propose()).await, RaftReply::Done(_)));
+        // Reads stay open to clients outside the cluster
+        let read = RaftRequest::Read { query: Vec::new() };
+        assert!(matches!(node.handle("stranger", read).await, RaftReply::Done(_)));
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,354 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use super::faults;
+use super::hello::{negotiate, Capabilities, Hello, HelloResponse};
+use super::identity::{node_id_from_public_key, parse_public_key, verify_signature, NodeIdentity};
+
+// Sent in clear before the handshake so Network::serve can tell secure
+// sessions apart from the plain JSON messages on the same port
+pub const SECURE_PREAMBLE: &[u8] = b"SECURE/1\n";
+
+// Noise XX: both sides transmit their static key, encrypted, during the handshake
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const MAX_NOISE_MESSAGE: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+const MAX_PLAINTEXT: usize = MAX_NOISE_MESSAGE - NOISE_TAG_LEN;
+// Upper bound of one application message (a chunk plus its header)
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Binds the per-session Noise key to the long-term Ed25519 node identity
+const STATIC_KEY_DOMAIN: &[u8] = b"decentralized-storage/noise-static-key/v1";
+
+// Handshake payload proving that the Noise static key belongs to a node identity
+#[derive(Serialize, Deserialize)]
+struct IdentityProof {
+    public_key: String, // hex Ed25519 public key
+    signature: String,  // hex signature over STATIC_KEY_DOMAIN || noise static key
+}
+
+impl IdentityProof {
+    fn new(identity: &NodeIdentity, noise_static: &[u8]) -> Self {
+        IdentityProof {
+            public_key: identity.public_key_hex(),
+            signature: hex::encode(identity.sign(&static_key_message(noise_static)).to_bytes()),
+        }
+    }
+
+    // Returns the node id of the peer that owns `noise_static`
+    fn verify(&self, noise_static: &[u8]) -> Result<String> {
+        let public_key = parse_public_key(&self.public_key)?;
+        verify_signature(&public_key, &static_key_message(noise_static), &self.signature)
+            .map_err(|_| anyhow!("Peer's Noise key is not signed by its node identity"))?;
+        Ok(node_id_from_public_key(&public_key))
+    }
+}
+
+fn static_key_message(noise_static: &[u8]) -> Vec<u8> {
+    let mut message = STATIC_KEY_DOMAIN.to_vec();
+    message.extend_from_slice(noise_static);
+    message
+}
+
+// Encrypted, mutually authenticated session with another node
+pub struct SecureChannel {
+    stream: TcpStream,
+    transport: TransportState,
+    remote_node_id: String,
+    // Set by the hello exchange, which connect and accept complete before returning
+    capabilities: Option<Capabilities>,
+}
+
+impl SecureChannel {
+    // Opens a session to `address` and fails unless the responder proves it is `expected_node_id`
+    pub async fn connect(address: &str, identity: &NodeIdentity, expected_node_id: &str) -> Result<Self> {
+        let mut stream = timeout(HANDSHAKE_TIMEOUT, faults::connect(&identity.node_id(), address))
+            .await
+            .map_err(|_| anyhow!("Connection to {} timed out", address))??;
+        stream.write_all(SECURE_PREAMBLE).await?;
+
+        let channel = timeout(HANDSHAKE_TIMEOUT, Self::initiate(stream, identity))
+            .await
+            .map_err(|_| anyhow!("Handshake with {} timed out", address))??;
+        if channel.remote_node_id != expected_node_id {
+            return Err(anyhow!(
+                "Peer at {} authenticated as {}, expected {}",
+                address, channel.remote_node_id, expected_node_id
+            ));
+        }
+        Ok(channel)
+    }
+
+    // Runs the responder side on a connection whose preamble was already consumed
+    pub async fn accept(stream: TcpStream, identity: &NodeIdentity) -> Result<Self> {
+        timeout(HANDSHAKE_TIMEOUT, Self::respond(stream, identity))
+            .await
+            .map_err(|_| anyhow!("Handshake timed out"))?
+    }
+
+    async fn initiate(mut stream: TcpStream, identity: &NodeIdentity) -> Result<Self> {
+        let (mut handshake, local_static) = new_handshake(true)?;
+        let mut buffer = vec![0u8; MAX_NOISE_MESSAGE];
+
+        // -> e
+        let len = handshake.write_message(&[], &mut buffer)?;
+        write_frame(&mut stream, &buffer[..len]).await?;
+
+        // <- e, ee, s, es + responder identity
+        let message = read_frame(&mut stream).await?;
+        let len = handshake.read_message(&message, &mut buffer)?;
+        let proof: IdentityProof = serde_json::from_slice(&buffer[..len])?;
+        let remote_static = handshake
+            .get_remote_static()
+            .ok_or_else(|| anyhow!("Responder did not send a static key"))?;
+        let remote_node_id = proof.verify(remote_static)?;
+
+        // -> s, se + initiator identity
+        let payload = serde_json::to_vec(&IdentityProof::new(identity, &local_static))?;
+        let len = handshake.write_message(&payload, &mut buffer)?;
+        write_frame(&mut stream, &buffer[..len]).await?;
+
+        let mut channel = SecureChannel {
+            stream,
+            transport: handshake.into_transport_mode()?,
+            remote_node_id,
+            capabilities: None,
+        };
+        channel.send_hello().await?;
+        Ok(channel)
+    }
+
+    async fn respond(mut stream: TcpStream, identity: &NodeIdentity) -> Result<Self> {
+        let (mut handshake, local_static) = new_handshake(false)?;
+        let mut buffer = vec![0u8; MAX_NOISE_MESSAGE];
+
+        // <- e
+        let message = read_frame(&mut stream).await?;
+        handshake.read_message(&message, &mut buffer)?;
+
+        // -> e, ee, s, es + responder identity
+        let payload = serde_json::to_vec(&IdentityProof::new(identity, &local_static))?;
+        let len = handshake.write_message(&payload, &mut buffer)?;
+        write_frame(&mut stream, &buffer[..len]).await?;
+
+        // <- s, se + initiator identity
+        let message = read_frame(&mut stream).await?;
+        let len = handshake.read_message(&message, &mut buffer)?;
+        let proof: IdentityProof = serde_json::from_slice(&buffer[..len])?;
+        let remote_static = handshake
+            .get_remote_static()
+            .ok_or_else(|| anyhow!("Initiator did not send a static key"))?;
+        let remote_node_id = proof.verify(remote_static)?;
+
+        let mut channel = SecureChannel {
+            stream,
+            transport: handshake.into_transport_mode()?,
+            remote_node_id,
+            capabilities: None,
+        };
+        channel.answer_hello().await?;
+        Ok(channel)
+    }
+
+    // Initiator side of the hello exchange: offers our capabilities and
+    // accepts the responder's answer, or fails with its refusal reason
+    async fn send_hello(&mut self) -> Result<()> {
+        let local = Hello::local();
+        self.send(&serde_json::to_vec(&local)?).await?;
+        match serde_json::from_slice::<HelloResponse>(&self.recv().await?)? {
+            HelloResponse::Accept(remote) => {
+                let capabilities = negotiate(&local, &remote)
+                    .map_err(|reason| anyhow!("Refusing node {}: {}", self.remote_node_id, reason))?;
+                self.capabilities = Some(capabilities);
+                Ok(())
+            }
+            HelloResponse::Refuse { reason } => {
+                Err(anyhow!("Node {} refused the connection: {}", self.remote_node_id, reason))
+            }
+        }
+    }
+
+    // Responder side: tells an incompatible initiator why it is refused
+    // before closing the session
+    async fn answer_hello(&mut self) -> Result<()> {
+        let local = Hello::local();
+        let remote: Hello = serde_json::from_slice(&self.recv().await?)?;
+        match negotiate(&local, &remote) {
+            Ok(capabilities) => {
+                self.send(&serde_json::to_vec(&HelloResponse::Accept(local))?).await?;
+                self.capabilities = Some(capabilities);
+                Ok(())
+            }
+            Err(reason) => {
+                self.send(&serde_json::to_vec(&HelloResponse::Refuse { reason: reason.clone() })?)
+                    .await?;
+                Err(anyhow!("Refusing node {}: {}", self.remote_node_id, reason))
+            }
+        }
+    }
+
+    // Authenticated node id of the other side
+    pub fn remote_node_id(&self) -> &str {
+        &self.remote_node_id
+    }
+
+    // Parameters agreed on in the hello exchange
+    pub fn capabilities(&self) -> &Capabilities {
+        self.capabilities
+            .as_ref()
+            .expect("capabilities are negotiated before the channel is returned")
+    }
+
+    // Before the hello exchange our own limit applies, afterwards the negotiated one
+    fn max_message_size(&self) -> usize {
+        self.capabilities
+            .as_ref()
+            .map_or(MAX_MESSAGE_SIZE, |capabilities| capabilities.max_frame_size as usize)
+    }
+
+    // Sends one application message: an encrypted length header followed by
+    // as many encrypted Noise frames as the message needs
+    pub async fn send(&mut self, message: &[u8]) -> Result<()> {
+        if message.len() > self.max_message_size() {
+            return Err(anyhow!("Message of {} bytes exceeds the maximum size", message.len()));
+        }
+        let mut buffer = vec![0u8; MAX_NOISE_MESSAGE];
+
+        let len = self
+            .transport
+            .write_message(&(message.len() as u32).to_le_bytes(), &mut buffer)?;
+        write_frame(&mut self.stream, &buffer[..len]).await?;
+
+        for piece in message.chunks(MAX_PLAINTEXT) {
+            let len = self.transport.write_message(piece, &mut buffer)?;
+            write_frame(&mut self.stream, &buffer[..len]).await?;
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        self.recv_at_most(self.max_message_size()).await
+    }
+
+    // Receives one message and fails, before reading its body, if the peer
+    // announces more than `limit` bytes
+    pub async fn recv_at_most(&mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; MAX_NOISE_MESSAGE];
+
+        let frame = read_frame(&mut self.stream).await?;
+        let len = self.transport.read_message(&frame, &mut buffer)?;
+        let header: [u8; 4] = buffer[..len]
+            .try_into()
+            .map_err(|_| anyhow!("Invalid message header"))?;
+        let total = u32::from_le_bytes(header) as usize;
+        if total > limit.min(self.max_message_size()) {
+            return Err(anyhow!("Peer announced a message of {} bytes", total));
+        }
+
+        let mut message = Vec::with_capacity(total);
+        while message.len() < total {
+            let frame = read_frame(&mut self.stream).await?;
+            let len = self.transport.read_message(&frame, &mut buffer)?;
+            message.extend_from_slice(&buffer[..len]);
+        }
+        if message.len() != total {
+            return Err(anyhow!("Message length mismatch"));
+        }
+        Ok(message)
+    }
+}
+
+// A fresh Noise static key is generated for every session; it is tied to the
+// node identity by the signed IdentityProof exchanged in the handshake
+fn new_handshake(initiator: bool) -> Result<(HandshakeState, Vec<u8>)> {
+    let params = NOISE_PARAMS.parse()?;
+    let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+    let builder = Builder::new(params).local_private_key(&keypair.private);
+    let handshake = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+    Ok((handshake, keypair.public))
+}
+
+// Noise messages are framed with a 4-byte little-endian length, like the chunks in encryption
+async fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> Result<()> {
+    stream.write_all(&(frame.len() as u32).to_le_bytes()).await?;
+    stream.write_all(frame).await?;
+    Ok(())
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let len = u32::from_le_bytes(header) as usize;
+    if len > MAX_NOISE_MESSAGE {
+        return Err(anyhow!("Frame of {} bytes exceeds the Noise message limit", len));
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+// Checks, without consuming anything, whether a new connection starts a secure session
+pub async fn is_secure_connection(stream: &TcpStream) -> bool {
+    timeout(HANDSHAKE_TIMEOUT, peek_preamble(stream))
+        .await
+        .unwrap_or(false)
+}
+
+async fn peek_preamble(stream: &TcpStream) -> bool {
+    let mut preamble = [0u8; SECURE_PREAMBLE.len()];
+    loop {
+        let read = match stream.peek(&mut preamble).await {
+            Ok(read) => read,
+            Err(_) => return false,
+        };
+        if read == 0 || preamble[..read] != SECURE_PREAMBLE[..read] {
+            return false;
+        }
+        if read == SECURE_PREAMBLE.len() {
+            return true;
+        }
+        // Only part of the preamble has arrived yet
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_messages_over_the_limit_are_refused_before_they_are_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server_identity = NodeIdentity::generate();
+        let server_id = server_identity.node_id();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut preamble = [0u8; SECURE_PREAMBLE.len()];
+            stream.read_exact(&mut preamble).await.unwrap();
+            let mut channel = SecureChannel::accept(stream, &server_identity).await.unwrap();
+            let short = channel.recv_at_most(4).await.unwrap();
+            let long = channel.recv_at_most(4).await;
+            (short, long.is_err())
+        });
+
+        let mut client = SecureChannel::connect(&address, &NodeIdentity::generate(), &server_id)
+            .await
+            .unwrap();
+        client.send(b"four").await.unwrap();
+        client.send(b"too long").await.unwrap();
+        let (short, refused) = server.await.unwrap();
+        assert_eq!(short, b"four");
+        assert!(refused);
+    }
+}
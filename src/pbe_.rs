@@ -1,8 +1,16 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use dotenv::dotenv;
+use std::env;
 use crate::node::StorageNode;
-use crate::proof_of_spacetime::periodic_check;
+use crate::placement::{select_storage_node, FirstFit, PlacementStrategy};
+use crate::reputation::Reputation;
+
+// Stakes granted when the engine starts, as `<user id>:<tokens>` entries
+// separated by commas, for deployments without the staking contract
+const STAKES_VAR: &str = "STAKES";
 
 
 // Structures for token and storage management
@@ -44,7 +52,7 @@ pub struct Permission {
     pub expiry: Option<u64>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum AccessType {
     Read,
     Write,
@@ -52,12 +60,58 @@ pub enum AccessType {
     Admin,
 }
 
+// Why an upload does not fit the user's stake
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum QuotaError {
+    NoStake { user_id: String },
+    StakeExpired { user_id: String, expiry: u64 },
+    QuotaExceeded { user_id: String, limit: u64, used: u64, reserved: u64, requested: u64 },
+}
+
+impl std::fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaError::NoStake { user_id } => write!(f, "User {} has no storage stake", user_id),
+            QuotaError::StakeExpired { user_id, expiry } => write!(f, "Storage stake of {} expired at {}", user_id, expiry),
+            QuotaError::QuotaExceeded { user_id, limit, used, reserved, requested } => write!(
+                f,
+                "Storage quota of {} exceeded: {} of {} bytes used, {} reserved, {} more requested",
+                user_id, used, limit, reserved, requested
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QuotaError {}
+
+// Space held for an upload between its start and its commit
+#[derive(Clone)]
+struct Reservation {
+    user_id: String,
+    size: u64,
+}
+
+// A user's stake and how much of it is taken
+#[derive(Clone, Debug, Serialize)]
+pub struct StorageUsage {
+    pub user_id: String,
+    pub limit: Option<u64>,
+    pub expiry: Option<u64>,
+    pub used: u64,
+    pub reserved: u64,
+}
+
 pub struct ProgrammableBusinessEngine {
     pub tokens: HashMap<String, StorageToken>,
     nodes: HashMap<String, StorageNode>,
     files: HashMap<String, FileMetadata>,
+    // Uploads in progress by upload id, counted against their user's stake
+    reservations: HashMap<String, Reservation>,
     // Token rate is the amount of storage bytes per token
     token_rate: u64,  // Storage bytes per token
+    // Chooses the node for new files, shared with the p2p and storage placement
+    placement: Arc<dyn PlacementStrategy>,
 }
 
 impl ProgrammableBusinessEngine {
@@ -66,10 +120,34 @@ impl ProgrammableBusinessEngine {
             tokens: HashMap::new(),
             nodes: HashMap::new(),
             files: HashMap::new(),
+            reservations: HashMap::new(),
             token_rate,
+            placement: Arc::new(FirstFit),
         }
     }
 
+    pub fn with_placement(mut self, placement: Arc<dyn PlacementStrategy>) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    // An engine with the stakes of STAKES already staked
+    pub fn from_env(token_rate: u64) -> Result<Self, String> {
+        dotenv().ok();
+        let mut engine = Self::new(token_rate);
+        if let Ok(value) = env::var(STAKES_VAR) {
+            for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+                let (user_id, amount) = entry
+                    .split_once(':')
+                    .and_then(|(user_id, amount)| Some((user_id.trim(), amount.trim().parse::<u64>().ok()?)))
+                    .filter(|(user_id, _)| !user_id.is_empty())
+                    .ok_or_else(|| format!("{} entries must be <user id>:<tokens>, got `{}`", STAKES_VAR, entry))?;
+                engine.stake_tokens(user_id, amount)?;
+            }
+        }
+        Ok(engine)
+    }
+
     // Token Management
     //Kullanıcının stake ettiği token miktarı ve süresi. Depolama hakkı kazanır.
     pub fn stake_tokens(&mut self, user_id: &str, amount: u64) -> Result<StorageToken, String> {
@@ -94,16 +172,73 @@ impl ProgrammableBusinessEngine {
     //Kullanıcının depolama alanı izni kontrol edilir.
     //Kullanıcının depolama hakkıyla, depolanmak istenen dosyanın boyutu karşılaştırılır.
     pub fn check_storage_allowance(&self, user_id: &str, required_space: u64) -> bool {
-        if let Some(token) = self.tokens.get(user_id) {
-            let current_usage = self.get_user_storage_usage(user_id);
-            println!("Token storage limit: {:?}", token.storage_limit);
+        self.storage_allowance(user_id, required_space).is_ok()
+    }
 
-            token.storage_limit >= current_usage + required_space && token.expiry > SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        } else {
-            false
+    // Checks `required_space` more bytes against the user's stake, counting
+    // the stored files and the uploads still in progress
+    pub fn storage_allowance(&self, user_id: &str, required_space: u64) -> Result<(), QuotaError> {
+        let limit = self.stake_limit(user_id)?;
+        let used = self.get_user_storage_usage(user_id);
+        let reserved = self.get_user_reserved_storage(user_id);
+        if used + reserved + required_space > limit {
+            return Err(QuotaError::QuotaExceeded {
+                user_id: user_id.to_string(),
+                limit,
+                used,
+                reserved,
+                requested: required_space,
+            });
+        }
+        Ok(())
+    }
+
+    // Bytes the user's stake allows in total, if the stake is still active
+    pub fn stake_limit(&self, user_id: &str) -> Result<u64, QuotaError> {
+        let token = self.tokens.get(user_id).ok_or_else(|| QuotaError::NoStake { user_id: user_id.to_string() })?;
+        if token.expiry <= unix_now() {
+            return Err(QuotaError::StakeExpired { user_id: user_id.to_string(), expiry: token.expiry });
+        }
+        Ok(token.storage_limit)
+    }
+
+    // Holds `size` bytes of the user's stake for `upload_id` until the upload
+    // is committed or released
+    pub fn reserve_storage(&mut self, user_id: &str, upload_id: &str, size: u64) -> Result<(), QuotaError> {
+        self.storage_allowance(user_id, size)?;
+        self.reservations.insert(upload_id.to_string(), Reservation { user_id: user_id.to_string(), size });
+        Ok(())
+    }
+
+    // Turns the reservation of `upload_id` into the entry of `file_id`, of
+    // `size` bytes as actually stored, which may be less than was reserved
+    pub fn commit_storage(&mut self, upload_id: &str, file_id: &str, node_id: &str, size: u64) -> Result<(), String> {
+        let reservation = self
+            .reservations
+            .remove(upload_id)
+            .ok_or_else(|| format!("No storage reserved for upload {}", upload_id))?;
+        self.insert_file_entry(file_id, &reservation.user_id, size, node_id);
+        Ok(())
+    }
+
+    // Drops the reservation of an upload that failed
+    pub fn release_storage(&mut self, upload_id: &str) -> bool {
+        self.reservations.remove(upload_id).is_some()
+    }
+
+    // Removes a deleted file, giving its bytes back to the owner's stake
+    pub fn remove_file_entry(&mut self, file_id: &str) -> Option<FileMetadata> {
+        self.files.remove(file_id)
+    }
+
+    pub fn storage_usage(&self, user_id: &str) -> StorageUsage {
+        let token = self.tokens.get(user_id);
+        StorageUsage {
+            user_id: user_id.to_string(),
+            limit: token.map(|token| token.storage_limit),
+            expiry: token.map(|token| token.expiry),
+            used: self.get_user_storage_usage(user_id),
+            reserved: self.get_user_reserved_storage(user_id),
         }
     }
 
@@ -132,14 +267,16 @@ println!("Node available miktarı registered: {:?}", node.available_space);
     
 
     //Yüklenen dosyanın boyutuna göre uygun node seçilir.
-    // Çıktı olarak node id döner.
-    pub fn assign_node(&self, file_size: u64) -> Option<String> {
+    // Çıktı olarak node döner.
+    pub fn assign_node<'a>(
+        &self,
+        file_id: &str,
+        file_size: u64,
+        nodes: impl IntoIterator<Item = &'a StorageNode>,
+        reputation: Option<&Reputation>,
+    ) -> Option<&'a StorageNode> {
         println!("File size: {:?}", file_size);
-        println!("Nodes: {:?}", self.nodes);
-        self.nodes
-            .iter()
-            .find(|(_, node)| node.available_space >= file_size && node.health_status)
-            .map(|(node_id, _)| node_id.clone())
+        select_storage_node(file_id, file_size, nodes, reputation, &*self.placement)
     }
 
     // Access Control
@@ -155,16 +292,17 @@ println!("Node available miktarı registered: {:?}", node.available_space);
         if !self.check_storage_allowance(owner_id, size) {
             return Err("Insufficient storage allowance".to_string());
         }
+        self.insert_file_entry(file_id, owner_id, size, node_id);
+        Ok(())
+    }
 
+    fn insert_file_entry(&mut self, file_id: &str, owner_id: &str, size: u64, node_id: &str) {
         let metadata = FileMetadata {
             file_id: file_id.to_string(),
             owner_id: owner_id.to_string(),
             size,
             node_id: node_id.to_string(),
-            created_at: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            created_at: unix_now(),
             permissions: vec![Permission {
                 user_id: owner_id.to_string(),
                 access_type: AccessType::Admin,
@@ -173,24 +311,27 @@ println!("Node available miktarı registered: {:?}", node.available_space);
         };
 
         self.files.insert(file_id.to_string(), metadata);
-        Ok(())
     }
 
     //Dosyaya erişim kontrolü yapılır.
     //Bir kullanıcının belli br dosyaya erişim izni kontrol edilir.
+    // A permission grants its own access type, and Admin grants every type.
+    // Files the engine has no entry for grant nothing: every upload is
+    // entered when it is committed, so an unknown file was never uploaded
+    // through the engine or has already been removed.
     pub fn check_access(&self, user_id: &str, file_id: &str, access_type: AccessType) -> bool {
-        if let Some(file) = self.files.get(file_id) {
+        let now = unix_now();
+        self.files.get(file_id).is_some_and(|file| {
             file.permissions.iter().any(|perm| {
-                perm.user_id == user_id 
-                && matches!(perm.access_type, AccessType::Admin) 
-                && perm.expiry.map_or(true, |exp| exp > SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs())
+                perm.user_id == user_id
+                    && (perm.access_type == access_type || perm.access_type == AccessType::Admin)
+                    && perm.expiry.is_none_or(|exp| exp > now)
             })
-        } else {
-            false
-        }
+        })
+    }
+
+    pub fn has_file(&self, file_id: &str) -> bool {
+        self.files.contains_key(file_id)
     }
 
     // Helper functions
@@ -203,6 +344,14 @@ println!("Node available miktarı registered: {:?}", node.available_space);
             .sum()
     }
 
+    fn get_user_reserved_storage(&self, user_id: &str) -> u64 {
+        self.reservations
+            .values()
+            .filter(|reservation| reservation.user_id == user_id)
+            .map(|reservation| reservation.size)
+            .sum()
+    }
+
     // // Smart Contract Integration
     // //Kullanıcının stake ettiği token miktarı kontrol edilir akıllı kontrat üzerinden.
     // pub fn verify_smart_contract_stake(&self, contract_address: &str, user_id: &str) -> Result<u64, String> {
@@ -226,6 +375,10 @@ println!("Node available miktarı registered: {:?}", node.available_space);
 
 }
 
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
 // Tests
 #[cfg(test)]
 mod tests {
@@ -244,8 +397,50 @@ mod tests {
     fn test_node_assignment() {
         let mut pbe = ProgrammableBusinessEngine::new(1_000_000);
         futures::executor::block_on(pbe.register_node("node1", 1_000_000_000)).unwrap(); // 1GB
-        let assigned_node = pbe.assign_node(100_000_000); // 100MB
+        let assigned_node = pbe.assign_node("file1", 100_000_000, pbe.nodes.values(), None); // 100MB
         assert!(assigned_node.is_some());
-        assert_eq!(assigned_node.unwrap(), "node1");
+        assert_eq!(assigned_node.unwrap().node_id, "node1");
+    }
+
+    #[test]
+    fn test_storage_reservations() {
+        let mut pbe = ProgrammableBusinessEngine::new(1_000);
+        assert_eq!(pbe.reserve_storage("user1", "file1", 10), Err(QuotaError::NoStake { user_id: "user1".to_string() }));
+
+        pbe.stake_tokens("user1", 10).unwrap(); // 10KB
+        pbe.reserve_storage("user1", "file1", 6_000).unwrap();
+        // The reservation counts until the upload is committed or released
+        assert!(matches!(
+            pbe.reserve_storage("user1", "file2", 6_000),
+            Err(QuotaError::QuotaExceeded { used: 0, reserved: 6_000, .. })
+        ));
+        pbe.commit_storage("file1", "file1", "node1", 5_000).unwrap();
+        pbe.reserve_storage("user1", "file2", 5_000).unwrap();
+        assert!(pbe.release_storage("file2"));
+        assert_eq!(pbe.storage_usage("user1").used, 5_000);
+
+        assert!(pbe.remove_file_entry("file1").is_some());
+        assert!(pbe.check_storage_allowance("user1", 10_000));
+    }
+
+    #[test]
+    fn test_check_access() {
+        let mut pbe = ProgrammableBusinessEngine::new(1_000);
+        pbe.insert_file_entry("file1", "user1", 10, "node1");
+        // The owner holds Admin, which grants every access type
+        assert!(pbe.check_access("user1", "file1", AccessType::Delete));
+        assert!(pbe.check_access("user1", "file1", AccessType::Read));
+        assert!(!pbe.check_access("user2", "file1", AccessType::Read));
+
+        pbe.files.get_mut("file1").unwrap().permissions.push(Permission {
+            user_id: "user2".to_string(),
+            access_type: AccessType::Delete,
+            expiry: None,
+        });
+        assert!(pbe.check_access("user2", "file1", AccessType::Delete));
+        assert!(!pbe.check_access("user2", "file1", AccessType::Write));
+
+        assert!(!pbe.has_file("file2"));
+        assert!(!pbe.check_access("user1", "file2", AccessType::Delete));
     }
 }
@@ -0,0 +1,484 @@
+use dotenv::dotenv;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+
+use crate::node::{DomainLevel, FailureDomain, Node, StorageNode};
+use crate::reputation::Reputation;
+
+// Selects the strategy of a deployment, e.g. PLACEMENT_STRATEGY=rendezvous
+const STRATEGY_VAR: &str = "PLACEMENT_STRATEGY";
+// Virtual nodes per node on the consistent hashing ring
+const VNODES_VAR: &str = "PLACEMENT_VNODES";
+pub const DEFAULT_VNODES: usize = 100;
+// Anti-affinity policy, e.g. ANTI_AFFINITY=zone and ANTI_AFFINITY_MAX=1 for
+// "no two chunks of a file in the same zone"
+const ANTI_AFFINITY_VAR: &str = "ANTI_AFFINITY";
+const ANTI_AFFINITY_MAX_VAR: &str = "ANTI_AFFINITY_MAX";
+
+// A node as placement sees it. Built from p2p / storage `Node`s and from the
+// engine's `StorageNode`s so all of them share the same strategies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub id: String,
+    pub available_space: u64,
+    pub total_space: u64,
+    pub labels: FailureDomain,
+}
+
+// Decides which nodes an object goes to. `key` identifies the object (chunk
+// or file id) so hash based strategies place it on the same nodes every time.
+pub trait PlacementStrategy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Indices of the candidates with room for `size` bytes, best first.
+    // Callers try them in this order until one accepts the object.
+    fn rank(&self, key: &str, size: u64, candidates: &[Candidate]) -> Vec<usize>;
+
+    fn select(&self, key: &str, size: u64, candidates: &[Candidate]) -> Option<usize> {
+        self.rank(key, size, candidates).into_iter().next()
+    }
+
+    // Whether every key has a home node of its own. The rebalancer moves the
+    // keys of such strategies home; for the others it evens out utilization.
+    fn keyed(&self) -> bool {
+        false
+    }
+}
+
+impl From<&Node> for Candidate {
+    fn from(node: &Node) -> Self {
+        Candidate {
+            id: node.id.clone(),
+            available_space: node.available_space,
+            total_space: node.total_space,
+            labels: node.labels.clone(),
+        }
+    }
+}
+
+impl From<&StorageNode> for Candidate {
+    fn from(node: &StorageNode) -> Self {
+        Candidate {
+            id: node.node_id.clone(),
+            available_space: node.available_space,
+            total_space: node.total_space,
+            labels: node.labels.clone(),
+        }
+    }
+}
+
+fn fitting(size: u64, candidates: &[Candidate]) -> Vec<usize> {
+    (0..candidates.len())
+        .filter(|&i| candidates[i].available_space >= size)
+        .collect()
+}
+
+// Keeps the given candidate order, so callers decide the preference (e.g. by reputation)
+pub struct FirstFit;
+
+impl PlacementStrategy for FirstFit {
+    fn name(&self) -> &'static str {
+        "first-fit"
+    }
+
+    fn rank(&self, _key: &str, size: u64, candidates: &[Candidate]) -> Vec<usize> {
+        fitting(size, candidates)
+    }
+}
+
+pub struct MostFreeSpace;
+
+impl PlacementStrategy for MostFreeSpace {
+    fn name(&self) -> &'static str {
+        "most-free"
+    }
+
+    fn rank(&self, _key: &str, size: u64, candidates: &[Candidate]) -> Vec<usize> {
+        let mut ranked = fitting(size, candidates);
+        // Stable, so ties keep the caller's order
+        ranked.sort_by(|&a, &b| candidates[b].available_space.cmp(&candidates[a].available_space));
+        ranked
+    }
+}
+
+// Picks nodes at random with a probability proportional to their capacity
+pub struct WeightedRandom {
+    rng: Mutex<StdRng>,
+}
+
+impl WeightedRandom {
+    pub fn new() -> Self {
+        WeightedRandom {
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    // Repeatable choices, for tests and simulations
+    #[cfg(test)]
+    pub fn seeded(seed: u64) -> Self {
+        WeightedRandom {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl Default for WeightedRandom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlacementStrategy for WeightedRandom {
+    fn name(&self) -> &'static str {
+        "weighted-random"
+    }
+
+    // Weighted sampling without replacement (Efraimidis-Spirakis): each node
+    // draws u^(1/weight) and the largest draws go first. Compared as ln(u)/weight,
+    // which keeps the order and does not round to 1 for large capacities.
+    fn rank(&self, _key: &str, size: u64, candidates: &[Candidate]) -> Vec<usize> {
+        let mut rng = self.rng.lock().unwrap();
+        let mut draws: Vec<(f64, usize)> = fitting(size, candidates)
+            .into_iter()
+            .map(|i| {
+                let weight = candidates[i].total_space.max(1) as f64;
+                (rng.gen::<f64>().ln() / weight, i)
+            })
+            .collect();
+        draws.sort_by(|a, b| b.0.total_cmp(&a.0));
+        draws.into_iter().map(|(_, i)| i).collect()
+    }
+}
+
+// Highest random weight hashing: every (key, node) pair gets a score and the
+// key goes to the best scoring nodes. Adding or removing a node only moves the
+// keys that scored best on it.
+pub struct Rendezvous;
+
+impl PlacementStrategy for Rendezvous {
+    fn name(&self) -> &'static str {
+        "rendezvous"
+    }
+
+    fn keyed(&self) -> bool {
+        true
+    }
+
+    fn rank(&self, key: &str, size: u64, candidates: &[Candidate]) -> Vec<usize> {
+        let mut ranked = fitting(size, candidates);
+        ranked.sort_by_key(|&i| std::cmp::Reverse(hash64(&[key.as_bytes(), candidates[i].id.as_bytes()])));
+        ranked
+    }
+}
+
+// Consistent hashing on a ring where every node owns `vnodes` points. The key
+// goes to the first nodes found clockwise from its own point.
+pub struct ConsistentHash {
+    vnodes: usize,
+}
+
+impl ConsistentHash {
+    pub fn new(vnodes: usize) -> Self {
+        ConsistentHash { vnodes: vnodes.max(1) }
+    }
+}
+
+impl PlacementStrategy for ConsistentHash {
+    fn name(&self) -> &'static str {
+        "consistent-hash"
+    }
+
+    fn keyed(&self) -> bool {
+        true
+    }
+
+    fn rank(&self, key: &str, size: u64, candidates: &[Candidate]) -> Vec<usize> {
+        let fits = fitting(size, candidates);
+        // The ring is built from every candidate, so a full node does not
+        // shift the keys of the others; it is only skipped when walking
+        let mut ring: Vec<(u64, usize)> = (0..candidates.len())
+            .flat_map(|i| {
+                (0..self.vnodes).map(move |v| (hash64(&[candidates[i].id.as_bytes(), &v.to_le_bytes()]), i))
+            })
+            .collect();
+        ring.sort_unstable();
+
+        let start = ring.partition_point(|(point, _)| *point < hash64(&[key.as_bytes()]));
+        let mut ranked = Vec::with_capacity(fits.len());
+        for offset in 0..ring.len() {
+            let (_, i) = ring[(start + offset) % ring.len()];
+            if fits.contains(&i) && !ranked.contains(&i) {
+                ranked.push(i);
+                if ranked.len() == fits.len() {
+                    break;
+                }
+            }
+        }
+        ranked
+    }
+}
+
+// Limits how many chunks of one file may share a failure domain, so losing
+// one domain costs a file at most `max_per_domain` of its chunks. Chunks are
+// stored once, which leaves spreading a file's chunks as what the policy can do.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AntiAffinity {
+    pub level: DomainLevel,
+    pub max_per_domain: usize,
+}
+
+impl AntiAffinity {
+    // Policy of this deployment from ANTI_AFFINITY (.env is loaded too); none when unset
+    pub fn from_env() -> Result<Option<Self>, String> {
+        dotenv().ok();
+        let level = match env::var(ANTI_AFFINITY_VAR) {
+            Ok(level) => level.parse()?,
+            Err(_) => return Ok(None),
+        };
+        let max_per_domain = match env::var(ANTI_AFFINITY_MAX_VAR) {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| format!("{} must be a number, got `{}`", ANTI_AFFINITY_MAX_VAR, value))?,
+            Err(_) => 1,
+        };
+        Ok(Some(AntiAffinity {
+            level,
+            max_per_domain: max_per_domain.max(1),
+        }))
+    }
+
+    // Domain of a node at the policy level. A node without that label is
+    // treated as a domain of its own, since nothing says it shares one.
+    pub fn domain_of(&self, node_id: &str, labels: &FailureDomain) -> String {
+        match labels.get(self.level) {
+            Some(label) => label.to_string(),
+            None => format!("node:{}", node_id),
+        }
+    }
+
+    // Whether one more chunk may go to `domain`, given the chunks of the file
+    // placed so far per domain
+    pub fn allows(&self, domain: &str, placed: &HashMap<String, usize>) -> bool {
+        placed.get(domain).copied().unwrap_or(0) < self.max_per_domain
+    }
+
+    // Domains holding more chunks of one file than allowed, with their count;
+    // `chunks` are the holders of the file's chunks
+    pub fn violations<'a, I>(&self, chunks: I) -> Vec<(String, usize)>
+    where
+        I: IntoIterator<Item = (&'a str, &'a FailureDomain)>,
+    {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for (node_id, labels) in chunks {
+            *counts.entry(self.domain_of(node_id, labels)).or_insert(0) += 1;
+        }
+        let mut violations: Vec<(String, usize)> = counts
+            .into_iter()
+            .filter(|(_, count)| *count > self.max_per_domain)
+            .collect();
+        violations.sort();
+        violations
+    }
+
+    // The violations of one file, for the report
+    pub fn report<'a, I>(&self, file_id: &str, file_name: &str, chunks: I) -> Vec<PlacementViolation>
+    where
+        I: IntoIterator<Item = (&'a str, &'a FailureDomain)>,
+    {
+        self.violations(chunks)
+            .into_iter()
+            .map(|(domain, chunks)| PlacementViolation {
+                file_id: file_id.to_string(),
+                file_name: file_name.to_string(),
+                level: self.level,
+                domain,
+                chunks,
+                max_per_domain: self.max_per_domain,
+            })
+            .collect()
+    }
+}
+
+// A file with more chunks in one failure domain than the anti-affinity policy allows
+#[derive(Clone, Debug, Serialize)]
+pub struct PlacementViolation {
+    pub file_id: String,
+    pub file_name: String,
+    pub level: DomainLevel,
+    pub domain: String,
+    pub chunks: usize,
+    pub max_per_domain: usize,
+}
+
+fn hash64(parts: &[&[u8]]) -> u64 {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+pub fn strategy_from_name(name: &str, vnodes: usize) -> Result<Arc<dyn PlacementStrategy>, String> {
+    match name {
+        "first-fit" => Ok(Arc::new(FirstFit)),
+        "most-free" => Ok(Arc::new(MostFreeSpace)),
+        "weighted-random" => Ok(Arc::new(WeightedRandom::new())),
+        "rendezvous" => Ok(Arc::new(Rendezvous)),
+        "consistent-hash" => Ok(Arc::new(ConsistentHash::new(vnodes))),
+        other => Err(format!(
+            "Unknown placement strategy `{}` (expected first-fit, most-free, weighted-random, rendezvous or consistent-hash)",
+            other
+        )),
+    }
+}
+
+// Strategy of this deployment from PLACEMENT_STRATEGY (.env is loaded too);
+// first-fit when unset
+pub fn strategy_from_env() -> Result<Arc<dyn PlacementStrategy>, String> {
+    dotenv().ok();
+    let vnodes = match env::var(VNODES_VAR) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{} must be a number, got `{}`", VNODES_VAR, value))?,
+        Err(_) => DEFAULT_VNODES,
+    };
+    match env::var(STRATEGY_VAR) {
+        Ok(name) => strategy_from_name(name.trim(), vnodes),
+        Err(_) => Ok(Arc::new(FirstFit)),
+    }
+}
+
+// Chooses among the server's own nodes: those taking files (healthy, not
+// draining and, when scored, not banned) go to the strategy best scored
+// first, by id among equals, so the choice never follows HashMap order
+pub fn select_storage_node<'a>(
+    key: &str,
+    size: u64,
+    nodes: impl IntoIterator<Item = &'a StorageNode>,
+    reputation: Option<&Reputation>,
+    strategy: &dyn PlacementStrategy,
+) -> Option<&'a StorageNode> {
+    let mut eligible: Vec<&StorageNode> = nodes
+        .into_iter()
+        .filter(|node| node.health_status && !node.draining)
+        .filter(|node| reputation.is_none_or(|reputation| !reputation.is_banned(&node.node_id)))
+        .collect();
+    eligible.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    if let Some(reputation) = reputation {
+        eligible.sort_by(|a, b| reputation.score(&b.node_id).total_cmp(&reputation.score(&a.node_id)));
+    }
+    let candidates: Vec<Candidate> = eligible.iter().map(|&node| Candidate::from(node)).collect();
+    strategy.select(key, size, &candidates).map(|index| eligible[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(spaces: &[(u64, u64)]) -> Vec<Candidate> {
+        spaces
+            .iter()
+            .enumerate()
+            .map(|(i, &(available_space, total_space))| Candidate {
+                id: format!("node-{}", i),
+                available_space,
+                total_space,
+                labels: FailureDomain::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_strategies_only_rank_nodes_with_room() {
+        let nodes = candidates(&[(10, 100), (500, 1000), (50, 100), (200, 300)]);
+        assert_eq!(FirstFit.rank("k", 40, &nodes), vec![1, 2, 3]);
+        assert_eq!(MostFreeSpace.rank("k", 40, &nodes), vec![1, 3, 2]);
+
+        let strategies: Vec<Arc<dyn PlacementStrategy>> = vec![
+            Arc::new(WeightedRandom::seeded(1)),
+            Arc::new(Rendezvous),
+            Arc::new(ConsistentHash::new(DEFAULT_VNODES)),
+        ];
+        for strategy in strategies {
+            let mut ranked = strategy.rank("chunk-1", 40, &nodes);
+            ranked.sort();
+            assert_eq!(ranked, vec![1, 2, 3], "{}", strategy.name());
+            assert_eq!(strategy.select("chunk-1", 5000, &nodes), None);
+        }
+    }
+
+    #[test]
+    fn test_hashing_strategies_move_few_keys_when_a_node_joins() {
+        let before = candidates(&[(1000, 1000); 4]);
+        let mut after = before.clone();
+        after.push(Candidate {
+            id: "node-new".to_string(),
+            available_space: 1000,
+            total_space: 1000,
+            labels: FailureDomain::default(),
+        });
+
+        let strategies: Vec<Arc<dyn PlacementStrategy>> =
+            vec![Arc::new(Rendezvous), Arc::new(ConsistentHash::new(DEFAULT_VNODES))];
+        for strategy in strategies {
+            let moved = (0..1000)
+                .map(|k| format!("key-{}", k))
+                .filter(|key| {
+                    let old = strategy.select(key, 1, &before).unwrap();
+                    let new = strategy.select(key, 1, &after).unwrap();
+                    // A key either stays or moves to the new node
+                    assert!(new == old || new == 4, "{}", strategy.name());
+                    new != old
+                })
+                .count();
+            // About a fifth of the keys should move to the new node
+            assert!((100..=350).contains(&moved), "{} moved {}", strategy.name(), moved);
+        }
+    }
+
+    #[test]
+    fn test_weighted_random_favours_capacity() {
+        let nodes = candidates(&[(100, 100), (900, 900)]);
+        let strategy = WeightedRandom::seeded(7);
+        let large_first = (0..1000)
+            .filter(|_| strategy.select("k", 1, &nodes) == Some(1))
+            .count();
+        assert!((800..=960).contains(&large_first), "{}", large_first);
+        assert!(strategy_from_name("round-robin", DEFAULT_VNODES).is_err());
+    }
+
+    #[test]
+    fn test_anti_affinity_counts_chunks_per_domain() {
+        let policy = AntiAffinity {
+            level: DomainLevel::Zone,
+            max_per_domain: 1,
+        };
+        let zone = |zone: &str| FailureDomain {
+            zone: Some(zone.to_string()),
+            ..Default::default()
+        };
+        let (a, b, unlabelled) = (zone("eu-1a"), zone("eu-1b"), FailureDomain::default());
+
+        let mut placed = HashMap::new();
+        assert!(policy.allows(&policy.domain_of("n1", &a), &placed));
+        placed.insert(policy.domain_of("n1", &a), 1);
+        assert!(!policy.allows(&policy.domain_of("n2", &a), &placed));
+        assert!(policy.allows(&policy.domain_of("n3", &b), &placed));
+
+        let chunks = [("n1", &a), ("n2", &a), ("n3", &b), ("n4", &unlabelled), ("n5", &unlabelled)];
+        assert_eq!(policy.violations(chunks), vec![("eu-1a".to_string(), 2)]);
+
+        // The report lists the file with the domain it crowds
+        let report = policy.report("file1", "notes.txt", chunks);
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].file_id.as_str(), report[0].domain.as_str()), ("file1", "eu-1a"));
+        assert_eq!((report[0].chunks, report[0].max_per_domain), (2, 1));
+        assert!(policy.report("file2", "spread.txt", [("n1", &a), ("n3", &b)]).is_empty());
+    }
+}
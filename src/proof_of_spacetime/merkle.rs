@@ -0,0 +1,117 @@
+use sha2::{Digest, Sha256};
+
+// Binary Merkle tree over the leaves of a stored file. Leaf and inner hashes
+// are domain separated, and a node without a sibling moves up a level as it
+// is, so the tree of n leaves has no duplicated nodes.
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+pub fn leaf_hash(leaf: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(leaf);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub struct MerkleTree {
+    // Leaf hashes first, the root alone last
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new<'a>(leaves: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        Self::from_leaf_hashes(leaves.into_iter().map(leaf_hash).collect())
+    }
+
+    pub fn from_leaf_hashes(hashes: Vec<Hash>) -> Self {
+        // An empty file is a single empty leaf
+        let mut levels = vec![if hashes.is_empty() { vec![leaf_hash(&[])] } else { hashes }];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap()[0]
+    }
+
+    pub fn leaf_count(&self) -> u64 {
+        self.levels[0].len() as u64
+    }
+
+    // Sibling hashes from the leaf at `index` up to the root, leaving out
+    // the levels where the node has no sibling
+    pub fn path(&self, index: u64) -> Vec<Hash> {
+        let mut index = index as usize;
+        let mut path = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                path.push(*sibling);
+            }
+            index /= 2;
+        }
+        path
+    }
+}
+
+// Whether `leaf` is leaf `index` of the `leaf_count` leaves under `root`
+pub fn verify_path(root: &Hash, leaf_count: u64, index: u64, leaf: &[u8], path: &[Hash]) -> bool {
+    if index >= leaf_count {
+        return false;
+    }
+    let mut hash = leaf_hash(leaf);
+    let (mut index, mut width) = (index, leaf_count);
+    let mut siblings = path.iter();
+    while width > 1 {
+        if index ^ 1 < width {
+            let Some(sibling) = siblings.next() else {
+                return false;
+            };
+            hash = if index % 2 == 0 { node_hash(&hash, sibling) } else { node_hash(sibling, &hash) };
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    siblings.next().is_none() && hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_verify_for_every_leaf() {
+        for count in 1..=9u64 {
+            let leaves: Vec<Vec<u8>> = (0..count).map(|i| vec![i as u8; 3]).collect();
+            let tree = MerkleTree::new(leaves.iter().map(Vec::as_slice));
+            for (index, leaf) in leaves.iter().enumerate() {
+                let path = tree.path(index as u64);
+                assert!(verify_path(&tree.root(), count, index as u64, leaf, &path));
+                assert!(!verify_path(&tree.root(), count, index as u64, b"other", &path));
+            }
+            assert!(!verify_path(&tree.root(), count, count, &leaves[0], &tree.path(0)));
+        }
+    }
+}
@@ -1,82 +1,463 @@
+use dotenv::dotenv;
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::fs::{metadata, read_dir, File};
-use std::io::{Read, Seek};
-use std::time::{Duration, SystemTime};
-use tokio::time::{sleep, Duration as TokioDuration};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, timeout, Duration as TokioDuration};
 
-use crate::node::StorageNode;
-use crate::storage_::Storage;
+use crate::reputation::Reputation;
+
+mod merkle;
+pub mod por;
+
+pub use merkle::Hash;
+use merkle::{verify_path, MerkleTree};
+use por::{respond_to_audit, Audit, AuditKey, AuditResponse, TagKey};
+
+// Proof of spacetime as a challenge protocol. When a file is stored, the
+// verifier commits to its bytes with the Merkle root over LEAF_SIZE leaves.
+// A challenge names the file and a fresh nonce, and the leaves asked for are
+// derived from the nonce; the node has to return those leaves with their
+// Merkle paths before the deadline, which it can only do while it still
+// holds the file. Challenges travel to the node over a Prover, which carries
+// them to wherever the node runs.
+//
+// Verifiers that cannot keep a tree per file use the tags mode instead (see
+// por.rs): MAC tags stored with the file, checked with the file's tag key.
 
 const CHALLENGE_TIMEOUT: Duration = Duration::new(30, 0); // 30 seconds timeout
+// Bytes of the stored file per Merkle leaf
+pub const LEAF_SIZE: usize = 16 * 1024;
+// Leaves asked for per challenge, fewer for smaller files
+const CHALLENGED_LEAVES: usize = 16;
+const MODE_VAR: &str = "POST_MODE";
+
+// How the verifier checks newly stored files
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PostMode {
+    // Merkle root per file, challenged with leaves and paths
+    #[default]
+    Merkle,
+    // Tag key per file, audited with tagged blocks
+    Tags,
+}
+
+// Mode of this deployment from POST_MODE (.env is loaded too); merkle when unset
+pub fn mode_from_env() -> Result<PostMode, String> {
+    dotenv().ok();
+    match env::var(MODE_VAR).as_deref().map(str::trim) {
+        Err(_) | Ok("merkle") => Ok(PostMode::Merkle),
+        Ok("tags") => Ok(PostMode::Tags),
+        Ok(other) => Err(format!("{} must be `merkle` or `tags`, got `{}`", MODE_VAR, other)),
+    }
+}
+
+// What the verifier keeps of a stored file: 40 bytes and the name
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Commitment {
+    pub file_name: String,
+    pub root: Hash,
+    pub leaf_count: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Challenge {
+    pub file_name: String,
+    pub nonce: [u8; 32],
+    pub deadline: u64, // Unix timestamp
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeafProof {
+    pub index: u64,
+    pub leaf: Vec<u8>,
+    pub path: Vec<Hash>,
+}
 
-// Generates a random word.
-fn generate_random_challenge() -> String {
-    let mut range = thread_rng();
-    (0..10).map(|_| range.gen_range(b'a'..=b'z') as char).collect()
-}
-
-// Reads a random part of the file.
-fn get_random_file_part(file_path: &str, byte_count: usize) -> Result<Vec<u8>, String> {
-    let mut file = File::open(file_path).map_err(|e| format!("Failed to open file: {}", e))?;
-    let file_size = metadata(file_path).map_err(|e| format!("Failed to get file metadata: {}", e))?.len() as usize;
-    let byte_count = std::cmp::min(byte_count, file_size);
-    let mut range = thread_rng();
-    let start_byte = range.gen_range(0..file_size - byte_count);
-    let mut buffer = vec![0; byte_count];
-    file.seek(std::io::SeekFrom::Start(start_byte as u64)).map_err(|e| format!("Failed to seek in file: {}", e))?;
-    file.read_exact(&mut buffer).map_err(|e| format!("Failed to read data from file: {}", e))?;
-    Ok(buffer)
-}
-
-// Performs the hash operation.
-fn generate_hash(file_part: &[u8], challenge_word: &str) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    hasher.update(file_part);
-    hasher.update(challenge_word.as_bytes());
-    hasher.finalize().to_vec()
-}
-
-// Function that responds to the challenge.
-fn respond_to_challenge(storage_path: &str) -> Result<Vec<u8>, String> {
-    let paths = read_dir(storage_path).map_err(|e| format!("Failed to read storage directory: {}", e))?;
-    let mut files: Vec<String> = paths.filter_map(|entry| entry.ok().map(|e| e.file_name().into_string().unwrap())).collect();
-    if files.is_empty() {
-        return Err("No files found in the storage directory.".to_string());
-    }
-    let mut range = thread_rng();
-    let random_file = files.choose(&mut range).ok_or("No valid files available.".to_string())?;
-    let file_part = get_random_file_part(&format!("{}/{}", storage_path, random_file), 100)?;
-    let challenge_word = generate_random_challenge();
-    let response_hash = generate_hash(&file_part, &challenge_word);
-    Ok(response_hash)
-}
-
-// Checks proof-of-spacetime for a given node
-fn proof_of_spacetime(node: &StorageNode) {
-    let start_time = SystemTime::now();
-    match respond_to_challenge(&node.storage_path) {
-        Ok(response_hash) => {
-            let elapsed = SystemTime::now().duration_since(start_time).unwrap();
-            if elapsed <= CHALLENGE_TIMEOUT {
-                println!("Node {} passed challenge! Hash: {:?}", node.node_id, response_hash);
-            } else {
-                println!("Node {} failed challenge: Timeout.", node.node_id);
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Proof {
+    pub nonce: [u8; 32], // the challenge answered
+    pub leaves: Vec<LeafProof>,
+}
+
+// Commits to the bytes of a file as the node stores them
+pub fn commit(file_name: &str, data: &[u8]) -> Commitment {
+    let tree = MerkleTree::new(data.chunks(LEAF_SIZE));
+    Commitment {
+        file_name: file_name.to_string(),
+        root: tree.root(),
+        leaf_count: tree.leaf_count(),
+    }
+}
+
+// Up to `wanted` distinct indices below `count`, in order, drawn from the
+// nonce. Verifier and prover derive the same ones, so a response is only
+// good for the nonce it was computed for.
+pub(crate) fn nonce_indices(nonce: &[u8; 32], count: u64, wanted: usize) -> Vec<u64> {
+    let wanted = (wanted as u64).min(count) as usize;
+    let mut indices = BTreeSet::new();
+    let mut counter = 0u64;
+    while indices.len() < wanted {
+        let mut hasher = Sha256::new();
+        hasher.update(nonce);
+        hasher.update(counter.to_le_bytes());
+        let digest = hasher.finalize();
+        indices.insert(u64::from_le_bytes(digest[..8].try_into().unwrap()) % count);
+        counter += 1;
+    }
+    indices.into_iter().collect()
+}
+
+impl Challenge {
+    pub fn new(commitment: &Commitment) -> Self {
+        Challenge {
+            file_name: commitment.file_name.clone(),
+            nonce: thread_rng().gen(),
+            deadline: unix_now() + CHALLENGE_TIMEOUT.as_secs(),
+        }
+    }
+
+    // Leaves asked for from a file of `leaf_count` leaves
+    pub fn indices(&self, leaf_count: u64) -> Vec<u64> {
+        nonce_indices(&self.nonce, leaf_count, CHALLENGED_LEAVES)
+    }
+
+    // Checks a proof received at `received_at` against the commitment
+    pub fn verify(&self, commitment: &Commitment, proof: &Proof, received_at: u64) -> Result<(), String> {
+        if received_at > self.deadline {
+            return Err("Proof arrived after the deadline".to_string());
+        }
+        if proof.nonce != self.nonce {
+            return Err("Proof answers another challenge".to_string());
+        }
+        let indices = self.indices(commitment.leaf_count);
+        if proof.leaves.len() != indices.len() {
+            return Err(format!("Expected {} leaves, got {}", indices.len(), proof.leaves.len()));
+        }
+        for (&index, leaf) in indices.iter().zip(&proof.leaves) {
+            if leaf.index != index
+                || leaf.leaf.len() > LEAF_SIZE
+                || !verify_path(&commitment.root, commitment.leaf_count, index, &leaf.leaf, &leaf.path)
+            {
+                return Err(format!("Leaf {} of {} does not match the commitment", index, self.file_name));
             }
         }
+        Ok(())
+    }
+}
+
+// Answers a challenge from the file as stored under `storage_path`; runs on
+// the node challenged
+pub fn respond_to_challenge(storage_path: &Path, challenge: &Challenge) -> Result<Proof, String> {
+    safe_file_name(&challenge.file_name)?;
+    let data = std::fs::read(storage_path.join(&challenge.file_name))
+        .map_err(|e| format!("Failed to read {}: {}", challenge.file_name, e))?;
+    let tree = MerkleTree::new(data.chunks(LEAF_SIZE));
+    let leaves = challenge
+        .indices(tree.leaf_count())
+        .into_iter()
+        .map(|index| {
+            let start = (index as usize).saturating_mul(LEAF_SIZE).min(data.len());
+            let end = start.saturating_add(LEAF_SIZE).min(data.len());
+            LeafProof { index, leaf: data[start..end].to_vec(), path: tree.path(index) }
+        })
+        .collect();
+    Ok(Proof { nonce: challenge.nonce, leaves })
+}
+
+// What a verifier sends a node, in either mode
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ProverRequest {
+    Challenge(Challenge),
+    Audit(Audit),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ProverResponse {
+    Proof(Proof),
+    Audit(AuditResponse),
+    Error(String),
+}
+
+// Answers a request from the files under `storage_path`; runs on the node
+pub fn answer(storage_path: &Path, request: &ProverRequest) -> ProverResponse {
+    let result = match request {
+        ProverRequest::Challenge(challenge) => respond_to_challenge(storage_path, challenge).map(ProverResponse::Proof),
+        ProverRequest::Audit(audit) => respond_to_audit(storage_path, audit).map(ProverResponse::Audit),
+    };
+    result.unwrap_or_else(ProverResponse::Error)
+}
+
+pub type ProverFuture<'a> = Pin<Box<dyn Future<Output = Result<ProverResponse, String>> + Send + 'a>>;
+
+// Carries a request to the node that has to answer it and brings the answer
+// back: the chunk service for p2p nodes (see p2p/chunk_service.rs), the node
+// directories of the API server for its own nodes
+pub trait Prover: Send + Sync {
+    fn prove(&self, node_id: &str, request: ProverRequest) -> ProverFuture<'_>;
+}
+
+// Stored files are named by the challenge, never a path
+fn safe_file_name(file_name: &str) -> Result<(), String> {
+    if file_name.contains(['/', '\\']) || file_name == ".." {
+        return Err(format!("Invalid file name in challenge: {}", file_name));
+    }
+    Ok(())
+}
+
+// What the verifier keeps to check one stored file
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileCheck {
+    Merkle(Commitment),
+    Tags(AuditKey),
+}
+
+impl FileCheck {
+    pub fn file_name(&self) -> &str {
+        match self {
+            FileCheck::Merkle(commitment) => &commitment.file_name,
+            FileCheck::Tags(audit_key) => &audit_key.file_name,
+        }
+    }
+}
+
+// What is kept of the files stored on each node, recorded when they are stored
+#[derive(Default)]
+pub struct Verifier {
+    mode: PostMode,
+    checks: Mutex<HashMap<String, HashMap<String, FileCheck>>>,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mode(mode: PostMode) -> Self {
+        Verifier { mode, ..Self::default() }
+    }
+
+    // Whether stored files are tagged for audits
+    pub fn mode(&self) -> PostMode {
+        self.mode
+    }
+
+    pub fn record(&self, node_id: &str, commitment: Commitment) {
+        self.insert(node_id, FileCheck::Merkle(commitment));
+    }
+
+    pub fn record_tags(&self, node_id: &str, audit_key: AuditKey) {
+        self.insert(node_id, FileCheck::Tags(audit_key));
+    }
+
+    // Records a file as the node stored it, in the verifier's mode; the tag
+    // key is only needed in tags mode
+    pub fn track(
+        &self,
+        node_id: &str,
+        file_name: &str,
+        stored: &[u8],
+        tag_key: impl FnOnce() -> Result<TagKey, String>,
+    ) -> Result<(), String> {
+        match self.mode {
+            PostMode::Merkle => self.record(node_id, commit(file_name, stored)),
+            PostMode::Tags => self.record_tags(node_id, AuditKey::new(file_name, tag_key()?, stored)?),
+        }
+        Ok(())
+    }
+
+    fn insert(&self, node_id: &str, check: FileCheck) {
+        self.checks
+            .lock()
+            .unwrap()
+            .entry(node_id.to_string())
+            .or_default()
+            .insert(check.file_name().to_string(), check);
+    }
+
+    // Stops challenging a node for a file that was deleted; by file id, the
+    // stored name without its extension, as nodes delete files
+    pub fn forget(&self, node_id: &str, file_id: &str) {
+        if let Some(files) = self.checks.lock().unwrap().get_mut(node_id) {
+            files.retain(|file_name, _| Path::new(file_name).file_stem() != Some(file_id.as_ref()));
+        }
+    }
+
+    // Challenges the node a file was moved to instead of the one it left
+    pub fn reassign(&self, from: &str, to: &str, file_name: &str) {
+        let mut checks = self.checks.lock().unwrap();
+        if let Some(check) = checks.get_mut(from).and_then(|files| files.remove(file_name)) {
+            checks.entry(to.to_string()).or_default().insert(file_name.to_string(), check);
+        }
+    }
+
+    pub fn node_ids(&self) -> Vec<String> {
+        self.checks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, files)| !files.is_empty())
+            .map(|(node_id, _)| node_id.clone())
+            .collect()
+    }
+
+    pub fn checks(&self, node_id: &str) -> Vec<FileCheck> {
+        self.checks
+            .lock()
+            .unwrap()
+            .get(node_id)
+            .map(|files| files.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// Challenges or audits the node on `check` through `prover`. The answer has
+// to arrive by the deadline, which is checked again on the reply.
+pub async fn check_node(prover: &dyn Prover, node_id: &str, check: &FileCheck) -> Result<(), String> {
+    let (request, deadline) = match check {
+        FileCheck::Merkle(commitment) => {
+            let challenge = Challenge::new(commitment);
+            let deadline = challenge.deadline;
+            (ProverRequest::Challenge(challenge), deadline)
+        }
+        FileCheck::Tags(audit_key) => {
+            let audit = Audit::new(audit_key);
+            let deadline = audit.deadline;
+            (ProverRequest::Audit(audit), deadline)
+        }
+    };
+    let wait = Duration::from_secs(deadline.saturating_sub(unix_now()));
+    let response = timeout(wait, prover.prove(node_id, request.clone()))
+        .await
+        .map_err(|_| "No answer before the deadline".to_string())??;
+    let received_at = unix_now();
+    match (check, &request, response) {
+        (FileCheck::Merkle(commitment), ProverRequest::Challenge(challenge), ProverResponse::Proof(proof)) => {
+            challenge.verify(commitment, &proof, received_at)
+        }
+        (FileCheck::Tags(audit_key), ProverRequest::Audit(audit), ProverResponse::Audit(response)) => {
+            audit.verify(audit_key, &response, received_at)
+        }
+        (_, _, ProverResponse::Error(e)) => Err(e),
+        _ => Err("Answer does not match the request".to_string()),
+    }
+}
+
+// Checks the node on one of its files, returns whether it passed; None if
+// nothing recorded is stored on it
+async fn proof_of_spacetime(prover: &dyn Prover, node_id: &str, verifier: &Verifier) -> Option<bool> {
+    let check = verifier.checks(node_id).choose(&mut thread_rng()).cloned()?;
+    match check_node(prover, node_id, &check).await {
+        Ok(()) => {
+            println!("Node {} passed challenge on {}!", node_id, check.file_name());
+            Some(true)
+        }
         Err(err) => {
-            println!("Node {} error while processing challenge: {}", node.node_id, err);
+            println!("Node {} failed challenge on {}: {}", node_id, check.file_name(), err);
+            Some(false)
         }
     }
 }
 
-// Periodically checks proof-of-spacetime for all nodes
-pub async fn periodic_check(nodes: Vec<StorageNode>) {
+// Periodically checks proof-of-spacetime for every node the verifier has
+// files on; outcomes feed the node reputation
+pub async fn periodic_check(verifier: Arc<Verifier>, reputation: Arc<Reputation>, prover: Arc<dyn Prover>) {
     loop {
-        for node in &nodes {
-            proof_of_spacetime(node);
+        for node_id in verifier.node_ids() {
+            if let Some(passed) = proof_of_spacetime(&*prover, &node_id, &verifier).await {
+                reputation.record_post(&node_id, passed);
+            }
         }
         sleep(TokioDuration::from_secs(30)).await; // Wait 30 seconds before next check
     }
 }
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers from one directory, as a node would on the other side
+    struct DirProver(std::path::PathBuf);
+
+    impl Prover for DirProver {
+        fn prove(&self, _node_id: &str, request: ProverRequest) -> ProverFuture<'_> {
+            Box::pin(async move { Ok(answer(&self.0, &request)) })
+        }
+    }
+
+    // Replays an answer computed for an earlier challenge
+    struct ReplayProver(Proof);
+
+    impl Prover for ReplayProver {
+        fn prove(&self, _node_id: &str, _request: ProverRequest) -> ProverFuture<'_> {
+            Box::pin(async move { Ok(ProverResponse::Proof(self.0.clone())) })
+        }
+    }
+
+    #[test]
+    fn test_challenge_round_trip() {
+        let dir = std::env::temp_dir().join(format!("post-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..5 * LEAF_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("file1"), &data).unwrap();
+        let commitment = commit("file1", &data);
+        assert_eq!(commitment.leaf_count, 6);
+
+        let challenge = Challenge::new(&commitment);
+        assert_eq!(challenge.indices(commitment.leaf_count).len(), 6);
+        let proof = respond_to_challenge(&dir, &challenge).unwrap();
+        assert!(challenge.verify(&commitment, &proof, unix_now()).is_ok());
+        assert!(challenge.verify(&commitment, &proof, challenge.deadline + 1).is_err());
+        // A proof is only good for the challenge it answers
+        assert!(Challenge::new(&commitment).verify(&commitment, &proof, unix_now()).is_err());
+
+        // A node that lost part of the file fails
+        let mut damaged = data.clone();
+        damaged[LEAF_SIZE * 3] ^= 1;
+        std::fs::write(dir.join("file1"), &damaged).unwrap();
+        let proof = respond_to_challenge(&dir, &challenge).unwrap();
+        assert!(challenge.verify(&commitment, &proof, unix_now()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_indices_follow_the_nonce() {
+        let indices = nonce_indices(&[7; 32], 1000, 16);
+        assert_eq!(indices.len(), 16);
+        assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(indices, nonce_indices(&[7; 32], 1000, 16));
+        assert_ne!(indices, nonce_indices(&[8; 32], 1000, 16));
+        assert_eq!(nonce_indices(&[7; 32], 3, 16), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_check_node_through_a_prover() {
+        let dir = std::env::temp_dir().join(format!("post-prover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..40 * LEAF_SIZE).map(|i| (i % 241) as u8).collect();
+        std::fs::write(dir.join("file1"), &data).unwrap();
+        let check = FileCheck::Merkle(commit("file1", &data));
+        let prover = DirProver(dir.clone());
+        assert!(check_node(&prover, "node1", &check).await.is_ok());
+
+        // The leaves of an earlier challenge do not answer a new one
+        let FileCheck::Merkle(commitment) = &check else { unreachable!() };
+        let earlier = respond_to_challenge(&dir, &Challenge::new(commitment)).unwrap();
+        assert!(check_node(&ReplayProver(earlier), "node1", &check).await.is_err());
+
+        std::fs::remove_file(dir.join("file1")).unwrap();
+        assert!(check_node(&prover, "node1", &check).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,259 @@
+use hmac::{Hmac, Mac, NewMac};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+
+use super::{nonce_indices, safe_file_name, unix_now, CHALLENGE_TIMEOUT};
+
+// Proof of retrievability with MAC tags, for verifiers that cannot keep a
+// Merkle tree per file. Every block of the stored file is tagged with a MAC
+// of the file name, the block index and the block, under a key derived from
+// the file key. The tags are appended to the stored file, so the verifier
+// only keeps the key and the block count; an audit asks for blocks drawn
+// from a fresh nonce with their tags and checks them with the key alone.
+//
+// Stored files start with a header saying whether they are tagged, written
+// in tags mode only. Tagged layout: header || data || tags || data length
+// (u64, little endian), where the data starts with the header. Files stored
+// before the header have none; they are never tagged.
+
+// Bytes of the stored file per tagged block
+pub const BLOCK_SIZE: usize = 16 * 1024;
+// Bytes of a tag, a truncated HMAC-SHA256
+pub const TAG_SIZE: usize = 16;
+const FOOTER_SIZE: usize = 8;
+const MAGIC: &[u8; 4] = b"DSF1";
+pub const HEADER_SIZE: usize = MAGIC.len() + 1;
+// Blocks asked for per audit, fewer for smaller files
+const AUDITED_BLOCKS: usize = 16;
+
+pub type TagKey = [u8; 32];
+
+// All the verifier keeps of a stored file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditKey {
+    pub file_name: String,
+    pub key: TagKey,
+    pub block_count: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Audit {
+    pub file_name: String,
+    pub nonce: [u8; 32],
+    pub deadline: u64, // Unix timestamp
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaggedBlock {
+    pub index: u64,
+    pub block: Vec<u8>,
+    pub tag: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditResponse {
+    pub nonce: [u8; 32], // the audit answered
+    pub blocks: Vec<TaggedBlock>,
+}
+
+// Header of a stored file. The encrypted data starts with a chunk length of
+// at most a few MB, so it never begins with MAGIC.
+pub fn header(tagged: bool) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()] = tagged as u8;
+    header
+}
+
+// Whether the stored file is tagged, from its header; None without one
+pub fn is_tagged(stored: &[u8]) -> Option<bool> {
+    match stored.get(..HEADER_SIZE)? {
+        [m0, m1, m2, m3, flag] if [*m0, *m1, *m2, *m3] == *MAGIC => Some(*flag == 1),
+        _ => None,
+    }
+}
+
+// Key of the tags of a file, from the file's encryption key
+pub fn tag_key(file_key: &[u8]) -> TagKey {
+    let mut mac = Hmac::<Sha256>::new_from_slice(file_key).expect("HMAC creation failed");
+    mac.update(b"proof-of-retrievability tags");
+    mac.finalize().into_bytes().into()
+}
+
+fn tag(key: &TagKey, file_name: &str, index: u64, block: &[u8]) -> [u8; TAG_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC creation failed");
+    mac.update(&(file_name.len() as u64).to_le_bytes());
+    mac.update(file_name.as_bytes());
+    mac.update(&index.to_le_bytes());
+    mac.update(block);
+    let mut tag = [0u8; TAG_SIZE];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_SIZE]);
+    tag
+}
+
+// Blocks of `data_len` bytes of data; an empty file is one empty block
+pub fn block_count(data_len: u64) -> u64 {
+    data_len.div_ceil(BLOCK_SIZE as u64).max(1)
+}
+
+// Appends the tags of `data` and the footer, as the file is stored
+pub fn append_tags(key: &TagKey, file_name: &str, data: &mut Vec<u8>) {
+    let data_len = data.len();
+    let mut tags = Vec::with_capacity(block_count(data_len as u64) as usize * TAG_SIZE + FOOTER_SIZE);
+    if data.is_empty() {
+        tags.extend_from_slice(&tag(key, file_name, 0, &[]));
+    }
+    for (index, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        tags.extend_from_slice(&tag(key, file_name, index as u64, block));
+    }
+    tags.extend_from_slice(&(data_len as u64).to_le_bytes());
+    data.extend_from_slice(&tags);
+}
+
+// Splits a tagged file into its data and tags
+fn split_tags(stored: &[u8]) -> Option<(&[u8], &[u8])> {
+    let footer_at = stored.len().checked_sub(FOOTER_SIZE)?;
+    let data_len = u64::from_le_bytes(stored[footer_at..].try_into().unwrap());
+    let tags_len = block_count(data_len).checked_mul(TAG_SIZE as u64)?;
+    if data_len.checked_add(tags_len)? != footer_at as u64 {
+        return None;
+    }
+    let (data, tags) = stored[..footer_at].split_at(data_len as usize);
+    Some((data, tags))
+}
+
+// The data and tags of a stored file, if its header says it is tagged
+pub fn tagged_parts(stored: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    match is_tagged(stored) {
+        Some(true) => split_tags(stored).ok_or_else(|| "Tagged file has a damaged trailer".to_string()),
+        _ => Err("File carries no tags".to_string()),
+    }
+}
+
+// The stored file as it was encrypted: without the header, and without the
+// tags when the header says there are some
+pub fn encrypted_part(stored: &[u8]) -> Result<&[u8], String> {
+    match is_tagged(stored) {
+        None => Ok(stored),
+        Some(false) => Ok(&stored[HEADER_SIZE..]),
+        Some(true) => tagged_parts(stored).map(|(data, _)| &data[HEADER_SIZE..]),
+    }
+}
+
+impl AuditKey {
+    // Verifier state for a file as stored, tags included
+    pub fn new(file_name: &str, key: TagKey, stored: &[u8]) -> Result<Self, String> {
+        let (data, _) = tagged_parts(stored).map_err(|e| format!("{}: {}", file_name, e))?;
+        Ok(AuditKey {
+            file_name: file_name.to_string(),
+            key,
+            block_count: block_count(data.len() as u64),
+        })
+    }
+}
+
+impl Audit {
+    pub fn new(audit_key: &AuditKey) -> Self {
+        Audit {
+            file_name: audit_key.file_name.clone(),
+            nonce: thread_rng().gen(),
+            deadline: unix_now() + CHALLENGE_TIMEOUT.as_secs(),
+        }
+    }
+
+    // Blocks asked for from a file of `block_count` blocks
+    pub fn indices(&self, block_count: u64) -> Vec<u64> {
+        nonce_indices(&self.nonce, block_count, AUDITED_BLOCKS)
+    }
+
+    // Checks a response received at `received_at` with the tag key
+    pub fn verify(&self, audit_key: &AuditKey, response: &AuditResponse, received_at: u64) -> Result<(), String> {
+        if received_at > self.deadline {
+            return Err("Response arrived after the deadline".to_string());
+        }
+        if response.nonce != self.nonce {
+            return Err("Response answers another audit".to_string());
+        }
+        let indices = self.indices(audit_key.block_count);
+        if response.blocks.len() != indices.len() {
+            return Err(format!("Expected {} blocks, got {}", indices.len(), response.blocks.len()));
+        }
+        for (&index, block) in indices.iter().zip(&response.blocks) {
+            if block.index != index
+                || index >= audit_key.block_count
+                || block.block.len() > BLOCK_SIZE
+                || block.tag != tag(&audit_key.key, &self.file_name, index, &block.block)
+            {
+                return Err(format!("Block {} of {} does not match its tag", index, self.file_name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Answers an audit from the file as stored under `storage_path`; runs on the
+// node audited
+pub fn respond_to_audit(storage_path: &Path, audit: &Audit) -> Result<AuditResponse, String> {
+    safe_file_name(&audit.file_name)?;
+    let stored = std::fs::read(storage_path.join(&audit.file_name))
+        .map_err(|e| format!("Failed to read {}: {}", audit.file_name, e))?;
+    let (data, tags) = tagged_parts(&stored).map_err(|e| format!("{}: {}", audit.file_name, e))?;
+    let blocks = audit
+        .indices(block_count(data.len() as u64))
+        .into_iter()
+        .map(|index| {
+            let start = (index as usize).saturating_mul(BLOCK_SIZE).min(data.len());
+            let end = start.saturating_add(BLOCK_SIZE).min(data.len());
+            let tag_start = (index as usize).saturating_mul(TAG_SIZE).min(tags.len());
+            let tag_end = tag_start.saturating_add(TAG_SIZE).min(tags.len());
+            TaggedBlock { index, block: data[start..end].to_vec(), tag: tags[tag_start..tag_end].to_vec() }
+        })
+        .collect();
+    Ok(AuditResponse { nonce: audit.nonce, blocks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_round_trip() {
+        let dir = std::env::temp_dir().join(format!("por-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = tag_key(b"0123456789abcdef");
+        let encrypted: Vec<u8> = (0..3 * BLOCK_SIZE + 10).map(|i| (i % 253) as u8).collect();
+        let mut stored = header(true).to_vec();
+        stored.extend_from_slice(&encrypted);
+        append_tags(&key, "file1.bin", &mut stored);
+        assert_eq!(encrypted_part(&stored).unwrap(), &encrypted[..]);
+        // Only the header decides whether there are tags
+        assert_eq!(encrypted_part(&encrypted).unwrap(), &encrypted[..]);
+        let mut untagged = header(false).to_vec();
+        untagged.extend_from_slice(&stored[HEADER_SIZE..]);
+        assert_eq!(encrypted_part(&untagged).unwrap(), &stored[HEADER_SIZE..]);
+        assert!(tagged_parts(&untagged).is_err());
+        std::fs::write(dir.join("file1.bin"), &stored).unwrap();
+
+        let audit_key = AuditKey::new("file1.bin", key, &stored).unwrap();
+        assert_eq!(audit_key.block_count, 4);
+        let audit = Audit::new(&audit_key);
+        let response = respond_to_audit(&dir, &audit).unwrap();
+        assert!(audit.verify(&audit_key, &response, unix_now()).is_ok());
+        assert!(audit.verify(&audit_key, &response, audit.deadline + 1).is_err());
+        // A response is only good for the audit it answers
+        assert!(Audit::new(&audit_key).verify(&audit_key, &response, unix_now()).is_err());
+        // Tags are bound to the key and the file name
+        let other = AuditKey { key: tag_key(b"another file key"), ..audit_key.clone() };
+        assert!(audit.verify(&other, &response, unix_now()).is_err());
+
+        // A node that lost part of the file fails
+        let mut damaged = stored.clone();
+        damaged[BLOCK_SIZE * 2 + 1] ^= 1;
+        std::fs::write(dir.join("file1.bin"), &damaged).unwrap();
+        let response = respond_to_audit(&dir, &audit).unwrap();
+        assert!(audit.verify(&audit_key, &response, unix_now()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,708 @@
+use dotenv::dotenv;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, Notify};
+
+use crate::node::StorageNode;
+use crate::placement::{AntiAffinity, Candidate, PlacementStrategy};
+
+// Time between two rebalancing rounds when no node joined in between
+pub const REBALANCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Bytes one round may move, e.g. REBALANCE_BUDGET=268435456. Moves beyond the
+// budget are planned again in a later round.
+const BUDGET_VAR: &str = "REBALANCE_BUDGET";
+pub const DEFAULT_BUDGET: u64 = 256 * 1024 * 1024;
+// A node more than this far above the mean utilization gives chunks away
+pub const UTILIZATION_TOLERANCE: f64 = 0.05;
+
+// A chunk and the node it is recorded on
+#[derive(Clone, Debug)]
+pub struct PlacedChunk {
+    pub file_id: String,
+    pub chunk_id: String,
+    pub size: u64,
+    pub hash: String,
+    pub node_id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ChunkMove {
+    pub file_id: String,
+    pub chunk_id: String,
+    pub size: u64,
+    pub hash: String,
+    pub from: String,
+    pub to: String,
+}
+
+// Projected usage of the nodes while a plan is built
+struct Planner<'a> {
+    nodes: &'a [Candidate],
+    used: HashMap<String, u64>,
+    policy: Option<&'a AntiAffinity>,
+    // file id -> chunks of the file per failure domain
+    domains: HashMap<String, HashMap<String, usize>>,
+    // chunk id -> nodes holding a copy
+    holders: HashMap<String, HashSet<String>>,
+}
+
+impl<'a> Planner<'a> {
+    fn new(chunks: &[PlacedChunk], nodes: &'a [Candidate], policy: Option<&'a AntiAffinity>) -> Self {
+        let mut indexed: HashMap<String, u64> = HashMap::new();
+        for chunk in chunks {
+            *indexed.entry(chunk.node_id.clone()).or_insert(0) += chunk.size;
+        }
+        // A node's own report also counts data of other owners; our index
+        // is the better figure when the report is stale
+        let used = nodes
+            .iter()
+            .map(|node| {
+                let reported = node.total_space.saturating_sub(node.available_space);
+                (node.id.clone(), reported.max(indexed.get(&node.id).copied().unwrap_or(0)))
+            })
+            .collect();
+
+        let mut planner = Planner {
+            nodes,
+            used,
+            policy,
+            domains: HashMap::new(),
+            holders: HashMap::new(),
+        };
+        for chunk in chunks {
+            planner
+                .holders
+                .entry(chunk.chunk_id.clone())
+                .or_default()
+                .insert(chunk.node_id.clone());
+            if let Some(domain) = planner.domain(&chunk.node_id) {
+                *planner
+                    .domains
+                    .entry(chunk.file_id.clone())
+                    .or_default()
+                    .entry(domain)
+                    .or_insert(0) += 1;
+            }
+        }
+        planner
+    }
+
+    fn node(&self, node_id: &str) -> Option<&'a Candidate> {
+        self.nodes.iter().find(|node| node.id == node_id)
+    }
+
+    fn domain(&self, node_id: &str) -> Option<String> {
+        let policy = self.policy?;
+        let node = self.node(node_id)?;
+        Some(policy.domain_of(&node.id, &node.labels))
+    }
+
+    fn utilization(&self, node: &Candidate) -> f64 {
+        if node.total_space == 0 {
+            return 1.0;
+        }
+        self.used.get(&node.id).copied().unwrap_or(0) as f64 / node.total_space as f64
+    }
+
+    fn mean_utilization(&self) -> f64 {
+        let total: u64 = self.nodes.iter().map(|node| node.total_space).sum();
+        let used: u64 = self.used.values().sum();
+        if total == 0 {
+            return 0.0;
+        }
+        used as f64 / total as f64
+    }
+
+    // Candidates as they would look with the chunk taken off its node
+    fn candidates<F>(&self, chunk: &PlacedChunk, filter: F) -> Vec<Candidate>
+    where
+        F: Fn(&Candidate) -> bool,
+    {
+        self.nodes
+            .iter()
+            .filter(|node| filter(node))
+            .map(|node| {
+                let mut used = self.used.get(&node.id).copied().unwrap_or(0);
+                if node.id == chunk.node_id {
+                    used = used.saturating_sub(chunk.size);
+                }
+                Candidate {
+                    available_space: node.total_space.saturating_sub(used),
+                    ..node.clone()
+                }
+            })
+            .collect()
+    }
+
+    // Whether the copy may go to `target`: the node holds no other copy of
+    // the chunk, and the anti-affinity policy still holds for the chunks of its file
+    fn allows(&self, chunk: &PlacedChunk, target: &str) -> bool {
+        if self.holders.get(&chunk.chunk_id).is_some_and(|holders| holders.contains(target)) {
+            return false;
+        }
+        let policy = match self.policy {
+            Some(policy) => policy,
+            None => return true,
+        };
+        let (from, to) = match (self.domain(&chunk.node_id), self.domain(target)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return false,
+        };
+        if from == to {
+            return true;
+        }
+        let mut placed = self.domains.get(&chunk.file_id).cloned().unwrap_or_default();
+        if let Some(count) = placed.get_mut(&from) {
+            *count = count.saturating_sub(1);
+        }
+        policy.allows(&to, &placed)
+    }
+
+    fn apply(&mut self, chunk: &PlacedChunk, target: &str) -> ChunkMove {
+        if let Some(used) = self.used.get_mut(&chunk.node_id) {
+            *used = used.saturating_sub(chunk.size);
+        }
+        *self.used.entry(target.to_string()).or_insert(0) += chunk.size;
+        let holders = self.holders.entry(chunk.chunk_id.clone()).or_default();
+        holders.remove(&chunk.node_id);
+        holders.insert(target.to_string());
+        if let (Some(from), Some(to)) = (self.domain(&chunk.node_id), self.domain(target)) {
+            let placed = self.domains.entry(chunk.file_id.clone()).or_default();
+            if let Some(count) = placed.get_mut(&from) {
+                *count = count.saturating_sub(1);
+            }
+            *placed.entry(to).or_insert(0) += 1;
+        }
+        ChunkMove {
+            file_id: chunk.file_id.clone(),
+            chunk_id: chunk.chunk_id.clone(),
+            size: chunk.size,
+            hash: chunk.hash.clone(),
+            from: chunk.node_id.clone(),
+            to: target.to_string(),
+        }
+    }
+}
+
+// Plans the moves that bring the chunks closer to the distribution the
+// strategy asks for, moving at most `budget` bytes. Keyed strategies send
+// every chunk to its home node; the others move chunks off nodes above the
+// mean utilization to nodes below it, picked by the strategy. Chunks on
+// nodes not in `nodes` are left to repair.
+pub fn plan_moves(
+    chunks: &[PlacedChunk],
+    nodes: &[Candidate],
+    strategy: &dyn PlacementStrategy,
+    policy: Option<&AntiAffinity>,
+    budget: u64,
+) -> Vec<ChunkMove> {
+    let mut planner = Planner::new(chunks, nodes, policy);
+    let mut order: Vec<&PlacedChunk> = chunks
+        .iter()
+        .filter(|chunk| planner.node(&chunk.node_id).is_some())
+        .collect();
+    order.sort_by(|a, b| a.chunk_id.cmp(&b.chunk_id));
+
+    let mean = planner.mean_utilization();
+    let mut moves = Vec::new();
+    let mut planned = 0u64;
+    for chunk in order {
+        if planned + chunk.size > budget {
+            continue;
+        }
+        let candidates = if strategy.keyed() {
+            planner.candidates(chunk, |_| true)
+        } else {
+            let source = planner.node(&chunk.node_id).unwrap();
+            if planner.utilization(source) <= mean + UTILIZATION_TOLERANCE {
+                continue;
+            }
+            planner.candidates(chunk, |node| {
+                node.id != chunk.node_id
+                    && node.total_space > 0
+                    && (planner.used.get(&node.id).copied().unwrap_or(0) + chunk.size) as f64 / node.total_space as f64
+                        <= mean
+            })
+        };
+
+        let target = match strategy.select(&chunk.chunk_id, chunk.size, &candidates) {
+            Some(i) => candidates[i].id.clone(),
+            None => continue,
+        };
+        if target == chunk.node_id || !planner.allows(chunk, &target) {
+            continue;
+        }
+        planned += chunk.size;
+        moves.push(planner.apply(chunk, &target));
+    }
+    moves
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RebalanceState {
+    Idle,
+    Running,
+    Paused,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RebalanceStatus {
+    pub state: RebalanceState,
+    pub budget: u64, // bytes per round
+    pub rounds: u64,
+    pub current: Option<ChunkMove>,
+    pub pending: Vec<ChunkMove>,
+    pub moved_chunks: u64,
+    pub moved_bytes: u64,
+    pub failed_moves: u64,
+    pub last_round: Option<u64>, // unix time the last round ended
+    pub last_error: Option<String>,
+}
+
+// Control and progress of the rebalancer, shared between the task moving the
+// chunks and the API
+pub struct Rebalancer {
+    paused: watch::Sender<bool>,
+    trigger: Notify,
+    status: Mutex<RebalanceStatus>,
+}
+
+impl Rebalancer {
+    pub fn new(budget: u64) -> Self {
+        Rebalancer {
+            paused: watch::channel(false).0,
+            trigger: Notify::new(),
+            status: Mutex::new(RebalanceStatus {
+                state: RebalanceState::Idle,
+                budget,
+                rounds: 0,
+                current: None,
+                pending: Vec::new(),
+                moved_chunks: 0,
+                moved_bytes: 0,
+                failed_moves: 0,
+                last_round: None,
+                last_error: None,
+            }),
+        }
+    }
+
+    // Budget from REBALANCE_BUDGET (.env is loaded too)
+    pub fn from_env() -> Result<Self, String> {
+        dotenv().ok();
+        let budget = match env::var(BUDGET_VAR) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map_err(|_| format!("{} must be a number of bytes, got `{}`", BUDGET_VAR, value))?,
+            Err(_) => DEFAULT_BUDGET,
+        };
+        Ok(Self::new(budget))
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.status.lock().unwrap().budget
+    }
+
+    // Stops before the next chunk; the move in progress is finished first
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    // Starts a round now instead of after REBALANCE_INTERVAL, e.g. when a node joined
+    pub fn trigger(&self) {
+        self.trigger.notify_one();
+    }
+
+    pub fn status(&self) -> RebalanceStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        if self.is_paused() {
+            status.state = RebalanceState::Paused;
+        }
+        status
+    }
+
+    pub async fn wait_for_round(&self) {
+        tokio::select! {
+            _ = tokio::time::sleep(REBALANCE_INTERVAL) => {}
+            _ = self.trigger.notified() => {}
+        }
+    }
+
+    pub async fn wait_while_paused(&self) {
+        let mut paused = self.paused.subscribe();
+        while *paused.borrow_and_update() {
+            if paused.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    pub fn start_round(&self, plan: Vec<ChunkMove>) {
+        let mut status = self.status.lock().unwrap();
+        status.state = RebalanceState::Running;
+        status.rounds += 1;
+        status.pending = plan;
+    }
+
+    pub fn start_move(&self, chunk_move: &ChunkMove) {
+        let mut status = self.status.lock().unwrap();
+        status.pending.retain(|pending| pending != chunk_move);
+        status.current = Some(chunk_move.clone());
+    }
+
+    pub fn finish_move(&self, chunk_move: &ChunkMove, result: Result<(), String>) {
+        let mut status = self.status.lock().unwrap();
+        status.current = None;
+        match result {
+            Ok(()) => {
+                status.moved_chunks += 1;
+                status.moved_bytes += chunk_move.size;
+            }
+            Err(e) => {
+                status.failed_moves += 1;
+                status.last_error = Some(e);
+            }
+        }
+    }
+
+    pub fn end_round(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.state = RebalanceState::Idle;
+        status.current = None;
+        status.pending.clear();
+        status.last_round = Some(unix_now());
+    }
+}
+
+// One round over the nodes of the API server, whose stored files are the
+// chunks here. Each file is copied, verified, switched with `switch` and only
+// then deleted from its old node; the copies run off the async runtime and
+// outside the node lock. Draining and unhealthy nodes take no part.
+pub async fn rebalance_nodes<F>(
+    nodes: &Mutex<HashMap<String, StorageNode>>,
+    rebalancer: &Rebalancer,
+    strategy: &dyn PlacementStrategy,
+    switch: F,
+) where
+    F: Fn(&ChunkMove),
+{
+    let (chunks, mut candidates) = {
+        let nodes = nodes.lock().unwrap();
+        let mut chunks = Vec::new();
+        let mut candidates = Vec::new();
+        for node in nodes.values().filter(|node| node.health_status && !node.draining) {
+            match node.stored_files() {
+                Ok(files) => chunks.extend(files.into_iter().map(|(file, size)| PlacedChunk {
+                    file_id: file_stem(&file),
+                    chunk_id: file,
+                    size,
+                    hash: String::new(),
+                    node_id: node.node_id.clone(),
+                })),
+                Err(e) => {
+                    eprintln!("Leaving node {} out of the rebalancing round: {}", node.node_id, e);
+                    continue;
+                }
+            }
+            candidates.push(Candidate::from(node));
+        }
+        (chunks, candidates)
+    };
+    candidates.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let plan = plan_moves(&chunks, &candidates, strategy, None, rebalancer.budget());
+    if !plan.is_empty() {
+        println!("Rebalancing {} file(s)", plan.len());
+    }
+
+    rebalancer.start_round(plan.clone());
+    for chunk_move in plan {
+        rebalancer.wait_while_paused().await;
+        rebalancer.start_move(&chunk_move);
+        let result = move_file(nodes, &chunk_move, &switch).await;
+        if let Err(e) = &result {
+            eprintln!(
+                "Failed to move file {} from node {} to node {}: {}",
+                chunk_move.chunk_id, chunk_move.from, chunk_move.to, e
+            );
+        }
+        rebalancer.finish_move(&chunk_move, result);
+    }
+    rebalancer.end_round();
+}
+
+// copy -> verify -> switch -> delete the source. Until the switch the source
+// copy is the one in use, so a failed move only removes the new copy.
+async fn move_file<F>(nodes: &Mutex<HashMap<String, StorageNode>>, chunk_move: &ChunkMove, switch: &F) -> Result<(), String>
+where
+    F: Fn(&ChunkMove),
+{
+    let (source, target) = {
+        let nodes = nodes.lock().unwrap();
+        let source = nodes.get(&chunk_move.from).cloned();
+        let target = nodes.get(&chunk_move.to).cloned();
+        (source, target)
+    };
+    let source = source.ok_or_else(|| format!("Node {} not found", chunk_move.from))?;
+    let mut target = target.ok_or_else(|| format!("Node {} not found", chunk_move.to))?;
+
+    // Copy and verify
+    let file = chunk_move.chunk_id.clone();
+    let copied = tokio::task::spawn_blocking(move || -> Result<StorageNode, String> {
+        let data = source
+            .read_stored_file(&file)
+            .map_err(|e| format!("Failed to read file {}: {}", file, e))?;
+        target.receive_file(&file, &data).map_err(|e| e.to_string())?;
+        let copy = target
+            .read_stored_file(&file)
+            .map_err(|e| format!("Failed to read the copy of {}: {}", file, e))?;
+        if hash(&copy) != hash(&data) {
+            let _ = target.delete_file(&file_stem(&file));
+            return Err(format!("The copy of {} on node {} does not match the original", file, target.node_id));
+        }
+        Ok(target)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    // Switch, unless the file was deleted or a node left meanwhile
+    let switched = {
+        let mut nodes = nodes.lock().unwrap();
+        let still_stored = nodes
+            .get(&chunk_move.from)
+            .is_some_and(|node| Path::new(&node.storage_path).join(&chunk_move.chunk_id).exists());
+        match nodes.get_mut(&chunk_move.to) {
+            Some(target) if still_stored && !target.draining => {
+                target.available_space = target.available_space.saturating_sub(chunk_move.size);
+                switch(chunk_move);
+                true
+            }
+            _ => false,
+        }
+    };
+    if !switched {
+        let mut copied = copied;
+        let file_id = chunk_move.file_id.clone();
+        let _ = tokio::task::spawn_blocking(move || copied.delete_file(&file_id)).await;
+        return Err(format!("File {} changed while it was moved; the copy was discarded", chunk_move.chunk_id));
+    }
+
+    // Delete the source
+    let source = nodes.lock().unwrap().get(&chunk_move.from).cloned();
+    let Some(mut source) = source else {
+        return Ok(());
+    };
+    let file_id = chunk_move.file_id.clone();
+    tokio::task::spawn_blocking(move || source.delete_file(&file_id))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| {
+            format!(
+                "File {} moved to node {} but the old copy on node {} could not be deleted: {}",
+                chunk_move.chunk_id, chunk_move.to, chunk_move.from, e
+            )
+        })?;
+    if let Some(source) = nodes.lock().unwrap().get_mut(&chunk_move.from) {
+        source.available_space = source.available_space.saturating_add(chunk_move.size).min(source.total_space);
+    }
+    println!("File {} moved from node {} to node {}", chunk_move.chunk_id, chunk_move.from, chunk_move.to);
+    Ok(())
+}
+
+// Stored files are named by their file id and the source file's extension
+pub(crate) fn file_stem(file: &str) -> String {
+    Path::new(file)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| file.to_string())
+}
+
+fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::DomainLevel;
+    use crate::placement::{FirstFit, Rendezvous};
+
+    fn node(id: &str, total_space: u64) -> Candidate {
+        Candidate {
+            id: id.to_string(),
+            available_space: total_space,
+            total_space,
+            labels: Default::default(),
+        }
+    }
+
+    fn chunks(node_id: &str, count: usize, size: u64) -> Vec<PlacedChunk> {
+        (0..count)
+            .map(|i| PlacedChunk {
+                file_id: format!("file-{}", i % 2),
+                chunk_id: format!("{}-chunk-{}", node_id, i),
+                size,
+                hash: String::new(),
+                node_id: node_id.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_new_node_gets_chunks_within_the_budget() {
+        let nodes = vec![node("a", 1000), node("b", 1000), node("c", 1000)];
+        let mut placed = chunks("a", 8, 50);
+        placed.extend(chunks("b", 8, 50));
+
+        let moves = plan_moves(&placed, &nodes, &FirstFit, None, u64::MAX);
+        assert!(!moves.is_empty());
+        assert!(moves.iter().all(|m| m.to == "c"));
+        let moved: u64 = moves.iter().map(|m| m.size).sum();
+        // Every node ends up within the tolerance of the mean (800 / 3000)
+        assert!((200..=300).contains(&moved), "moved {}", moved);
+
+        let limited = plan_moves(&placed, &nodes, &FirstFit, None, 120);
+        assert_eq!(limited.len(), 2);
+    }
+
+    #[test]
+    fn test_keyed_strategy_moves_only_the_keys_of_the_new_node() {
+        let nodes = vec![node("a", 1000), node("b", 1000), node("c", 1000)];
+        let mut placed = chunks("a", 16, 10);
+        for chunk in placed.iter_mut() {
+            let home = Rendezvous.select(&chunk.chunk_id, chunk.size, &nodes[..2]).unwrap();
+            chunk.node_id = nodes[home].id.clone();
+        }
+
+        assert!(plan_moves(&placed, &nodes[..2], &Rendezvous, None, u64::MAX).is_empty());
+        let moves = plan_moves(&placed, &nodes, &Rendezvous, None, u64::MAX);
+        assert!(!moves.is_empty());
+        assert!(moves.iter().all(|m| m.to == "c"));
+    }
+
+    #[test]
+    fn test_moves_respect_anti_affinity() {
+        let mut nodes = vec![node("a", 1000), node("b", 1000), node("c", 1000)];
+        nodes[0].labels.zone = Some("z1".to_string());
+        nodes[1].labels.zone = Some("z2".to_string());
+        nodes[2].labels.zone = Some("z2".to_string());
+        let chunk = |file_id: &str, chunk_id: &str, node_id: &str| PlacedChunk {
+            file_id: file_id.to_string(),
+            chunk_id: chunk_id.to_string(),
+            size: 200,
+            hash: String::new(),
+            node_id: node_id.to_string(),
+        };
+        // x and y are chunks of one file, on a (z1) and b (z2); z, of another file, is on a
+        let placed = vec![chunk("f", "x", "a"), chunk("f", "y", "b"), chunk("g", "z", "a")];
+
+        let policy = AntiAffinity {
+            level: DomainLevel::Zone,
+            max_per_domain: 1,
+        };
+        // Moving x off a to c would put both chunks of its file in z2; z is
+        // the only chunk of its file, so the policy does not hold it back
+        let moves = plan_moves(&placed, &nodes, &FirstFit, Some(&policy), u64::MAX);
+        assert_eq!(moves.len(), 1);
+        assert_eq!((moves[0].chunk_id.as_str(), moves[0].from.as_str(), moves[0].to.as_str()), ("z", "a", "c"));
+    }
+
+    #[test]
+    fn test_status_tracks_pause_and_progress() {
+        let rebalancer = Rebalancer::new(DEFAULT_BUDGET);
+        let chunk_move = ChunkMove {
+            file_id: "f".to_string(),
+            chunk_id: "c".to_string(),
+            size: 10,
+            hash: String::new(),
+            from: "a".to_string(),
+            to: "b".to_string(),
+        };
+        rebalancer.start_round(vec![chunk_move.clone()]);
+        rebalancer.start_move(&chunk_move);
+        rebalancer.pause();
+        assert_eq!(rebalancer.status().state, RebalanceState::Paused);
+        rebalancer.finish_move(&chunk_move, Ok(()));
+        rebalancer.resume();
+        rebalancer.end_round();
+
+        let status = rebalancer.status();
+        assert_eq!(status.state, RebalanceState::Idle);
+        assert_eq!((status.moved_chunks, status.moved_bytes, status.rounds), (1, 10, 1));
+        assert!(status.pending.is_empty() && status.current.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_nodes_moves_files_to_a_new_node() {
+        let root = std::env::temp_dir().join(format!("rebalance-{}", uuid::Uuid::new_v4()));
+        let mut nodes: HashMap<String, StorageNode> = ["a", "b"]
+            .iter()
+            .map(|id| {
+                let storage_path = root.join(id);
+                std::fs::create_dir_all(&storage_path).unwrap();
+                let node = StorageNode {
+                    node_id: id.to_string(),
+                    storage_path: storage_path.to_string_lossy().to_string(),
+                    total_space: 1000,
+                    available_space: 1000,
+                    health_status: true,
+                    last_checked: 0,
+                    labels: Default::default(),
+                    draining: false,
+                    key_store: None,
+                };
+                (id.to_string(), node)
+            })
+            .collect();
+        for i in 0..4 {
+            std::fs::write(root.join("a").join(format!("f{}.bin", i)), vec![i as u8; 100]).unwrap();
+        }
+        nodes.get_mut("a").unwrap().available_space = 600;
+        let nodes = Mutex::new(nodes);
+        let rebalancer = Rebalancer::new(DEFAULT_BUDGET);
+        let switched = Mutex::new(Vec::new());
+
+        rebalance_nodes(&nodes, &rebalancer, &FirstFit, |chunk_move| {
+            switched.lock().unwrap().push(chunk_move.clone())
+        })
+        .await;
+
+        // b ends at the mean of 200 of 2000 bytes, a within the tolerance above it
+        let switched = switched.into_inner().unwrap();
+        assert_eq!(switched.len(), 2);
+        let nodes = nodes.into_inner().unwrap();
+        for moved in &switched {
+            assert_eq!((moved.from.as_str(), moved.to.as_str()), ("a", "b"));
+            assert!(nodes["a"].read_stored_file(&moved.chunk_id).is_err());
+            let index = moved.file_id[1..].parse::<u8>().unwrap();
+            assert_eq!(nodes["b"].read_stored_file(&moved.chunk_id).unwrap(), vec![index; 100]);
+        }
+        assert_eq!(nodes["a"].stored_files().unwrap().len(), 2);
+        assert_eq!((nodes["a"].available_space, nodes["b"].available_space), (800, 800));
+        let status = rebalancer.status();
+        assert_eq!((status.moved_chunks, status.moved_bytes, status.failed_moves), (2, 200, 0));
+        std::fs::remove_dir_all(root).ok();
+    }
+}
@@ -0,0 +1,256 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::node::Node;
+
+// Evidence loses half of its weight after this long, so old failures are
+// forgiven and a banned peer eventually gets another chance
+pub const SCORE_HALF_LIFE: Duration = Duration::from_secs(24 * 60 * 60);
+// Peers scoring below this are excluded from placement
+pub const BAN_THRESHOLD: f64 = 0.2;
+// Latency at which the latency component drops to 0.5
+const REFERENCE_LATENCY_MS: f64 = 500.0;
+// Weight of a new latency sample in the moving average
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+// Component weights, summing to 1
+const TRANSFER_WEIGHT: f64 = 0.35;
+const POST_WEIGHT: f64 = 0.3;
+const UPTIME_WEIGHT: f64 = 0.2;
+const LATENCY_WEIGHT: f64 = 0.15;
+
+// Decayed evidence collected about one peer
+#[derive(Clone, Debug, Default)]
+struct PeerEvidence {
+    transfers_ok: f64,
+    transfers_failed: f64,
+    post_passed: f64,
+    post_failed: f64,
+    uptime_alive: f64,
+    uptime_total: f64,
+    latency_ms: Option<f64>,
+    updated_at: u64,
+}
+
+impl PeerEvidence {
+    fn decay(&mut self, now: u64) {
+        if self.updated_at != 0 && now > self.updated_at {
+            let factor = 0.5f64.powf((now - self.updated_at) as f64 / SCORE_HALF_LIFE.as_secs_f64());
+            self.transfers_ok *= factor;
+            self.transfers_failed *= factor;
+            self.post_passed *= factor;
+            self.post_failed *= factor;
+            self.uptime_alive *= factor;
+            self.uptime_total *= factor;
+        }
+        self.updated_at = now;
+    }
+
+    // Every ratio starts from one success and one failure (Laplace prior), so a
+    // new peer scores 0.5 and a single event cannot ban or promote it
+    fn score(&self) -> f64 {
+        let transfers = (self.transfers_ok + 1.0) / (self.transfers_ok + self.transfers_failed + 2.0);
+        let post = (self.post_passed + 1.0) / (self.post_passed + self.post_failed + 2.0);
+        let uptime = (self.uptime_alive + 1.0) / (self.uptime_total + 2.0);
+        let latency = match self.latency_ms {
+            Some(latency) => REFERENCE_LATENCY_MS / (REFERENCE_LATENCY_MS + latency),
+            None => 0.5,
+        };
+        TRANSFER_WEIGHT * transfers + POST_WEIGHT * post + UPTIME_WEIGHT * uptime + LATENCY_WEIGHT * latency
+    }
+}
+
+// Reputation of one peer as reported by the API
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerScore {
+    pub node_id: String,
+    pub score: f64,
+    pub banned: bool,
+    pub transfers_ok: f64,
+    pub transfers_failed: f64,
+    pub post_passed: f64,
+    pub post_failed: f64,
+    pub uptime: f64,
+    pub latency_ms: Option<f64>,
+}
+
+// Scores peers from transfer results, latency, proof-of-spacetime outcomes and uptime
+#[derive(Default)]
+pub struct Reputation {
+    peers: Mutex<HashMap<String, PeerEvidence>>,
+}
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update<F: FnOnce(&mut PeerEvidence)>(&self, node_id: &str, apply: F) {
+        let mut peers = self.peers.lock().unwrap();
+        let evidence = peers.entry(node_id.to_string()).or_default();
+        evidence.decay(unix_now());
+        apply(evidence);
+    }
+
+    // Outcome of a chunk transfer to or from the peer; latency only for successes
+    pub fn record_transfer(&self, node_id: &str, success: bool, latency: Option<Duration>) {
+        self.update(node_id, |evidence| {
+            if success {
+                evidence.transfers_ok += 1.0;
+            } else {
+                evidence.transfers_failed += 1.0;
+            }
+            if let Some(latency) = latency {
+                let sample = latency.as_secs_f64() * 1000.0;
+                evidence.latency_ms = Some(match evidence.latency_ms {
+                    Some(average) => average + LATENCY_SMOOTHING * (sample - average),
+                    None => sample,
+                });
+            }
+        });
+    }
+
+    pub fn record_post(&self, node_id: &str, passed: bool) {
+        self.update(node_id, |evidence| {
+            if passed {
+                evidence.post_passed += 1.0;
+            } else {
+                evidence.post_failed += 1.0;
+            }
+        });
+    }
+
+    // One liveness observation from the failure detector
+    pub fn record_liveness(&self, node_id: &str, alive: bool) {
+        self.update(node_id, |evidence| {
+            evidence.uptime_total += 1.0;
+            if alive {
+                evidence.uptime_alive += 1.0;
+            }
+        });
+    }
+
+    // Score in [0, 1]; unknown peers get the neutral 0.5
+    pub fn score(&self, node_id: &str) -> f64 {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get_mut(node_id) {
+            Some(evidence) => {
+                evidence.decay(unix_now());
+                evidence.score()
+            }
+            None => PeerEvidence::default().score(),
+        }
+    }
+
+    pub fn is_banned(&self, node_id: &str) -> bool {
+        self.score(node_id) < BAN_THRESHOLD
+    }
+
+    pub fn peer_score(&self, node_id: &str) -> Option<PeerScore> {
+        let mut peers = self.peers.lock().unwrap();
+        let evidence = peers.get_mut(node_id)?;
+        evidence.decay(unix_now());
+        Some(to_peer_score(node_id, evidence))
+    }
+
+    pub fn scores(&self) -> Vec<PeerScore> {
+        let now = unix_now();
+        let mut peers = self.peers.lock().unwrap();
+        let mut scores: Vec<PeerScore> = peers
+            .iter_mut()
+            .map(|(node_id, evidence)| {
+                evidence.decay(now);
+                to_peer_score(node_id, evidence)
+            })
+            .collect();
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        scores
+    }
+
+    // Placement order: banned peers removed, best score first. The sort is
+    // stable, so peers with equal scores keep their original order.
+    pub fn rank_nodes(&self, nodes: Vec<Node>) -> Vec<Node> {
+        let mut ranked: Vec<(f64, Node)> = nodes
+            .into_iter()
+            .map(|node| (self.score(&node.id), node))
+            .filter(|(score, _)| *score >= BAN_THRESHOLD)
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranked.into_iter().map(|(_, node)| node).collect()
+    }
+}
+
+fn to_peer_score(node_id: &str, evidence: &PeerEvidence) -> PeerScore {
+    let score = evidence.score();
+    PeerScore {
+        node_id: node_id.to_string(),
+        score,
+        banned: score < BAN_THRESHOLD,
+        transfers_ok: evidence.transfers_ok,
+        transfers_failed: evidence.transfers_failed,
+        post_passed: evidence.post_passed,
+        post_failed: evidence.post_failed,
+        uptime: if evidence.uptime_total > 0.0 {
+            evidence.uptime_alive / evidence.uptime_total
+        } else {
+            0.0
+        },
+        latency_ms: evidence.latency_ms,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str) -> Node {
+        Node {
+            id: id.to_string(),
+            storage_path: String::new(),
+            total_space: 1000,
+            available_space: 1000,
+            address: String::new(),
+            labels: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_failing_peer_is_banned_and_ranked_out() {
+        let reputation = Reputation::new();
+        for _ in 0..20 {
+            reputation.record_transfer("good", true, Some(Duration::from_millis(50)));
+            reputation.record_post("good", true);
+            reputation.record_transfer("bad", false, None);
+            reputation.record_post("bad", false);
+            reputation.record_liveness("bad", false);
+        }
+        assert!(reputation.score("good") > reputation.score("unknown"));
+        assert!(reputation.is_banned("bad"));
+
+        let ranked = reputation.rank_nodes(vec![node("bad"), node("unknown"), node("good")]);
+        let ids: Vec<&str> = ranked.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, vec!["good", "unknown"]);
+    }
+
+    #[test]
+    fn test_evidence_decays_towards_neutral() {
+        let mut evidence = PeerEvidence {
+            transfers_failed: 10.0,
+            updated_at: 1_000,
+            ..Default::default()
+        };
+        let before = evidence.score();
+        evidence.decay(1_000 + SCORE_HALF_LIFE.as_secs() * 4);
+        assert!((evidence.transfers_failed - 10.0 / 16.0).abs() < 1e-9);
+        assert!(evidence.score() > before);
+    }
+}
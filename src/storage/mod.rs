@@ -1,82 +1,38 @@
 use crate::node::Node;
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use crate::p2p::bandwidth::Throttle;
+use crate::p2p::chunk_service::{ChunkAck, ChunkClient};
+use crate::p2p::identity::NodeIdentity;
+use crate::placement::{Candidate, PlacementStrategy};
+use crate::reputation::Reputation;
+use std::fs;
 use std::path::Path;
-use sha2::{Sha256, Digest};
-use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt, BufWriter};
 use std::time::Duration;
 use tokio::time::timeout;
-use anyhow::Error;
-
-pub fn store_file(
-    encrypted_data_content: &[u8], 
-    node_storage_path: &str,
-    file_name: &str,
-    node_id: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Check for empty parameters
-    if node_storage_path.is_empty() || file_name.is_empty() {
-        return Err("Storage path or file name cannot be empty.".into());
-    }
-
-    // Create the directory path if it doesn't exist
-    let dir_path = Path::new(node_storage_path);
-    if !dir_path.exists() {
-        fs::create_dir_all(&dir_path)
-            .map_err(|e| format!("Failed to create directory {}: {}", dir_path.display(), e))?;
-    }
-
-    println!("Storing file at: {}", dir_path.display());
-    // Use Path manipulation for file path
-    let file_path = dir_path.join(file_name);
-     // Ensure parent directory exists
-     if let Some(parent) = file_path.parent() {
-        fs::create_dir_all(parent)
-            .map_err(|e| format!("Failed to create directory {}: {}", parent.display(), e))?;
-    }
-
-
-    println!("Storing file at*****************: {}", file_path.display());
-    let mut file = File::create(&file_path)
-        .map_err(|e| format!("Failed to create file {}: {}", file_path.display(), e))?;
-
-    // Write the encrypted data to the file
-    file.write_all(encrypted_data_content).map_err(|e| {
-        format!(
-            "Failed to write data to file {}: {}",
-            file_path.display(),
-            e
-        )
-    })?;
-
-    // Ensure the file is fully written to disk
-    file.sync_all()
-        .map_err(|e| format!("Failed to sync data to file {}: {}", file_path.display(), e))?;
-
-    // Generate a hash for the file for integrity check
-    let mut hasher = Sha256::new();
-    hasher.update(encrypted_data_content);
-    let file_hash = hex::encode(hasher.finalize());
-
-    println!("File successfully stored at {}, hash: {}", file_path.display(), file_hash);
-
-    // Store the file hash along with node_id in a registry 
-    store_file_hash(file_hash, node_id);
-
-    Ok(())
-}
-
-pub fn store_file_hash(file_hash: String, node_id: &str) {
-    println!("Storing hash for file on node {}: {}", node_id, file_hash);
-}
 
 pub async fn can_store_file(
-    nodes: &mut Vec<Node>, // List of all nodes
+    nodes: &mut [Node], // List of all nodes
+    key: &str,         // Chunk or file id, used by hash based strategies
     file_size: u64,    // Size of the file
+    reputation: &Reputation, // Banned peers are skipped, better scored peers come first
+    placement: &dyn PlacementStrategy, // Order in which the remaining nodes are tried
 ) -> Option<String> {
+    let candidates: Vec<Candidate> = reputation
+        .rank_nodes(nodes.to_vec())
+        .iter()
+        .map(Candidate::from)
+        .collect();
+    let ranked: Vec<String> = placement
+        .rank(key, file_size, &candidates)
+        .into_iter()
+        .map(|index| candidates[index].id.clone())
+        .collect();
+
     // Return the ID of the node that can store the file
-    for node in nodes {
+    for node_id in ranked {
+        let node = match nodes.iter_mut().find(|node| node.id == node_id) {
+            Some(node) => node,
+            None => continue,
+        };
         let storage_dir: &Path = Path::new(&node.storage_path);
         println!("Checking storage for node: {}", node.id);
 
@@ -113,15 +69,18 @@ pub async fn can_store_file(
 
 
 
-// store_chunk_on_node fonksiyonu: Verilen chunk'ı seçilen node'a kaydeder
+// store_chunk_on_node fonksiyonu: Verilen chunk'ı seçilen node'a kaydeder.
+// The chunk travels over an encrypted session authenticated by node identity,
+// and only counts as stored once the node acknowledges the hash it persisted.
 pub async fn store_chunk_on_node(
+    chunk_id: &str,
     chunk_data: &[u8],
     node: &Node,
+    identity: &NodeIdentity,
+    throttle: &Throttle, // Bant genişliği sınırı ve trafik sınıfı
     max_retries: u8, // Maksimum tekrar deneme sayısı başarısız olursa
     timeout_duration: u64, // Timeout süresi, örneğin saniye olarak
-) -> anyhow::Result<()> {
-    let node_address = node.address.clone();  // Örnek: "127.0.0.1:8080"
-    
+) -> anyhow::Result<ChunkAck> {
     // Bağlantı hatalarını ve tekrarları kontrol et
     let mut attempt = 0;
     let mut last_error: Option<anyhow::Error> = None;
@@ -129,31 +88,22 @@ pub async fn store_chunk_on_node(
     while attempt < max_retries {
         attempt += 1;
 
-        //zaman aşımı süresi ile bağlantıyı kontrol et
-        match timeout(Duration::from_secs(timeout_duration), TcpStream::connect(&node_address)).await {
-            //tcp bağlantısı başarılı bir şekilde kuruldu hedef node'a chunk'ı gönder
-            Ok(Ok(stream)) => {
-                let mut writer = BufWriter::new(stream);
-                
-                match writer.write_all(chunk_data).await {
-                    Ok(_) => {
-                        // Veri yazma işlemi başarılı bir şekilde tamamlandı
-                        writer.flush().await?;
-                        println!("Chunk successfully stored on node: {}", node.id);
-                        return Ok(()); // Başarılı bir şekilde veri gönderildi
-                    }
-                    Err(e) => {
-                        eprintln!("Error writing to node {}: {:?}", node.id, e);
-                        last_error = Some(Error::new(e));
-                    }
-                }
+        //zaman aşımı süresi ile güvenli oturumu kur ve chunk'ı gönder
+        let transfer = async {
+            let mut client = ChunkClient::connect(node, identity).await?.with_throttle(throttle.clone());
+            client.put(chunk_id, chunk_data).await
+        };
+        match timeout(Duration::from_secs(timeout_duration), transfer).await {
+            Ok(Ok(ack)) => {
+                println!("Chunk {} stored on node {}, acknowledged hash: {}", chunk_id, node.id, ack.hash);
+                return Ok(ack);
             },
             Ok(Err(e)) => {
-                eprintln!("Error connecting to node {}: {:?}", node.id, e);
-                last_error = Some(Error::from(e));  // Wrap the error into `anyhow::Error`
+                eprintln!("Error storing chunk on node {}: {:?}", node.id, e);
+                last_error = Some(e);
             },
             Err(_) => {
-                eprintln!("Connection to node {} timed out.", node.id);
+                eprintln!("Transfer to node {} timed out.", node.id);
             },
         }
 
@@ -166,5 +116,5 @@ pub async fn store_chunk_on_node(
     }
 
     // Sonuçta, tüm denemeler başarısız olduysa hatayı döndür
-    Err(last_error.unwrap_or_else(|| anyhow::Error::new(std::io::Error::new(std::io::ErrorKind::Other, "Unknown error"))))
+    Err(last_error.unwrap_or_else(|| anyhow::Error::new(std::io::Error::other("Unknown error"))))
 }
@@ -1,8 +1,6 @@
 use sha2::{Sha256, Digest};
-use aes::{Aes256, BlockEncrypt, BlockDecrypt};
-use aes::cipher::{NewBlockCipher, BlockCipher};
 //use aes::block_cipher::generic_array::GenericArray;
-use bytes::{Bytes, Buf};
+use bytes::Bytes;
 use std::collections::HashMap;
 use std::path::Path;
 
@@ -0,0 +1,321 @@
+use chrono::Utc;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use hmac::Mac;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use super::metadata::MetadataStore;
+use super::pipeline::{ENCRYPT_WORKERS, MAX_IN_FLIGHT_CHUNKS, PIPELINE_CHUNK_SIZE};
+use super::{transaction, ChunkInfo, FileMetadata, StorageAPI};
+use crate::encryption::SegmentCipher;
+use crate::ipfs::{self, CarReader, CarWriter, Cid, CidLookup, CidRecord, LookupFuture};
+use crate::node::Node;
+
+// CAR import and export for exchanging data with IPFS. An imported CAR
+// becomes a file with one chunk per block, encrypted like the chunks of an
+// upload and recorded with the block's CID, so it can be exported again
+// block for block. Uploaded files are exported as UnixFS, with the root CID
+// recorded at upload.
+
+// What import_blocks stored: the chunks, the HMAC over them, the content id
+// and the manifest blocks
+type ImportedBlocks = (Vec<ChunkInfo>, String, String, Vec<ChunkInfo>);
+
+impl StorageAPI {
+    // Stores the blocks of a CARv1 file as chunks, as one upload transaction.
+    // Every block is checked against its CID. Returns the file id.
+    pub async fn import_car(&self, car_path: &str, owner: &str) -> Result<String, Box<dyn Error>> {
+        let path = car_path.to_string();
+        let reader = tokio::task::spawn_blocking(move || CarReader::new(BufReader::new(File::open(path)?))).await??;
+        let mut file = FileMetadata {
+            file_id: Uuid::new_v4().to_string(),
+            file_name: Path::new(car_path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or("Failed to get file name")?
+                .to_string(),
+            node_id: "".to_string(),
+            // The blocks take at most the size of the CAR; set exactly once they are stored
+            file_size: std::fs::metadata(car_path)?.len(),
+            chunks: Vec::new(),
+            hmac: String::new(),
+            timestamp: Utc::now().timestamp() as u64,
+            owner: owner.to_string(),
+            content_id: String::new(),
+            manifest: Vec::new(),
+            cids: reader.roots().iter().map(Cid::to_string).collect(),
+            imported: true,
+        };
+
+        self.check_stake(owner, file.file_size).await?;
+
+        let nodes = self.network.get_live_nodes().await;
+        let upload = transaction::new_upload(&file, &self.network.identity().node_id(), &self.session);
+        self.metadata.begin_upload(upload).await?;
+
+        let imported = match tokio::time::timeout(
+            transaction::UPLOAD_TIMEOUT,
+            self.import_blocks(&file.file_id, reader, nodes),
+        )
+        .await
+        {
+            Ok(Ok(imported)) => imported,
+            Ok(Err(e)) => {
+                eprintln!("Import of {} failed, rolling it back: {:?}", file.file_name, e);
+                if let Err(e) = transaction::roll_back(&self.network, &self.metadata, &file.file_id).await {
+                    eprintln!("{:?}", e);
+                }
+                return Err(e);
+            }
+            Err(_) => {
+                eprintln!("Import of {} timed out, rolling it back", file.file_name);
+                if let Err(e) = transaction::roll_back(&self.network, &self.metadata, &file.file_id).await {
+                    eprintln!("{:?}", e);
+                }
+                return Err("Import timed out".into());
+            }
+        };
+        (file.chunks, file.hmac, file.content_id, file.manifest) = imported;
+        file.file_size = file.chunks.iter().map(|chunk| chunk.size).sum();
+
+        transaction::commit(&self.network, &self.metadata, file.clone()).await?;
+        println!(
+            "Imported {} block(s) from {} with roots {:?}",
+            file.chunks.len(),
+            file.file_name,
+            file.cids
+        );
+        Ok(file.file_id)
+    }
+
+    // Reads the blocks in CAR order on the blocking pool, where each is
+    // checked against its CID and encrypted, and stores them
+    // MAX_IN_FLIGHT_CHUNKS at a time, then the manifest over them. A block
+    // repeated in the CAR is stored once.
+    async fn import_blocks<R: Read + Send + 'static>(
+        &self,
+        file_id: &str,
+        mut reader: CarReader<R>,
+        nodes: Vec<Node>,
+    ) -> Result<ImportedBlocks, Box<dyn Error>> {
+        let cipher = SegmentCipher::for_file(&self.key_store, &self.master_key(), file_id)?;
+        let mut hmac = cipher.hmac();
+        let placement = self.placement(nodes);
+
+        let (sender, receiver) = mpsc::channel(ENCRYPT_WORKERS);
+        let reading = tokio::task::spawn_blocking(move || {
+            let mut seen = HashSet::new();
+            loop {
+                let block = match reader.next_block() {
+                    Ok(Some(block)) if !seen.insert(block.cid) => continue,
+                    Ok(Some(block)) => Ok((block.cid, cipher.encrypt_segment(&block.data))),
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+                let failed = block.is_err();
+                // The receiver is gone once a dispatch failed
+                if sender.blocking_send(block).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        // Received in CAR order, so the HMAC sees the segments in sequence
+        let segments = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|block| (block, receiver))
+        })
+        .map_ok(|(cid, segment)| {
+            hmac.update(&segment);
+            (cid, segment)
+        });
+
+        let mut stored: Vec<(usize, ChunkInfo)> = segments
+            .enumerate()
+            .map(|(index, block)| {
+                let placement = &placement;
+                async move {
+                    let (cid, segment) = block?;
+                    let mut chunk = self
+                        .dispatch_chunk(file_id, Uuid::new_v4().to_string(), segment, placement)
+                        .await?;
+                    chunk.cid = Some(cid.to_string());
+                    Ok::<_, Box<dyn Error>>((index, chunk))
+                }
+            })
+            .buffer_unordered(MAX_IN_FLIGHT_CHUNKS)
+            .try_collect()
+            .await?;
+        reading.await?;
+
+        stored.sort_by_key(|(index, _)| *index);
+        let chunks: Vec<ChunkInfo> = stored.into_iter().map(|(_, chunk)| chunk).collect();
+        let (content_id, manifest) = self.store_manifest(file_id, &chunks, &placement).await?;
+        Ok((chunks, hex::encode(hmac.finalize().into_bytes()), content_id, manifest))
+    }
+
+    // Writes the file as a CARv1 to `destination_path`: an imported file with
+    // its original roots and blocks, an uploaded file as a UnixFS DAG with
+    // CIDv1 raw leaves under dag-pb nodes. Returns the root CIDs.
+    pub async fn export_car(&self, file_id: &str, destination_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let file = self.metadata.file(file_id).await?.ok_or("File not found")?;
+        let result = if file.imported {
+            self.export_blocks(&file, destination_path).await
+        } else {
+            self.export_unixfs(&file, destination_path).await
+        };
+        match result {
+            Ok(roots) => {
+                println!("Exported file {} to {} with roots {:?}", file_id, destination_path, roots);
+                Ok(roots)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(destination_path);
+                Err(e)
+            }
+        }
+    }
+
+    // The blocks are decrypted and checked against their CIDs as they are
+    // written, and the file HMAC before the CAR is finished
+    async fn export_blocks(&self, file: &FileMetadata, destination_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let roots = file.cids.iter().map(|cid| cid.parse()).collect::<io::Result<Vec<Cid>>>()?;
+        let cipher = SegmentCipher::load(&self.key_store, &self.master_key(), &file.file_id)?;
+        let mut hmac = cipher.hmac();
+        let mut writer = CarWriter::new(BufWriter::new(File::create(destination_path)?), &roots)?;
+
+        let mut blocks = stream::iter(file.chunks.iter())
+            .map(|chunk| async move { Ok::<_, Box<dyn Error>>((chunk, self.fetch_chunk(&file.file_id, chunk).await?)) })
+            .buffered(MAX_IN_FLIGHT_CHUNKS);
+        while let Some(block) = blocks.next().await {
+            let (chunk, segment) = block?;
+            hmac.update(&segment);
+            let data = cipher.decrypt_segment(&segment)?;
+            let cid: Cid = chunk
+                .cid
+                .as_deref()
+                .ok_or_else(|| format!("Chunk {} has no CID", chunk.chunk_id))?
+                .parse()?;
+            if !cid.matches(&data) {
+                return Err(format!("Block {} does not match its CID", cid).into());
+            }
+            writer.write_block(&cid, &data)?;
+        }
+        if hex::encode(hmac.finalize().into_bytes()) != file.hmac {
+            return Err("HMAC verification failed".into());
+        }
+        writer.finish()?;
+        Ok(file.cids.clone())
+    }
+
+    // The file is downloaded and verified first, then laid out as UnixFS
+    async fn export_unixfs(&self, file: &FileMetadata, destination_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let plaintext_path = format!("{}.plain", destination_path);
+        self.download_file_for_reading(&file.file_id, &plaintext_path, "").await?;
+        let expected = file.cids.first().cloned();
+        let destination = destination_path.to_string();
+        let plaintext = plaintext_path.clone();
+        let result = tokio::task::spawn_blocking(move || write_unixfs_car(&plaintext, &destination, expected)).await;
+        let _ = std::fs::remove_file(&plaintext_path);
+        Ok(vec![result??.to_string()])
+    }
+
+    // CID lookups over the replicated file metadata, for the API
+    pub fn cid_lookup(&self) -> Arc<dyn CidLookup> {
+        self.metadata.clone()
+    }
+}
+
+// Writes the plaintext file as a UnixFS CAR: the dag-pb nodes root first,
+// then the leaves in file order. `expected` is the root CID recorded at upload.
+fn write_unixfs_car(plaintext_path: &str, destination_path: &str, expected: Option<String>) -> io::Result<Cid> {
+    let mut leaves = Vec::new();
+    let mut input = BufReader::new(File::open(plaintext_path)?);
+    loop {
+        let block = read_block(&mut input)?;
+        if block.is_empty() {
+            break;
+        }
+        leaves.push((Cid::new_v1(ipfs::RAW, &block), block.len() as u64));
+    }
+    let unixfs = ipfs::unixfs_file(&leaves);
+    if let Some(expected) = expected.filter(|expected| *expected != unixfs.root.to_string()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File exports as {} instead of the recorded root {}", unixfs.root, expected),
+        ));
+    }
+
+    let mut writer = CarWriter::new(BufWriter::new(File::create(destination_path)?), &[unixfs.root])?;
+    for (cid, node) in &unixfs.nodes {
+        writer.write_block(cid, node)?;
+    }
+    if leaves.is_empty() {
+        writer.write_block(&unixfs.root, b"")?;
+    }
+    let mut input = BufReader::new(File::open(plaintext_path)?);
+    for (cid, _) in &leaves {
+        writer.write_block(cid, &read_block(&mut input)?)?;
+    }
+    writer.finish()?;
+    Ok(unixfs.root)
+}
+
+// Reads up to PIPELINE_CHUNK_SIZE bytes, the size of the leaves and of the
+// upload chunks; shorter only at the end of the file
+fn read_block<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let mut block = Vec::with_capacity(PIPELINE_CHUNK_SIZE);
+    input.take(PIPELINE_CHUNK_SIZE as u64).read_to_end(&mut block)?;
+    Ok(block)
+}
+
+// Whether the file has `cid` as a root, in any CID version
+fn has_root(file: &FileMetadata, cid: &Cid) -> bool {
+    file.cids.iter().any(|root| same_cid(root, cid))
+}
+
+// The imported block of the file with `cid`
+fn block_with<'a>(file: &'a FileMetadata, cid: &Cid) -> Option<&'a ChunkInfo> {
+    file.chunks.iter().find(|chunk| chunk.cid.as_deref().is_some_and(|block| same_cid(block, cid)))
+}
+
+fn same_cid(text: &str, cid: &Cid) -> bool {
+    text.parse::<Cid>().is_ok_and(|other| other.to_v1() == cid.to_v1())
+}
+
+// Whether the file has `cid` as a root or holds it as an imported block
+pub(super) fn holds_cid(file: &FileMetadata, cid: &Cid) -> bool {
+    has_root(file, cid) || block_with(file, cid).is_some()
+}
+
+impl CidLookup for MetadataStore {
+    fn lookup(&self, cid: &Cid) -> LookupFuture<'_> {
+        let cid = *cid;
+        Box::pin(async move {
+            let files = self.files_by_cid(&cid).await.map_err(|e| e.to_string())?;
+            Ok(files
+                .iter()
+                .filter_map(|file| {
+                    let (root, size) = if has_root(file, &cid) {
+                        (true, file.file_size)
+                    } else {
+                        (false, block_with(file, &cid)?.size)
+                    };
+                    Some(CidRecord {
+                        cid: cid.to_string(),
+                        file_id: file.file_id.clone(),
+                        file_name: file.file_name.clone(),
+                        owner: file.owner.clone(),
+                        root,
+                        size,
+                    })
+                })
+                .collect())
+        })
+    }
+}
@@ -0,0 +1,352 @@
+use futures::stream::{self, StreamExt};
+use std::error::Error;
+use std::ops::Range;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+use super::pipeline::MAX_IN_FLIGHT_CHUNKS;
+use super::{calculate_hash, ChunkInfo, StorageAPI};
+use crate::encryption::SegmentCipher;
+
+// A file is described by a Merkle DAG over its stored chunks:
+//   height 0 nodes link to the data chunks in file order,
+//   height n nodes link to up to MANIFEST_FANOUT nodes of height n - 1.
+// Each node is hashed over its canonical encoding and the hash of the root
+// is the file's content id. The nodes are stored like data chunks under
+// their hash, so the DAG can be fetched and checked from the content id alone.
+
+pub const MANIFEST_FANOUT: usize = 256;
+const MAGIC: &[u8; 4] = b"DSM1";
+const HASH_LENGTH: usize = 32;
+const MAX_CHUNK_ID_LENGTH: usize = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    pub chunk_id: String,
+    pub hash: [u8; HASH_LENGTH],
+    // Stored bytes of the chunks below the link
+    pub size: u64,
+}
+
+impl Link {
+    fn from_chunk(chunk: &ChunkInfo) -> Result<Self, String> {
+        if !valid_chunk_id(&chunk.chunk_id) {
+            return Err(format!("Chunk id {} cannot be linked", chunk.chunk_id));
+        }
+        let hash = hex::decode(&chunk.hash)
+            .ok()
+            .and_then(|hash| <[u8; HASH_LENGTH]>::try_from(hash).ok())
+            .ok_or_else(|| format!("Chunk {} has an invalid hash", chunk.chunk_id))?;
+        Ok(Link {
+            chunk_id: chunk.chunk_id.clone(),
+            hash,
+            size: chunk.size,
+        })
+    }
+
+    // Where the linked chunk is stored is not part of the DAG
+    fn to_chunk(&self) -> ChunkInfo {
+        ChunkInfo {
+            chunk_id: self.chunk_id.clone(),
+            node_id: String::new(),
+            size: self.size,
+            hash: hex::encode(self.hash),
+            cid: None,
+            post_root: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestNode {
+    pub height: u8,
+    pub links: Vec<Link>,
+}
+
+impl ManifestNode {
+    // MAGIC || height || link count (u16) || per link: hash || size (u64) || id length (u8) || id
+    // Integers are big endian, so every node has exactly one encoding
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 3 + self.links.len() * (HASH_LENGTH + 9 + 36));
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.height);
+        bytes.extend_from_slice(&(self.links.len() as u16).to_be_bytes());
+        for link in &self.links {
+            bytes.extend_from_slice(&link.hash);
+            bytes.extend_from_slice(&link.size.to_be_bytes());
+            bytes.push(link.chunk_id.len() as u8);
+            bytes.extend_from_slice(link.chunk_id.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("Not a manifest node".to_string());
+        }
+        let height = reader.take(1)?[0];
+        let count = u16::from_be_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+        // Only the root of an empty file has no links
+        if count > MANIFEST_FANOUT || (count == 0 && height > 0) {
+            return Err(format!("Manifest node has {} links", count));
+        }
+
+        let mut links = Vec::with_capacity(count);
+        for _ in 0..count {
+            let hash: [u8; HASH_LENGTH] = reader.take(HASH_LENGTH)?.try_into().unwrap();
+            let size = u64::from_be_bytes(reader.take(8)?.try_into().unwrap());
+            let length = reader.take(1)?[0] as usize;
+            let chunk_id = std::str::from_utf8(reader.take(length)?)
+                .ok()
+                .filter(|id| valid_chunk_id(id))
+                .ok_or("Manifest link has an invalid chunk id")?
+                .to_string();
+            // Manifest nodes are stored under their hash
+            if height > 0 && chunk_id != hex::encode(hash) {
+                return Err(format!("Manifest link {} is not named by its hash", chunk_id));
+            }
+            links.push(Link { chunk_id, hash, size });
+        }
+        if !reader.bytes.is_empty() {
+            return Err("Trailing bytes after the manifest node".to_string());
+        }
+        Ok(ManifestNode { height, links })
+    }
+
+    fn size(&self) -> u64 {
+        self.links.iter().map(|link| link.size).sum()
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < length {
+            return Err("Truncated manifest node".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+}
+
+// The encoded nodes of a file's DAG, root last
+pub struct Dag {
+    pub content_id: String,
+    pub blocks: Vec<(String, Vec<u8>)>,
+}
+
+// Builds the DAG over the data chunks in file order
+pub fn build(chunks: &[ChunkInfo]) -> Result<Dag, String> {
+    let mut blocks = Vec::new();
+    let mut links = chunks.iter().map(Link::from_chunk).collect::<Result<Vec<_>, _>>()?;
+    let mut height = 0u8;
+    loop {
+        let nodes: Vec<ManifestNode> = if links.is_empty() {
+            vec![ManifestNode { height, links: Vec::new() }]
+        } else {
+            links
+                .chunks(MANIFEST_FANOUT)
+                .map(|links| ManifestNode { height, links: links.to_vec() })
+                .collect()
+        };
+        links = Vec::with_capacity(nodes.len());
+        for node in &nodes {
+            let bytes = node.encode();
+            let id = calculate_hash(&bytes);
+            links.push(Link {
+                hash: hex::decode(&id).unwrap().try_into().unwrap(),
+                chunk_id: id.clone(),
+                size: node.size(),
+            });
+            blocks.push((id, bytes));
+        }
+        if nodes.len() == 1 {
+            let content_id = links.remove(0).chunk_id;
+            return Ok(Dag { content_id, blocks });
+        }
+        height = height.checked_add(1).ok_or("Manifest is too deep")?;
+    }
+}
+
+fn valid_chunk_id(chunk_id: &str) -> bool {
+    !chunk_id.is_empty()
+        && chunk_id.len() <= MAX_CHUNK_ID_LENGTH
+        && chunk_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl StorageAPI {
+    // Fetches the DAG of `content_id` from whichever nodes hold its blocks and
+    // returns the data chunks it links to, in file order. Every block is
+    // checked against the hash its parent recorded, down from the content id.
+    pub async fn resolve_manifest(&self, content_id: &str) -> Result<Vec<ChunkInfo>, Box<dyn Error>> {
+        // Corrupt blocks are reported under the file when it is ours
+        let file_id = match self.metadata.file_by_content_id(content_id).await? {
+            Some(file) => file.file_id,
+            None => content_id.to_string(),
+        };
+        let root = ChunkInfo {
+            chunk_id: content_id.to_string(),
+            node_id: String::new(),
+            size: 0,
+            hash: content_id.to_string(),
+            cid: None,
+            post_root: None,
+        };
+
+        let mut level = vec![root];
+        let mut expected_height: Option<u8> = None;
+        loop {
+            let mut next = Vec::new();
+            for block in &level {
+                let node = ManifestNode::decode(&self.fetch_chunk(&file_id, block).await?)?;
+                if expected_height.is_some_and(|height| height != node.height) {
+                    return Err(format!("Manifest block {} is at the wrong height", block.chunk_id).into());
+                }
+                expected_height = Some(node.height);
+                next.extend(node.links.iter().map(Link::to_chunk));
+            }
+            match expected_height {
+                Some(0) => return Ok(next),
+                Some(height) => expected_height = Some(height - 1),
+                None => unreachable!(),
+            }
+            level = next;
+        }
+    }
+
+    // Downloads the chunks in `chunks` (indexes in file order) of the file
+    // with `content_id` and writes them decrypted to `destination_path`.
+    // The file HMAC covers the whole file, so the chunks are verified against
+    // the DAG instead. Returns the number of bytes written.
+    pub async fn download_range(
+        &self,
+        content_id: &str,
+        chunks: Range<usize>,
+        destination_path: &str,
+    ) -> Result<u64, Box<dyn Error>> {
+        let file = self
+            .metadata
+            .file_by_content_id(content_id)
+            .await?
+            .ok_or("No file with this content id")?;
+        if file.imported {
+            return Err("The file holds the blocks of an imported CAR, export it as a CAR instead".into());
+        }
+        let mut leaves = self.resolve_manifest(content_id).await?;
+        if chunks.start > chunks.end || chunks.end > leaves.len() {
+            return Err(format!("Chunks {:?} are out of range, the file has {}", chunks, leaves.len()).into());
+        }
+        // The recorded holders are tried first, as for full downloads
+        for leaf in &mut leaves[chunks.clone()] {
+            if let Some(chunk) = file.chunks.iter().find(|chunk| chunk.chunk_id == leaf.chunk_id) {
+                leaf.node_id = chunk.node_id.clone();
+            }
+        }
+
+        let cipher = SegmentCipher::load(&self.key_store, &self.master_key(), &file.file_id)?;
+        let mut output = File::create(destination_path).await?;
+        let result = async {
+            let mut written = 0;
+            let mut segments = stream::iter(leaves[chunks].iter())
+                .map(|chunk| self.fetch_chunk(&file.file_id, chunk))
+                .buffered(MAX_IN_FLIGHT_CHUNKS);
+            while let Some(segment) = segments.next().await {
+                let plaintext = cipher.decrypt_segment(&segment?)?;
+                output.write_all(&plaintext).await?;
+                written += plaintext.len() as u64;
+            }
+            output.flush().await?;
+            Ok::<u64, Box<dyn Error>>(written)
+        }
+        .await;
+
+        if result.is_err() {
+            drop(output);
+            let _ = tokio::fs::remove_file(destination_path).await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(count: usize) -> Vec<ChunkInfo> {
+        (0..count)
+            .map(|i| ChunkInfo {
+                chunk_id: format!("chunk-{}", i),
+                node_id: format!("node-{}", i % 3),
+                size: 1000 + i as u64,
+                hash: calculate_hash(&i.to_be_bytes()),
+                cid: None,
+                post_root: None,
+            })
+            .collect()
+    }
+
+    // Walks the DAG from the root the way resolve_manifest does
+    fn leaves(dag: &Dag) -> Vec<ChunkInfo> {
+        let block = |id: &str| {
+            let (_, bytes) = dag.blocks.iter().find(|(block_id, _)| block_id == id).unwrap();
+            assert_eq!(calculate_hash(bytes), id);
+            ManifestNode::decode(bytes).unwrap()
+        };
+        let mut level = vec![block(&dag.content_id)];
+        while level[0].height > 0 {
+            level = level
+                .iter()
+                .flat_map(|node| node.links.iter().map(|link| block(&link.chunk_id)))
+                .collect();
+        }
+        level.iter().flat_map(|node| node.links.iter().map(Link::to_chunk)).collect()
+    }
+
+    #[test]
+    fn test_dag_links_every_chunk_in_order() {
+        let chunks = chunks(MANIFEST_FANOUT * 2 + 5);
+        let dag = build(&chunks).unwrap();
+        // Three height 0 nodes under one root
+        assert_eq!(dag.blocks.len(), 4);
+        assert_eq!(dag.blocks.last().unwrap().0, dag.content_id);
+
+        let leaves = leaves(&dag);
+        assert_eq!(leaves.len(), chunks.len());
+        for (leaf, chunk) in leaves.iter().zip(&chunks) {
+            assert_eq!((&leaf.chunk_id, leaf.size, &leaf.hash), (&chunk.chunk_id, chunk.size, &chunk.hash));
+        }
+
+        // Only the chunk contents go into the content id, not where they are stored
+        let mut moved = chunks.clone();
+        moved[0].node_id = "elsewhere".to_string();
+        assert_eq!(build(&moved).unwrap().content_id, dag.content_id);
+        moved[0].hash = calculate_hash(b"other");
+        assert_ne!(build(&moved).unwrap().content_id, dag.content_id);
+        assert_eq!(build(&[]).unwrap().blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_malformed_nodes_are_rejected() {
+        let dag = build(&chunks(3)).unwrap();
+        let (_, root) = dag.blocks.last().unwrap();
+        let node = ManifestNode::decode(root).unwrap();
+        assert_eq!(node.encode(), *root);
+
+        assert!(ManifestNode::decode(&root[..root.len() - 1]).is_err());
+        let mut trailing = root.clone();
+        trailing.push(0);
+        assert!(ManifestNode::decode(&trailing).is_err());
+
+        // A height 1 link must be named by its hash
+        let mut parent = ManifestNode { height: 1, links: node.links.clone() };
+        assert!(ManifestNode::decode(&parent.encode()).is_err());
+        parent.links[0].chunk_id = hex::encode(parent.links[0].hash);
+        parent.links.truncate(1);
+        assert!(ManifestNode::decode(&parent.encode()).is_ok());
+    }
+}
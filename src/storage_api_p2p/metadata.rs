@@ -0,0 +1,604 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::car::holds_cid;
+use super::FileMetadata;
+use crate::ipfs::Cid;
+use crate::p2p::identity::NodeIdentity;
+use crate::p2p::raft::{self, Proposal, RaftNode, RaftRequest, RaftStatus, StateMachine};
+use crate::pbe_::{AccessType, Permission};
+
+// File manifests, chunk locations, permissions and quotas, replicated with
+// Raft over the metadata nodes. Every read and write goes through the leader,
+// so all nodes see the same index.
+
+// Commands made for a user name that user and are checked against the
+// file's owner and permissions when applied. Moving chunks and setting
+// quotas are the cluster's own bookkeeping and name no user; they come from
+// the nodes the metadata nodes take proposals from.
+#[derive(Serialize, Deserialize)]
+enum Command {
+    // By the owner or a user with delete access
+    RemoveFile { file_id: String, user_id: String },
+    // Only applied while the chunk is still recorded on `from`
+    MoveChunk { file_id: String, chunk_id: String, from: String, to: String },
+    // By the owner or a user with admin access
+    Grant { file_id: String, permission: Permission, granted_by: String },
+    Revoke { file_id: String, user_id: String, revoked_by: String },
+    SetQuota { owner: String, limit: Option<u64> },
+    // Upload transactions, see transaction.rs
+    BeginUpload(PendingUpload),
+    // Records a chunk write before it is sent, if `node_id` has `size` bytes
+    // left next to the reservations of all pending uploads
+    Reserve { upload_id: String, write: PendingWrite, available: u64 },
+    Release { upload_id: String, chunk_id: String, node_id: String },
+    PrepareUpload(FileMetadata),
+    CommitUpload { upload_id: String },
+    // By the upload's coordinator, or by any node once the upload expired
+    AbortUpload { upload_id: String },
+}
+
+#[derive(Serialize, Deserialize)]
+enum Query {
+    File { file_id: String },
+    Files,
+    FileByContentId { content_id: String },
+    // Files with the CID as a root or as an imported block
+    FilesByCid { cid: String },
+    ChunksOnNode { node_id: String },
+    Permissions { file_id: String },
+    Quota { owner: String },
+    PendingUpload { upload_id: String },
+    PendingUploads,
+}
+
+// A chunk written, or being written, for an upload that is not committed yet
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingWrite {
+    pub chunk_id: String,
+    pub node_id: String,
+    pub size: u64,
+}
+
+// An upload between BeginUpload and its commit. Its writes reserve space on
+// their nodes and are what a rollback deletes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingUpload {
+    pub upload_id: String, // the file id the manifest is committed under
+    pub owner: String,
+    pub file_size: u64,
+    pub coordinator: String, // node running the upload
+    pub session: String,     // run of the coordinator, so a restarted node recognizes its leftovers
+    pub started_at: u64,
+    pub expires_at: u64,
+    pub writes: Vec<PendingWrite>,
+    // Complete manifest once every chunk is stored; a prepared upload can be committed by recovery
+    pub manifest: Option<FileMetadata>,
+    // Rolled back; kept until all of its writes are deleted
+    pub aborted: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Quota {
+    pub owner: String,
+    pub limit: Option<u64>, // bytes; None is unlimited
+    pub used: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct MetadataState {
+    files: HashMap<String, FileMetadata>,
+    permissions: HashMap<String, Vec<Permission>>,
+    quotas: HashMap<String, u64>,
+    #[serde(default)]
+    uploads: HashMap<String, PendingUpload>,
+}
+
+impl MetadataState {
+    fn used_by(&self, owner: &str) -> u64 {
+        self.files.values().filter(|file| file.owner == owner).map(|file| file.file_size).sum()
+    }
+
+    // Bytes of the owner's files and of its uploads in progress, without `file_id`
+    fn charged_to(&self, owner: &str, file_id: &str) -> u64 {
+        let files = self.files.values().filter(|file| file.owner == owner && file.file_id != file_id);
+        let uploads = self
+            .uploads
+            .values()
+            .filter(|upload| upload.owner == owner && upload.upload_id != file_id && !upload.aborted);
+        files.map(|file| file.file_size).sum::<u64>() + uploads.map(|upload| upload.file_size).sum::<u64>()
+    }
+
+    fn check_quota(&self, owner: &str, file_id: &str, size: u64) -> std::result::Result<(), String> {
+        if let Some(&limit) = self.quotas.get(owner) {
+            let used = self.charged_to(owner, file_id);
+            if used + size > limit {
+                return Err(format!(
+                    "Quota of {} exceeded: {} of {} bytes used, {} more requested",
+                    owner, used, limit, size
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn reserved_on(&self, node_id: &str) -> u64 {
+        self.uploads
+            .values()
+            .flat_map(|upload| upload.writes.iter())
+            .filter(|write| write.node_id == node_id)
+            .map(|write| write.size)
+            .sum()
+    }
+
+    // Whether the user owns the file or holds one of `access` on it at `now`
+    fn allows(&self, file_id: &str, user_id: &str, access: &[AccessType], now: u64) -> std::result::Result<(), String> {
+        let file = self.files.get(file_id).ok_or_else(|| format!("File {} not found", file_id))?;
+        let granted = self.permissions.get(file_id).is_some_and(|permissions| {
+            permissions.iter().any(|permission| {
+                permission.user_id == user_id
+                    && access.contains(&permission.access_type)
+                    && permission.expiry.is_none_or(|expiry| expiry > now)
+            })
+        });
+        if file.owner == user_id || granted {
+            Ok(())
+        } else {
+            Err(format!("User {} may not change file {}", user_id, file_id))
+        }
+    }
+
+    fn execute(&mut self, proposal: &Proposal, command: Command) -> std::result::Result<bool, String> {
+        let now = proposal.proposed_at;
+        match command {
+            Command::RemoveFile { file_id, user_id } => {
+                if !self.files.contains_key(&file_id) {
+                    return Ok(false);
+                }
+                self.allows(&file_id, &user_id, &[AccessType::Delete, AccessType::Admin], now)?;
+                self.permissions.remove(&file_id);
+                Ok(self.files.remove(&file_id).is_some())
+            }
+            Command::MoveChunk { file_id, chunk_id, from, to } => {
+                let chunk = self
+                    .files
+                    .get_mut(&file_id)
+                    .and_then(|file| {
+                        file.chunks
+                            .iter_mut()
+                            .chain(file.manifest.iter_mut())
+                            .find(|chunk| chunk.chunk_id == chunk_id)
+                    });
+                match chunk {
+                    Some(chunk) if chunk.node_id == from => {
+                        chunk.node_id = to;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }
+            Command::Grant { file_id, permission, granted_by } => {
+                self.allows(&file_id, &granted_by, &[AccessType::Admin], now)?;
+                let permissions = self.permissions.entry(file_id).or_default();
+                permissions.retain(|existing| existing.user_id != permission.user_id);
+                permissions.push(permission);
+                Ok(true)
+            }
+            Command::Revoke { file_id, user_id, revoked_by } => {
+                self.allows(&file_id, &revoked_by, &[AccessType::Admin], now)?;
+                let permissions = self.permissions.entry(file_id).or_default();
+                let before = permissions.len();
+                permissions.retain(|existing| existing.user_id != user_id);
+                Ok(permissions.len() != before)
+            }
+            Command::SetQuota { owner, limit } => {
+                match limit {
+                    Some(limit) => self.quotas.insert(owner, limit),
+                    None => self.quotas.remove(&owner),
+                };
+                Ok(true)
+            }
+            Command::BeginUpload(upload) => {
+                if self.files.contains_key(&upload.upload_id) || self.uploads.contains_key(&upload.upload_id) {
+                    return Err(format!("Upload {} already exists", upload.upload_id));
+                }
+                self.check_quota(&upload.owner, &upload.upload_id, upload.file_size)?;
+                self.uploads.insert(upload.upload_id.clone(), upload);
+                Ok(true)
+            }
+            Command::Reserve { upload_id, write, available } => {
+                let reserved = self.reserved_on(&write.node_id);
+                if reserved + write.size > available {
+                    return Err(format!(
+                        "Node {} has {} of {} bytes reserved, no room for {} more",
+                        write.node_id, reserved, available, write.size
+                    ));
+                }
+                let upload = self.open_upload(&upload_id)?;
+                upload.writes.push(write);
+                Ok(true)
+            }
+            Command::Release { upload_id, chunk_id, node_id } => {
+                let upload = match self.uploads.get_mut(&upload_id) {
+                    Some(upload) => upload,
+                    None => return Ok(false),
+                };
+                let before = upload.writes.len();
+                upload.writes.retain(|write| !(write.chunk_id == chunk_id && write.node_id == node_id));
+                let released = upload.writes.len() != before;
+                if upload.aborted && upload.writes.is_empty() {
+                    self.uploads.remove(&upload_id);
+                }
+                Ok(released)
+            }
+            Command::PrepareUpload(manifest) => {
+                let upload = self.open_upload(&manifest.file_id)?;
+                upload.manifest = Some(manifest);
+                Ok(true)
+            }
+            Command::CommitUpload { upload_id } => {
+                let manifest = self
+                    .open_upload(&upload_id)?
+                    .manifest
+                    .clone()
+                    .ok_or_else(|| format!("Upload {} is not prepared", upload_id))?;
+                self.check_quota(&manifest.owner, &upload_id, manifest.file_size)?;
+                self.uploads.remove(&upload_id);
+                self.files.insert(upload_id, manifest);
+                Ok(true)
+            }
+            Command::AbortUpload { upload_id } => match self.uploads.get_mut(&upload_id) {
+                // Committed already, or never begun
+                None => Ok(false),
+                Some(upload) if upload.coordinator != proposal.proposer && upload.expires_at > now => Err(format!(
+                    "Upload {} is run by node {} and has not expired",
+                    upload_id, upload.coordinator
+                )),
+                Some(upload) => {
+                    upload.aborted = true;
+                    if upload.writes.is_empty() {
+                        self.uploads.remove(&upload_id);
+                    }
+                    Ok(true)
+                }
+            },
+        }
+    }
+
+    fn open_upload(&mut self, upload_id: &str) -> std::result::Result<&mut PendingUpload, String> {
+        match self.uploads.get_mut(upload_id) {
+            Some(upload) if !upload.aborted => Ok(upload),
+            Some(_) => Err(format!("Upload {} was rolled back", upload_id)),
+            None => Err(format!("Upload {} not found", upload_id)),
+        }
+    }
+
+    fn answer(&self, query: Query) -> serde_json::Result<Vec<u8>> {
+        match query {
+            Query::File { file_id } => serde_json::to_vec(&self.files.get(&file_id)),
+            Query::Files => serde_json::to_vec(&self.files.values().collect::<Vec<_>>()),
+            Query::FileByContentId { content_id } => serde_json::to_vec(
+                &self
+                    .files
+                    .values()
+                    .find(|file| !file.content_id.is_empty() && file.content_id == content_id),
+            ),
+            Query::FilesByCid { cid } => {
+                let files: Vec<&FileMetadata> = match cid.parse::<Cid>() {
+                    Ok(cid) => self.files.values().filter(|file| holds_cid(file, &cid)).collect(),
+                    Err(_) => Vec::new(),
+                };
+                serde_json::to_vec(&files)
+            }
+            Query::ChunksOnNode { node_id } => serde_json::to_vec(
+                &self
+                    .files
+                    .values()
+                    .flat_map(|file| file.stored_chunks())
+                    .filter(|chunk| chunk.node_id == node_id)
+                    .map(|chunk| &chunk.chunk_id)
+                    .collect::<Vec<_>>(),
+            ),
+            Query::Permissions { file_id } => {
+                serde_json::to_vec(self.permissions.get(&file_id).unwrap_or(&Vec::new()))
+            }
+            Query::Quota { owner } => serde_json::to_vec(&Quota {
+                limit: self.quotas.get(&owner).copied(),
+                used: self.used_by(&owner),
+                owner,
+            }),
+            Query::PendingUpload { upload_id } => serde_json::to_vec(&self.uploads.get(&upload_id)),
+            Query::PendingUploads => serde_json::to_vec(&self.uploads.values().collect::<Vec<_>>()),
+        }
+    }
+}
+
+impl StateMachine for MetadataState {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        let parsed = serde_json::from_slice::<Proposal>(command)
+            .and_then(|proposal| serde_json::from_slice::<Command>(&proposal.command).map(|command| (proposal, command)));
+        let result = match parsed {
+            Ok((proposal, command)) => self.execute(&proposal, command),
+            Err(e) => Err(format!("Malformed metadata command: {}", e)),
+        };
+        serde_json::to_vec(&result).unwrap_or_default()
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        let answer = serde_json::from_slice::<Query>(query)
+            .map_err(|e| e.to_string())
+            .and_then(|query| self.answer(query).map_err(|e| e.to_string()));
+        match answer {
+            Ok(answer) => answer,
+            Err(e) => {
+                eprintln!("Failed to answer metadata query: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        match serde_json::from_slice(snapshot) {
+            Ok(state) => *self = state,
+            Err(e) => eprintln!("Failed to restore the metadata snapshot: {:?}", e),
+        }
+    }
+}
+
+// Client of the replicated metadata; also the replica itself when this node
+// is one of the metadata nodes
+pub struct MetadataStore {
+    identity: Arc<NodeIdentity>,
+    members: HashMap<String, String>,
+    replica: Option<Arc<RaftNode>>,
+}
+
+impl MetadataStore {
+    // Joins the metadata nodes `members` (node id -> address), keeping the
+    // raft log and snapshots in `dir`
+    pub fn open(dir: &Path, identity: Arc<NodeIdentity>, members: HashMap<String, String>) -> Result<Self> {
+        let replica = if members.contains_key(&identity.node_id()) {
+            let node = RaftNode::open(dir, identity.clone(), members.clone(), Box::<MetadataState>::default())?;
+            node.spawn();
+            println!("Serving metadata as one of {} metadata node(s)", members.len());
+            Some(node)
+        } else {
+            println!("Using the metadata nodes {:?}", members.values().collect::<Vec<_>>());
+            None
+        };
+        Ok(MetadataStore {
+            identity,
+            members,
+            replica,
+        })
+    }
+
+    // The raft node to serve on this node's listener, if it is a metadata node
+    pub fn replica(&self) -> Option<Arc<RaftNode>> {
+        self.replica.clone()
+    }
+
+    pub async fn status(&self) -> Option<RaftStatus> {
+        match &self.replica {
+            Some(replica) => Some(replica.status().await),
+            None => None,
+        }
+    }
+
+    async fn execute(&self, command: Command) -> Result<bool> {
+        let command = serde_json::to_vec(&command)?;
+        let reply = raft::request(self.replica.as_ref(), &self.identity, &self.members, RaftRequest::Propose { command }).await?;
+        serde_json::from_slice::<std::result::Result<bool, String>>(&reply)?.map_err(|e| anyhow!(e))
+    }
+
+    async fn query<T: for<'de> Deserialize<'de>>(&self, query: Query) -> Result<T> {
+        let query = serde_json::to_vec(&query)?;
+        let reply = raft::request(self.replica.as_ref(), &self.identity, &self.members, RaftRequest::Read { query }).await?;
+        Ok(serde_json::from_slice(&reply)?)
+    }
+
+    // Whether the file was there to remove; fails unless the user may delete it
+    pub async fn remove_file(&self, file_id: &str, user_id: &str) -> Result<bool> {
+        self.execute(Command::RemoveFile { file_id: file_id.to_string(), user_id: user_id.to_string() }).await
+    }
+
+    // Records that a chunk moved, unless it is no longer recorded on `from`
+    // (the file was deleted or the chunk moved meanwhile); returns whether it did
+    pub async fn move_chunk(&self, file_id: &str, chunk_id: &str, from: &str, to: &str) -> Result<bool> {
+        self.execute(Command::MoveChunk {
+            file_id: file_id.to_string(),
+            chunk_id: chunk_id.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+        .await
+    }
+
+    pub async fn grant(&self, file_id: &str, permission: Permission, granted_by: &str) -> Result<()> {
+        self.execute(Command::Grant { file_id: file_id.to_string(), permission, granted_by: granted_by.to_string() })
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn revoke(&self, file_id: &str, user_id: &str, revoked_by: &str) -> Result<bool> {
+        self.execute(Command::Revoke {
+            file_id: file_id.to_string(),
+            user_id: user_id.to_string(),
+            revoked_by: revoked_by.to_string(),
+        })
+        .await
+    }
+
+    pub async fn set_quota(&self, owner: &str, limit: Option<u64>) -> Result<()> {
+        self.execute(Command::SetQuota { owner: owner.to_string(), limit }).await.map(|_| ())
+    }
+
+    pub async fn file(&self, file_id: &str) -> Result<Option<FileMetadata>> {
+        self.query(Query::File { file_id: file_id.to_string() }).await
+    }
+
+    pub async fn files(&self) -> Result<Vec<FileMetadata>> {
+        self.query(Query::Files).await
+    }
+
+    pub async fn file_by_content_id(&self, content_id: &str) -> Result<Option<FileMetadata>> {
+        self.query(Query::FileByContentId { content_id: content_id.to_string() }).await
+    }
+
+    pub async fn files_by_cid(&self, cid: &Cid) -> Result<Vec<FileMetadata>> {
+        self.query(Query::FilesByCid { cid: cid.to_string() }).await
+    }
+
+    // Ids of the chunks whose recorded holder is `node_id`
+    pub async fn chunks_on_node(&self, node_id: &str) -> Result<Vec<String>> {
+        self.query(Query::ChunksOnNode { node_id: node_id.to_string() }).await
+    }
+
+    pub async fn permissions(&self, file_id: &str) -> Result<Vec<Permission>> {
+        self.query(Query::Permissions { file_id: file_id.to_string() }).await
+    }
+
+    pub async fn quota(&self, owner: &str) -> Result<Quota> {
+        self.query(Query::Quota { owner: owner.to_string() }).await
+    }
+
+    // Registers an upload; fails if it would take the owner over quota
+    pub async fn begin_upload(&self, upload: PendingUpload) -> Result<()> {
+        self.execute(Command::BeginUpload(upload)).await.map(|_| ())
+    }
+
+    // Reserves space for a chunk about to be written to `chunk.node_id`;
+    // `available` is the free space the node last announced
+    pub async fn reserve(&self, upload_id: &str, write: PendingWrite, available: u64) -> Result<()> {
+        self.execute(Command::Reserve { upload_id: upload_id.to_string(), write, available })
+            .await
+            .map(|_| ())
+    }
+
+    // Drops a write that was deleted again, with its reservation
+    pub async fn release(&self, upload_id: &str, write: &PendingWrite) -> Result<bool> {
+        self.execute(Command::Release {
+            upload_id: upload_id.to_string(),
+            chunk_id: write.chunk_id.clone(),
+            node_id: write.node_id.clone(),
+        })
+        .await
+    }
+
+    pub async fn prepare_upload(&self, manifest: FileMetadata) -> Result<()> {
+        self.execute(Command::PrepareUpload(manifest)).await.map(|_| ())
+    }
+
+    // Publishes the prepared manifest and releases the upload's reservations
+    pub async fn commit_upload(&self, upload_id: &str) -> Result<()> {
+        self.execute(Command::CommitUpload { upload_id: upload_id.to_string() }).await.map(|_| ())
+    }
+
+    // Marks the upload rolled back so it can no longer commit; false if it
+    // was committed already or never begun
+    pub async fn abort_upload(&self, upload_id: &str) -> Result<bool> {
+        self.execute(Command::AbortUpload { upload_id: upload_id.to_string() }).await
+    }
+
+    pub async fn pending_upload(&self, upload_id: &str) -> Result<Option<PendingUpload>> {
+        self.query(Query::PendingUpload { upload_id: upload_id.to_string() }).await
+    }
+
+    pub async fn pending_uploads(&self) -> Result<Vec<PendingUpload>> {
+        self.query(Query::PendingUploads).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(state: &mut MetadataState, proposer: &str, proposed_at: u64, command: Command) -> std::result::Result<bool, String> {
+        let proposal = Proposal {
+            proposer: proposer.to_string(),
+            proposed_at,
+            command: serde_json::to_vec(&command).unwrap(),
+        };
+        serde_json::from_slice(&state.apply(&serde_json::to_vec(&proposal).unwrap())).unwrap()
+    }
+
+    fn file(file_id: &str, owner: &str) -> FileMetadata {
+        FileMetadata {
+            file_id: file_id.to_string(),
+            file_name: "notes.txt".to_string(),
+            node_id: "node1".to_string(),
+            file_size: 10,
+            chunks: Vec::new(),
+            hmac: String::new(),
+            timestamp: 0,
+            owner: owner.to_string(),
+            content_id: String::new(),
+            manifest: Vec::new(),
+            cids: Vec::new(),
+            imported: false,
+        }
+    }
+
+    fn upload(upload_id: &str, owner: &str, coordinator: &str, started_at: u64, expires_at: u64) -> PendingUpload {
+        PendingUpload {
+            upload_id: upload_id.to_string(),
+            owner: owner.to_string(),
+            file_size: 10,
+            coordinator: coordinator.to_string(),
+            session: "run1".to_string(),
+            started_at,
+            expires_at,
+            writes: Vec::new(),
+            manifest: None,
+            aborted: false,
+        }
+    }
+
+    fn permission(user_id: &str, access_type: AccessType, expiry: Option<u64>) -> Permission {
+        Permission { user_id: user_id.to_string(), access_type, expiry }
+    }
+
+    #[test]
+    fn test_user_commands_are_checked_against_owner_and_permissions() {
+        let mut state = MetadataState::default();
+        apply(&mut state, "node1", 100, Command::BeginUpload(upload("file1", "alice", "node1", 100, 200))).unwrap();
+        apply(&mut state, "node1", 100, Command::PrepareUpload(file("file1", "alice"))).unwrap();
+        apply(&mut state, "node1", 100, Command::CommitUpload { upload_id: "file1".to_string() }).unwrap();
+
+        // Only the owner or an admin grants, and only delete access removes
+        let grant = |permission, granted_by: &str| Command::Grant {
+            file_id: "file1".to_string(),
+            permission,
+            granted_by: granted_by.to_string(),
+        };
+        assert!(apply(&mut state, "node1", 100, grant(permission("bob", AccessType::Admin, None), "bob")).is_err());
+        apply(&mut state, "node1", 100, grant(permission("bob", AccessType::Read, None), "alice")).unwrap();
+        apply(&mut state, "node1", 100, grant(permission("carol", AccessType::Delete, Some(200)), "alice")).unwrap();
+        assert!(apply(&mut state, "node1", 100, grant(permission("dave", AccessType::Read, None), "bob")).is_err());
+
+        let remove = |user_id: &str| Command::RemoveFile { file_id: "file1".to_string(), user_id: user_id.to_string() };
+        assert!(apply(&mut state, "node1", 100, remove("bob")).is_err());
+        // Expired by the time the proposal was taken
+        assert!(apply(&mut state, "node1", 300, remove("carol")).is_err());
+        assert_eq!(apply(&mut state, "node1", 150, remove("carol")), Ok(true));
+        assert_eq!(apply(&mut state, "node1", 150, remove("alice")), Ok(false));
+    }
+
+    #[test]
+    fn test_only_the_coordinator_aborts_an_upload_before_it_expires() {
+        let mut state = MetadataState::default();
+        apply(&mut state, "node1", 100, Command::BeginUpload(upload("file1", "alice", "node1", 100, 200))).unwrap();
+
+        let abort = || Command::AbortUpload { upload_id: "file1".to_string() };
+        assert!(apply(&mut state, "node2", 150, abort()).is_err());
+        assert_eq!(apply(&mut state, "node2", 250, abort()), Ok(true));
+        assert!(state.uploads.is_empty());
+    }
+}
@@ -1,63 +1,204 @@
-use crate::encryption::{decrypt_file_chunked, encrypt_file_chunked, split_file};
-use crate::p2p::{find_available_node, Network};
-use crate::node::Node;
-use crate::proof_of_spacetime::periodic_check;
-use crate::storage::{self, can_store_file, store_chunk_on_node, store_file};
-use actix_web::body::MessageBody;
-use chrono::{Duration, Utc};
-use libp2p::core::network;
+use crate::p2p::dht::{Contact, Dht, Key};
+use crate::p2p::bandwidth::{BandwidthConfig, BandwidthLimiter};
+use crate::p2p::chunk_service::{ChunkClient, StoredChunk};
+use crate::p2p::identity::NodeIdentity;
+use crate::p2p::membership::{MembershipEvent, PeerStatus};
+use crate::p2p::peerstore::{bootstrap_peers_from_env, merge_bootstrap, Peerstore};
+use crate::p2p::raft::{configured_members, members_from_env};
+use crate::p2p::Network;
+use crate::integrity::{CorruptCopy, Integrity, NodeIntegrity};
+use crate::ipfs::CidLookup;
+use crate::key_management::{get_master_key, KEY_FILE_PATH};
+use crate::node::{FailureDomain, Node};
+use crate::pbe_::{AccessType, ProgrammableBusinessEngine, QuotaError};
+use crate::placement::{strategy_from_env, AntiAffinity, PlacementViolation};
+use crate::rebalance::Rebalancer;
+use metadata::MetadataStore;
+use crate::reputation::PeerScore;
+use chrono::Utc;
+use bytes::Bytes;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use dotenv::dotenv;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::env;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::runtime::Runtime;
-use tokio::sync::Mutex;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpListener;
 use uuid::Uuid;
 
+mod car;
+mod manifest;
+mod metadata;
+mod pipeline;
+mod post;
+mod rebalance;
+mod repair;
+#[cfg(test)]
+mod simulation;
+mod transaction;
+
 // Dosya metadata yapısı
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub file_id: String,
     pub file_name: String,
     pub node_id: String,
     pub file_size: u64,
-    chunks: Vec<ChunkInfo>,
+    chunks: Vec<ChunkInfo>, // dosya sırasına göre
+    hmac: String,           // hex HMAC over the chunks in order, checked on download
     timestamp: u64, // Dosyanın yüklendiği zaman
     pub owner: String,
+    // Hash of the root of the Merkle DAG over `chunks`, see manifest.rs;
+    // empty for files uploaded before manifests
+    #[serde(default)]
+    pub content_id: String,
+    // The DAG nodes, stored as chunks named by their hash
+    #[serde(default)]
+    manifest: Vec<ChunkInfo>,
+    // IPFS root CIDs: the UnixFS root of an uploaded file, the roots of an imported CAR
+    #[serde(default)]
+    pub cids: Vec<String>,
+    // The chunks are the blocks of an imported CAR, one block per chunk,
+    // recorded with their CIDs; see car.rs
+    #[serde(default)]
+    imported: bool,
+}
+
+impl FileMetadata {
+    // Data chunks and manifest blocks, everything the file keeps on nodes
+    fn stored_chunks(&self) -> impl Iterator<Item = &ChunkInfo> {
+        self.chunks.iter().chain(self.manifest.iter())
+    }
 }
 
 // Chunk bilgisi yapısı
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChunkInfo {
     chunk_id: String,
     node_id: String,
     size: u64,
     hash: String,
+    // IPFS CID of a block imported from a CAR
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cid: Option<String>,
+    // Hex Merkle root of the chunk for proof-of-spacetime challenges, see
+    // post.rs; None for chunks stored without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    post_root: Option<String>,
 }
 
+// Address of the p2p node started by storage_api_from_env
+const P2P_ADDR_VAR: &str = "P2P_ADDR";
+const P2P_STORAGE_PATH_VAR: &str = "P2P_STORAGE_PATH";
+const DEFAULT_P2P_STORAGE_PATH: &str = "storage/p2p";
+
 fn calculate_hash(data: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
     format!("{:x}", hasher.finalize())
 }
 
-// StorageAPI yapısı
+// StorageAPI yapısı. Clones share the same network, stores and background
+// state; a download stream keeps one to fetch chunks while it is read.
+#[derive(Clone)]
 pub struct StorageAPI {
     network: Arc<Network>,
-    // filemetadata ve dosya adı eşleştirmesi
-    file_index: Arc<Mutex<HashMap<String, FileMetadata>>>,
-    storage_path: String,
+    // Chunk hash -> holder lookups for chunks not in the metadata
+    dht: Arc<Dht>,
+    // File manifests, chunk locations, permissions and quotas, replicated
+    // over the metadata nodes
+    metadata: Arc<MetadataStore>,
+    // Where the per-file encryption keys are kept, and the key they are
+    // encrypted with; MASTER_KEY when not given
+    key_store: PathBuf,
+    master_key: Option<[u8; 32]>,
+    // Moves chunks to new and less used nodes in the background
+    rebalancer: Arc<Rebalancer>,
+    // Verified and corrupt chunk reads per node; corrupt copies go to repair
+    integrity: Arc<Integrity>,
+    // Tells the pending uploads of this run apart from the ones a crash left behind
+    session: String,
+    // User stakes; when set, every upload has to fit its owner's stake
+    stakes: Option<Arc<std::sync::Mutex<ProgrammableBusinessEngine>>>,
 }
 
 impl StorageAPI {
+    // Starts a node whose metadata nodes are taken from METADATA_NODES
     pub async fn new(
         storage_path: &str,
         server_addr: SocketAddr,
         initial_peers: Vec<SocketAddr>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(server_addr).await?;
+        Self::with_listener(storage_path, listener, initial_peers, None).await
+    }
+
+    // Starts a node that serves on an already bound listener, so callers that
+    // pick a free port keep it until the node runs. The metadata nodes (node
+    // id -> address) are those from METADATA_NODES when None
+    pub async fn with_listener(
+        storage_path: &str,
+        listener: TcpListener,
+        initial_peers: Vec<SocketAddr>,
+        metadata_nodes: Option<HashMap<String, String>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let server_addr = listener.local_addr()?;
+        // Load this node's Ed25519 identity; the node id is derived from its public key
+        let identity = Arc::new(NodeIdentity::load_or_generate(
+            &Path::new(storage_path).join("node_identity.json"),
+        )?);
+        let local_node = Node {
+            id: identity.node_id(),
+            storage_path: storage_path.to_string(),
+            total_space: 1024 * 1024 * 1024, // 1GB storage space
+            available_space: 1024 * 1024 * 1024,
+            address: server_addr.to_string(),
+            // Failure domain from NODE_HOST / NODE_RACK / NODE_ZONE / NODE_REGION
+            labels: FailureDomain::from_env(),
+        };
+
+        // Bootstrap from the given peers, BOOTSTRAP_PEERS / SEED_FILE and the
+        // peers remembered from the previous run
+        let peerstore = Peerstore::load(&Path::new(storage_path).join("peers.json"))?;
+        let initial_peers = merge_bootstrap(
+            vec![initial_peers, bootstrap_peers_from_env()?, peerstore.addresses()],
+            server_addr,
+        );
+        println!("Bootstrapping from {} peer(s): {:?}", initial_peers.len(), initial_peers);
+
+        let placement = strategy_from_env()?;
+        println!("Placing chunks with the {} strategy", placement.name());
+
+        // Join the metadata nodes, or be the only one
+        let metadata_nodes = match metadata_nodes {
+            Some(members) => members,
+            None => members_from_env(&identity.node_id(), &server_addr.to_string())?,
+        };
+        let metadata = Arc::new(MetadataStore::open(
+            &Path::new(storage_path).join("metadata"),
+            identity.clone(),
+            metadata_nodes,
+        )?);
+
         //create a new network
-        let network = Arc::new(Network::new());
+        let dht = Arc::new(Dht::new(Contact::from(&local_node), identity.clone()));
+        let mut network = Network::new(identity)
+            .with_dht(dht.clone())
+            .with_chunk_dir(PathBuf::from(storage_path))
+            .with_listen_addr(server_addr)
+            .with_local_node(local_node.clone())
+            .with_peerstore(peerstore)
+            .with_bandwidth(Arc::new(BandwidthLimiter::new(BandwidthConfig::from_env())))
+            .with_placement(placement)
+            .with_anti_affinity(AntiAffinity::from_env()?);
+        if let Some(replica) = metadata.replica() {
+            network = network.with_raft(replica);
+        }
+        let network = Arc::new(network);
 
         // let node = Node {
         //     id: Uuid::new_v4().to_string(),
@@ -88,47 +229,154 @@ impl StorageAPI {
 
         // Start the server
         tokio::spawn(async move {
-            if let Err(e) = network_clone.start_server(server_addr).await {
+            if let Err(e) = network_clone.serve(listener).await {
                 eprintln!("Failed to start server: {:?}", e);
             }
         });
 
+        // Heartbeats and failure detection
+        let network_clone3 = Arc::clone(&network);
+        let heartbeat_node = local_node.clone();
+        tokio::spawn(async move {
+            network_clone3.run_heartbeats(heartbeat_node).await;
+        });
+
         // Discover peers
         tokio::spawn(async move {
-            network_clone2.periodic_peer_update(initial_peers).await;
+            network_clone2.periodic_peer_update(initial_peers, local_node).await;
         });
 
-    //         // Spawn periodic challenge check
-    // tokio::spawn({
-    //     let network_clone = network.clone();
-    //     async move {
-    //         println!("******************");
-    //         println!("Starting periodic challenge check");
-    //         periodic_check(&network_clone).await;
-    //         println!("******************");
-    //     }
-    // });
+        // Rebalance periodically, within REBALANCE_BUDGET bytes per round
+        let rebalancer = Arc::new(Rebalancer::from_env()?);
+        let integrity = Arc::new(Integrity::new());
+        tokio::spawn(rebalance::run_rebalancer(
+            Arc::clone(&network),
+            Arc::clone(&metadata),
+            Arc::clone(&rebalancer),
+            Arc::clone(&integrity),
+        ));
+
+        // Replace corrupt chunk copies found by readers
+        tokio::spawn(repair::run_repair(
+            Arc::clone(&network),
+            Arc::clone(&dht),
+            Arc::clone(&metadata),
+            Arc::clone(&integrity),
+        ));
+
+        // React to membership changes: chunks on a dead node are down to their
+        // remaining copies and have to be repaired, a new node gets its share
+        // of the chunks
+        let mut membership_events = network.membership().subscribe();
+        let metadata_clone = Arc::clone(&metadata);
+        let rebalancer_clone = Arc::clone(&rebalancer);
+        tokio::spawn(async move {
+            loop {
+                match membership_events.recv().await {
+                    Ok(MembershipEvent::Joined { node_id }) => {
+                        println!("Node {} joined, starting a rebalancing round", node_id);
+                        rebalancer_clone.trigger();
+                    }
+                    Ok(MembershipEvent::Died { node_id }) => {
+                        let affected = match metadata_clone.chunks_on_node(&node_id).await {
+                            Ok(affected) => affected,
+                            Err(e) => {
+                                eprintln!("Failed to look up the chunks on node {}: {:?}", node_id, e);
+                                continue;
+                            }
+                        };
+                        if !affected.is_empty() {
+                            eprintln!(
+                                "Node {} is dead, {} chunk(s) need repair: {:?}",
+                                node_id,
+                                affected.len(),
+                                affected
+                            );
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        eprintln!("Missed {} membership events", missed);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
 
+        // Finish or roll back the uploads an earlier run left pending, once
+        // the nodes holding their chunks had time to show up
+        let session = Uuid::new_v4().to_string();
+        let network_clone4 = Arc::clone(&network);
+        let metadata_clone2 = Arc::clone(&metadata);
+        let session_clone = session.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(transaction::RECOVERY_DELAY).await;
+            transaction::recover_uploads(&network_clone4, &metadata_clone2, &session_clone).await;
+        });
 
-        // // Zamanlı kontrol işlemini başlat
-        // let storage_path_clone = storage_path.to_string();
-        // tokio::spawn(async move {
-        //     periodic_check(&storage_path_clone).await;
-        // });
+        // Expire and republish provider records
+        let dht_clone = Arc::clone(&dht);
+        tokio::spawn(async move {
+            dht_clone.run_maintenance().await;
+        });
+
+        // Challenge the holders of the chunks over their chunk service
+        tokio::spawn(post::run_checks(Arc::clone(&network), Arc::clone(&metadata)));
 
         Ok(Self {
             network,
-            file_index: Arc::new(Mutex::new(HashMap::new())),
-            storage_path: storage_path.to_string(),
+            dht,
+            metadata,
+            key_store: PathBuf::from(KEY_FILE_PATH),
+            master_key: None,
+            rebalancer,
+            integrity,
+            session,
+            stakes: None,
         })
     }
 
+    // Keeps the file keys of this node in its own key store instead of the shared one
+    pub fn with_key_store(mut self, key_store: PathBuf) -> Self {
+        self.key_store = key_store;
+        self
+    }
+
+    // Encrypts the keys in the key store with `master_key` instead of MASTER_KEY
+    #[cfg(test)]
+    pub fn with_master_key(mut self, master_key: [u8; 32]) -> Self {
+        self.master_key = Some(master_key);
+        self
+    }
+
+    // Limits uploads by the stakes in `stakes`, shared with the API
+    pub fn with_stakes(mut self, stakes: Arc<std::sync::Mutex<ProgrammableBusinessEngine>>) -> Self {
+        self.stakes = Some(stakes);
+        self
+    }
+
+    fn master_key(&self) -> [u8; 32] {
+        self.master_key.unwrap_or_else(get_master_key)
+    }
+
+    // Pause, resume and progress of the rebalancer; share it with the API
+    pub fn rebalancer(&self) -> Arc<Rebalancer> {
+        self.rebalancer.clone()
+    }
+
+    // Permissions, quotas and the raft status of the metadata nodes
+    pub fn metadata(&self) -> Arc<MetadataStore> {
+        self.metadata.clone()
+    }
+
     // upload_file fonksiyonu, veriyi şifreler ve düğümlere yükler
+    // Keys are generated and stored per file by key_management, so the
+    // password is not used to derive them
     pub async fn upload_file(
         &self,
         file_path: &str,
         owner: &str,
-        encryption_password: &str,
+        _encryption_password: &str,
     ) -> Result<String, Box<dyn std::error::Error>> {
         // Create a new file metadata
         let mut file = FileMetadata {
@@ -142,126 +390,96 @@ impl StorageAPI {
             node_id: "".to_string(),
             file_size: std::fs::metadata(file_path)?.len(),
             chunks: Vec::new(),
+            hmac: String::new(),
             timestamp: Utc::now().timestamp() as u64,
             owner: owner.to_string(),
+            content_id: String::new(),
+            manifest: Vec::new(),
+            cids: Vec::new(),
+            imported: false,
         };
 
-        // // find available node
-        let mut nodes = self.network.get_nodes().await;
-        // let node_id = can_store_file(&mut nodes, file.file_size)
-        //     .await
-        //     .ok_or("No node found with enough storage space")?;
-        // // selected node is the node that has enough space to store the file
-        // let selected_node = nodes
-        //     .iter()
-        //     .find(|n| n.id == node_id)
-        //     .ok_or("Node not found")?;
-
-        // selected_node.clone().storage_path = format!("{}/{}", self.storage_path, selected_node.id);
-
-        // Separate the file into chunks and encrypt
-        let encrypted_path = "C:/Users/melisates/Documents/encrypted_file.mp4";
-        // if !Path::new(&encrypted_path).exists() {
-        //     return Err("Encrypted file was not created.".into());
-        // }
-        encrypt_file_chunked(
-            &file.file_id,
-            file_path,
-            encrypted_path,
-            encryption_password,
-        )?;
-        let chunks = split_file(&encrypted_path, 1024 * 1024); // 1MB chunk boyutu
-        let mut chunk_infos = Vec::new();
-
-        let mut chunk_count = 0;
-
-        // 4. Save and share chunks on each node
-        for chunk_data in chunks.iter() {
-            chunk_count += 1;
-            println!("chunk count: {:?}", chunk_count);
-            // Get nodes in the network
-
-            println!("chunkdata: {:?}", chunk_data.len());
-            // Find a node that can store the chunk
-            if let Some(node_id) = can_store_file(&mut nodes, chunk_data.len() as u64).await {
-                // Find the selected node
-                let selected_node = nodes.iter_mut().find(|node| node.id == node_id).unwrap();
-                println!("selected node for every chunkdata: {:?}", selected_node.id);
-                println!(
-                    "selected node for every chunkdata: {:?}",
-                    selected_node.storage_path
-                );
-
-                // fn sanitize_path_component(input: &str) -> String {
-                //     input
-                //         .chars()
-                //         .filter(|c| c.is_alphanumeric() || *c == '_')
-                //         .collect()
-                // }
-                // let sanitized_file_name = sanitize_path_component(&file.file_name.replace(" ", ""));
-                // let sanitized_node_id =
-                //     sanitize_path_component(&node_id.replace(".", "").replace(":", ""));
-
-                // Store the chunk data on the selected node
-                // store_file(
-                //     chunk_data,
-                //     &selected_node.storage_path,
-                //     &format!("{}/{}", sanitized_file_name, sanitized_node_id),
-                //     &node_id,
-                // )?;
-                // println!("file_name: {:?}", file.file_name);
-
-                //selected_node.available_space -= chunk_data.len() as u64;
-
-                // Share the chunk with the network
-                if let Err(e) = store_chunk_on_node_with_retry(chunk_data, &selected_node, 3).await
-                {
-                    eprintln!(
-                        "Failed to store chunk on node {} after retries: {:?}",
-                        selected_node.id, e
-                    );
-                    return Err(e);
+        // Chunks go to the peers the failure detector considers alive
+        let nodes = self.network.get_live_nodes().await;
+
+        self.check_stake(owner, file.file_size).await?;
+
+        // The upload is a transaction: its chunks are written under a pending
+        // upload and only become a file when the manifest is committed
+        let upload = transaction::new_upload(&file, &self.network.identity().node_id(), &self.session);
+        self.metadata.begin_upload(upload).await?;
+
+        // Read, encrypt and store the chunks concurrently
+        let uploaded = match tokio::time::timeout(
+            transaction::UPLOAD_TIMEOUT,
+            self.upload_pipeline(&file.file_id, file_path, nodes),
+        )
+        .await
+        {
+            Ok(Ok(uploaded)) => uploaded,
+            Ok(Err(e)) => {
+                eprintln!("Upload of {} failed, rolling it back: {:?}", file.file_name, e);
+                if let Err(e) = transaction::roll_back(&self.network, &self.metadata, &file.file_id).await {
+                    eprintln!("{:?}", e);
                 }
-
-                let chunk_info = ChunkInfo {
-                    chunk_id: Uuid::new_v4().to_string(),
-                    node_id: selected_node.id.clone(),
-                    size: chunk_data.len() as u64,
-                    hash: calculate_hash(chunk_data),
-                };
-                // Chunk bilgilerini sakla
-                chunk_infos.push(chunk_info.clone());
-                file.chunks.push(chunk_info);
-
-                println!(
-                    "CHUNK  Node ID: {}, Used: {}, Available: {}, Chunk Size: {}",
-                    selected_node.id,
-                    selected_node.total_space,
-                    selected_node.available_space,
-                    chunk_data.len() as u64
-                );
-            } else {
-                println!("No suitable node found to store the chunk!");
-                return Err("No suitable node found to store the chunk.".into());
+                return Err(e);
             }
-        }
+            Err(_) => {
+                eprintln!("Upload of {} timed out, rolling it back", file.file_name);
+                if let Err(e) = transaction::roll_back(&self.network, &self.metadata, &file.file_id).await {
+                    eprintln!("{:?}", e);
+                }
+                return Err("Upload timed out".into());
+            }
+        };
+        file.chunks = uploaded.chunks;
+        file.hmac = uploaded.hmac;
+        file.content_id = uploaded.content_id;
+        file.manifest = uploaded.manifest;
+        file.cids = vec![uploaded.cid];
+
+        // Tüm parçalar başarıyla yüklendiyse, dosya indeksine ekle
+        println!("All {} chunks uploaded successfully.", file.chunks.len());
+        transaction::commit(&self.network, &self.metadata, file.clone()).await?;
+        println!("File uploaded successfully: {:?}", file.file_name);
+        println!("File ID: {:?}, content id: {}", file.file_id, file.content_id);
+        Ok(file.file_id)
+    }
 
-        if chunk_count == chunks.len() {
-            // Tüm parçalar başarıyla yüklendiyse, dosya indeksine ekle
-            println!("All chunks uploaded successfully.");
-            // Başarıyla dosya yüklemesi tamamlandı
-            println!("File uploaded successfully: {:?}", file.file_name);
-            println!("File ID: {:?}", file.file_id);
-            self.file_index
-                .lock()
-                .await
-                .insert(file.file_id.clone(), file.clone());
-            Ok(file.file_id)
-        } else {
-            // Yükleme işlemi sırasında bir hata meydana geldi, gerekli işlemi yap
-            eprintln!("Failed to upload all chunks, aborting file upload.");
-            Err("Failed to upload all chunks.".into())
+    // Resolves a chunk hash to the peers holding it through the DHT
+    pub async fn locate_chunk(&self, chunk_hash: &str) -> Result<Vec<Contact>, Box<dyn std::error::Error>> {
+        let key = Key::from_chunk_hash(chunk_hash)?;
+        Ok(self.dht.find_providers(key).await)
+    }
+
+    // With stakes attached, the owner's quota in the metadata store is set to
+    // their stake, and an upload that does not fit is refused with a
+    // QuotaError before any chunk is sent. BeginUpload then reserves the space
+    // against that quota, counting uploads running on other nodes too; usage
+    // follows the committed and deleted files.
+    pub(super) async fn check_stake(&self, owner: &str, size: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(stakes) = &self.stakes else {
+            return Ok(());
+        };
+        let limit = stakes.lock().unwrap().stake_limit(owner)?;
+        self.metadata.set_quota(owner, Some(limit)).await?;
+        let used = self.metadata.quota(owner).await?.used;
+        let reserved: u64 = self
+            .metadata
+            .pending_uploads()
+            .await?
+            .iter()
+            .filter(|upload| upload.owner == owner && !upload.aborted)
+            .map(|upload| upload.file_size)
+            .sum();
+        if used + reserved + size > limit {
+            return Err(QuotaError::QuotaExceeded { user_id: owner.to_string(), limit, used, reserved, requested: size }.into());
         }
+        Ok(())
+    }
+
+    async fn chunk_holders(&self, chunk: &ChunkInfo) -> Result<Vec<Node>, Box<dyn std::error::Error>> {
+        chunk_holders(&self.network, &self.dht, chunk).await
     }
 
     // Düğüm listesini al
@@ -269,25 +487,123 @@ impl StorageAPI {
         Ok(self.network.get_nodes().await)
     }
 
+    // Reputation of every scored peer, best first
+    pub fn list_peer_scores(&self) -> Vec<PeerScore> {
+        self.network.reputation().scores()
+    }
+
+    // Opens a session with the chunk service of a known peer
+    async fn chunk_client(&self, node_id: &str) -> Result<ChunkClient, Box<dyn std::error::Error>> {
+        let node = self.network.get_node_by_id(node_id).await.ok_or("Node not found")?;
+        Ok(ChunkClient::connect(&node, &self.network.identity()).await?)
+    }
+
+    pub async fn list_node_chunks(&self, node_id: &str) -> Result<Vec<StoredChunk>, Box<dyn std::error::Error>> {
+        Ok(self.chunk_client(node_id).await?.list().await?)
+    }
+
+    // Size of the chunk on the peer, None when it does not hold it
+    pub async fn node_chunk_size(&self, node_id: &str, chunk_id: &str) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        Ok(self.chunk_client(node_id).await?.has(chunk_id).await?)
+    }
+
+    // The whole chunk, or `length` bytes from `offset` when a range is given
+    pub async fn read_node_chunk(
+        &self,
+        node_id: &str,
+        chunk_id: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut client = self.chunk_client(node_id).await?;
+        let data = match range {
+            Some((offset, length)) => client.get_range(chunk_id, offset, length).await?,
+            None => client.get(chunk_id).await?,
+        };
+        Ok(data)
+    }
+
+    // Verified and corrupt chunk reads per node, most corrupt first
+    pub fn list_node_integrity(&self) -> Vec<NodeIntegrity> {
+        self.integrity.counters()
+    }
+
+    // Corrupt copies reported by reads and not repaired yet
+    pub fn pending_repairs(&self) -> Vec<CorruptCopy> {
+        self.integrity.pending_repairs()
+    }
+
+    // Failure detector view of the known peers
+    pub async fn list_peer_states(&self) -> Vec<PeerStatus> {
+        self.network.membership().states().await
+    }
+
+    // Files with more chunks in one failure domain than the anti-affinity
+    // policy allows, e.g. after nodes were relabelled or uploaded without a policy
+    pub async fn placement_violations(&self) -> Vec<PlacementViolation> {
+        let policy = match self.network.anti_affinity() {
+            Some(policy) => policy.clone(),
+            None => return Vec::new(),
+        };
+        let nodes: HashMap<String, Node> = self
+            .network
+            .get_nodes()
+            .await
+            .into_iter()
+            .map(|node| (node.id.clone(), node))
+            .collect();
+
+        let mut report = Vec::new();
+        let files = match self.metadata.files().await {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Failed to read the file metadata: {:?}", e);
+                return Vec::new();
+            }
+        };
+        for file in &files {
+            // Chunks on nodes we no longer know cannot be checked and are left out
+            let placed = file
+                .chunks
+                .iter()
+                .filter_map(|chunk| nodes.get(&chunk.node_id))
+                .map(|node| (node.id.as_str(), &node.labels));
+            report.extend(policy.report(&file.file_id, &file.file_name, placed));
+        }
+        report
+    }
+
     // Dosya listesini al
     pub async fn list_files(&self) -> Result<Vec<FileMetadata>, Box<dyn std::error::Error>> {
-        let index = self.file_index.lock().await;
-        Ok(index.values().cloned().collect())
+        Ok(self.metadata.files().await?)
+    }
+
+    // Only the owner or a user granted delete or admin access may delete the
+    // file. Checked here before any chunk is touched; the metadata store
+    // checks it again when the file is removed.
+    async fn check_delete(&self, file: &FileMetadata, user_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if file.owner != user_id {
+            let permissions = self.metadata.permissions(&file.file_id).await?;
+            let allowed = permissions.iter().any(|permission| {
+                permission.user_id == user_id
+                    && matches!(permission.access_type, AccessType::Delete | AccessType::Admin)
+                    && permission.expiry.is_none_or(|expiry| expiry > Utc::now().timestamp() as u64)
+            });
+            if !allowed {
+                return Err(format!("User {} may not delete file {}", user_id, file.file_id).into());
+            }
+        }
+        Ok(())
     }
 
-    pub async fn delete_file(&self, file_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    pub async fn delete_file(&self, file_id: &str, user_id: &str) -> Result<String, Box<dyn std::error::Error>> {
         // Dosya bilgilerini al
-        let file = self
-            .file_index
-            .lock()
-            .await
-            .get(file_id)
-            .ok_or("File not found")?
-            .clone();
+        let file = self.metadata.file(file_id).await?.ok_or("File not found")?;
+        self.check_delete(&file, user_id).await?;
 
         // Dosya parçalarını sırayla sil
+        let chunks: Vec<&ChunkInfo> = file.stored_chunks().collect();
         let mut chunk_count = 0;
-        for chunk in file.chunks.iter() {
+        for chunk in chunks.iter() {
             let mut stored_node = self
                 .network
                 .get_node_by_id(&chunk.node_id)
@@ -295,7 +611,7 @@ impl StorageAPI {
                 .ok_or("Node not found")?;
 
             // Parçayı noddan sil
-            if let Err(e) = delete_chunk_from_node(&stored_node, &chunk.chunk_id).await {
+            if let Err(e) = delete_chunk_from_node(&stored_node, &chunk.chunk_id, &self.network.identity()).await {
                 eprintln!(
                     "Failed to delete chunk {} from node {}: {:?}",
                     chunk.chunk_id, stored_node.id, e
@@ -309,9 +625,9 @@ impl StorageAPI {
         }
 
         // Tüm parçalar başarıyla silindiyse dosyayı indeksden sil
-        if chunk_count == file.chunks.len() {
+        if chunk_count == chunks.len() {
             println!("All chunks deleted successfully.");
-            self.file_index.lock().await.remove(file_id);
+            self.metadata.remove_file(file_id, user_id).await?;
             Ok(format!("File with ID {} deleted successfully", file_id))
         } else {
             // Bir veya daha fazla parça silinemedi, dosya silme işlemi başarısız
@@ -324,38 +640,16 @@ impl StorageAPI {
         &self,
         file_id: &str,
         destination_path: &str,
-        encryption_password: &str,
+        _encryption_password: &str,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        let file = self
-            .file_index
-            .lock()
-            .await
-            .get(file_id)
-            .ok_or("File not found")?
-            .clone();
-
-        let mut full_file_data = Vec::new();
-
-        for chunk in file.chunks.iter() {
-            let selected_node = self
-                .network
-                .get_node_by_id(&chunk.node_id)
-                .await
-                .ok_or("Node not found")?;
-
-            let chunk_data = download_chunk_for_reading(&selected_node, &chunk.chunk_id).await?;
-            full_file_data.extend(chunk_data);
-        }
-
-        let decrypted_path = format!("{}.decrypted", destination_path);
-        if let Err(e) = decrypt_file_chunked(
-            &file.file_id,
-            &decrypted_path,
-            destination_path,
-            encryption_password,
-        ) {
-            eprintln!("Failed to decrypt file: {:?}", e);
-            return Err(e.into());
+        // Chunks are fetched concurrently and decrypted in order; the file is
+        // removed again if any chunk or the file HMAC does not verify
+        let mut output = tokio::fs::File::create(destination_path).await?;
+        if let Err(e) = self.download_to_writer(file_id, &mut output).await {
+            drop(output);
+            let _ = tokio::fs::remove_file(destination_path).await;
+            eprintln!("Failed to download file {}: {:?}", file_id, e);
+            return Err(e);
         }
 
         println!(
@@ -368,41 +662,43 @@ impl StorageAPI {
         ))
     }
 
+    // The decrypted file as a stream of bytes, for HTTP responses and other
+    // pipelines. Chunks are prefetched MAX_IN_FLIGHT_CHUNKS ahead, and
+    // dropping the stream cancels the fetches still outstanding.
+    pub async fn download_stream(
+        &self,
+        file_id: &str,
+    ) -> Result<impl Stream<Item = std::io::Result<Bytes>> + 'static, Box<dyn std::error::Error>> {
+        let file = self.metadata.file(file_id).await?.ok_or("File not found")?;
+        self.plaintext_stream(&file)
+    }
+
+    // Writes the decrypted file into `writer` and returns the number of bytes
+    // written. On error `writer` may hold part of the file.
+    pub async fn download_to_writer<W: AsyncWrite + Unpin>(
+        &self,
+        file_id: &str,
+        writer: &mut W,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let file = self.metadata.file(file_id).await?.ok_or("File not found")?;
+        self.write_plaintext(&file, writer).await
+    }
+
     pub async fn download_file_and_remove(
-        &mut self,
+        &self,
         file_id: &str,
+        user_id: &str,
         destination_path: &str,
-        encryption_password: &str,
+        _encryption_password: &str,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        let file = self
-            .file_index
-            .lock()
-            .await
-            .get(file_id)
-            .ok_or("File not found")?
-            .clone();
+        let file = self.metadata.file(file_id).await?.ok_or("File not found")?;
+        self.check_delete(&file, user_id).await?;
 
-        let mut full_file_data = Vec::new();
-
-        for chunk in file.chunks.iter() {
-            let mut selected_node = self
-                .network
-                .get_node_by_id(&chunk.node_id)
-                .await
-                .ok_or("Node not found")?;
-
-            let chunk_data =
-                download_chunk_and_remove_from_node(&mut selected_node, &chunk.chunk_id).await?;
-            full_file_data.extend(chunk_data);
-        }
-
-        let decrypted_path = format!("{}.decrypted", destination_path);
-        decrypt_file_chunked(
-            &file.file_id,
-            &decrypted_path,
-            destination_path,
-            encryption_password,
-        )?;
+        // The chunks are only removed once the whole file was restored and verified
+        self.download_pipeline(&file, destination_path).await?;
+        let chunks: Vec<ChunkInfo> = file.stored_chunks().cloned().collect();
+        self.delete_chunks(&chunks).await?;
+        self.metadata.remove_file(file_id, user_id).await?;
 
         println!(
             "File downloaded and removed from network successfully to: {:?}",
@@ -415,92 +711,70 @@ impl StorageAPI {
     }
 }
 
-async fn download_chunk_for_reading(
-    node: &Node,
-    chunk_id: &str,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let chunk_path = format!("{}/{}", node.storage_path, chunk_id);
-    let chunk_data = match tokio::fs::read(&chunk_path).await {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!(
-                "Failed to read chunk {chunk_id} from node {node_address}: {error:?}",
-                chunk_id = chunk_id,
-                node_address = node.address,
-                error = e
-            );
-            return Err(Box::new(e));
+// Nodes that may hold the chunk, in the order to try them: the node
+// recorded at upload time if we still know it, then the known providers
+// returned by the DHT
+async fn chunk_holders(network: &Network, dht: &Dht, chunk: &ChunkInfo) -> Result<Vec<Node>, Box<dyn std::error::Error>> {
+    let mut holders = Vec::new();
+    if let Some(node) = network.get_node_by_id(&chunk.node_id).await {
+        holders.push(node);
+    }
+    for provider in dht.find_providers(Key::from_chunk_hash(&chunk.hash)?).await {
+        if holders.iter().any(|node| node.id == provider.node_id) {
+            continue;
         }
-    };
-    println!("Chunk {} read from node {}", chunk_id, node.id);
-    Ok(chunk_data)
-}
-
-async fn download_chunk_and_remove_from_node(
-    node: &mut Node,
-    chunk_id: &str,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let chunk_path: String = format!("{}/{}", node.storage_path, chunk_id);
-    let chunk_data = tokio::fs::read(&chunk_path).await?;
-
-    delete_chunk_from_node(node, chunk_id).await?;
-
-    // Kapasiteyi güncelle
-    node.free_up_space(chunk_data.len() as u64).await;
-
-    println!(
-        "Chunk {} downloaded and removed from node {}",
-        chunk_id, node.id
-    );
-    Ok(chunk_data)
+        if let Some(node) = network.get_node_by_id(&provider.node_id).await {
+            holders.push(node);
+        }
+    }
+    Ok(holders)
 }
 
 // Yardımcı fonksiyon: Noddan bir parçayı sil
 async fn delete_chunk_from_node(
     node: &Node,
     chunk_id: &str,
+    identity: &NodeIdentity,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Parçayı noddan silme işlemi
-    let chunk_path: String = format!("{}/{}", node.storage_path, chunk_id);
-    if let Err(e) = tokio::fs::remove_file(chunk_path).await {
+    let mut client = ChunkClient::connect(node, identity).await?;
+    if let Err(e) = client.delete(chunk_id).await {
         eprintln!("Error deleting chunk: {:?}", e);
-        return Err(Box::new(e));
+        return Err(e.into());
     }
     println!("Chunk {} deleted from node {}", chunk_id, node.id);
     Ok(())
 }
 
-// store_chunk_on_node_with_retry: Chunk'ı node'a kaydetmek için retry mekanizması içerir
-// Eğer başarısız olursa, belirli bir süre bekler ve tekrar dener
-pub async fn store_chunk_on_node_with_retry(
-    chunk_data: &[u8],
-    selected_node: &Node,
-    max_retries: u8,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut attempt = 0;
-    let mut last_error: Option<Box<dyn std::error::Error + Send + Sync>> = None;
-
-    while attempt < max_retries {
-        attempt += 1;
-        match store_chunk_on_node(chunk_data, selected_node, max_retries, 120).await {
-            Ok(_) => return Ok(()), // Success
-            Err(e) => {
-                last_error = Some(e.to_string().into());
-                eprintln!(
-                    "Attempt {}: Failed to store chunk on node {}: {:?}",
-                    attempt, selected_node.id, e
-                );
-            }
-        }
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-    }
+// The p2p node the HTTP server runs beside the nodes it manages, listening on
+// P2P_ADDR and keeping its data under P2P_STORAGE_PATH. None when P2P_ADDR
+// is unset.
+pub async fn storage_api_from_env() -> Result<Option<StorageAPI>, Box<dyn std::error::Error>> {
+    dotenv().ok();
+    let server_addr: SocketAddr = match env::var(P2P_ADDR_VAR) {
+        Ok(addr) if !addr.trim().is_empty() => addr
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid {} {:?}: {}", P2P_ADDR_VAR, addr, e))?,
+        _ => return Ok(None),
+    };
+    let storage_path = env::var(P2P_STORAGE_PATH_VAR).unwrap_or_else(|_| DEFAULT_P2P_STORAGE_PATH.to_string());
+    std::fs::create_dir_all(&storage_path)?;
+    let key_store = Path::new(&storage_path).join("key_data.json");
+    Ok(Some(StorageAPI::new(&storage_path, server_addr, Vec::new()).await?.with_key_store(key_store)))
+}
 
-    Err(last_error.unwrap_or_else(|| {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Unknown error",
-        ))
-    }))
+// CID lookups for a process outside the cluster, such as the HTTP server: it
+// reads the file metadata from the METADATA_NODES as a client, under an
+// identity kept in `dir`. None when METADATA_NODES is unset.
+pub fn cid_lookup_from_env(dir: &Path) -> Result<Option<Arc<dyn CidLookup>>, Box<dyn std::error::Error>> {
+    let members = configured_members()?;
+    if members.is_empty() {
+        return Ok(None);
+    }
+    let identity = Arc::new(NodeIdentity::load_or_generate(&dir.join("node_identity.json"))?);
+    let metadata = MetadataStore::open(&dir.join("metadata"), identity, members)?;
+    Ok(Some(Arc::new(metadata)))
 }
 
 pub async fn wait_for_peers(
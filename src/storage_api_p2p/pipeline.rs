@@ -0,0 +1,451 @@
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use hmac::Mac;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::manifest;
+use super::metadata::PendingWrite;
+use super::transaction::remove_write;
+use super::{calculate_hash, delete_chunk_from_node, ChunkInfo, FileMetadata, StorageAPI};
+use crate::encryption::SegmentCipher;
+use crate::integrity::corrupt_copy;
+use crate::ipfs::{self, Cid};
+use crate::node::Node;
+use crate::p2p::bandwidth::Priority;
+use crate::p2p::chunk_service::ChunkClient;
+use crate::placement::{AntiAffinity, PlacementStrategy};
+use crate::proof_of_spacetime::commit;
+use crate::storage::{can_store_file, store_chunk_on_node};
+
+// Plaintext bytes per chunk; each chunk is encrypted on its own
+pub const PIPELINE_CHUNK_SIZE: usize = 1024 * 1024;
+// Chunks encrypted concurrently ahead of the dispatch stage
+pub const ENCRYPT_WORKERS: usize = 4;
+// Chunks being transferred at the same time, in either direction. Together
+// with ENCRYPT_WORKERS this bounds the memory used by a transfer.
+pub const MAX_IN_FLIGHT_CHUNKS: usize = 8;
+// Distinct nodes a chunk is offered to before the upload fails
+const MAX_NODE_ATTEMPTS: usize = 3;
+const CHUNK_TRANSFER_TIMEOUT: u64 = 120; // seconds
+
+pub(super) struct UploadedFile {
+    pub chunks: Vec<ChunkInfo>,
+    pub hmac: String, // hex HMAC over the stored chunks in file order
+    pub content_id: String,
+    pub manifest: Vec<ChunkInfo>,
+    pub cid: String, // IPFS CID of the plaintext as a UnixFS file
+}
+
+// Candidate nodes shared by the concurrent dispatches of one upload
+pub(super) struct Placement {
+    nodes: Vec<Node>,
+    in_flight: HashMap<String, usize>,
+    // Chunks of the file per failure domain, when an anti-affinity policy is set
+    policy: Option<AntiAffinity>,
+    domains: HashMap<String, usize>,
+}
+
+impl StorageAPI {
+    // read -> encrypt -> dispatch. Blocks are read in order, encrypted on the
+    // blocking pool and stored on up to MAX_IN_FLIGHT_CHUNKS nodes at a time;
+    // a chunk whose node fails is retried on another node. The manifest DAG
+    // over the stored chunks goes out last. Every chunk is written as part of
+    // the pending upload `file_id`.
+    pub(super) async fn upload_pipeline(
+        &self,
+        file_id: &str,
+        file_path: &str,
+        nodes: Vec<Node>,
+    ) -> Result<UploadedFile, Box<dyn Error>> {
+        let cipher = SegmentCipher::for_file(&self.key_store, &self.master_key(), file_id)?;
+        let mut hmac = cipher.hmac();
+        let mut leaves = Vec::new();
+        let placement = self.placement(nodes);
+
+        let file = File::open(file_path).await?;
+        let blocks = stream::try_unfold(file, |mut file| async move {
+            let block = read_block(&mut file, PIPELINE_CHUNK_SIZE).await?;
+            Ok::<_, std::io::Error>(if block.is_empty() { None } else { Some((block, file)) })
+        });
+
+        // `try_buffered` keeps file order, so the HMAC sees the segments in
+        // sequence and the IPFS leaves are collected in order
+        let segments = blocks
+            .map_ok(|block| {
+                let cipher = cipher.clone();
+                async move {
+                    tokio::task::spawn_blocking(move || {
+                        let leaf = (Cid::new_v1(ipfs::RAW, &block), block.len() as u64);
+                        (leaf, cipher.encrypt_segment(&block))
+                    })
+                    .await
+                    .map_err(std::io::Error::other)
+                }
+            })
+            .try_buffered(ENCRYPT_WORKERS)
+            .map_ok(|(leaf, segment)| {
+                leaves.push(leaf);
+                hmac.update(&segment);
+                segment
+            });
+
+        let mut stored: Vec<(usize, ChunkInfo)> = segments
+            .enumerate()
+            .map(|(index, segment)| {
+                let placement = &placement;
+                async move {
+                    let chunk = self
+                        .dispatch_chunk(file_id, Uuid::new_v4().to_string(), segment?, placement)
+                        .await?;
+                    Ok::<_, Box<dyn Error>>((index, chunk))
+                }
+            })
+            .buffer_unordered(MAX_IN_FLIGHT_CHUNKS)
+            .try_collect()
+            .await?;
+
+        stored.sort_by_key(|(index, _)| *index);
+        let chunks: Vec<ChunkInfo> = stored.into_iter().map(|(_, chunk)| chunk).collect();
+
+        let (content_id, manifest) = self.store_manifest(file_id, &chunks, &placement).await?;
+        Ok(UploadedFile {
+            chunks,
+            hmac: hex::encode(hmac.finalize().into_bytes()),
+            content_id,
+            manifest,
+            cid: ipfs::unixfs_file(&leaves).root.to_string(),
+        })
+    }
+
+    pub(super) fn placement(&self, nodes: Vec<Node>) -> Mutex<Placement> {
+        Mutex::new(Placement {
+            nodes,
+            in_flight: HashMap::new(),
+            policy: self.network.anti_affinity().cloned(),
+            domains: HashMap::new(),
+        })
+    }
+
+    // Stores the manifest DAG over `chunks`; its blocks are named by their
+    // hash so they can be found by it. Returns the content id and the blocks.
+    pub(super) async fn store_manifest(
+        &self,
+        upload_id: &str,
+        chunks: &[ChunkInfo],
+        placement: &Mutex<Placement>,
+    ) -> Result<(String, Vec<ChunkInfo>), Box<dyn Error>> {
+        let dag = manifest::build(chunks)?;
+        let mut blocks = Vec::with_capacity(dag.blocks.len());
+        for (block_id, block) in dag.blocks {
+            blocks.push(self.dispatch_chunk(upload_id, block_id, block, placement).await?);
+        }
+        Ok((dag.content_id, blocks))
+    }
+
+    pub(super) async fn dispatch_chunk(
+        &self,
+        upload_id: &str,
+        chunk_id: String,
+        segment: Vec<u8>,
+        placement: &Mutex<Placement>,
+    ) -> Result<ChunkInfo, Box<dyn Error>> {
+        let size = segment.len() as u64;
+        let identity = self.network.identity();
+        let reputation = self.network.reputation();
+        let strategy = self.network.placement();
+        let throttle = self.network.bandwidth().throttle(Priority::User);
+        let mut tried = HashSet::new();
+        let mut last_error: Option<Box<dyn Error>> = None;
+
+        for _ in 0..MAX_NODE_ATTEMPTS {
+            let node = match select_node(placement, &chunk_id, size, &tried, &reputation, &*strategy).await {
+                Some(node) => node,
+                None => break,
+            };
+            tried.insert(node.id.clone());
+
+            // Reserve the space against the node's announced free space and
+            // every other pending upload before anything is sent
+            let write = PendingWrite {
+                chunk_id: chunk_id.clone(),
+                node_id: node.id.clone(),
+                size,
+            };
+            let announced = match self.network.get_node_by_id(&node.id).await {
+                Some(announced) => announced.available_space,
+                None => node.available_space + size,
+            };
+            if let Err(e) = self.metadata.reserve(upload_id, write.clone(), announced).await {
+                eprintln!("Could not reserve space for chunk {} on node {}: {:?}", chunk_id, node.id, e);
+                release_node(placement, &node.id, Some(size)).await;
+                last_error = Some(e.into());
+                continue;
+            }
+
+            let started = std::time::Instant::now();
+            let result =
+                store_chunk_on_node(&chunk_id, &segment, &node, &identity, &throttle, 1, CHUNK_TRANSFER_TIMEOUT).await;
+            reputation.record_transfer(&node.id, result.is_ok(), result.is_ok().then(|| started.elapsed()));
+            release_node(placement, &node.id, result.is_err().then_some(size)).await;
+            // A failed attempt may still have reached the node; if it cannot
+            // be deleted now it stays recorded and is removed at commit
+            if result.is_err() && remove_write(&self.network, &write).await {
+                let _ = self.metadata.release(upload_id, &write).await;
+            }
+
+            match result {
+                Ok(_) => {
+                    return Ok(ChunkInfo {
+                        post_root: Some(hex::encode(commit(&chunk_id, &segment).root)),
+                        chunk_id,
+                        node_id: node.id,
+                        size,
+                        hash: calculate_hash(&segment),
+                        cid: None,
+                    })
+                }
+                Err(e) => {
+                    eprintln!("Chunk {} could not be stored on node {}, trying another node: {:?}", chunk_id, node.id, e);
+                    last_error = Some(e.into());
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            "No suitable node found to store the chunk (not enough space or the anti-affinity policy is exhausted).".into()
+        }))
+    }
+
+    // Writes the file decrypted to `destination_path`. The file is removed
+    // again if any chunk or the file HMAC does not verify.
+    pub(super) async fn download_pipeline(&self, file: &FileMetadata, destination_path: &str) -> Result<(), Box<dyn Error>> {
+        let mut output = File::create(destination_path).await?;
+        let result = self.write_plaintext(file, &mut output).await;
+        if result.is_err() {
+            drop(output);
+            let _ = tokio::fs::remove_file(destination_path).await;
+        }
+        result.map(|_| ())
+    }
+
+    // Writes the decrypted file to `writer` in file order and returns the
+    // number of bytes written
+    pub(super) async fn write_plaintext<W: AsyncWrite + Unpin>(
+        &self,
+        file: &FileMetadata,
+        writer: &mut W,
+    ) -> Result<u64, Box<dyn Error>> {
+        let mut plaintext = Box::pin(self.plaintext_stream(file)?);
+        let mut written = 0;
+        while let Some(bytes) = plaintext.next().await {
+            let bytes = bytes?;
+            writer.write_all(&bytes).await?;
+            written += bytes.len() as u64;
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+
+    // The decrypted file as a stream of chunks in file order. Up to
+    // MAX_IN_FLIGHT_CHUNKS chunks are fetched ahead of the reader. The fetches
+    // run inside the stream, so dropping it cancels the ones outstanding.
+    // Every chunk is checked against its hash before it is decrypted; the file
+    // HMAC can only be checked at the end, and a mismatch ends the stream with
+    // an error after the last chunk.
+    pub(super) fn plaintext_stream(
+        &self,
+        file: &FileMetadata,
+    ) -> Result<impl Stream<Item = io::Result<Bytes>> + 'static, Box<dyn Error>> {
+        if file.imported {
+            return Err(format!("File {} holds the blocks of an imported CAR, export it as a CAR instead", file.file_id).into());
+        }
+        // The chunk list has to be the one the content id was computed over
+        if !file.content_id.is_empty() && manifest::build(&file.chunks)?.content_id != file.content_id {
+            return Err(format!("Chunks of file {} do not match its content id", file.file_id).into());
+        }
+        let cipher = SegmentCipher::load(&self.key_store, &self.master_key(), &file.file_id)?;
+        let hmac = cipher.hmac();
+
+        let api = self.clone();
+        let file_id = file.file_id.clone();
+        let segments = stream::iter(file.chunks.clone())
+            .map(move |chunk| {
+                let api = api.clone();
+                let file_id = file_id.clone();
+                async move {
+                    api.fetch_chunk(&file_id, &chunk)
+                        .await
+                        .map_err(|e| io::Error::other(e.to_string()))
+                }
+            })
+            .buffered(MAX_IN_FLIGHT_CHUNKS);
+
+        let state = (Box::pin(segments), cipher, hmac, file.hmac.clone());
+        Ok(stream::try_unfold(state, |(mut segments, cipher, mut hmac, expected)| async move {
+            match segments.next().await {
+                Some(segment) => {
+                    let segment = segment?;
+                    hmac.update(&segment);
+                    let plaintext = Bytes::from(cipher.decrypt_segment(&segment)?);
+                    Ok(Some((plaintext, (segments, cipher, hmac, expected))))
+                }
+                None if hex::encode(hmac.finalize().into_bytes()) != expected => {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "HMAC verification failed"))
+                }
+                None => Ok(None),
+            }
+        }))
+    }
+
+    // Reads one chunk from the first holder that returns the expected bytes.
+    // Every copy is checked against the hash recorded at upload; a corrupt
+    // copy is reported for repair and the next holder is tried.
+    pub(super) async fn fetch_chunk(&self, file_id: &str, chunk: &ChunkInfo) -> Result<Vec<u8>, Box<dyn Error>> {
+        let identity = self.network.identity();
+        let reputation = self.network.reputation();
+        let mut last_error: Option<Box<dyn Error>> = None;
+
+        for node in self.chunk_holders(chunk).await? {
+            let started = std::time::Instant::now();
+            let result = async {
+                let mut client = ChunkClient::connect(&node, &identity)
+                    .await?
+                    .with_throttle(self.network.bandwidth().throttle(Priority::User));
+                client.get(&chunk.chunk_id).await
+            }
+            .await;
+            let result = result.map_err(Box::<dyn Error>::from).and_then(|data| {
+                let hash = calculate_hash(&data);
+                if hash == chunk.hash {
+                    return Ok(data);
+                }
+                self.integrity
+                    .report_corrupt(corrupt_copy(file_id, &chunk.chunk_id, &node.id, &chunk.hash, &hash));
+                Err(format!("Chunk {} from node {} does not match its hash", chunk.chunk_id, node.id).into())
+            });
+            reputation.record_transfer(&node.id, result.is_ok(), result.is_ok().then(|| started.elapsed()));
+
+            match result {
+                Ok(data) => {
+                    self.integrity.record_verified(&node.id);
+                    return Ok(data);
+                }
+                Err(e) => {
+                    eprintln!("Failed to read chunk {} from node {}: {:?}", chunk.chunk_id, node.id, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| format!("No reachable holder for chunk {}", chunk.chunk_id).into()))
+    }
+
+    // Deletes the file's chunks from their nodes, MAX_IN_FLIGHT_CHUNKS at a time
+    pub(super) async fn delete_chunks(&self, chunks: &[ChunkInfo]) -> Result<(), Box<dyn Error>> {
+        let identity = self.network.identity();
+        stream::iter(chunks.iter())
+            .map(|chunk| {
+                let identity = &identity;
+                async move {
+                    let mut node = self
+                        .network
+                        .get_node_by_id(&chunk.node_id)
+                        .await
+                        .ok_or_else(|| format!("Node {} not found", chunk.node_id))?;
+                    delete_chunk_from_node(&node, &chunk.chunk_id, identity).await?;
+                    node.free_up_space(chunk.size).await;
+                    Ok::<_, Box<dyn Error>>(())
+                }
+            })
+            .buffer_unordered(MAX_IN_FLIGHT_CHUNKS)
+            .try_collect::<Vec<()>>()
+            .await?;
+        Ok(())
+    }
+}
+
+// Picks a node for a chunk of `size` bytes among the nodes not tried yet,
+// preferring nodes with no transfer in flight so concurrent chunks spread out
+async fn select_node(
+    placement: &Mutex<Placement>,
+    chunk_id: &str,
+    size: u64,
+    tried: &HashSet<String>,
+    reputation: &crate::reputation::Reputation,
+    strategy: &dyn PlacementStrategy,
+) -> Option<Node> {
+    let mut placement = placement.lock().await;
+    let untried: Vec<Node> = placement
+        .nodes
+        .iter()
+        .filter(|node| !tried.contains(&node.id))
+        .filter(|node| match &placement.policy {
+            Some(policy) => policy.allows(&policy.domain_of(&node.id, &node.labels), &placement.domains),
+            None => true,
+        })
+        .cloned()
+        .collect();
+    let mut idle: Vec<Node> = untried
+        .iter()
+        .filter(|node| placement.in_flight.get(&node.id).copied().unwrap_or(0) == 0)
+        .cloned()
+        .collect();
+
+    let node_id = match can_store_file(&mut idle, chunk_id, size, reputation, strategy).await {
+        Some(node_id) => node_id,
+        None => can_store_file(&mut untried.clone(), chunk_id, size, reputation, strategy).await?,
+    };
+    *placement.in_flight.entry(node_id.clone()).or_insert(0) += 1;
+    let node = placement.nodes.iter_mut().find(|node| node.id == node_id)?;
+    node.reduce_available_space(size);
+    let node = node.clone();
+    if let Some(policy) = placement.policy.clone() {
+        *placement.domains.entry(policy.domain_of(&node.id, &node.labels)).or_insert(0) += 1;
+    }
+    Some(node)
+}
+
+// Ends a transfer to `node_id`; `refund` gives back the space and the
+// failure domain slot of a failed chunk
+async fn release_node(placement: &Mutex<Placement>, node_id: &str, refund: Option<u64>) {
+    let mut guard = placement.lock().await;
+    let placement = &mut *guard;
+    if let Some(count) = placement.in_flight.get_mut(node_id) {
+        *count = count.saturating_sub(1);
+    }
+    let size = match refund {
+        Some(size) => size,
+        None => return,
+    };
+    let node = match placement.nodes.iter_mut().find(|node| node.id == node_id) {
+        Some(node) => node,
+        None => return,
+    };
+    node.available_space = (node.available_space + size).min(node.total_space);
+    let domain = placement
+        .policy
+        .as_ref()
+        .map(|policy| policy.domain_of(&node.id, &node.labels));
+    if let Some(count) = domain.and_then(|domain| placement.domains.get_mut(&domain)) {
+        *count = count.saturating_sub(1);
+    }
+}
+
+// Reads up to `size` bytes; shorter only at the end of the file
+async fn read_block(file: &mut File, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut block = vec![0u8; size];
+    let mut filled = 0;
+    while filled < size {
+        let read = file.read(&mut block[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    block.truncate(filled);
+    Ok(block)
+}
@@ -0,0 +1,61 @@
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+use super::metadata::MetadataStore;
+use crate::p2p::Network;
+use crate::proof_of_spacetime::{check_node, Commitment, FileCheck, LEAF_SIZE};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Proof of spacetime for the stored chunks. Every round each node holding
+// chunks is challenged on one of them over its chunk service. The
+// commitment is the Merkle root kept with the chunk in the file metadata, so
+// it follows the chunk when it is moved or repaired. Outcomes feed the
+// node's reputation.
+pub(super) async fn run_checks(network: Arc<Network>, metadata: Arc<MetadataStore>) {
+    loop {
+        sleep(CHECK_INTERVAL).await;
+        check_round(&network, &metadata).await;
+    }
+}
+
+// Challenges every holder once and returns whether each passed
+pub(super) async fn check_round(network: &Network, metadata: &MetadataStore) -> Vec<(String, bool)> {
+    let files = match metadata.files().await {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Skipping the proof-of-spacetime round, the file metadata is unavailable: {:?}", e);
+            return Vec::new();
+        }
+    };
+    let mut held: HashMap<String, Vec<Commitment>> = HashMap::new();
+    for chunk in files.iter().flat_map(|file| file.stored_chunks()) {
+        let Some(root) = chunk.post_root.as_deref().and_then(|root| hex::decode(root).ok()?.try_into().ok()) else {
+            continue;
+        };
+        held.entry(chunk.node_id.clone()).or_default().push(Commitment {
+            file_name: chunk.chunk_id.clone(),
+            root,
+            leaf_count: chunk.size.div_ceil(LEAF_SIZE as u64).max(1),
+        });
+    }
+
+    let reputation = network.reputation();
+    let mut outcomes = Vec::new();
+    for (node_id, commitments) in held {
+        let commitment = commitments.choose(&mut thread_rng()).unwrap().clone();
+        let passed = match check_node(network, &node_id, &FileCheck::Merkle(commitment.clone())).await {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Node {} failed challenge on chunk {}: {}", node_id, commitment.file_name, e);
+                false
+            }
+        };
+        reputation.record_post(&node_id, passed);
+        outcomes.push((node_id, passed));
+    }
+    outcomes
+}
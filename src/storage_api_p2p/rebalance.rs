@@ -0,0 +1,150 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use super::metadata::MetadataStore;
+use super::{calculate_hash, delete_chunk_from_node};
+use crate::integrity::{corrupt_copy, Integrity};
+use crate::p2p::bandwidth::Priority;
+use crate::p2p::chunk_service::ChunkClient;
+use crate::p2p::Network;
+use crate::placement::Candidate;
+use crate::rebalance::{plan_moves, ChunkMove, PlacedChunk, Rebalancer};
+use crate::storage::store_chunk_on_node;
+
+const CHUNK_TRANSFER_TIMEOUT: u64 = 120; // seconds
+
+// Runs a round every REBALANCE_INTERVAL, or earlier when triggered
+pub(super) async fn run_rebalancer(
+    network: Arc<Network>,
+    metadata: Arc<MetadataStore>,
+    rebalancer: Arc<Rebalancer>,
+    integrity: Arc<Integrity>,
+) {
+    loop {
+        rebalancer.wait_for_round().await;
+        rebalancer.wait_while_paused().await;
+        rebalance_round(&network, &metadata, &rebalancer, &integrity).await;
+    }
+}
+
+// Plans the moves for the live nodes and carries them out one chunk at a
+// time, at rebalance priority so user transfers keep most of the bandwidth
+async fn rebalance_round(
+    network: &Network,
+    metadata: &MetadataStore,
+    rebalancer: &Rebalancer,
+    integrity: &Integrity,
+) {
+    let nodes: Vec<Candidate> = network.get_live_nodes().await.iter().map(Candidate::from).collect();
+    let files = match metadata.files().await {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Skipping the rebalancing round, the file metadata is unavailable: {:?}", e);
+            return;
+        }
+    };
+    let chunks: Vec<PlacedChunk> = files
+        .iter()
+        .flat_map(|file| {
+            file.stored_chunks().map(move |chunk| PlacedChunk {
+                file_id: file.file_id.clone(),
+                chunk_id: chunk.chunk_id.clone(),
+                size: chunk.size,
+                hash: chunk.hash.clone(),
+                node_id: chunk.node_id.clone(),
+            })
+        })
+        .collect();
+
+    let plan = plan_moves(
+        &chunks,
+        &nodes,
+        &*network.placement(),
+        network.anti_affinity(),
+        rebalancer.budget(),
+    );
+    if !plan.is_empty() {
+        println!("Rebalancing {} chunk(s)", plan.len());
+    }
+
+    rebalancer.start_round(plan.clone());
+    for chunk_move in plan {
+        rebalancer.wait_while_paused().await;
+        rebalancer.start_move(&chunk_move);
+        let result = move_chunk(network, metadata, integrity, &chunk_move).await;
+        if let Err(e) = &result {
+            eprintln!(
+                "Failed to move chunk {} from node {} to node {}: {:?}",
+                chunk_move.chunk_id, chunk_move.from, chunk_move.to, e
+            );
+        }
+        rebalancer.finish_move(&chunk_move, result.map_err(|e| e.to_string()));
+    }
+    rebalancer.end_round();
+}
+
+// copy -> verify -> switch metadata -> delete the source. Until the metadata
+// is switched the source copy is the one in use, so a failed move only
+// leaves an extra copy behind, which is removed again when possible.
+async fn move_chunk(
+    network: &Network,
+    metadata: &MetadataStore,
+    integrity: &Integrity,
+    chunk_move: &ChunkMove,
+) -> Result<(), Box<dyn Error>> {
+    let identity = network.identity();
+    let source = network
+        .get_node_by_id(&chunk_move.from)
+        .await
+        .ok_or_else(|| format!("Node {} not found", chunk_move.from))?;
+    let target = network
+        .get_node_by_id(&chunk_move.to)
+        .await
+        .ok_or_else(|| format!("Node {} not found", chunk_move.to))?;
+
+    // Copy
+    let mut client = ChunkClient::connect(&source, &identity)
+        .await?
+        .with_throttle(network.bandwidth().throttle(Priority::Rebalance));
+    let data = client.get(&chunk_move.chunk_id).await?;
+    let hash = calculate_hash(&data);
+    if hash != chunk_move.hash {
+        integrity.report_corrupt(corrupt_copy(
+            &chunk_move.file_id,
+            &chunk_move.chunk_id,
+            &source.id,
+            &chunk_move.hash,
+            &hash,
+        ));
+        return Err(format!("Chunk {} on node {} does not match its hash", chunk_move.chunk_id, source.id).into());
+    }
+    let throttle = network.bandwidth().throttle(Priority::Rebalance);
+    store_chunk_on_node(&chunk_move.chunk_id, &data, &target, &identity, &throttle, 1, CHUNK_TRANSFER_TIMEOUT).await?;
+
+    // Verify
+    let stored = ChunkClient::connect(&target, &identity).await?.has(&chunk_move.chunk_id).await?;
+    if stored != Some(data.len() as u64) {
+        let _ = delete_chunk_from_node(&target, &chunk_move.chunk_id, &identity).await;
+        return Err(format!("Chunk {} was not stored intact on node {}", chunk_move.chunk_id, target.id).into());
+    }
+
+    // Switch metadata, unless the file was deleted or the chunk moved meanwhile
+    let switched = metadata
+        .move_chunk(&chunk_move.file_id, &chunk_move.chunk_id, &chunk_move.from, &chunk_move.to)
+        .await;
+    if !matches!(switched, Ok(true)) {
+        let _ = delete_chunk_from_node(&target, &chunk_move.chunk_id, &identity).await;
+        return Err(format!("Chunk {} changed while it was moved; the copy was discarded", chunk_move.chunk_id).into());
+    }
+
+    // Delete the source
+    if let Err(e) = delete_chunk_from_node(&source, &chunk_move.chunk_id, &identity).await {
+        return Err(format!(
+            "Chunk {} moved to node {} but the old copy on node {} could not be deleted: {}",
+            chunk_move.chunk_id, target.id, source.id, e
+        )
+        .into());
+    }
+    println!("Chunk {} moved from node {} to node {}", chunk_move.chunk_id, source.id, target.id);
+    Ok(())
+}
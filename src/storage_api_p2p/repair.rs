@@ -0,0 +1,88 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use super::metadata::MetadataStore;
+use super::{calculate_hash, chunk_holders, delete_chunk_from_node};
+use crate::integrity::{CorruptCopy, Integrity};
+use crate::p2p::bandwidth::Priority;
+use crate::p2p::chunk_service::ChunkClient;
+use crate::p2p::dht::Dht;
+use crate::p2p::Network;
+use crate::storage::store_chunk_on_node;
+
+const CHUNK_TRANSFER_TIMEOUT: u64 = 120; // seconds
+
+// Repairs the corrupt copies reported by readers, one at a time and at
+// repair priority
+pub(super) async fn run_repair(
+    network: Arc<Network>,
+    dht: Arc<Dht>,
+    metadata: Arc<MetadataStore>,
+    integrity: Arc<Integrity>,
+) {
+    loop {
+        let copy = integrity.next_corrupt().await;
+        let result = repair_copy(&network, &dht, &metadata, &copy).await;
+        match &result {
+            Ok(()) => println!("Repaired chunk {} on node {}", copy.chunk_id, copy.node_id),
+            Err(e) => eprintln!("Failed to repair chunk {} on node {}: {:?}", copy.chunk_id, copy.node_id, e),
+        }
+        integrity.record_repair(&copy.node_id, result.is_ok());
+    }
+}
+
+// Overwrites the corrupt copy with an intact one read from another holder.
+// Chunks carry no parity yet (see hello::ERASURE_CODING_SUPPORTED), so a
+// chunk whose only copy is corrupt cannot be rebuilt.
+async fn repair_copy(
+    network: &Network,
+    dht: &Dht,
+    metadata: &MetadataStore,
+    copy: &CorruptCopy,
+) -> Result<(), Box<dyn Error>> {
+    let chunk = match metadata.file(&copy.file_id).await? {
+        Some(file) => file.stored_chunks().find(|chunk| chunk.chunk_id == copy.chunk_id).cloned(),
+        None => None,
+    };
+    let chunk = match chunk {
+        Some(chunk) => chunk,
+        None => return Ok(()), // the file was deleted meanwhile
+    };
+    let identity = network.identity();
+
+    let holders = chunk_holders(network, dht, &chunk).await?;
+    let mut intact = None;
+    for node in holders {
+        if node.id == copy.node_id {
+            continue;
+        }
+        let data = async {
+            let mut client = ChunkClient::connect(&node, &identity)
+                .await?
+                .with_throttle(network.bandwidth().throttle(Priority::Repair));
+            client.get(&chunk.chunk_id).await
+        }
+        .await;
+        if let Ok(data) = data {
+            if calculate_hash(&data) == chunk.hash {
+                intact = Some(data);
+                break;
+            }
+        }
+    }
+    let data = intact.ok_or_else(|| format!("No intact copy of chunk {} is left", chunk.chunk_id))?;
+
+    let target = network
+        .get_node_by_id(&copy.node_id)
+        .await
+        .ok_or_else(|| format!("Node {} not found", copy.node_id))?;
+    let _ = delete_chunk_from_node(&target, &chunk.chunk_id, &identity).await;
+    let throttle = network.bandwidth().throttle(Priority::Repair);
+    store_chunk_on_node(&chunk.chunk_id, &data, &target, &identity, &throttle, 1, CHUNK_TRANSFER_TIMEOUT).await?;
+
+    let stored = ChunkClient::connect(&target, &identity).await?.has(&chunk.chunk_id).await?;
+    if stored != Some(data.len() as u64) {
+        return Err(format!("Chunk {} was not stored intact on node {}", chunk.chunk_id, target.id).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,553 @@
+// In-process cluster for integration tests. Every node is a full StorageAPI
+// with its own tokio runtime, loopback port, storage directory and key store,
+// so a crash can be simulated by dropping the runtime and a restart by
+// starting a new one on the same directory. Connection and disk faults are
+// injected through p2p::faults. Only the basic round trip runs by default;
+// the heavier scenarios are ignored and run with `cargo test -- --ignored`.
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::env;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tokio::sync::oneshot;
+use tokio::time::{sleep, Duration, Instant};
+use uuid::Uuid;
+
+use super::StorageAPI;
+use crate::p2p::faults::{self, FaultPlan};
+use crate::p2p::identity::NodeIdentity;
+use crate::p2p::membership::PeerState;
+
+// Overrides the seed of every cluster, to replay a failed run
+const SEED_VAR: &str = "SIM_SEED";
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a crashed node's runtime gets to drop its tasks and sockets
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+// The key stores are per node, but they are all encrypted with this key
+const MASTER_KEY: [u8; 32] = *b"simulated-cluster-master-key-001";
+
+struct RunningNode {
+    api: Arc<StorageAPI>,
+    stop: oneshot::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+pub struct SimNode {
+    node_id: String,
+    address: SocketAddr,
+    storage_path: PathBuf,
+    // Bound when the cluster starts and handed to the node on its first boot
+    listener: Option<TcpListener>,
+    running: Option<RunningNode>,
+}
+
+pub struct SimCluster {
+    root: PathBuf,
+    rng: StdRng,
+    faults: Arc<FaultPlan>,
+    nodes: Vec<SimNode>,
+    // Every node is a metadata node, so they all share one metadata store
+    metadata_nodes: HashMap<String, String>,
+}
+
+impl SimCluster {
+    // Starts `size` nodes and waits until every node sees all others alive
+    pub async fn start(size: usize, seed: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        let seed = env::var(SEED_VAR).ok().and_then(|s| s.parse().ok()).unwrap_or(seed);
+        println!("Simulated cluster of {} nodes, seed {} (set {} to replay)", size, seed, SEED_VAR);
+
+        let mut cluster = SimCluster {
+            root: env::temp_dir().join(format!("sim-cluster-{}", Uuid::new_v4())),
+            rng: StdRng::seed_from_u64(seed),
+            faults: Arc::new(FaultPlan::new(seed)),
+            nodes: Vec::new(),
+            metadata_nodes: HashMap::new(),
+        };
+        for index in 0..size {
+            let storage_path = cluster.root.join(format!("node-{}", index));
+            std::fs::create_dir_all(&storage_path)?;
+            // Create the identity up front so faults can be registered before the node dials anyone
+            let identity = NodeIdentity::load_or_generate(&storage_path.join("node_identity.json"))?;
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            cluster.nodes.push(SimNode {
+                node_id: identity.node_id(),
+                address: listener.local_addr()?,
+                storage_path,
+                listener: Some(listener),
+                running: None,
+            });
+        }
+        cluster.metadata_nodes = cluster
+            .nodes
+            .iter()
+            .map(|node| (node.node_id.clone(), node.address.to_string()))
+            .collect();
+        for index in 0..size {
+            cluster.boot(index).await?;
+        }
+        cluster.wait_until_converged().await?;
+        Ok(cluster)
+    }
+
+    pub fn size(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn api(&self, index: usize) -> Arc<StorageAPI> {
+        self.nodes[index]
+            .running
+            .as_ref()
+            .map(|running| running.api.clone())
+            .unwrap_or_else(|| panic!("Node {} is not running", index))
+    }
+
+    pub fn node_id(&self, index: usize) -> &str {
+        &self.nodes[index].node_id
+    }
+
+    pub fn storage_path(&self, index: usize) -> &Path {
+        &self.nodes[index].storage_path
+    }
+
+    // Writes a file of `size` bytes generated from the cluster seed
+    pub fn random_file(&mut self, name: &str, size: usize) -> std::io::Result<PathBuf> {
+        let mut data = vec![0u8; size];
+        self.rng.fill(&mut data[..]);
+        let path = self.root.join(name);
+        std::fs::write(&path, data)?;
+        Ok(path)
+    }
+
+    pub fn scratch_path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    // Connections between nodes of different groups fail until heal()
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let groups = groups
+            .iter()
+            .map(|group| group.iter().map(|&index| self.nodes[index].node_id.clone()).collect())
+            .collect();
+        self.faults.partition(groups);
+    }
+
+    pub fn drop_connections(&self, rate: f64) {
+        self.faults.set_drop_rate(rate);
+    }
+
+    pub fn delay(&self, from: usize, to: usize, delay: Duration) {
+        self.faults.set_delay(&self.nodes[from].node_id, &self.nodes[to].node_id, delay);
+    }
+
+    pub fn set_disk_full(&self, index: usize, full: bool) {
+        self.faults.set_disk_full(&self.nodes[index].node_id, full);
+    }
+
+    pub fn heal(&self) {
+        self.faults.heal();
+    }
+
+    // Stops the node's runtime, which drops its listener, tasks and in-memory state.
+    // The storage directory, identity and key store survive for restart().
+    pub async fn crash(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        if let Some(running) = node.running.take() {
+            faults::unregister(&node.node_id);
+            let _ = running.stop.send(());
+            drop(running.api);
+            let _ = tokio::task::spawn_blocking(move || running.thread.join()).await;
+            println!("Simulated crash of node {}", index);
+        }
+    }
+
+    // Starts a crashed node again on the same address and directory
+    pub async fn restart(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        if self.nodes[index].running.is_none() {
+            self.boot(index).await?;
+            println!("Simulated restart of node {}", index);
+        }
+        Ok(())
+    }
+
+    async fn boot(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let peers: Vec<SocketAddr> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(other, node)| *other != index && node.running.is_some())
+            .map(|(_, node)| node.address)
+            .collect();
+        let node = &mut self.nodes[index];
+        // A restarted node binds its old address again
+        let listener = match node.listener.take() {
+            Some(listener) => listener,
+            None => TcpListener::bind(node.address)?,
+        };
+        self.faults.set_address(&node.node_id, &node.address.to_string());
+        faults::register(&node.node_id, self.faults.clone());
+        node.running = Some(spawn_node(&node.storage_path, listener, peers, self.metadata_nodes.clone()).await?);
+        Ok(())
+    }
+
+    // Waits until every running node considers every other running node alive
+    pub async fn wait_until_converged(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let deadline = Instant::now() + CONVERGENCE_TIMEOUT;
+        let running: Vec<&SimNode> = self.nodes.iter().filter(|node| node.running.is_some()).collect();
+        loop {
+            let mut converged = true;
+            for node in &running {
+                let api = &node.running.as_ref().unwrap().api;
+                let alive = api
+                    .list_peer_states()
+                    .await
+                    .into_iter()
+                    .filter(|status| status.state == PeerState::Alive)
+                    .filter(|status| running.iter().any(|other| other.node_id == status.node_id))
+                    .count();
+                converged &= alive + 1 >= running.len();
+            }
+            if converged {
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                return Err("Simulated cluster did not converge in time".into());
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+    }
+}
+
+impl Drop for SimCluster {
+    fn drop(&mut self) {
+        for node in &mut self.nodes {
+            faults::unregister(&node.node_id);
+            if let Some(running) = node.running.take() {
+                let _ = running.stop.send(());
+                drop(running.api);
+                let _ = running.thread.join();
+            }
+        }
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+// Runs one node on a runtime of its own until it is told to stop
+async fn spawn_node(
+    storage_path: &Path,
+    listener: TcpListener,
+    peers: Vec<SocketAddr>,
+    metadata_nodes: HashMap<String, String>,
+) -> Result<RunningNode, Box<dyn std::error::Error>> {
+    let storage_path = storage_path.to_path_buf();
+    let (started_tx, started_rx) = oneshot::channel();
+    let (stop, stop_rx) = oneshot::channel::<()>();
+    listener.set_nonblocking(true)?;
+
+    let thread = std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                let _ = started_tx.send(Err(e.to_string()));
+                return;
+            }
+        };
+        let started = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(listener).map_err(|e| e.to_string())?;
+            StorageAPI::with_listener(&storage_path.to_string_lossy(), listener, peers, Some(metadata_nodes))
+                .await
+                .map(|api| {
+                    Arc::new(
+                        api.with_key_store(storage_path.join("key_data.json"))
+                            .with_master_key(MASTER_KEY),
+                    )
+                })
+                .map_err(|e| e.to_string())
+        });
+        let ok = started.is_ok();
+        let _ = started_tx.send(started);
+        if ok {
+            runtime.block_on(async {
+                let _ = stop_rx.await;
+            });
+        }
+        // Dropping the runtime aborts every task of the node, as a crash would
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    });
+
+    let api = started_rx.await.map_err(|_| "Node thread exited during startup")??;
+    Ok(RunningNode { api, stop, thread })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{StreamExt, TryStreamExt};
+
+    const FILE_SIZE: usize = 3 * 1024 * 1024 + 123;
+
+    async fn round_trip(cluster: &mut SimCluster, node: usize, name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let source = cluster.random_file(name, FILE_SIZE)?;
+        let api = cluster.api(node);
+        let file_id = api.upload_file(&source.to_string_lossy(), "sim", "").await?;
+        let destination = cluster.scratch_path(&format!("{}.out", name));
+        api.download_file_for_reading(&file_id, &destination.to_string_lossy(), "")
+            .await?;
+        Ok(std::fs::read(&source)? == std::fs::read(&destination)?)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_and_download_across_nodes() {
+        let mut cluster = SimCluster::start(3, 1).await.unwrap();
+        assert!(round_trip(&mut cluster, 0, "plain").await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_partitioned_or_full_nodes_refuse_uploads() {
+        let mut cluster = SimCluster::start(3, 2).await.unwrap();
+
+        cluster.partition(&[&[0], &[1, 2]]);
+        assert!(round_trip(&mut cluster, 0, "partitioned").await.is_err());
+        cluster.heal();
+
+        cluster.set_disk_full(1, true);
+        cluster.set_disk_full(2, true);
+        assert!(round_trip(&mut cluster, 0, "full").await.is_err());
+        cluster.heal();
+
+        assert!(round_trip(&mut cluster, 0, "healed").await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_delayed_and_dropped_connections() {
+        let mut cluster = SimCluster::start(3, 4).await.unwrap();
+
+        cluster.delay(0, 1, Duration::from_millis(300));
+        cluster.delay(0, 2, Duration::from_millis(300));
+        let started = Instant::now();
+        assert!(round_trip(&mut cluster, 0, "delayed").await.unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        cluster.drop_connections(1.0);
+        assert!(round_trip(&mut cluster, 0, "dropped").await.is_err());
+    }
+
+    // Chunk files, named by their uuid, in a node's storage directory
+    fn stored_chunks(cluster: &SimCluster, index: usize) -> usize {
+        std::fs::read_dir(cluster.storage_path(index))
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| Uuid::parse_str(&entry.file_name().to_string_lossy()).is_ok())
+            .count()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_failed_upload_rolls_back_its_chunks() {
+        let mut cluster = SimCluster::start(3, 5).await.unwrap();
+        let source = cluster.random_file("rollback", 16 * 1024 * 1024).unwrap();
+        let api = cluster.api(0);
+
+        // Slow transfers down so the disks fill up while the upload is running
+        cluster.delay(0, 1, Duration::from_millis(300));
+        cluster.delay(0, 2, Duration::from_millis(300));
+        let fill_disks = async {
+            for _ in 0..1000 {
+                if stored_chunks(&cluster, 1) + stored_chunks(&cluster, 2) > 0 {
+                    cluster.set_disk_full(1, true);
+                    cluster.set_disk_full(2, true);
+                    return;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let path = source.to_string_lossy().into_owned();
+        let (result, ()) = tokio::join!(api.upload_file(&path, "sim", ""), fill_disks);
+        assert!(result.is_err());
+
+        // Every chunk that made it to a node was deleted again
+        assert_eq!(stored_chunks(&cluster, 1) + stored_chunks(&cluster, 2), 0);
+        assert!(api.metadata().pending_uploads().await.unwrap().is_empty());
+        assert!(api.list_files().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_manifest_resolves_by_content_id() {
+        let mut cluster = SimCluster::start(3, 6).await.unwrap();
+        let source = cluster.random_file("manifest", FILE_SIZE).unwrap();
+        let api = cluster.api(0);
+        let file_id = api.upload_file(&source.to_string_lossy(), "sim", "").await.unwrap();
+        let file = api.metadata().file(&file_id).await.unwrap().unwrap();
+        assert!(!file.content_id.is_empty());
+
+        // Any node can walk the DAG from the content id alone
+        let leaves = cluster.api(1).resolve_manifest(&file.content_id).await.unwrap();
+        let leaf_ids: Vec<&str> = leaves.iter().map(|chunk| chunk.chunk_id.as_str()).collect();
+        let chunk_ids: Vec<&str> = file.chunks.iter().map(|chunk| chunk.chunk_id.as_str()).collect();
+        assert_eq!(leaf_ids, chunk_ids);
+
+        let destination = cluster.scratch_path("manifest.part");
+        let written = api
+            .download_range(&file.content_id, 1..3, &destination.to_string_lossy())
+            .await
+            .unwrap();
+        let chunk_size = super::super::pipeline::PIPELINE_CHUNK_SIZE;
+        let expected = &std::fs::read(&source).unwrap()[chunk_size..3 * chunk_size];
+        assert_eq!(written, expected.len() as u64);
+        assert_eq!(std::fs::read(&destination).unwrap(), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_uploads_are_limited_by_stake() {
+        let mut cluster = SimCluster::start(3, 6).await.unwrap();
+        let source = cluster.random_file("stake", FILE_SIZE).unwrap();
+        let source = source.to_string_lossy();
+        let stakes = Arc::new(std::sync::Mutex::new(crate::pbe_::ProgrammableBusinessEngine::new(1_000_000)));
+        let api = (*cluster.api(0)).clone().with_stakes(stakes.clone());
+
+        let quota_error = |e: Box<dyn std::error::Error>| e.downcast::<crate::pbe_::QuotaError>().map(|e| *e).ok();
+        let refused = api.upload_file(&source, "sim", "").await.unwrap_err();
+        assert_eq!(quota_error(refused), Some(crate::pbe_::QuotaError::NoStake { user_id: "sim".to_string() }));
+
+        // 5 MB fits one file of FILE_SIZE but not two
+        stakes.lock().unwrap().stake_tokens("sim", 5).unwrap();
+        let file_id = api.upload_file(&source, "sim", "").await.unwrap();
+        let refused = api.upload_file(&source, "sim", "").await.unwrap_err();
+        assert!(matches!(quota_error(refused), Some(crate::pbe_::QuotaError::QuotaExceeded { used, .. }) if used == FILE_SIZE as u64));
+
+        assert!(api.delete_file(&file_id, "someone-else").await.is_err());
+        api.delete_file(&file_id, "sim").await.unwrap();
+        assert_eq!(api.metadata.quota("sim").await.unwrap().used, 0);
+        api.upload_file(&source, "sim", "").await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_download_stream_and_writer() {
+        let mut cluster = SimCluster::start(3, 6).await.unwrap();
+        let source = cluster.random_file("stream", FILE_SIZE).unwrap();
+        let expected = std::fs::read(&source).unwrap();
+        let api = cluster.api(0);
+        let file_id = api.upload_file(&source.to_string_lossy(), "sim", "").await.unwrap();
+
+        // The chunks are on the other nodes; the key is in the uploader's key store
+        let streamed: Vec<bytes::Bytes> = api.download_stream(&file_id).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(streamed.concat(), expected);
+
+        let mut written = Vec::new();
+        let size = api.download_to_writer(&file_id, &mut written).await.unwrap();
+        assert_eq!(size, FILE_SIZE as u64);
+        assert_eq!(written, expected);
+
+        // Dropping a stream part way through leaves the file readable
+        let mut stream = Box::pin(api.download_stream(&file_id).await.unwrap());
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(&first[..], &expected[..first.len()]);
+        drop(stream);
+        let mut again = Vec::new();
+        api.download_to_writer(&file_id, &mut again).await.unwrap();
+        assert_eq!(again, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_car_export_and_import_round_trip() {
+        let mut cluster = SimCluster::start(3, 6).await.unwrap();
+        let source = cluster.random_file("car", FILE_SIZE).unwrap();
+        let api = cluster.api(0);
+        let file_id = api.upload_file(&source.to_string_lossy(), "sim", "").await.unwrap();
+
+        let exported = cluster.scratch_path("exported.car");
+        let roots = api.export_car(&file_id, &exported.to_string_lossy()).await.unwrap();
+        let reader = crate::ipfs::CarReader::new(std::fs::File::open(&exported).unwrap()).unwrap();
+        assert_eq!(reader.roots().iter().map(|cid| cid.to_string()).collect::<Vec<_>>(), roots);
+
+        let importer = cluster.api(1);
+        let imported_id = importer.import_car(&exported.to_string_lossy(), "sim").await.unwrap();
+        let records = api.cid_lookup().lookup(&roots[0].parse().unwrap()).await.unwrap();
+        let mut holders: Vec<&str> = records.iter().map(|record| record.file_id.as_str()).collect();
+        holders.sort();
+        let mut expected = vec![file_id.as_str(), imported_id.as_str()];
+        expected.sort();
+        assert_eq!(holders, expected);
+
+        // The blocks are stored encrypted, and the node holding their keys
+        // exports them decrypted to the same CAR
+        let imported = importer.metadata().file(&imported_id).await.unwrap().unwrap();
+        let mut blocks = crate::ipfs::CarReader::new(std::fs::File::open(&exported).unwrap()).unwrap();
+        let first = blocks.next_block().unwrap().unwrap();
+        let chunk = &imported.chunks[0];
+        assert_eq!(chunk.cid.as_deref(), Some(first.cid.to_string().as_str()));
+        let holder = (0..cluster.size()).find(|&i| cluster.node_id(i) == chunk.node_id).unwrap();
+        assert_ne!(std::fs::read(cluster.storage_path(holder).join(&chunk.chunk_id)).unwrap(), first.data);
+
+        let reexported = cluster.scratch_path("reexported.car");
+        importer.export_car(&imported_id, &reexported.to_string_lossy()).await.unwrap();
+        assert_eq!(std::fs::read(&reexported).unwrap(), std::fs::read(&exported).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_chunks_survive_a_holder_restart() {
+        let mut cluster = SimCluster::start(3, 3).await.unwrap();
+        let source = cluster.random_file("restart", FILE_SIZE).unwrap();
+        let api = cluster.api(0);
+        let file_id = api.upload_file(&source.to_string_lossy(), "sim", "").await.unwrap();
+        let (chunk_id, holder) = {
+            let file = api.metadata().file(&file_id).await.unwrap().unwrap();
+            let chunk = &file.chunks[0];
+            let holder = (1..cluster.size()).find(|&i| cluster.node_id(i) == chunk.node_id).unwrap();
+            (chunk.chunk_id.clone(), holder)
+        };
+
+        cluster.crash(holder).await;
+        let destination = cluster.scratch_path("restart.out");
+        assert!(api
+            .download_file_for_reading(&file_id, &destination.to_string_lossy(), "")
+            .await
+            .is_err());
+
+        // The chunk is still on the crashed node's disk
+        assert!(cluster.storage_path(holder).join(&chunk_id).exists());
+        cluster.restart(holder).await.unwrap();
+        cluster.wait_until_converged().await.unwrap();
+        api.download_file_for_reading(&file_id, &destination.to_string_lossy(), "")
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&source).unwrap(), std::fs::read(&destination).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "starts a simulated cluster, run with --ignored"]
+    async fn test_holders_are_challenged_over_the_network() {
+        let mut cluster = SimCluster::start(3, 7).await.unwrap();
+        let source = cluster.random_file("post", FILE_SIZE).unwrap();
+        let api = cluster.api(0);
+        let file_id = api.upload_file(&source.to_string_lossy(), "sim", "").await.unwrap();
+
+        let outcomes = super::super::post::check_round(&api.network, &api.metadata()).await;
+        assert!(!outcomes.is_empty());
+        assert!(outcomes.iter().all(|(_, passed)| *passed));
+
+        // A holder that lost its chunks, manifest blocks included, cannot answer for them
+        let file = api.metadata().file(&file_id).await.unwrap().unwrap();
+        let holder = (0..cluster.size()).find(|&i| cluster.node_id(i) == file.chunks[0].node_id).unwrap();
+        for chunk in file.stored_chunks().filter(|chunk| chunk.node_id == cluster.node_id(holder)) {
+            std::fs::remove_file(cluster.storage_path(holder).join(&chunk.chunk_id)).unwrap();
+        }
+        let outcomes = super::super::post::check_round(&api.network, &api.metadata()).await;
+        assert!(outcomes.contains(&(cluster.node_id(holder).to_string(), false)));
+        let score = api.network.reputation().peer_score(cluster.node_id(holder)).unwrap();
+        assert!(score.post_failed > 0.0);
+    }
+}
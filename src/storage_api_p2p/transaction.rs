@@ -0,0 +1,175 @@
+use chrono::Utc;
+use std::error::Error;
+use std::time::Duration;
+
+use super::metadata::{MetadataStore, PendingUpload, PendingWrite};
+use super::{delete_chunk_from_node, FileMetadata};
+use crate::p2p::chunk_service::ChunkClient;
+use crate::p2p::Network;
+
+// An upload runs as a transaction in the metadata store:
+//   begin -> reserve + write every chunk -> prepare (manifest) -> commit
+// Each chunk is recorded as a pending write before it is sent, so a rollback
+// knows every chunk that may have reached a node, including the ones still
+// in flight when the upload failed.
+
+// Uploads still pending after this long are rolled back
+pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+// Lets peer discovery find the nodes holding leftover chunks before recovery
+pub const RECOVERY_DELAY: Duration = Duration::from_secs(15);
+
+pub(super) fn new_upload(file: &FileMetadata, coordinator: &str, session: &str) -> PendingUpload {
+    let now = Utc::now().timestamp() as u64;
+    PendingUpload {
+        upload_id: file.file_id.clone(),
+        owner: file.owner.clone(),
+        file_size: file.file_size,
+        coordinator: coordinator.to_string(),
+        session: session.to_string(),
+        started_at: now,
+        expires_at: now + UPLOAD_TIMEOUT.as_secs(),
+        writes: Vec::new(),
+        manifest: None,
+        aborted: false,
+    }
+}
+
+// Prepares the manifest, deletes the writes that did not make it into it
+// (attempts on nodes that failed) and commits. When the outcome is unknown,
+// e.g. the commit timed out, the upload is aborted; that fails if the commit
+// went through after all, in which case the file is there.
+pub(super) async fn commit(
+    network: &Network,
+    metadata: &MetadataStore,
+    manifest: FileMetadata,
+) -> Result<(), Box<dyn Error>> {
+    let upload_id = manifest.file_id.clone();
+    let result = async {
+        metadata.prepare_upload(manifest.clone()).await?;
+        let writes = metadata.pending_upload(&upload_id).await?.map(|upload| upload.writes).unwrap_or_default();
+        for write in writes.iter().filter(|write| !in_manifest(&manifest, write)) {
+            if remove_write(network, write).await {
+                metadata.release(&upload_id, write).await?;
+            } else {
+                eprintln!("Stray chunk {} of upload {} left on node {}", write.chunk_id, upload_id, write.node_id);
+            }
+        }
+        metadata.commit_upload(&upload_id).await
+    }
+    .await;
+    let e = match result {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    if metadata.file(&upload_id).await.ok().flatten().is_some() {
+        return Ok(());
+    }
+    eprintln!("Failed to commit upload {}, rolling it back: {:?}", upload_id, e);
+    roll_back(network, metadata, &upload_id).await?;
+    Err(e.into())
+}
+
+// Aborts the upload and deletes every chunk it wrote. Writes that cannot be
+// deleted now, e.g. because their node is down, stay recorded and are
+// retried by the next recovery pass.
+pub(super) async fn roll_back(network: &Network, metadata: &MetadataStore, upload_id: &str) -> Result<(), Box<dyn Error>> {
+    if !metadata.abort_upload(upload_id).await? {
+        return Ok(());
+    }
+    let upload = match metadata.pending_upload(upload_id).await? {
+        Some(upload) => upload,
+        None => return Ok(()), // nothing was written
+    };
+
+    let mut remaining = 0;
+    for write in &upload.writes {
+        if remove_write(network, write).await {
+            metadata.release(upload_id, write).await?;
+        } else {
+            remaining += 1;
+        }
+    }
+    if remaining > 0 {
+        return Err(format!(
+            "Upload {} rolled back, {} chunk(s) could not be deleted yet",
+            upload_id, remaining
+        )
+        .into());
+    }
+    println!("Upload {} rolled back, {} chunk(s) deleted", upload_id, upload.writes.len());
+    Ok(())
+}
+
+// Deletes a written chunk; true once the node no longer has it
+pub(super) async fn remove_write(network: &Network, write: &PendingWrite) -> bool {
+    let identity = network.identity();
+    let node = match network.get_node_by_id(&write.node_id).await {
+        Some(node) => node,
+        None => return false,
+    };
+    if delete_chunk_from_node(&node, &write.chunk_id, &identity).await.is_ok() {
+        return true;
+    }
+    // The write may never have arrived
+    match ChunkClient::connect(&node, &identity).await {
+        Ok(mut client) => matches!(client.has(&write.chunk_id).await, Ok(None)),
+        Err(_) => false,
+    }
+}
+
+// Finishes or aborts the uploads left behind by an earlier run of this node
+// and the uploads whose coordinator did not finish them in time. A prepared
+// upload whose chunks are all in place is committed; any other is rolled back.
+pub(super) async fn recover_uploads(network: &Network, metadata: &MetadataStore, session: &str) {
+    let local_id = network.identity().node_id();
+    let now = Utc::now().timestamp() as u64;
+    let uploads = match metadata.pending_uploads().await {
+        Ok(uploads) => uploads,
+        Err(e) => {
+            eprintln!("Failed to list the pending uploads: {:?}", e);
+            return;
+        }
+    };
+
+    for upload in uploads {
+        let leftover = upload.coordinator == local_id && upload.session != session;
+        if !leftover && upload.expires_at > now {
+            continue;
+        }
+        let result = match &upload.manifest {
+            Some(manifest) if !upload.aborted && manifest_stored(network, manifest).await => {
+                commit(network, metadata, manifest.clone()).await
+            }
+            _ => roll_back(network, metadata, &upload.upload_id).await,
+        };
+        match result {
+            Ok(()) => println!("Recovered pending upload {}", upload.upload_id),
+            Err(e) => eprintln!("Failed to recover pending upload {}: {:?}", upload.upload_id, e),
+        }
+    }
+}
+
+// Whether every chunk and manifest block of the file is on its node with the recorded size
+async fn manifest_stored(network: &Network, manifest: &FileMetadata) -> bool {
+    let identity = network.identity();
+    for chunk in manifest.stored_chunks() {
+        let node = match network.get_node_by_id(&chunk.node_id).await {
+            Some(node) => node,
+            None => return false,
+        };
+        let stored = match ChunkClient::connect(&node, &identity).await {
+            Ok(mut client) => client.has(&chunk.chunk_id).await.ok().flatten(),
+            Err(_) => None,
+        };
+        if stored != Some(chunk.size) {
+            return false;
+        }
+    }
+    true
+}
+
+fn in_manifest(manifest: &FileMetadata, write: &PendingWrite) -> bool {
+    manifest
+        .stored_chunks()
+        .any(|chunk| chunk.chunk_id == write.chunk_id && chunk.node_id == write.node_id)
+}